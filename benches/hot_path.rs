@@ -0,0 +1,123 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use typeto_server::{Counters, Retransmit, Room, ServerMessage};
+
+/// A room with `participants` joined, each with a live receiver so sends
+/// aren't short-circuited.
+fn room_with(participants: usize) -> (Room, Vec<broadcast::Receiver<ServerMessage>>) {
+    let mut room = Room::new("bench".to_string());
+    let receivers = (0..participants)
+        .map(|i| {
+            let (tx, rx) = broadcast::channel(32);
+            room.join(
+                format!("participant{}", i),
+                tx,
+                Arc::new(Counters::default()),
+                Arc::default(),
+            )
+            .unwrap();
+            rx
+        })
+        .collect();
+    (room, receivers)
+}
+
+/// Fills every participant's history to the cap with 60-character lines.
+fn fill_history(room: &mut Room, participants: usize) {
+    for i in 0..participants {
+        let id = format!("participant{}", i);
+        for _ in 0..500 {
+            for pos in 0..60 {
+                room.handle_keypress(&id, "x", Some(pos));
+            }
+            room.handle_keypress(&id, "Enter", None);
+        }
+    }
+    room.flush_outbox();
+}
+
+fn keypress(c: &mut Criterion) {
+    let (mut room, _receivers) = room_with(2);
+    c.bench_function("keypress", |b| {
+        b.iter(|| {
+            room.handle_keypress("participant0", black_box("a"), Some(0));
+            room.handle_keypress("participant0", black_box("Backspace"), Some(1));
+            room.flush_outbox();
+        })
+    });
+}
+
+fn render(c: &mut Criterion) {
+    let (mut room, _receivers) = room_with(2);
+    fill_history(&mut room, 2);
+    c.bench_function("render full history", |b| {
+        b.iter(|| black_box(room.render("participant0")))
+    });
+}
+
+fn fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast");
+    for participants in 1..=4 {
+        let (room, _receivers) = room_with(participants);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(participants),
+            &participants,
+            |b, _| {
+                b.iter(|| {
+                    room.broadcast(
+                        ServerMessage::KeyPress {
+                            key: Arc::from("a"),
+                            source: Arc::from("participant0"),
+                            cursor_pos: Some(0),
+                            rev: None,
+                        },
+                        None,
+                    )
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn encoding(c: &mut Criterion) {
+    let (mut room, _receivers) = room_with(2);
+    fill_history(&mut room, 2);
+    let messages = [
+        (
+            "keyPress",
+            ServerMessage::KeyPress {
+                key: Arc::from("a"),
+                source: Arc::from("participant0"),
+                cursor_pos: Some(12),
+                rev: None,
+            },
+        ),
+        (
+            "gotRoom",
+            ServerMessage::GotRoom {
+                room: Box::new(room.render("participant0")),
+            },
+        ),
+    ];
+    let mut group = c.benchmark_group("encode");
+    for (name, message) in &messages {
+        group.bench_with_input(BenchmarkId::new("json", name), message, |b, message| {
+            b.iter(|| serde_json::to_vec(black_box(message)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("msgpack", name), message, |b, message| {
+            b.iter(|| rmp_serde::to_vec_named(black_box(message)).unwrap())
+        });
+        // As each connection sends it: numbered, and kept for a resume.
+        let mut retransmit = Retransmit::default();
+        retransmit.ack(0);
+        group.bench_with_input(BenchmarkId::new("stamped", name), message, |b, message| {
+            b.iter(|| retransmit.stamp(black_box(message), None).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, keypress, render, fan_out, encoding);
+criterion_main!(benches);
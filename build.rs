@@ -0,0 +1,41 @@
+use std::{path::Path, process::Command};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    git_commit();
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/typeto.proto");
+        let descriptors =
+            protox::compile(["typeto.proto"], ["proto"]).expect("proto/typeto.proto is invalid");
+        prost_build::Config::new()
+            .service_generator(tonic_build::configure().service_generator())
+            .compile_fds(descriptors)
+            .expect("failed to generate gRPC code");
+    }
+}
+
+/// Passes the commit being built on as `TYPETO_GIT_COMMIT`, for
+/// `/api/server-info`: from that variable if it is set, as a Docker build
+/// without `.git` can, or else from git.
+fn git_commit() {
+    println!("cargo:rerun-if-env-changed=TYPETO_GIT_COMMIT");
+    for path in [".git/HEAD", ".git/index"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    let commit = std::env::var("TYPETO_GIT_COMMIT").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    if let Some(commit) = commit.filter(|commit| !commit.is_empty()) {
+        println!("cargo:rustc-env=TYPETO_GIT_COMMIT={}", commit);
+    }
+}
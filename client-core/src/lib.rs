@@ -0,0 +1,13 @@
+//! The client half of typeto's WebSocket protocol, kept apart from any
+//! socket or UI so that clients can share one implementation: the state
+//! to resume a dropped connection with, when to reconnect, and how each
+//! delta from the server changes the room. Builds for wasm32, where the
+//! `wasm` feature adds bindings for the web GUI.
+
+mod line;
+mod session;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use line::{apply_key, byte_offset, char_len, direction, is_character, is_edit, is_non_event};
+pub use session::{Session, Update};
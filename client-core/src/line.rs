@@ -0,0 +1,104 @@
+//! Editing a line, shared by the server and its clients so a client's copy
+//! of a line stays what the server has. Cursor positions count characters (Unicode
+//! scalar values) in the order they were typed, whatever direction the line
+//! reads in.
+
+use unicode_bidi::{bidi_class, BidiClass};
+
+const NON_EVENTS: [&str; 19] = [
+    "Shift",
+    "Meta",
+    "Control",
+    "Alt",
+    "Enter",
+    "Escape",
+    "Backspace",
+    "ArrowLeft",
+    "ArrowRight",
+    "ArrowUp",
+    "ArrowDown",
+    "Tab",
+    "Delete",
+    "DeleteAt",
+    "CtrlA",
+    "CtrlE",
+    "CtrlK",
+    "CtrlB",
+    "CtrlF",
+];
+
+/// Whether `key` names an action rather than a character to type.
+pub fn is_non_event(key: &str) -> bool {
+    NON_EVENTS.contains(&key)
+}
+
+/// Whether `key` is sent for what it types rather than what it does. The
+/// server only inserts single characters.
+pub fn is_character(key: &str) -> bool {
+    key.chars().count() == 1 && !NON_EVENTS.contains(&key)
+}
+
+/// How many cursor positions `line` has before its end.
+pub fn char_len(line: &str) -> usize {
+    line.chars().count()
+}
+
+/// The byte offset of character `pos`, or None past the end of `line`.
+pub fn byte_offset(line: &str, pos: usize) -> Option<usize> {
+    line.char_indices()
+        .map(|(at, _)| at)
+        .chain([line.len()])
+        .nth(pos)
+}
+
+/// Whether the server counts `key` at `cursor_pos` as an edit, bumping the
+/// line's revision.
+pub fn is_edit(key: &str, cursor_pos: Option<usize>) -> bool {
+    key == "Enter"
+        || cursor_pos.is_some_and(|pos| match key {
+            "CtrlK" | "Delete" | "DeleteAt" | "Space" => true,
+            "Backspace" => pos > 0,
+            _ => is_character(key),
+        })
+}
+
+/// Applies `key` pressed at `cursor_pos` to a line in progress. Enter, which
+/// ends the line, and keys that only move the cursor leave it alone.
+pub fn apply_key(line: &mut String, key: &str, cursor_pos: Option<usize>) {
+    let Some(pos) = cursor_pos else {
+        return;
+    };
+    let end = |at: &usize| *at < line.len();
+    match (key, byte_offset(line, pos)) {
+        ("CtrlK", Some(at)) => line.truncate(at),
+        ("Delete" | "DeleteAt", Some(at)) if end(&at) => {
+            line.remove(at);
+        }
+        ("Backspace", _) if pos > 0 => {
+            if let Some(at) = byte_offset(line, pos - 1).filter(end) {
+                line.remove(at);
+            }
+        }
+        ("Space", Some(at)) => line.insert(at, ' '),
+        (_, Some(at)) if is_character(key) => line.insert_str(at, key),
+        _ => {}
+    }
+}
+
+/// The direction a line reads in, as the server sends it in `directions`:
+/// that of its first strongly directional character outside an isolate,
+/// `"auto"` if it has none.
+pub fn direction(line: &str) -> &'static str {
+    let mut isolates = 0usize;
+    for c in line.chars() {
+        match bidi_class(c) {
+            BidiClass::LRI | BidiClass::RLI | BidiClass::FSI => isolates += 1,
+            BidiClass::PDI => isolates = isolates.saturating_sub(1),
+            _ if isolates > 0 => {}
+            BidiClass::L => return "ltr",
+            BidiClass::R | BidiClass::AL => return "rtl",
+            _ => {}
+        }
+    }
+    "auto"
+}
@@ -0,0 +1,365 @@
+//! One client's connection to a room across reconnects: what to send when a
+//! socket opens, the room as the server's messages leave it, and when to try
+//! again after the socket drops. Sockets and clocks belong to the caller,
+//! which passes times in as milliseconds from any fixed point.
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::line::{apply_key, char_len, direction, is_edit};
+
+/// Wait before the first reconnect attempt; doubled after each failure.
+const RETRY_MIN_MS: u64 = 500;
+const RETRY_MAX_MS: u64 = 5_000;
+
+/// What a message from the server changed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "update", content = "data", rename_all = "camelCase")]
+pub enum Update {
+    /// The whole room arrived, on joining or after missed messages.
+    Room,
+    /// A participant's line changed, or they committed it.
+    Line(String),
+    /// Another tab took over this participant; it should stay disconnected,
+    /// or the two would keep taking the room back from each other.
+    Displaced,
+    /// The room moved to another server, whose WebSocket URL this is; the
+    /// next socket should be opened there.
+    Moved(String),
+    /// Nothing the session tracks: notices, errors, creator tokens, mentions
+    /// and the like, for the caller to show.
+    Other(Value),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    /// None until the server creates a room for a `newroom` request.
+    room_id: Option<String>,
+    participant_id: Option<String>,
+    room: Option<Value>,
+    /// Revision of our own line, so the server can merge what we type with
+    /// typing from our other tabs and devices.
+    rev: u64,
+    cursor: usize,
+    /// Highest message number seen, so a dropped connection can resume.
+    last_seq: Option<u64>,
+    /// What the server gave this connection to resume it with.
+    resume_secret: Option<String>,
+    acked_seq: Option<u64>,
+    open: bool,
+    displaced: bool,
+    failures: u32,
+    retry_at: u64,
+    /// The server's `reconnect` hint, for the next time the socket drops.
+    retry_after: Option<u64>,
+    max_backoff: Option<u64>,
+}
+
+impl Session {
+    /// A session for `room_id`, or for a new room when None, as
+    /// `participant_id` if one was kept from before.
+    pub fn new(room_id: Option<String>, participant_id: Option<String>) -> Self {
+        Self {
+            room_id,
+            participant_id,
+            ..Self::default()
+        }
+    }
+
+    pub fn room_id(&self) -> Option<&str> {
+        self.room_id.as_deref()
+    }
+
+    pub fn participant_id(&self) -> Option<&str> {
+        self.participant_id.as_deref()
+    }
+
+    /// The room as the server's messages so far leave it, shaped like the
+    /// `room` of a `gotRoom`.
+    pub fn room(&self) -> Option<&Value> {
+        self.room.as_ref()
+    }
+
+    pub fn last_seq(&self) -> Option<u64> {
+        self.last_seq
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub fn is_displaced(&self) -> bool {
+        self.displaced
+    }
+
+    /// The socket opened; returns the first message to send on it. After a
+    /// dropped connection this asks the server to replay what was missed.
+    pub fn opened(&mut self) -> Value {
+        self.open = true;
+        self.failures = 0;
+        self.max_backoff = None;
+        let mut hello = match (&self.room_id, self.room.is_some(), self.last_seq) {
+            (None, _, _) => json!({"type": "newroom"}),
+            (Some(id), true, Some(seq)) => json!({
+                "type": "fetchRoom",
+                "id": id,
+                "lastSeq": seq,
+                "resumeSecret": self.resume_secret,
+            }),
+            (Some(id), _, _) => json!({"type": "fetchRoom", "id": id}),
+        };
+        self.stamp(&mut hello);
+        hello
+    }
+
+    /// The socket dropped at `now`. A wait the server hinted at is kept to,
+    /// once, and its longest backoff until a socket opens again.
+    pub fn closed(&mut self, now: u64) {
+        self.open = false;
+        self.acked_seq = None;
+        let max = self.max_backoff.unwrap_or(RETRY_MAX_MS);
+        let backoff = match self.retry_after.take() {
+            Some(retry_after) => retry_after,
+            None => RETRY_MIN_MS
+                .saturating_mul(1 << self.failures.min(16))
+                .min(max),
+        };
+        self.retry_at = now.saturating_add(backoff);
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// The socket was closed with `reason`, which may be the server's
+    /// `reconnect` hint as JSON; call before `closed`.
+    pub fn close_reason(&mut self, reason: &str) {
+        if let Ok(hint) = serde_json::from_str::<Value>(reason) {
+            self.hint(&hint);
+        }
+    }
+
+    /// Whether to open a new socket at `now`.
+    pub fn should_connect(&self, now: u64) -> bool {
+        !self.open && !self.displaced && now >= self.retry_at
+    }
+
+    /// The `ack` to send, if messages arrived since the last one.
+    pub fn ack(&mut self) -> Option<Value> {
+        let seq = self
+            .last_seq
+            .filter(|_| self.open && self.acked_seq != self.last_seq)?;
+        self.acked_seq = Some(seq);
+        Some(json!({"type": "ack", "seq": seq}))
+    }
+
+    /// Applies a message from the server.
+    pub fn receive(&mut self, text: &str) -> Result<Update, String> {
+        let message: Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+        if let Some(seq) = message["seq"].as_u64() {
+            self.last_seq = Some(seq);
+        }
+        let source = message["source"].as_str().unwrap_or_default().to_string();
+        let mine = self.participant_id.as_ref() == Some(&source);
+        let kind = message["type"].as_str().unwrap_or_default().to_string();
+        match kind.as_str() {
+            "roomCreated" | "gotRoom" => {
+                let room = &message["room"];
+                if !room.is_object() {
+                    return Err("room message without a room".to_string());
+                }
+                self.room_id = room["id"]
+                    .as_str()
+                    .map(str::to_string)
+                    .or(self.room_id.take());
+                if let Some(you) = room["yourId"].as_str() {
+                    self.participant_id = Some(you.to_string());
+                }
+                self.rev = room["yourRev"].as_u64().unwrap_or(0);
+                self.room = Some(room.clone());
+                self.cursor = char_len(self.own_line());
+                Ok(Update::Room)
+            }
+            "keyPress" => {
+                let key = message["key"].as_str().unwrap_or_default().to_string();
+                let key = key.as_str();
+                let pos = message["cursorPos"].as_u64().map(|pos| pos as usize);
+                let Some(line) = self.line_mut(&source) else {
+                    return Ok(Update::Other(message));
+                };
+                apply_key(line, key, pos);
+                self.sync_directions(&source);
+                // Typed on another of our tabs or devices: keep our cursor
+                // on the same character.
+                if let (true, Some(at)) = (mine, pos) {
+                    self.rev = message["rev"].as_u64().unwrap_or(self.rev);
+                    match key {
+                        "CtrlK" => self.cursor = self.cursor.min(at),
+                        "Backspace" | "Delete" | "DeleteAt" => {
+                            let removed = if key == "Backspace" {
+                                at.checked_sub(1)
+                            } else {
+                                Some(at)
+                            };
+                            if removed.is_some_and(|removed| removed < self.cursor) {
+                                self.cursor -= 1;
+                            }
+                        }
+                        _ if is_edit(key, pos) && at <= self.cursor => self.cursor += 1,
+                        _ => {}
+                    }
+                    self.cursor = self.cursor.min(char_len(self.own_line()));
+                }
+                Ok(Update::Line(source))
+            }
+            // Someone's line a word at a time, for connections that asked
+            // for that instead of key presses.
+            "typing" => {
+                let typed = message["line"].as_str().unwrap_or_default().to_string();
+                let Some(line) = self.line_mut(&source) else {
+                    return Ok(Update::Other(message));
+                };
+                *line = typed;
+                self.sync_directions(&source);
+                Ok(Update::Line(source))
+            }
+            "committed" => {
+                let Some(lines) = self.lines_mut(&source) else {
+                    return Ok(Update::Other(message));
+                };
+                lines.pop();
+                lines.push(message["final"].clone());
+                lines.push(Value::String(String::new()));
+                self.sync_directions(&source);
+                if mine {
+                    self.rev = message["rev"].as_u64().unwrap_or(self.rev);
+                    self.cursor = 0;
+                }
+                Ok(Update::Line(source))
+            }
+            "resumeSecret" => {
+                self.resume_secret = message["secret"].as_str().map(str::to_string);
+                Ok(Update::Other(message))
+            }
+            "sessionTakenOver" => {
+                self.displaced = true;
+                Ok(Update::Displaced)
+            }
+            // The close that follows may not carry the hint.
+            "serverShutdown" => {
+                self.hint(&message["reconnect"]);
+                Ok(Update::Other(message))
+            }
+            "moveTo" => {
+                let Some(url) = message["url"].as_str() else {
+                    return Err("moveTo without a url".to_string());
+                };
+                self.hint(&message["reconnect"]);
+                Ok(Update::Moved(url.to_string()))
+            }
+            _ => Ok(Update::Other(message)),
+        }
+    }
+
+    /// Where our cursor is in our own line.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Moves our cursor, as on a click or tap.
+    pub fn set_cursor(&mut self, pos: usize) {
+        self.cursor = pos.min(char_len(self.own_line()));
+    }
+
+    /// Our own line in progress.
+    pub fn own_line(&self) -> &str {
+        let id = self.participant_id.as_deref().unwrap_or_default();
+        self.room
+            .as_ref()
+            .and_then(|room| room["messages"][id].as_array()?.last()?.as_str())
+            .unwrap_or_default()
+    }
+
+    /// Presses `key` at our cursor: edits our own line at once, moves the
+    /// cursor, and returns the `keyPress` to send, or None before there's a
+    /// room to type in.
+    pub fn press(&mut self, key: &str) -> Option<Value> {
+        let id = self.participant_id.clone()?;
+        let len = char_len(self.own_line());
+        let at = self.cursor;
+        // Moves are reported where the cursor ends up, except the arrows,
+        // which are reported where it started.
+        let (sent, cursor) = match key {
+            "CtrlA" => (0, 0),
+            "CtrlE" => (len, len),
+            "CtrlB" => (at.saturating_sub(1), at.saturating_sub(1)),
+            "CtrlF" => ((at + 1).min(len), (at + 1).min(len)),
+            "ArrowLeft" => (at, at.saturating_sub(1)),
+            "ArrowRight" => (at, (at + 1).min(len)),
+            "Enter" => (at, 0),
+            "Backspace" if at > 0 => (at, at - 1),
+            "CtrlK" | "Delete" | "DeleteAt" => (at, at),
+            _ if is_edit(key, Some(at)) => (at, at + 1),
+            _ => (at, at),
+        };
+        let lines = self.lines_mut(&id)?;
+        if key == "Enter" {
+            lines.push(Value::String(String::new()));
+        } else if let Some(Value::String(line)) = lines.last_mut() {
+            apply_key(line, key, Some(sent));
+        }
+        self.sync_directions(&id);
+        let mut press = json!({"type": "keyPress", "key": key, "cursorPos": sent, "rev": self.rev});
+        if is_edit(key, Some(sent)) {
+            self.rev += 1;
+        }
+        self.cursor = cursor.min(char_len(self.own_line()));
+        self.stamp(&mut press);
+        Some(press)
+    }
+
+    fn hint(&mut self, hint: &Value) {
+        if let Some(retry_after) = hint["retryAfterMs"].as_u64() {
+            self.retry_after = Some(retry_after);
+        }
+        if let Some(max_backoff) = hint["maxBackoffMs"].as_u64() {
+            self.max_backoff = Some(max_backoff);
+        }
+    }
+
+    /// Adds our participant id, which the server uses to resume us.
+    fn stamp(&self, message: &mut Value) {
+        if let Some(id) = &self.participant_id {
+            message["socketId"] = json!(id);
+        }
+    }
+
+    fn lines_mut(&mut self, id: &str) -> Option<&mut Vec<Value>> {
+        self.room
+            .as_mut()?
+            .get_mut("messages")?
+            .get_mut(id)?
+            .as_array_mut()
+    }
+
+    /// Works out `id`'s line directions afresh after their lines changed.
+    fn sync_directions(&mut self, id: &str) {
+        let Some(room) = self.room.as_mut() else {
+            return;
+        };
+        let Some(lines) = room["messages"][id].as_array() else {
+            return;
+        };
+        let directions = lines
+            .iter()
+            .map(|line| json!(direction(line.as_str().unwrap_or_default())))
+            .collect();
+        if let Some(all) = room.get_mut("directions").and_then(Value::as_object_mut) {
+            all.insert(id.to_string(), Value::Array(directions));
+        }
+    }
+
+    fn line_mut(&mut self, id: &str) -> Option<&mut String> {
+        match self.lines_mut(id)?.last_mut()? {
+            Value::String(line) => Some(line),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! JavaScript bindings. Messages go in and out as JSON text, as they travel
+//! on the socket, and times are `Date.now()`.
+
+use wasm_bindgen::prelude::*;
+
+use crate::Session;
+
+fn text(message: Option<serde_json::Value>) -> Option<String> {
+    message.map(|message| message.to_string())
+}
+
+#[wasm_bindgen(js_name = Session)]
+pub struct JsSession(Session);
+
+#[wasm_bindgen(js_class = Session)]
+impl JsSession {
+    #[wasm_bindgen(constructor)]
+    pub fn new(room_id: Option<String>, participant_id: Option<String>) -> Self {
+        Self(Session::new(room_id, participant_id))
+    }
+
+    #[wasm_bindgen(getter, js_name = roomId)]
+    pub fn room_id(&self) -> Option<String> {
+        self.0.room_id().map(str::to_string)
+    }
+
+    #[wasm_bindgen(getter, js_name = participantId)]
+    pub fn participant_id(&self) -> Option<String> {
+        self.0.participant_id().map(str::to_string)
+    }
+
+    /// The room as JSON text.
+    #[wasm_bindgen(getter)]
+    pub fn room(&self) -> Option<String> {
+        text(self.0.room().cloned())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn cursor(&self) -> usize {
+        self.0.cursor()
+    }
+
+    #[wasm_bindgen(js_name = setCursor)]
+    pub fn set_cursor(&mut self, pos: usize) {
+        self.0.set_cursor(pos)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn displaced(&self) -> bool {
+        self.0.is_displaced()
+    }
+
+    pub fn opened(&mut self) -> String {
+        self.0.opened().to_string()
+    }
+
+    pub fn closed(&mut self, now: f64) {
+        self.0.closed(now as u64)
+    }
+
+    #[wasm_bindgen(js_name = closeReason)]
+    pub fn close_reason(&mut self, reason: &str) {
+        self.0.close_reason(reason)
+    }
+
+    #[wasm_bindgen(js_name = shouldConnect)]
+    pub fn should_connect(&self, now: f64) -> bool {
+        self.0.should_connect(now as u64)
+    }
+
+    pub fn ack(&mut self) -> Option<String> {
+        text(self.0.ack())
+    }
+
+    /// What the message changed, as `{"update": ..., "data": ...}` text.
+    pub fn receive(&mut self, message: &str) -> Result<String, JsError> {
+        let update = self.0.receive(message).map_err(|err| JsError::new(&err))?;
+        Ok(serde_json::to_string(&update).unwrap_or_default())
+    }
+
+    pub fn press(&mut self, key: &str) -> Option<String> {
+        text(self.0.press(key))
+    }
+}
@@ -0,0 +1,199 @@
+use serde_json::json;
+use typeto_client_core::{Session, Update};
+
+fn joined() -> Session {
+    let mut session = Session::new(Some("abc".to_string()), Some("alice".to_string()));
+    session.opened();
+    let room = json!({
+        "type": "gotRoom",
+        "seq": 1,
+        "room": {
+            "id": "abc",
+            "messages": {"alice": ["hello"], "bob": ["hey", "yo"]},
+            "directions": {"alice": ["ltr"], "bob": ["ltr", "ltr"]},
+            "yourId": "alice",
+            "yourRev": 4,
+        },
+    });
+    assert_eq!(session.receive(&room.to_string()), Ok(Update::Room));
+    session
+}
+
+#[test]
+fn local_keys_echo_and_carry_the_revision() {
+    let mut session = joined();
+    assert_eq!(session.cursor(), 5);
+    let press = session.press("!").unwrap();
+    assert_eq!(press["cursorPos"], 5);
+    assert_eq!(press["rev"], 4);
+    assert_eq!(press["socketId"], "alice");
+    assert_eq!(session.own_line(), "hello!");
+
+    // Moving the cursor isn't an edit, so the revision stays put.
+    assert_eq!(session.press("CtrlA").unwrap()["rev"], 5);
+    assert_eq!(session.press("Delete").unwrap()["rev"], 5);
+    assert_eq!(session.own_line(), "ello!");
+    assert_eq!(session.press("Enter").unwrap()["rev"], 6);
+    assert_eq!(session.own_line(), "");
+    assert_eq!(
+        session.room().unwrap()["messages"]["alice"],
+        json!(["ello!", ""])
+    );
+}
+
+#[test]
+fn deltas_edit_the_right_line() {
+    let mut session = joined();
+    let press =
+        json!({"type": "keyPress", "seq": 2, "key": "Backspace", "source": "bob", "cursorPos": 1});
+    assert_eq!(
+        session.receive(&press.to_string()),
+        Ok(Update::Line("bob".to_string()))
+    );
+    let commit = json!({"type": "committed", "seq": 3, "final": "o!", "source": "bob"});
+    session.receive(&commit.to_string()).unwrap();
+    assert_eq!(
+        session.room().unwrap()["messages"]["bob"],
+        json!(["hey", "o!", ""])
+    );
+
+    // Typed on another device: our cursor stays on the same character.
+    session.set_cursor(2);
+    let press = json!({"type": "keyPress", "seq": 4, "key": "x", "source": "alice", "cursorPos": 0, "rev": 9});
+    session.receive(&press.to_string()).unwrap();
+    assert_eq!(session.own_line(), "xhello");
+    assert_eq!(session.cursor(), 3);
+    assert_eq!(session.press("y").unwrap()["rev"], 9);
+
+    let notice = json!({"type": "serverNotice", "seq": 5, "message": "restarting"});
+    let Ok(Update::Other(other)) = session.receive(&notice.to_string()) else {
+        panic!("expected the notice back");
+    };
+    assert_eq!(other["message"], "restarting");
+    assert!(session.receive("{").is_err());
+}
+
+#[test]
+fn reconnecting_resumes_from_the_last_message() {
+    let mut session = Session::new(None, None);
+    assert_eq!(session.opened()["type"], "newroom");
+    let secret = json!({"type": "resumeSecret", "seq": 1, "secret": "s3cret"});
+    session.receive(&secret.to_string()).unwrap();
+    let created = json!({
+        "type": "roomCreated",
+        "seq": 2,
+        "room": {"id": "new", "messages": {"me": [""]}, "yourId": "me"},
+    });
+    session.receive(&created.to_string()).unwrap();
+    assert_eq!(session.ack(), Some(json!({"type": "ack", "seq": 2})));
+    assert_eq!(session.ack(), None);
+
+    session.closed(1_000);
+    assert!(!session.should_connect(1_000));
+    assert!(session.should_connect(1_500));
+    let hello = session.opened();
+    assert_eq!(
+        hello,
+        json!({
+            "type": "fetchRoom",
+            "id": "new",
+            "lastSeq": 2,
+            "resumeSecret": "s3cret",
+            "socketId": "me",
+        })
+    );
+
+    // Each failed attempt waits longer, up to a limit.
+    session.closed(0);
+    session.closed(0);
+    assert!(!session.should_connect(999));
+    assert!(session.should_connect(1_000));
+    for _ in 0..10 {
+        session.closed(0);
+    }
+    assert!(session.should_connect(5_000));
+
+    session.opened();
+    session
+        .receive(&json!({"type": "sessionTakenOver", "seq": 2}).to_string())
+        .unwrap();
+    session.closed(0);
+    assert!(!session.should_connect(u64::MAX));
+}
+
+#[test]
+fn reconnecting_waits_as_the_server_hints() {
+    let mut session = joined();
+    let shutdown = json!({
+        "type": "serverShutdown",
+        "reconnect": {"retryAfterMs": 1_800, "maxBackoffMs": 3_000},
+    });
+    assert!(matches!(
+        session.receive(&shutdown.to_string()),
+        Ok(Update::Other(_))
+    ));
+    session.closed(0);
+    assert!(!session.should_connect(1_799));
+    assert!(session.should_connect(1_800));
+
+    // Failing after that backs off as usual, but no further than hinted.
+    for _ in 0..10 {
+        session.closed(0);
+    }
+    assert!(session.should_connect(3_000));
+
+    session.opened();
+    session.close_reason(r#"{"retryAfterMs":9000,"maxBackoffMs":9000}"#);
+    session.closed(0);
+    assert!(!session.should_connect(8_999));
+    assert!(session.should_connect(9_000));
+
+    let moved = json!({
+        "type": "moveTo",
+        "url": "wss://green.example/ws",
+        "reconnect": {"retryAfterMs": 700, "maxBackoffMs": 5_000},
+    });
+    assert_eq!(
+        session.receive(&moved.to_string()),
+        Ok(Update::Moved("wss://green.example/ws".to_string()))
+    );
+    session.closed(0);
+    assert!(!session.should_connect(699));
+    assert!(session.should_connect(700));
+
+    session.opened();
+    session.close_reason("Going away");
+    session.closed(0);
+    assert!(session.should_connect(500));
+}
+
+#[test]
+fn positions_count_characters_in_typing_order() {
+    let mut session = joined();
+    session.set_cursor(0);
+    for key in ["ש", "ל", "Space"] {
+        session.press(key).unwrap();
+    }
+    assert_eq!(session.own_line(), "של hello");
+    assert_eq!(session.cursor(), 3);
+    assert_eq!(session.press("Backspace").unwrap()["cursorPos"], 3);
+    assert_eq!(session.press("CtrlK").unwrap()["cursorPos"], 2);
+    assert_eq!(session.own_line(), "של");
+    assert_eq!(
+        session.room().unwrap()["directions"]["alice"],
+        json!(["rtl"])
+    );
+}
+
+#[test]
+fn typing_summaries_replace_the_line() {
+    let mut session = joined();
+    let typing = json!({"type": "typing", "seq": 2, "source": "bob", "line": "שלום "});
+    assert_eq!(
+        session.receive(&typing.to_string()),
+        Ok(Update::Line("bob".to_string()))
+    );
+    let room = session.room().unwrap();
+    assert_eq!(room["messages"]["bob"], json!(["hey", "שלום "]));
+    assert_eq!(room["directions"]["bob"], json!(["ltr", "rtl"]));
+}
@@ -0,0 +1,16 @@
+//! Raw bytes off the wire: each line is one text frame from the same socket.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use typeto_server::fuzzing::Session;
+
+fuzz_target!(|data: &[u8]| {
+    let mut session = Session::default();
+    for frame in data.split(|&b| b == b'\n') {
+        if let Ok(text) = std::str::from_utf8(frame) {
+            session.frame(text);
+        }
+    }
+    session.check();
+});
@@ -0,0 +1,178 @@
+//! Well-formed JSON shaped like the protocol, so the fuzzer spends its time
+//! in the room code rather than on the parser's syntax errors. Fields can
+//! still come out missing, of the wrong type or with hostile values.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use serde_json::{json, Map, Value};
+use typeto_server::fuzzing::Session;
+
+#[derive(Debug, Arbitrary)]
+enum Key {
+    Named(NamedKey),
+    Char(char),
+    Text(String),
+}
+
+#[derive(Debug, Arbitrary)]
+enum NamedKey {
+    Enter,
+    Space,
+    Backspace,
+    Delete,
+    DeleteAt,
+    CtrlK,
+    ArrowLeft,
+    Shift,
+}
+
+#[derive(Debug, Arbitrary)]
+enum Field {
+    Str(String),
+    Num(i64),
+    Float(f64),
+    Bool(bool),
+    Null,
+    Missing,
+}
+
+impl Field {
+    fn insert(self, object: &mut Map<String, Value>, name: &str) {
+        let value = match self {
+            Field::Str(s) => json!(s),
+            Field::Num(n) => json!(n),
+            Field::Float(f) => json!(f),
+            Field::Bool(b) => json!(b),
+            Field::Null => Value::Null,
+            Field::Missing => return,
+        };
+        object.insert(name.to_string(), value);
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+enum Frame {
+    NewRoom {
+        socket_id: Field,
+    },
+    FetchRoom {
+        id: Field,
+        socket_id: Field,
+    },
+    KeyPress {
+        key: Key,
+        cursor_pos: Option<u32>,
+    },
+    /// A key press whose fields may have the wrong types.
+    RawKeyPress {
+        key: Field,
+        cursor_pos: Field,
+    },
+    UpdateRoomSettings {
+        mode: Field,
+        idle_ttl_secs: Field,
+        max_age_secs: Field,
+        max_participants: Field,
+        listed: Field,
+        topic: Field,
+    },
+    SetPrefs {
+        theme: Field,
+        font_size: Field,
+        sound: Field,
+    },
+    Authenticate {
+        public_key: Field,
+        signature: Field,
+    },
+    /// Any type tag at all.
+    Unknown {
+        kind: String,
+    },
+}
+
+impl Frame {
+    fn into_json(self) -> Value {
+        let mut object = Map::new();
+        let kind = match self {
+            Frame::NewRoom { socket_id } => {
+                socket_id.insert(&mut object, "socketId");
+                "newroom"
+            }
+            Frame::FetchRoom { id, socket_id } => {
+                id.insert(&mut object, "id");
+                socket_id.insert(&mut object, "socketId");
+                "fetchRoom"
+            }
+            Frame::KeyPress { key, cursor_pos } => {
+                let key = match key {
+                    Key::Named(named) => format!("{:?}", named),
+                    Key::Char(c) => c.to_string(),
+                    Key::Text(text) => text,
+                };
+                object.insert("key".to_string(), json!(key));
+                object.insert("cursorPos".to_string(), json!(cursor_pos));
+                "keyPress"
+            }
+            Frame::RawKeyPress { key, cursor_pos } => {
+                key.insert(&mut object, "key");
+                cursor_pos.insert(&mut object, "cursorPos");
+                "keyPress"
+            }
+            Frame::UpdateRoomSettings {
+                mode,
+                idle_ttl_secs,
+                max_age_secs,
+                max_participants,
+                listed,
+                topic,
+            } => {
+                let mut settings = Map::new();
+                mode.insert(&mut settings, "mode");
+                idle_ttl_secs.insert(&mut settings, "idleTtlSecs");
+                max_age_secs.insert(&mut settings, "maxAgeSecs");
+                max_participants.insert(&mut settings, "maxParticipants");
+                listed.insert(&mut settings, "listed");
+                topic.insert(&mut settings, "topic");
+                object.insert("settings".to_string(), Value::Object(settings));
+                "updateRoomSettings"
+            }
+            Frame::SetPrefs {
+                theme,
+                font_size,
+                sound,
+            } => {
+                let mut prefs = Map::new();
+                theme.insert(&mut prefs, "theme");
+                font_size.insert(&mut prefs, "fontSize");
+                sound.insert(&mut prefs, "sound");
+                object.insert("prefs".to_string(), Value::Object(prefs));
+                "setPrefs"
+            }
+            Frame::Authenticate {
+                public_key,
+                signature,
+            } => {
+                public_key.insert(&mut object, "publicKey");
+                signature.insert(&mut object, "signature");
+                "authenticate"
+            }
+            Frame::Unknown { kind } => {
+                object.insert("type".to_string(), json!(kind));
+                return Value::Object(object);
+            }
+        };
+        object.insert("type".to_string(), json!(kind));
+        Value::Object(object)
+    }
+}
+
+fuzz_target!(|frames: Vec<Frame>| {
+    let mut session = Session::default();
+    for frame in frames {
+        session.frame(&frame.into_json().to_string());
+    }
+    session.check();
+});
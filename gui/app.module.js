@@ -1,6 +1,8 @@
 import cre from "https://unpkg.com/cre@0.3.0/cre.js";
 import ghIconModule from "./gh-icon.module.js";
 console.log("hey there pardner 🤠");
+/** @typedef {import("./protocol").ClientMessage} ClientMessage */
+/** @typedef {import("./protocol").ServerMessage} ServerMessage */
 const nonEvents = [
   "Shift",
   "Meta",
@@ -22,9 +24,20 @@ const nonEvents = [
   "CtrlF"
 ];
 
+// The key presses the server counts as edits to a line, bumping its revision
+const isEdit = (key, cursorPos) =>
+  key === "Enter" ||
+  (cursorPos !== undefined &&
+    (["CtrlK", "Delete", "DeleteAt", "Space"].includes(key) ||
+      (key === "Backspace" && cursorPos > 0) ||
+      (!nonEvents.includes(key) && key.length === 1)));
+
 class App {
   constructor() {
     this.socketId = localStorage.getItem("socketId");
+    // Sent when joining and quoted in the server's errors and logs, to match
+    // a bug report to them
+    this.traceId = crypto.randomUUID?.() ?? Math.random().toString(36).slice(2);
     this.setup();
     this.connected = false;
     this.clipped = false;
@@ -32,8 +45,27 @@ class App {
     this.keyboardInput = null; // Reference to the hidden input
     this.mainHeaderHeight = 20; // Store header height, matches CSS
     window.clippy = this.clipboard;
+    // Revision of our own line, so the server can merge what we type with
+    // typing from our other tabs and devices
+    this.rev = 0;
+    // Highest message number seen, so a dropped connection can resume
+    this.lastSeq = null;
+    // Sent to this connection alone; resuming needs it, as ids are public
+    this.resumeSecret = null;
+    this.ackedSeq = null;
+    setInterval(() => {
+      if (this.lastSeq !== this.ackedSeq && this.ws?.readyState === WebSocket.OPEN) {
+        this.ws.json({ type: "ack", seq: this.lastSeq });
+        this.ackedSeq = this.lastSeq;
+      }
+    }, 1000);
     setInterval(() => {
       // Check WebSocket connection periodically
+      // A tab replaced by another one stays disconnected, or the two would
+      // keep taking the room back from each other
+      if (this.displaced) {
+        return;
+      }
       if (this.ws && this.ws.readyState !== WebSocket.OPEN && this.ws.readyState !== WebSocket.CONNECTING) {
         this.connected = false;
         this.teardown();
@@ -50,11 +82,30 @@ class App {
       const proto = window.location.protocol.includes("s") ? "wss://" : "ws://";
       const domain = window.location.hostname;
       const wsPath = "/ws"; // WebSocket endpoint path
-      this.ws = new WebSocket(`${proto}${domain}:${window.location.port}${wsPath}`);
+      // Embedding sites pass a signed token in the page URL; forward it to the socket
+      const pageParams = new URLSearchParams(window.location.search);
+      const wsParams = new URLSearchParams();
+      for (const name of ["token", "embed", "invite"]) {
+        if (pageParams.get(name)) wsParams.set(name, pageParams.get(name));
+      }
+      // In a cluster, reaches the node that serves the room
+      const roomId = window.location.pathname.replace("/", "");
+      if (roomId) wsParams.set("room", roomId);
+      const wsQuery = wsParams.toString() ? `?${wsParams}` : "";
+      // A room handed off to another server is followed there
+      const wsBase = this.movedTo ?? `${proto}${domain}:${window.location.port}${wsPath}`;
+      this.ws = new WebSocket(`${wsBase}${wsQuery}`);
 
       this.ws.addEventListener("open", this.rootHandler);
       this.ws.addEventListener("message", this.messageHandler);
+      /** @param {ClientMessage} obj */
       this.ws.json = (obj) => {
+        if (obj.type === "keyPress") {
+          obj = { ...obj, rev: this.rev };
+          if (isEdit(obj.key, obj.cursorPos)) {
+            this.rev++;
+          }
+        }
         this.ws.send(JSON.stringify({
           ...obj,
           socketId: this.socketId,
@@ -63,7 +114,8 @@ class App {
       window.addEventListener("keydown", this.keydownHandler);
       this.pasteListener = document.addEventListener("paste", (evt) => {
         var clipboardData = evt.clipboardData || window.clipboardData;
-        var pastedText = clipboardData.getData("text/plain");
+        // The server refuses control characters, such as line breaks.
+        var pastedText = clipboardData.getData("text/plain").replace(/\p{Cc}/gu, "");
         pastedText.split("").map((char) =>
           this.ws.json({
             type: "keyPress",
@@ -96,6 +148,12 @@ class App {
     const {
       key,
     } = evt;
+    // A timer with a hard stop ran out, the owner froze our input, or it's
+    // someone else's turn; the server would refuse the keys
+    if (this.typingLocked || this.room?.frozen?.includes(this.socketId) ||
+        (this.room?.settings.mode === "turns" && this.room.turn !== this.socketId)) {
+      return;
+    }
     // Prevent Firefox from opening search box when '/' or "'" is pressed
     if ((key === '/' || key === "'") && navigator.userAgent.includes('Firefox')) {
       evt.preventDefault();
@@ -316,28 +374,177 @@ class App {
   };
   rootHandler = () => {
     this.connected = true;
-    if (window.location.pathname === "/") {
+    // Joins once helloAck says what the server supports
+    this.ws.json({ type: "hello" });
+  };
+  // Whether helloAck advertised a capability, one of the CAPABILITIES bits
+  can = (capability) => (this.capabilities & capability) !== 0;
+  join = () => {
+    // ?granularity=word or line: others' typing a word or a line at a time, for screen readers
+    const params = new URLSearchParams(window.location.search);
+    const granularity = this.can(CAPABILITIES.lineMode) && params.get("granularity");
+    // ?sounds: typewriter sounds for others' typing
+    const sounds = this.can(CAPABILITIES.keySounds) && params.has("sounds");
+    const hello = {
+      locale: navigator.language,
+      traceId: this.traceId,
+      ...(granularity && { granularity }),
+      ...(sounds && { sounds: true }),
+    };
+    if (window.location.pathname === "/" && !this.can(CAPABILITIES.createRooms) && !params.get("invite")) {
+      renderError("New rooms on this server need an invitation.");
+    } else if (window.location.pathname === "/") {
       this.ws.json({
         type: "newroom",
+        ...hello,
       });
+    } else if (this.room && this.lastSeq !== null && this.can(CAPABILITIES.resume)) {
+      // Back after a dropped connection: the server replays what was
+      // missed, or sends the whole room if it can't
+      this.ws.json({
+        type: "fetchRoom",
+        id: window.location.pathname.replace("/", ""),
+        lastSeq: this.lastSeq,
+        resumeSecret: this.resumeSecret,
+        ...hello,
+      });
+      this.setupInputHandling();
     } else {
       this.ws.json({
         type: "fetchRoom",
         id: window.location.pathname.replace("/", ""),
+        ...hello,
       });
     }
   };
+  // Counts down the owner's timer in the header, from the time left by the
+  // server's reckoning so that clocks that disagree don't matter
+  startCountdown = (msLeft) => {
+    clearInterval(this.countdownTimer);
+    this.timerDeadline = msLeft > 0 ? Date.now() + msLeft : null;
+    if (this.timerDeadline) {
+      this.countdownTimer = setInterval(() => this.room && renderMainHeader(this.room), 1000);
+    }
+    if (this.room) renderMainHeader(this.room);
+  };
+  // Shows a system line above the user's own current line
+  showNotice = (message) => {
+    const ownMessages = this.room?.messages[this.socketId];
+    if (!ownMessages) {
+      renderError(message);
+      return;
+    }
+    ownMessages.splice(-1, 0, `> ${message}`);
+    renderParticipantMessages(this.socketId, ownMessages, true);
+  };
   messageHandler = (raw) => {
+    /** @type {ServerMessage & { seq?: number }} */
     const body = JSON.parse(raw.data);
+    if (typeof body.seq === "number") {
+      this.lastSeq = body.seq;
+    }
     if (body?.room?.yourId) {
       this.socketId = body.room.yourId;
       localStorage.setItem("socketId", this.socketId);
     }
     switch (body.type) {
+      case "helloAck":
+        this.capabilities = body.capabilities;
+        this.join();
+        break;
       case "room-is-crowded":
         // Use the message from the server if available, otherwise use a default
         renderError(body.message || "Sorry, this room is full.");
         break;
+      case "creatorToken":
+        // Kept so the creator can later delete the room via the API
+        localStorage.setItem(`creatorToken:${body.room}`, body.token);
+        break;
+      case "serverNotice":
+        this.showNotice(body.message);
+        break;
+      case "archived":
+        renderArchived(body.room, () => this.ws.json({ type: "reviveRoom", id: body.room.id }));
+        break;
+      case "revived":
+        // Join the room as if arriving afresh
+        this.join();
+        break;
+      case "degraded":
+        this.showNotice(body.reason === "snapshotsOnly"
+          ? "Your connection is too slow; updates are reduced for now"
+          : "Your connection is too slow; catching up");
+        break;
+      case "serverShutdown":
+        this.showNotice("The server is restarting; reconnecting shortly");
+        break;
+      case "moveTo":
+        this.movedTo = body.url;
+        this.showNotice("This room moved to another server; reconnecting shortly");
+        break;
+      case "expiring":
+        this.showNotice(`This room expires in ${Math.ceil(body.secondsLeft / 60)} minute(s)`);
+        break;
+      case "timer":
+        this.startCountdown(body.timer.msLeft);
+        break;
+      case "timerEnded":
+        this.startCountdown(0);
+        this.typingLocked = body.locked;
+        this.showNotice(body.locked ? "Time's up; typing is locked" : "The timer was stopped");
+        break;
+      case "turn":
+        if (this.room) {
+          this.room.turn = body.participant;
+          this.showNotice(body.participant === this.socketId ? "Your turn" : `${getShortId(body.participant)}'s turn`);
+        }
+        break;
+      case "race":
+        this.raceProgress = {};
+        this.showNotice(`Race! Type this as fast as you can: ${body.race.text}`);
+        break;
+      case "raceProgress":
+        if (this.raceProgress) {
+          this.raceProgress[body.progress.participant] = body.progress;
+          renderMainHeader(this.room);
+        }
+        break;
+      case "raceEnded":
+        this.raceProgress = null;
+        this.showNotice(`Race over: ${body.standings.map((p, i) =>
+          `${i + 1}. ${getShortId(p.participant)} ${p.wpm} wpm, ${p.accuracy}%${p.place ? "" : " (unfinished)"}`).join("; ") || "nobody typed"}`);
+        renderMainHeader(this.room);
+        break;
+      case "notStarted":
+        // Counted from the server's clock; try again when the room opens
+        renderCountdown(body.startsAt, body.secondsLeft, () => this.join());
+        break;
+      case "mention":
+        this.showNotice(`${getShortId(body.source)} mentioned you`);
+        if (document.hidden && !document.title.startsWith("(@) ")) {
+          document.title = `(@) ${document.title}`;
+          document.addEventListener("visibilitychange", () => {
+            document.title = document.title.replace(/^\(@\) /, "");
+          }, { once: true });
+        }
+        break;
+      case "resumeSecret":
+        this.resumeSecret = body.secret;
+        break;
+      case "sessionTakenOver":
+        this.displaced = true;
+        this.showNotice(`${body.message} Reload to continue here.`);
+        break;
+      case "error":
+        // For matching a bug report to the server's logs
+        console.warn("Server error", body.message, { connectionId: body.connectionId, traceId: body.traceId });
+        // Before a room is shown there's nowhere else to put the message
+        if (this.room) {
+          this.showNotice(body.message);
+        } else {
+          renderError(body.connectionId ? `${body.message} (ref ${body.connectionId})` : body.message);
+        }
+        break;
       case "roomCreated":
         window.history.pushState(
           "chatpage",
@@ -345,6 +552,7 @@ class App {
           `/${body.room.id}`,
         );
         this.room = body.room;
+        this.rev = body.room.yourRev ?? 0;
         this.cursorPos = this.room.messages[this.socketId]?.slice(-1)[0]?.length || 0;
         fullRender(this.socketId, this.room);
         // Setup input handling after room is ready
@@ -359,7 +567,13 @@ class App {
           );
         }
         this.room = body.room;
+        this.rev = body.room.yourRev ?? 0;
         this.cursorPos = this.room.messages[this.socketId]?.slice(-1)[0]?.length || 0;
+        this.typingLocked = body.room.typingLocked;
+        // Progress so far isn't in the room view; it fills in as people type
+        if (!body.room.race) this.raceProgress = null;
+        else this.raceProgress ??= {};
+        this.startCountdown(body.room.timer?.msLeft ?? 0);
         fullRender(this.socketId, this.room);
          // Setup input handling after room is ready
         this.setupInputHandling();
@@ -370,10 +584,32 @@ class App {
         if (commitTarget) {
             commitTarget.splice(-1, 1, body.final);
             commitTarget.push("");
+            // Committed from another of our tabs or devices
+            if (commitSourceId === this.socketId) {
+                this.rev = body.rev ?? this.rev;
+                this.cursorPos = 0;
+            }
             // Re-render the specific participant's section after commit
             renderParticipantMessages(commitSourceId, commitTarget, commitSourceId === this.socketId);
+            if (commitSourceId !== this.socketId && body.final) {
+                announce(`${getShortId(commitSourceId)}: ${body.final}`);
+            }
+        }
+        break;
+      case "typing":
+        // Sent instead of key presses with ?granularity=word
+        const typingTarget = this.room.messages[body.source];
+        if (typingTarget) {
+            const previous = typingTarget.slice(-1)[0];
+            typingTarget.splice(-1, 1, body.line);
+            renderParticipantLast(body.source, body.line);
+            // Read out just the new words when the line has only grown
+            announce(body.line.startsWith(previous) ? body.line.slice(previous.length) : body.line);
         }
         break;
+      case "keySound":
+        playKeySound(body.sound);
+        break;
       case "keyPress":
         const pressSourceId = body.source;
         const pressTarget = this.room.messages[pressSourceId];
@@ -408,11 +644,13 @@ class App {
                 }
             }
             else if (body.key === "Backspace") {
-                if (body.cursorPos !== undefined && body.cursorPos > 0) {
-                    // Delete character at cursor position - 1
-                    const newLine = currentLastLine.slice(0, body.cursorPos - 1) + 
-                                   currentLastLine.slice(body.cursorPos);
-                    pressTarget.splice(-1, 1, newLine);
+                if (body.cursorPos !== undefined) {
+                    // Delete character at cursor position - 1; nothing at the start of the line
+                    if (body.cursorPos > 0) {
+                        const newLine = currentLastLine.slice(0, body.cursorPos - 1) + 
+                                       currentLastLine.slice(body.cursorPos);
+                        pressTarget.splice(-1, 1, newLine);
+                    }
                 } else {
                     // Fallback to old behavior
                     pressTarget.splice(-1, 1, currentLastLine.slice(0, -1));
@@ -480,6 +718,24 @@ class App {
                 // Fallback to normal rendering
                 renderParticipantLast(pressSourceId, pressTarget.slice(-1)[0]);
             }
+
+            // Typed on another of our tabs or devices: keep our cursor on
+            // the same character
+            if (pressSourceId === this.socketId && body.cursorPos !== undefined) {
+                this.rev = body.rev ?? this.rev;
+                const at = body.cursorPos;
+                if (body.key === "CtrlK") {
+                    this.cursorPos = Math.min(this.cursorPos, at);
+                } else if (body.key === "Backspace" || body.key === "Delete" || body.key === "DeleteAt") {
+                    const removed = body.key === "Backspace" ? at - 1 : at;
+                    if (removed >= 0 && removed < this.cursorPos) {
+                        this.cursorPos--;
+                    }
+                } else if (isEdit(body.key, at) && at <= this.cursorPos) {
+                    this.cursorPos++;
+                }
+                renderMyLastWithCursor(pressTarget.slice(-1)[0], this.cursorPos);
+            }
         }
         break;
     }
@@ -571,7 +827,101 @@ function renderError(message) {
    if (mainHeader) mainHeader.innerHTML = padString("Error");
 
 }
+// An expired room kept for a while: its lines, read-only, and a way to bring it back
+function renderArchived(archived, revive) {
+  const chatContainer = document.querySelector("#chat-container");
+  const mainHeader = document.querySelector("#main-header");
+  if (!chatContainer) return;
+  chatContainer.innerHTML = "";
+  const deletedAt = new Date(archived.deletedAt * 1000).toLocaleString();
+  chatContainer.appendChild(
+    cre("div.error", { style: "padding: 20px; text-align: center;" },
+      `This room has expired. It is kept until ${deletedAt}.`),
+  );
+  for (const [id, lines] of Object.entries(archived.messages)) {
+    const finished = lines.filter((line) => line);
+    if (!finished.length) continue;
+    const heading = id === "_system" ? "room events" : getShortId(id);
+    chatContainer.appendChild(cre("div", { style: `color: ${archived.colors[id] ?? "inherit"}` }, [
+      cre("div", padString(heading)),
+      ...finished.map((line) => cre("div", line)),
+    ]));
+  }
+  const button = cre("button", "Revive room");
+  button.addEventListener("click", revive);
+  chatContainer.appendChild(cre("div", { style: "padding: 20px; text-align: center;" }, [button]));
+  if (mainHeader) mainHeader.innerHTML = padString("Archived");
+}
+
+function renderCountdown(startsAt, secondsLeft, join) {
+  const chatContainer = document.querySelector("#chat-container");
+  const mainHeader = document.querySelector("#main-header");
+  if (!chatContainer) return;
+  chatContainer.innerHTML = "";
+  const text = cre("div.error", { style: "padding: 20px; text-align: center;" });
+  chatContainer.appendChild(text);
+  if (mainHeader) mainHeader.innerHTML = padString("Not started");
+  const opensAt = new Date(startsAt * 1000).toLocaleString();
+  const deadline = Date.now() + secondsLeft * 1000;
+  const tick = () => {
+    const left = Math.max(0, Math.ceil((deadline - Date.now()) / 1000));
+    const hours = Math.floor(left / 3600);
+    const minutes = String(Math.floor(left / 60) % 60).padStart(2, "0");
+    const seconds = String(left % 60).padStart(2, "0");
+    text.textContent = `This room opens at ${opensAt}, in ${hours}:${minutes}:${seconds}.`;
+    if (left === 0) {
+      clearInterval(timer);
+      join();
+    }
+  };
+  const timer = setInterval(tick, 1000);
+  tick();
+}
+
 // Helper to get the short ID
+// Only filled in when a granularity was asked for; otherwise every key press would be read out
+function announce(text) {
+  const announcer = document.getElementById("announcer");
+  if (announcer && new URLSearchParams(window.location.search).get("granularity") && text.trim()) {
+    announcer.textContent = text;
+  }
+}
+
+// Pitch and length of each typewriter sound, played as a short click
+// Bits of helloAck's capabilities, as protocol.d.ts describes them
+const CAPABILITIES = {
+  createRooms: 1,
+  resume: 2,
+  revive: 4,
+  search: 8,
+  keySounds: 16,
+  lineMode: 32,
+  pushMentions: 64,
+  mirror: 128,
+};
+
+const KEY_SOUNDS = {
+  keypress: [1200, 0.02],
+  backspace: [600, 0.03],
+  bell: [2400, 0.3],
+  return: [300, 0.12],
+};
+let audioContext = null;
+
+function playKeySound(sound) {
+  const [frequency, length] = KEY_SOUNDS[sound] ?? [];
+  if (!frequency) return;
+  audioContext ??= new AudioContext();
+  const oscillator = audioContext.createOscillator();
+  const gain = audioContext.createGain();
+  oscillator.frequency.value = frequency;
+  gain.gain.setValueAtTime(0.1, audioContext.currentTime);
+  gain.gain.exponentialRampToValueAtTime(0.001, audioContext.currentTime + length);
+  oscillator.connect(gain).connect(audioContext.destination);
+  oscillator.start();
+  oscillator.stop(audioContext.currentTime + length);
+}
+
 function getShortId(id) {
   return id?.substring(0, 4) || "??";
 }
@@ -625,6 +975,10 @@ function renderParticipantMessages(participantId, messages, isSelf) {
     messagesDom = cre("ul", cre("li", ""));
   }
 
+  // Each line reads in the direction of its first letter, so Hebrew or Arabic lines
+  // sit right-aligned among English ones
+  messagesDom.querySelectorAll("li").forEach((li) => (li.dir = "auto"));
+
   messagesContainer.querySelector("ul")?.remove(); // Clear previous messages
   messagesContainer.appendChild(messagesDom);
 }
@@ -688,7 +1042,7 @@ function renderParticipantLastWithCursor(participantId, message, cursorPos) {
 }
 
 // Creates the DOM structure for a single participant's section
-function renderParticipantSection(container, participantId, messages, isSelf, participantCount) {
+function renderParticipantSection(container, participantId, messages, isSelf, participantCount, color, localeHint) {
   const sectionId = `participant-${participantId}`;
   let section = document.getElementById(sectionId);
 
@@ -708,6 +1062,11 @@ function renderParticipantSection(container, participantId, messages, isSelf, pa
   const availableHeightForSections = container.clientHeight - totalDividerHeight; // Use actual container height
   const sectionHeight = Math.max(20, availableHeightForSections / participantCount); // Ensure min height
   section.style.height = `${sectionHeight}px`;
+  // Assigned by the server, so everyone sees the same person in the same color
+  section.style.color = color || "";
+  // Right-to-left languages read from the right, and screen readers pick a voice by lang
+  section.dir = localeHint?.rtl ? "rtl" : "";
+  section.lang = localeHint?.locale || "";
 
 
   // Render messages within the section
@@ -733,6 +1092,10 @@ function padString(string, clip) {
     }</span> =${hyphens}`;
 }
 
+function escapeHtml(text) {
+  return text.replace(/&/g, "&amp;").replace(/</g, "&lt;").replace(/>/g, "&gt;");
+}
+
 // Renders the main header at the top
 function renderMainHeader(room) {
   // Determine active participant count (other participants plus self)
@@ -747,9 +1110,27 @@ function renderMainHeader(room) {
     headerMessage = window.app.clipped
       ? `typeto.me | chat link copied! Send it to friends.`
       : `typeto.me | Send this URL to friends: ${window.location.href}`;
+    // Easier to read out over the phone
+    if (room.shortcode && !window.app.clipped) {
+      headerMessage += ` (or ${window.location.host}/j/${room.shortcode})`;
+    }
   } else {
     headerMessage = `${topMessageBase} | ${participantCount} participants in room ${room.id}`;
   }
+  // Latest join, leave or topic change, recorded by the server as a line
+  const lastEvent = room?.messages?.["_system"]?.slice(-2)[0];
+  if (lastEvent) {
+    headerMessage += ` | ${escapeHtml(lastEvent)}`;
+  }
+  if (window.app.raceProgress) {
+    const standings = Object.values(window.app.raceProgress)
+      .map((p) => `${escapeHtml(getShortId(p.participant))} ${Math.floor(p.correct * 100 / p.length)}%`);
+    headerMessage += ` | race: ${standings.join(" ") || "go!"}`;
+  }
+  if (window.app.timerDeadline) {
+    const left = Math.max(0, Math.ceil((window.app.timerDeadline - Date.now()) / 1000));
+    headerMessage += ` | timer ${Math.floor(left / 60)}:${String(left % 60).padStart(2, "0")}`;
+  }
 
   const paddedHeaderMessage = padString(headerMessage, participantCount <= 1)
     .replace(
@@ -818,6 +1199,11 @@ function fullRender(socketID, room, availableHeight = null) {
 
   container.innerHTML = ""; // Clear previous sections
 
+  // Pinned by the owner above everyone's lines
+  if (room.settings?.prompt) {
+    container.appendChild(cre("div.prompt", { style: "padding: 4px 8px; font-weight: bold; flex: none;" }, room.settings.prompt));
+  }
+
   // Determine active participants (other participants plus self)
   const participantIds = (room.otherParticipantIds || []).concat([socketID]);
   const participantCount = participantIds.length;
@@ -832,7 +1218,7 @@ function fullRender(socketID, room, availableHeight = null) {
 
   // Function to render a section and potentially a divider
   const renderSectionAndDivider = (id, isSelf) => {
-      renderParticipantSection(container, id, room.messages[id], isSelf, participantCount);
+      renderParticipantSection(container, id, room.messages[id], isSelf, participantCount, room.colors?.[id], room.locales?.[id]);
       renderedCount++;
       // Add divider after this section if it's not the very last section overall
       if (renderedCount < participantCount) {
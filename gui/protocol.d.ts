@@ -0,0 +1,261 @@
+// Generated from the server's Rust types; do not edit. Regenerate with
+// `UPDATE_TYPES=1 cargo test --test typescript`.
+//
+// Every ServerMessage also carries `seq`, its number for `ack` and resuming.
+
+export type ClientMessage = { "type": "newroom", socketId?: string | null, 
+/**
+ * The joiner's language tag, e.g. `he-IL`, and keyboard layout.
+ */
+locale?: string | null, layout?: string | null, 
+/**
+ * `ansi` to be sent terminal escape sequences instead of messages.
+ */
+format?: StreamFormat, 
+/**
+ * `word` or `line` to be sent others' typing a word or a line at a
+ * time, e.g. for a screen reader.
+ */
+granularity?: Granularity, 
+/**
+ * To be sent a `keySound` after each of others' key presses.
+ */
+sounds?: boolean, 
+/**
+ * The client's own id for this session, quoted in `error`s and the
+ * server's logs.
+ */
+traceId?: string | null, } | { "type": "fetchRoom", 
+/**
+ * May be left out with a `resumeToken`.
+ */
+id?: string, socketId?: string | null, 
+/**
+ * From a `reconnect` hint; stands for `id` and `socketId`.
+ */
+resumeToken?: string | null, 
+/**
+ * The last `seq` seen on a dropped connection, to resume from.
+ */
+lastSeq?: number | null, 
+/**
+ * The dropped connection's `resumeSecret`, without which it isn't
+ * resumed.
+ */
+resumeSecret?: string | null, locale?: string | null, layout?: string | null, format?: StreamFormat, granularity?: Granularity, sounds?: boolean, traceId?: string | null, } | { "type": "keyPress", key: string, cursorPos?: number | null, 
+/**
+ * The revision of the typist's line `cursor_pos` was taken against.
+ */
+rev?: number | null, } | { "type": "updateRoomSettings", settings: RoomSettingsUpdate, } | { "type": "startTimer", seconds: number, lock?: boolean, } | { "type": "claimOwner", token: string, } | { "type": "report", reason: string, } | { "type": "freeze", socketId: string, } | { "type": "unfreeze", socketId: string, } | { "type": "setPrompt", prompt?: string, } | { "type": "startRace", text?: string, } | { "type": "endRace" } | { "type": "getPrefs" } | { "type": "setPrefs", prefs: UserPrefs, } | { "type": "searchHistory", query: string, regex?: boolean, } | { "type": "reviveRoom", id: string, } | { "type": "getChallenge" } | { "type": "hello" } | { "type": "ack", seq: number, } | { "type": "authenticate", publicKey: string, signature: string, };
+
+export type ServerMessage = { "type": "gotRoom", room: RoomView, } | { "type": "room-is-crowded", message: string, } | { "type": "committed", final: string, source: string, rev?: number, } | { "type": "keyPress", key: string, source: string, cursorPos: number | null, rev?: number, } | { "type": "error", message: string, } | { "type": "prefs", prefs: UserPrefs, } | { "type": "challenge", challenge: string, } | { "type": "authenticated", identity: string, } | { "type": "helloAck", protocolVersion: number, 
+/**
+ * A bit for each optional capability the instance has on:
+ * 1 creating rooms, 2 resuming, 4 reviving archived rooms,
+ * 8 `searchHistory`, 16 `sounds`, 32 `granularity` other than
+ * `key`, 64 push notifications for mentions, 128 anyone on several
+ * devices at once. Unknown bits are to be ignored.
+ */
+capabilities: number, } | { "type": "creatorToken", room: string, token: string, } | { "type": "mention", room: string, source: string, line: string, } | { "type": "serverNotice", message: string, } | { "type": "sessionTakenOver", message: string, } | { "type": "resumeSecret", secret: string, } | { "type": "typing", source: string, line: string, } | { "type": "keySound", source: string, sound: KeySound, } | { "type": "archived", room: ArchivedView, } | { "type": "revived", room: string, } | { "type": "expiring", expiresAt: number, secondsLeft: number, } | { "type": "notStarted", startsAt: number, secondsLeft: number, } | { "type": "timer", timer: TimerView, } | { "type": "timerEnded", locked: boolean, } | { "type": "turn", participant: string, endsAtMs: number | null, } | { "type": "race", race: RaceView, } | { "type": "raceProgress", progress: RaceProgress, } | { "type": "raceEnded", standings: Array<RaceProgress>, } | { "type": "serverShutdown", reconnect: ReconnectHint, } | { "type": "moveTo", url: string, reconnect: ReconnectHint, } | { "type": "degraded", reason: DegradedReason, } | { "type": "searchResults", query: string, hits: Array<Hit>, 
+/**
+ * More lines matched than `hits` holds.
+ */
+truncated: boolean, };
+
+export type RoomView = { messages: { [key in string]: Array<string> }, participants: number, id: string, yourId: string, theirId: string | null, otherParticipantIds: Array<string>, ownerId: string | null, settings: RoomSettings, 
+/**
+ * The revision of your line, for `keyPress`.
+ */
+yourRev: number, 
+/**
+ * Everyone's color, by participant id, including those who left.
+ */
+colors: { [key in string]: string }, 
+/**
+ * The code of the room's short link, `/j/<code>`. Rooms created before
+ * short links have none.
+ */
+shortcode: string | null, 
+/**
+ * The language and keyboard layout of those who said, by participant id.
+ */
+locales: { [key in string]: LocaleHint }, 
+/**
+ * The direction of each line in `messages`, line for line.
+ */
+directions: { [key in string]: Array<Direction> }, 
+/**
+ * When the room's maximum age runs out, in seconds since the epoch.
+ */
+expiresAt: number | null, 
+/**
+ * When the room goes if everyone leaves now and nobody comes back.
+ */
+idleTimeoutAt: number, 
+/**
+ * The frontends the instance offers, if it has more than one.
+ */
+gui: GuiBundles | null, 
+/**
+ * The countdown running, if the owner started one.
+ */
+timer: TimerView | null, 
+/**
+ * Whether a countdown with `lock` ran out, so that nobody can type.
+ */
+typingLocked: boolean, 
+/**
+ * The typing race being run, if any.
+ */
+race: RaceView | null, 
+/**
+ * Participants whose input the owner froze.
+ */
+frozen: Array<string>, 
+/**
+ * In mode `turns`, whose turn it is.
+ */
+turn: string | null, };
+
+export type ArchivedView = { id: string, archivedAt: number, 
+/**
+ * When the room is deleted unless it is revived first.
+ */
+deletedAt: number, messages: { [key in string]: Array<string> }, colors: { [key in string]: string }, };
+
+export type GuiBundles = { default: string, available: Array<string>, };
+
+export type RoomSettings = { mode: RoomMode, 
+/**
+ * How long an empty room is kept before it is cleaned up; the instance
+ * default when unset.
+ */
+idleTtlSecs: number | null, 
+/**
+ * Hard lifetime counted from creation, regardless of activity.
+ */
+maxAgeSecs: number | null, maxParticipants: number, listed: boolean, topic: string | null, 
+/**
+ * The language the room is held in, if the owner set one.
+ */
+locale: LocaleHint | null, 
+/**
+ * Unix seconds before which joins are refused, for a room scheduled
+ * with `POST /admin/rooms`.
+ */
+startsAt: number | null, 
+/**
+ * Interview mode: every key press is recorded with its time, for the
+ * creator to export.
+ */
+recordKeystrokes: boolean, 
+/**
+ * Pinned above everyone's lines with `setPrompt`, say an icebreaker or
+ * an exercise.
+ */
+prompt: string | null, 
+/**
+ * In mode `turns`, how long each turn lasts at most.
+ */
+turnSecs: number | null, };
+
+export type RoomSettingsUpdate = { mode?: RoomMode | null, 
+/**
+ * `0` goes back to the instance default.
+ */
+idleTtlSecs?: number | null, 
+/**
+ * `0` clears the limit.
+ */
+maxAgeSecs?: number | null, maxParticipants?: number | null, listed?: boolean | null, 
+/**
+ * An empty string clears the topic.
+ */
+topic?: string | null, 
+/**
+ * The room's language tag and keyboard layout, replacing both; empty
+ * strings clear them.
+ */
+locale?: string | null, layout?: string | null, recordKeystrokes?: boolean | null, 
+/**
+ * `0` clears the limit.
+ */
+turnSecs?: number | null, };
+
+export type RoomMode = "live" | "line" | "turns";
+
+export type LocaleHint = { 
+/**
+ * A BCP 47 language tag, e.g. `he-IL` or `sr-Latn`.
+ */
+locale: string | null, 
+/**
+ * The keyboard layout typed on, e.g. `dvorak` or `he-standard`.
+ */
+layout: string | null, 
+/**
+ * Whether `locale` is written right to left.
+ */
+rtl: boolean, };
+
+export type Direction = "ltr" | "rtl" | "auto";
+
+export type StreamFormat = "json" | "ansi";
+
+export type Granularity = "key" | "word" | "line";
+
+export type KeySound = "keypress" | "backspace" | "bell" | "return";
+
+export type UserPrefs = { theme?: string | null, fontSize?: number | null, sound?: boolean | null, 
+/**
+ * Receives a POST when someone mentions this user, e.g. an ntfy topic.
+ */
+pushUrl?: string | null, 
+/**
+ * Do not disturb: mentions of this user are neither shown to them nor
+ * pushed.
+ */
+dnd?: boolean | null, };
+
+export type Results = { hits: Array<Hit>, 
+/**
+ * More lines matched than `hits` holds.
+ */
+truncated: boolean, };
+
+export type Hit = { participant: string, index: number, line: string, };
+
+export type TimerView = { 
+/**
+ * Unix milliseconds, by the server's clock.
+ */
+endsAtMs: number, 
+/**
+ * Left when this was sent; count down from this rather than from
+ * `endsAtMs` if the clocks may disagree.
+ */
+msLeft: number, lock: boolean, };
+
+export type RaceView = { text: string, 
+/**
+ * Unix milliseconds.
+ */
+startedAtMs: number, };
+
+export type RaceProgress = { participant: string, 
+/**
+ * Characters of the text typed right, out of `length`.
+ */
+correct: number, length: number, 
+/**
+ * Percent of the characters typed that were right.
+ */
+accuracy: number, 
+/**
+ * Words a minute, a word being five characters typed right.
+ */
+wpm: number, 
+/**
+ * 1 for the first to finish, and so on.
+ */
+place: number | null, };
@@ -0,0 +1,21 @@
+CREATE TABLE rooms (
+    id TEXT PRIMARY KEY,
+    owner_id TEXT,
+    settings JSONB NOT NULL,
+    created_at BIGINT NOT NULL,
+    creator_token_hash TEXT
+);
+
+-- Finished lines of each room, in the order they were written.
+CREATE TABLE room_history (
+    seq BIGSERIAL PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    participant TEXT NOT NULL,
+    text TEXT NOT NULL
+);
+CREATE INDEX room_history_room_id ON room_history (room_id, seq);
+
+CREATE TABLE user_prefs (
+    identity TEXT PRIMARY KEY,
+    prefs JSONB NOT NULL
+);
@@ -0,0 +1,6 @@
+-- A history row is either one line or a zstd-compressed segment standing in
+-- for a run of lines.
+ALTER TABLE room_history
+    ALTER COLUMN participant DROP NOT NULL,
+    ALTER COLUMN text DROP NOT NULL,
+    ADD COLUMN segment BYTEA;
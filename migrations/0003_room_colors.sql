@@ -0,0 +1,2 @@
+-- Each participant's color, by participant id.
+ALTER TABLE rooms ADD COLUMN colors JSONB NOT NULL DEFAULT '{}';
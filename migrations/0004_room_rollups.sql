@@ -0,0 +1,10 @@
+-- Each room's activity per UTC day. Rows are kept after the room expires.
+CREATE TABLE room_rollups (
+    room_id TEXT NOT NULL,
+    day DATE NOT NULL,
+    lines BIGINT NOT NULL DEFAULT 0,
+    characters BIGINT NOT NULL DEFAULT 0,
+    peak_participants BIGINT NOT NULL DEFAULT 0,
+    active_minutes BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (day, room_id)
+);
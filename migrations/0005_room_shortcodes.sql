@@ -0,0 +1,3 @@
+-- The code of each room's short link, /j/<code>.
+ALTER TABLE rooms ADD COLUMN shortcode TEXT;
+CREATE INDEX rooms_shortcode ON rooms (shortcode);
@@ -0,0 +1,2 @@
+-- Each participant's language and keyboard-layout hint, by participant id.
+ALTER TABLE rooms ADD COLUMN locales JSONB NOT NULL DEFAULT '{}';
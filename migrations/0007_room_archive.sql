@@ -0,0 +1,4 @@
+-- Set once a room has expired, while it is kept to be read or revived.
+ALTER TABLE rooms ADD COLUMN archived JSONB;
+CREATE INDEX rooms_archived_at ON rooms (((archived->>'at')::BIGINT))
+    WHERE archived IS NOT NULL;
@@ -0,0 +1,129 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{header, Body, Request, Response, StatusCode};
+use std::net::IpAddr;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::AccessConfig,
+    oidc::cookie,
+    security::{self, Event},
+};
+
+const SECRET_COOKIE: &str = "typeto_secret";
+
+/// Instance-wide gate for personal deployments: HTTP Basic credentials, a
+/// shared secret carried in a cookie, or both (either one is then enough).
+pub struct AccessGate {
+    basic: Option<(String, String)>,
+    shared_secret: Option<String>,
+}
+
+impl AccessGate {
+    pub fn new(config: &AccessConfig) -> Result<Self, String> {
+        let basic = match (&config.username, &config.password) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            (None, None) => None,
+            _ => return Err("[access] needs both username and password".to_string()),
+        };
+        if basic.is_none() && config.shared_secret.is_none() {
+            return Err("[access] needs username/password or shared_secret".to_string());
+        }
+        Ok(Self {
+            basic,
+            shared_secret: config.shared_secret.clone(),
+        })
+    }
+
+    /// Returns the response to send instead of serving `req`, or `None` if
+    /// the request may proceed. Wrong credentials from `ip` are logged.
+    pub fn check(&self, req: &Request<Body>, ip: Option<IpAddr>) -> Option<Response<Body>> {
+        if self.basic_ok(req) {
+            return None;
+        }
+
+        if let Some(secret) = &self.shared_secret {
+            if cookie(req, SECRET_COOKIE).is_some_and(|value| same(&value, secret)) {
+                return None;
+            }
+            // `?secret=` on any page sets the cookie and strips itself from the URL.
+            if let Some(query) = req.uri().query() {
+                let (matched, rest): (Vec<_>, Vec<_>) =
+                    url::form_urlencoded::parse(query.as_bytes())
+                        .partition(|(key, _)| key == "secret");
+                if matched.iter().any(|(_, value)| same(value, secret)) {
+                    let rest = url::form_urlencoded::Serializer::new(String::new())
+                        .extend_pairs(rest)
+                        .finish();
+                    let location = if rest.is_empty() {
+                        req.uri().path().to_string()
+                    } else {
+                        format!("{}?{}", req.uri().path(), rest)
+                    };
+                    return Some(
+                        Response::builder()
+                            .status(StatusCode::FOUND)
+                            .header(header::LOCATION, location)
+                            .header(
+                                header::SET_COOKIE,
+                                format!(
+                                    "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age=31536000",
+                                    SECRET_COOKIE, secret
+                                ),
+                            )
+                            .body(Body::empty())
+                            .unwrap(),
+                    );
+                }
+            }
+        }
+
+        let offered = req.headers().contains_key(header::AUTHORIZATION)
+            || cookie(req, SECRET_COOKIE).is_some()
+            || req.uri().query().is_some_and(|query| {
+                url::form_urlencoded::parse(query.as_bytes()).any(|(key, _)| key == "secret")
+            });
+        if offered {
+            security::log(
+                ip,
+                Event::AuthFailed {
+                    credential: "access",
+                },
+            );
+        }
+
+        let mut builder = Response::builder().status(StatusCode::UNAUTHORIZED);
+        if self.basic.is_some() {
+            builder = builder.header(header::WWW_AUTHENTICATE, "Basic realm=\"typeto\"");
+        }
+        Some(
+            builder
+                .body(Body::from("This instance is private."))
+                .unwrap(),
+        )
+    }
+
+    fn basic_ok(&self, req: &Request<Body>) -> bool {
+        let Some((username, password)) = &self.basic else {
+            return false;
+        };
+        let Some(decoded) = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+        else {
+            return false;
+        };
+        match decoded.split_once(':') {
+            // Evaluate both comparisons so timing doesn't reveal which one failed.
+            Some((user, pass)) => same(user, username) & same(pass, password),
+            None => false,
+        }
+    }
+}
+
+fn same(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
@@ -0,0 +1,155 @@
+use futures_util::{stream, StreamExt};
+use hyper::{header, service::service_fn, Body, Request, Response, Server, StatusCode};
+use rustls_acme::{caches::DirCache, AcmeConfig as Acme};
+use std::{
+    convert::Infallible,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+};
+use tracing::{error, info, warn};
+
+use crate::{config::AcmeConfig, listener::PeerAddr, serve, SharedState};
+
+/// Serves the app over TLS on `https_port`, fetching and renewing the
+/// certificate for the configured domains via the TLS-ALPN-01 challenge, and
+/// redirects plain HTTP on `http_port` to it.
+pub async fn run(config: &AcmeConfig, state: SharedState) -> Result<(), String> {
+    if config.domains.is_empty() {
+        return Err("[acme] needs at least one domain.".to_string());
+    }
+
+    if config.http_port != 0 {
+        let addr = SocketAddr::from(([0, 0, 0, 0], config.http_port));
+        let https_port = config.https_port;
+        let redirect = Server::try_bind(&addr)
+            .map_err(|err| format!("Failed to bind {}: {}", addr, err))?
+            .serve(hyper::service::make_service_fn(move |_conn| async move {
+                Ok::<_, Infallible>(service_fn(move |req| redirect_to_https(req, https_port)))
+            }));
+        info!("Redirecting http://0.0.0.0:{} to HTTPS", config.http_port);
+        tokio::spawn(async move {
+            if let Err(err) = redirect.await {
+                error!("HTTP redirect server error: {}", err);
+            }
+        });
+    }
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], config.https_port));
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|err| format!("Failed to bind {}: {}", addr, err))?;
+    let tcp_incoming = Box::pin(stream::unfold(listener, |listener| async {
+        let accepted = listener.accept().await.map(|(stream, _)| stream);
+        Some((accepted, listener))
+    }));
+
+    let tls_incoming = Acme::new(config.domains.clone())
+        .contact(
+            config
+                .contact
+                .iter()
+                .map(|email| format!("mailto:{}", email)),
+        )
+        .cache(DirCache::new(config.cache_dir.clone()))
+        .directory_lets_encrypt(!config.staging)
+        .tokio_incoming(tcp_incoming, vec![b"http/1.1".to_vec()])
+        // A failed accept would otherwise end the server.
+        .filter_map(|conn| async move {
+            match conn {
+                Ok(tls) => {
+                    let (tcp, _) = tls.get_ref().get_ref();
+                    let ip = tcp.get_ref().peer_addr().ok().map(|addr| addr.ip());
+                    Some(Ok::<_, io::Error>(TlsConn { inner: tls, ip }))
+                }
+                Err(err) => {
+                    warn!("TLS accept failed: {}", err);
+                    None
+                }
+            }
+        });
+
+    info!(
+        "Server running on https://{}:{}",
+        config.domains[0], config.https_port
+    );
+    serve(hyper::server::accept::from_stream(tls_incoming), state)
+        .await
+        .map_err(|err| format!("Server error: {}", err))
+}
+
+/// A TLS connection plus the client address recorded at accept time.
+struct TlsConn<S> {
+    inner: S,
+    ip: Option<IpAddr>,
+}
+
+impl<S> PeerAddr for TlsConn<S> {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TlsConn<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TlsConn<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn redirect_to_https(
+    req: Request<Body>,
+    https_port: u16,
+) -> Result<Response<Body>, Infallible> {
+    let Some(host) = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("Missing Host header."))
+            .unwrap());
+    };
+    let host = host.split(':').next().unwrap_or(host);
+    let authority = match https_port {
+        443 => host.to_string(),
+        port => format!("{}:{}", host, port),
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    Ok(Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, format!("https://{}{}", authority, path))
+        .body(Body::empty())
+        .unwrap())
+}
@@ -0,0 +1,359 @@
+//! Each room in memory is owned by a task of its own, which runs what is
+//! asked of the room one command at a time, in the order the commands were
+//! sent. Nothing else touches a room, so there is no lock to wait on across
+//! rooms, and whatever one command does (persisting, scheduling broadcasts,
+//! counting activity) is done before the next one starts. [`Rooms`] maps ids
+//! to the tasks' handles and is only locked to look one up.
+//!
+//! After each command a task publishes a [`Published`] copy of what readers
+//! need to know about its room: listings, the API and searches read that
+//! instead of queueing behind key presses.
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+use crate::{metrics::RoomMemory, storage::HistoryLine, Counters, Room, RoomSettings, Traffic};
+
+/// A command gets the room, or `None` once it was closed.
+type Command = Box<dyn FnOnce(&mut Option<Room>) + Send>;
+
+/// Sends commands to a room's task. Clones reach the same room.
+#[derive(Clone)]
+pub struct RoomHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    published: Arc<RwLock<Arc<Published>>>,
+}
+
+impl RoomHandle {
+    /// Starts the task that owns `room`. It runs until the room is closed or
+    /// every handle to it is dropped.
+    pub fn spawn(room: Room) -> Self {
+        let (commands, mut received) = mpsc::unbounded_channel::<Command>();
+        let mut last = Arc::new(Published::of(&room, None));
+        let published = Arc::new(RwLock::new(last.clone()));
+        let publishing = published.clone();
+        let id = room.id.clone();
+        tokio::spawn(async move {
+            let mut room = Some(room);
+            while let Some(command) = received.recv().await {
+                // The room is kept as the command left it, like a poisoned
+                // lock taken anyway: the alternative is losing the room.
+                if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| command(&mut room))) {
+                    error!("Room {} panicked: {}", id, panic_message(panic.as_ref()));
+                }
+                let Some(room) = &room else {
+                    break;
+                };
+                last = Arc::new(Published::of(room, Some(&last)));
+                *publishing.write().unwrap() = last.clone();
+            }
+        });
+        Self {
+            commands,
+            published,
+        }
+    }
+
+    /// The room as of the last command it finished.
+    pub fn published(&self) -> Arc<Published> {
+        self.published.read().unwrap().clone()
+    }
+
+    /// Runs `command` on the room once what was sent before it is done, and
+    /// returns what it returned; `None` if the room was closed.
+    pub async fn call<R: Send + 'static>(
+        &self,
+        command: impl FnOnce(&mut Room) -> R + Send + 'static,
+    ) -> Option<R> {
+        let (reply, replied) = oneshot::channel();
+        self.send(move |room| {
+            if let Some(room) = room {
+                let _ = reply.send(command(room));
+            }
+        });
+        replied.await.ok()
+    }
+
+    /// Runs `command` on the room without waiting for it.
+    pub fn cast(&self, command: impl FnOnce(&mut Room) + Send + 'static) {
+        self.send(move |room| {
+            if let Some(room) = room {
+                command(room);
+            }
+        });
+    }
+
+    /// Takes the room out of its task if `close` says to, and returns it.
+    /// Commands sent after that find the room closed.
+    pub async fn close_if(
+        &self,
+        close: impl FnOnce(&mut Room) -> bool + Send + 'static,
+    ) -> Option<Room> {
+        let (reply, replied) = oneshot::channel();
+        self.send(move |room| {
+            if room.as_mut().is_some_and(close) {
+                let _ = reply.send(room.take());
+            }
+        });
+        replied.await.ok().flatten()
+    }
+
+    fn send(&self, command: impl FnOnce(&mut Option<Room>) + Send + 'static) {
+        let _ = self.commands.send(Box::new(command));
+    }
+
+    fn is(&self, other: &RoomHandle) -> bool {
+        self.commands.same_channel(&other.commands)
+    }
+}
+
+/// The rooms in memory, by id.
+#[derive(Default)]
+pub struct Rooms(Mutex<HashMap<String, RoomHandle>>);
+
+impl Rooms {
+    pub fn new(rooms: impl IntoIterator<Item = Room>) -> Self {
+        Self(Mutex::new(
+            rooms
+                .into_iter()
+                .map(|room| (room.id.clone(), RoomHandle::spawn(room)))
+                .collect(),
+        ))
+    }
+
+    pub fn get(&self, id: &str) -> Option<RoomHandle> {
+        self.0.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.0.lock().unwrap().contains_key(id)
+    }
+
+    /// Every room as of the last command it finished, in no particular
+    /// order.
+    pub fn published(&self) -> Vec<Arc<Published>> {
+        self.0
+            .lock()
+            .unwrap()
+            .values()
+            .map(RoomHandle::published)
+            .collect()
+    }
+
+    /// Every room's id and handle, in no particular order.
+    pub fn all(&self) -> Vec<(String, RoomHandle)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, handle)| (id.clone(), handle.clone()))
+            .collect()
+    }
+
+    /// Starts serving `room`, with `first` the first thing its task does,
+    /// unless there already is a room with its id. Returns whether it did.
+    pub fn open(&self, room: Room, first: impl FnOnce(&mut Room) + Send + 'static) -> bool {
+        let mut rooms = self.0.lock().unwrap();
+        if rooms.contains_key(&room.id) {
+            return false;
+        }
+        let id = room.id.clone();
+        let handle = RoomHandle::spawn(room);
+        handle.cast(first);
+        rooms.insert(id, handle);
+        true
+    }
+
+    /// Forgets `handle`'s room, if `id` is still that room.
+    pub fn remove(&self, id: &str, handle: &RoomHandle) {
+        let mut rooms = self.0.lock().unwrap();
+        if rooms.get(id).is_some_and(|current| current.is(handle)) {
+            rooms.remove(id);
+        }
+    }
+
+    /// Forgets room `id` and takes it out of its task, if it is in memory.
+    pub async fn close(&self, id: &str) -> Option<Room> {
+        let handle = self.0.lock().unwrap().remove(id)?;
+        handle.close_if(|_| true).await
+    }
+
+    /// Runs `command` on every room and collects what it returned.
+    pub async fn each<R: Send + 'static>(
+        &self,
+        command: impl Fn(&mut Room) -> R + Clone + Send + 'static,
+    ) -> Vec<R> {
+        let mut results = Vec::new();
+        for (_, handle) in self.all() {
+            results.extend(handle.call(command.clone()).await);
+        }
+        results
+    }
+}
+
+/// What readers see of a room, as its task left it after a command.
+pub struct Published {
+    pub id: String,
+    /// Who is here, each once, in the order they came.
+    pub participants: Vec<String>,
+    /// Their connections, a participant on several devices counting for
+    /// each.
+    pub connections: usize,
+    pub owner_id: Option<String>,
+    pub settings: RoomSettings,
+    pub seq: u64,
+    pub created_at: SystemTime,
+    pub shortcode: Option<String>,
+    pub creator_token_hash: Option<String>,
+    expires_at: Option<SystemTime>,
+    idle_timeout_at: SystemTime,
+    /// Finished lines, in the order they were finished; shared with the previous
+    /// publication until one is finished.
+    pub lines: Arc<Vec<HistoryLine>>,
+    lines_counted: (u64, usize),
+    /// What the finished lines hold, counted along with `lines`.
+    finished_memory: usize,
+    pub memory: RoomMemory,
+    departed_traffic: Vec<(String, Traffic)>,
+    /// Each connection's counters, which go on counting, and their values
+    /// when it joined.
+    live_traffic: Vec<(String, Arc<Counters>, Traffic)>,
+}
+
+impl Published {
+    fn of(room: &Room, previous: Option<&Published>) -> Self {
+        let mut participants: Vec<String> = Vec::new();
+        for participant in &room.participants {
+            if !participants.contains(&participant.id) {
+                participants.push(participant.id.clone());
+            }
+        }
+        // The count alone misses a line finished once a participant is at
+        // `MAX_HISTORY`, and `seq` alone misses lines brought back whole.
+        let finished = room
+            .messages
+            .values()
+            .map(|lines| lines.len().saturating_sub(1))
+            .sum();
+        let lines_counted = (room.seq, finished);
+        let (lines, finished_memory) = match previous {
+            Some(previous) if previous.lines_counted == lines_counted => {
+                (previous.lines.clone(), previous.finished_memory)
+            }
+            _ => (Arc::new(finished_lines(room)), room.finished_memory()),
+        };
+        Self {
+            id: room.id.clone(),
+            participants,
+            connections: room.participants.len(),
+            owner_id: room.owner_id.clone(),
+            settings: room.settings.clone(),
+            seq: room.seq,
+            created_at: room.created_at,
+            shortcode: room.shortcode.clone(),
+            creator_token_hash: room.creator_token_hash.clone(),
+            expires_at: room.expires_at(),
+            idle_timeout_at: room.idle_timeout_at(),
+            lines,
+            lines_counted,
+            finished_memory,
+            memory: room.memory(finished_memory),
+            departed_traffic: room
+                .departed_traffic
+                .iter()
+                .map(|(id, traffic)| (id.clone(), *traffic))
+                .collect(),
+            live_traffic: room
+                .participants
+                .iter()
+                .map(|p| (p.id.clone(), p.traffic.clone(), p.traffic_at_join))
+                .collect(),
+        }
+    }
+
+    /// Whether the next sweep expires the room, as `Room::is_expired`.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        if self.expires_at.is_some_and(|expires_at| expires_at < now) {
+            return true;
+        }
+        self.connections == 0 && self.idle_timeout_at < now
+    }
+
+    /// Each participant's traffic in the room, up to now, including those
+    /// who left.
+    pub fn traffic(&self) -> BTreeMap<String, Traffic> {
+        let mut traffic: BTreeMap<String, Traffic> =
+            self.departed_traffic.iter().cloned().collect();
+        for (id, counters, at_join) in &self.live_traffic {
+            traffic
+                .entry(id.clone())
+                .or_default()
+                .add(counters.snapshot().since(*at_join));
+        }
+        traffic
+    }
+}
+
+/// `room`'s finished lines, in the order they were finished.
+pub fn finished_lines(room: &Room) -> Vec<HistoryLine> {
+    // The last line of each participant is the one still being typed.
+    let finished = |participant: &str| {
+        room.messages
+            .get(participant)
+            .map_or(&[][..], |lines| &lines[..lines.len().saturating_sub(1)])
+    };
+    let mut ordered: HashMap<&str, usize> = HashMap::new();
+    for participant in &room.line_order {
+        *ordered.entry(participant).or_default() += 1;
+    }
+    // Lines whose order isn't known come first, participant by participant;
+    // an order for a line since dropped is skipped.
+    let mut participants: Vec<&String> = room.messages.keys().collect();
+    participants.sort();
+    let mut lines = Vec::new();
+    let mut next: HashMap<&str, isize> = HashMap::new();
+    for participant in participants {
+        let known = ordered.get(participant.as_str()).copied().unwrap_or(0);
+        let unordered = finished(participant).len() as isize - known as isize;
+        for text in &finished(participant)[..unordered.max(0) as usize] {
+            lines.push(HistoryLine {
+                participant: participant.clone(),
+                text: text.clone(),
+            });
+        }
+        next.insert(participant, unordered);
+    }
+    for participant in &room.line_order {
+        let Some(index) = next.get_mut(participant.as_str()) else {
+            continue;
+        };
+        if let Some(text) = usize::try_from(*index)
+            .ok()
+            .and_then(|index| finished(participant).get(index))
+        {
+            lines.push(HistoryLine {
+                participant: participant.clone(),
+                text: text.clone(),
+            });
+        }
+        *index += 1;
+    }
+    lines
+}
+
+/// What a panic said, if it said it with a string.
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("(no message)")
+}
@@ -0,0 +1,554 @@
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{
+    audit::{AuditEvent, AuditQuery},
+    config::AdminConfig,
+    embed::valid_room_id,
+    expiry, generate_random_string,
+    handoff::{self, ExportRequest, Handoff},
+    invite, json_response, metrics,
+    rollup::{self, RollupQuery},
+    schedule,
+    security::{self, Event},
+    shortlink, store_opened,
+    webhook::{self, HookRequest},
+    Room, SharedState,
+};
+
+const MAX_REQUEST_BYTES: u64 = 4096;
+/// For a handoff import, which carries whole rooms.
+const MAX_HANDOFF_BYTES: u64 = 64 * 1024 * 1024;
+const MAX_ANNOUNCEMENT_LEN: usize = 500;
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+const MAX_AUDIT_LIMIT: usize = 1000;
+const DEFAULT_MAINTENANCE_MESSAGE: &str =
+    "This server is about to go down for maintenance. New rooms can't be created right now.";
+
+/// Set while the server is draining for maintenance.
+pub struct Maintenance {
+    pub message: String,
+    pub deadline: Option<Instant>,
+}
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MaintenanceRequest {
+    message: Option<String>,
+    /// Exit after this long even if rooms are still in use.
+    deadline_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(default)]
+pub struct AnnounceRequest {
+    message: String,
+}
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(rename_all = "camelCase", default)]
+pub struct InviteRequest {
+    /// How long the invitation lasts; a week if unset.
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CreateRoomRequest {
+    /// Letters, digits, `-` and `_`; chosen by the server if unset.
+    id: Option<String>,
+    /// Unix seconds; joins are refused until then.
+    starts_at: Option<u64>,
+    /// How long the room is open from `startsAt`; an hour if unset.
+    duration_secs: Option<u64>,
+}
+
+/// Operator endpoints under `/admin/`, authorized by a bearer token that is
+/// separate from any user-facing login.
+pub struct Admin {
+    token: String,
+}
+
+impl Admin {
+    pub fn new(config: &AdminConfig) -> Result<Self, String> {
+        if config.token.is_empty() {
+            return Err("[admin] token must not be empty.".to_string());
+        }
+        Ok(Self {
+            token: config.token.clone(),
+        })
+    }
+
+    pub fn authorized(&self, req: &Request<Body>) -> bool {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| self.accepts(token))
+    }
+
+    pub fn accepts(&self, token: &str) -> bool {
+        bool::from(token.as_bytes().ct_eq(self.token.as_bytes()))
+    }
+
+    pub async fn route(
+        &self,
+        req: Request<Body>,
+        state: &SharedState,
+        ip: Option<IpAddr>,
+    ) -> Response<Body> {
+        if !self.authorized(&req) {
+            if req.headers().contains_key(header::AUTHORIZATION) {
+                security::log(
+                    ip,
+                    Event::AuthFailed {
+                        credential: "admin",
+                    },
+                );
+            }
+            return json_response(
+                StatusCode::UNAUTHORIZED,
+                json!({"error": "Missing or wrong admin token."}),
+            );
+        }
+
+        match (req.method().clone(), req.uri().path()) {
+            (Method::POST, "/admin/maintenance") => {
+                let request: MaintenanceRequest = match read_json(req).await {
+                    Ok(request) => request,
+                    Err(response) => return response,
+                };
+                let message = request
+                    .message
+                    .unwrap_or_else(|| DEFAULT_MAINTENANCE_MESSAGE.to_string());
+                let deadline = request
+                    .deadline_secs
+                    .map(|secs| Instant::now() + Duration::from_secs(secs));
+                let active_rooms = start_maintenance(state, message, deadline);
+                json_response(
+                    StatusCode::OK,
+                    json!({"maintenance": true, "activeRooms": active_rooms}),
+                )
+            }
+            (Method::POST, "/admin/announce") => {
+                let request: AnnounceRequest = match read_json(req).await {
+                    Ok(request) => request,
+                    Err(response) => return response,
+                };
+                let message = request.message.trim().to_string();
+                if message.is_empty() || message.chars().count() > MAX_ANNOUNCEMENT_LEN {
+                    return json_response(
+                        StatusCode::BAD_REQUEST,
+                        json!({"error": format!(
+                            "Message must be 1 to {} characters.",
+                            MAX_ANNOUNCEMENT_LEN
+                        )}),
+                    );
+                }
+                info!("Announcement: {}", message);
+                state.audit.record(AuditEvent::Admin {
+                    action: "announce".to_string(),
+                    detail: Some(message.clone()),
+                });
+                let sockets = state.announce(message);
+                json_response(StatusCode::OK, json!({"sockets": sockets}))
+            }
+            (Method::GET, "/admin/audit") => {
+                let params: HashMap<String, String> = req
+                    .uri()
+                    .query()
+                    .map(|query| {
+                        url::form_urlencoded::parse(query.as_bytes())
+                            .into_owned()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let query = AuditQuery {
+                    room: params.get("room").cloned(),
+                    since: params.get("since").and_then(|since| since.parse().ok()),
+                    limit: params
+                        .get("limit")
+                        .and_then(|limit| limit.parse().ok())
+                        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+                        .clamp(1, MAX_AUDIT_LIMIT),
+                };
+                match state.audit.query(&query).await {
+                    Ok(entries) => json_response(StatusCode::OK, json!({"entries": entries})),
+                    Err(err) => {
+                        json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"error": err}))
+                    }
+                }
+            }
+            (Method::GET, "/admin/rollups") => {
+                let params: HashMap<String, String> = req
+                    .uri()
+                    .query()
+                    .map(|query| {
+                        url::form_urlencoded::parse(query.as_bytes())
+                            .into_owned()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let query = RollupQuery {
+                    room: params.get("room").cloned(),
+                    since: params.get("since").cloned(),
+                    until: params.get("until").cloned(),
+                };
+                let days = [&query.since, &query.until];
+                if days
+                    .into_iter()
+                    .flatten()
+                    .any(|day| chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").is_err())
+                {
+                    return json_response(
+                        StatusCode::BAD_REQUEST,
+                        json!({"error": "since and until must be dates as YYYY-MM-DD."}),
+                    );
+                }
+                // Include what live rooms did since the last flush.
+                rollup::flush(state).await;
+                state.store_writer.settle().await;
+                match state.store.load_rollups(&query).await {
+                    Ok(days) => json_response(StatusCode::OK, json!({"days": days})),
+                    Err(err) => {
+                        json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"error": err}))
+                    }
+                }
+            }
+            (Method::POST, "/admin/invites") => {
+                let request: InviteRequest = match read_json(req).await {
+                    Ok(request) => request,
+                    Err(response) => return response,
+                };
+                let ttl = request
+                    .ttl_secs
+                    .map_or(invite::DEFAULT_TTL, Duration::from_secs);
+                let token = state.invites.issue(ttl);
+                state.audit.record(AuditEvent::Admin {
+                    action: "invite".to_string(),
+                    detail: None,
+                });
+                json_response(
+                    StatusCode::OK,
+                    json!({"invite": token, "expiresInSecs": ttl.as_secs()}),
+                )
+            }
+            (Method::POST, "/admin/rooms") => {
+                let request: CreateRoomRequest = match read_json(req).await {
+                    Ok(request) => request,
+                    Err(response) => return response,
+                };
+                create_room(state, request).await
+            }
+            (Method::POST, "/admin/handoff/export") => {
+                let request: ExportRequest = match read_json(req).await {
+                    Ok(request) => request,
+                    Err(response) => return response,
+                };
+                handoff::export(state, request).await
+            }
+            (Method::POST, "/admin/handoff/import") => {
+                let handoff: Handoff = match read_json_up_to(req, MAX_HANDOFF_BYTES).await {
+                    Ok(handoff) => handoff,
+                    Err(response) => return response,
+                };
+                handoff::import(state, handoff).await
+            }
+            #[cfg(feature = "discord")]
+            (Method::GET, "/admin/bridge/discord") => crate::discord::list(state),
+            #[cfg(feature = "discord")]
+            (Method::POST, "/admin/bridge/discord") => {
+                let request: crate::discord::BridgeRequest = match read_json(req).await {
+                    Ok(request) => request,
+                    Err(response) => return response,
+                };
+                crate::discord::add(state, request)
+            }
+            #[cfg(feature = "discord")]
+            (Method::DELETE, path) if path.starts_with("/admin/bridge/discord/") => {
+                crate::discord::remove(state, &path["/admin/bridge/discord/".len()..])
+            }
+            (Method::GET, "/admin/webhooks") => webhook::list(state),
+            (Method::POST, "/admin/webhooks") => {
+                let request: HookRequest = match read_json(req).await {
+                    Ok(request) => request,
+                    Err(response) => return response,
+                };
+                webhook::add(state, request)
+            }
+            (Method::POST, path) if path.starts_with("/admin/webhooks/") => {
+                match path["/admin/webhooks/".len()..].strip_suffix("/secret") {
+                    Some(id) => webhook::rotate_secret(state, id),
+                    None => json_response(StatusCode::NOT_FOUND, json!({"error": "Not found."})),
+                }
+            }
+            (Method::DELETE, path) if path.starts_with("/admin/webhooks/") => {
+                webhook::remove(state, &path["/admin/webhooks/".len()..])
+            }
+            (Method::GET, "/admin/rooms") => json_response(
+                StatusCode::OK,
+                json!({
+                    "total": state.traffic.snapshot(),
+                    "rooms": metrics::rooms(state),
+                }),
+            ),
+            (Method::GET, path) if path.starts_with("/admin/rooms/") => {
+                let id = &path["/admin/rooms/".len()..];
+                match metrics::rooms(state).into_iter().find(|room| room.id == id) {
+                    Some(room) => json_response(StatusCode::OK, json!(room)),
+                    None => json_response(StatusCode::NOT_FOUND, json!({"error": "No such room."})),
+                }
+            }
+            (method @ (Method::PUT | Method::DELETE), path)
+                if path.starts_with("/admin/rooms/") =>
+            {
+                match path["/admin/rooms/".len()..].split_once("/shadow-bans/") {
+                    Some((room_id, participant)) => {
+                        shadow_ban(state, room_id, participant, method == Method::PUT).await
+                    }
+                    None => json_response(StatusCode::NOT_FOUND, json!({"error": "Not found."})),
+                }
+            }
+            (Method::GET, "/admin/connections") => json_response(
+                StatusCode::OK,
+                json!({
+                    "sockets": state.notices.receiver_count(),
+                    "abnormalCloses": state.closes.snapshot(),
+                    "degraded": state.degradations.snapshot(),
+                }),
+            ),
+            (Method::GET, "/admin/metrics") => Response::builder()
+                .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(metrics::prometheus(state)))
+                .unwrap(),
+            (Method::DELETE, "/admin/maintenance") => {
+                state.maintenance.lock().unwrap().take();
+                info!("Maintenance mode cancelled");
+                state.audit.record(AuditEvent::Admin {
+                    action: "maintenanceCancelled".to_string(),
+                    detail: None,
+                });
+                json_response(StatusCode::OK, json!({"maintenance": false}))
+            }
+            _ => json_response(StatusCode::NOT_FOUND, json!({"error": "Not found."})),
+        }
+    }
+}
+
+/// Creates an empty room, whatever `[rooms] creation` says; it expires like
+/// any other room nobody is in.
+async fn create_room(state: &SharedState, request: CreateRoomRequest) -> Response<Body> {
+    let id = request.id.unwrap_or_else(|| generate_random_string(6));
+    if !valid_room_id(&id) {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({"error": "Room ids are 1 to 64 letters, digits, '-' or '_'."}),
+        );
+    }
+    let mut room = Room::new(id.clone());
+    let window = match (request.starts_at, request.duration_secs) {
+        (None, Some(_)) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({"error": "durationSecs needs startsAt."}),
+            );
+        }
+        (None, None) => None,
+        (Some(starts_at), duration_secs) => {
+            let duration_secs = duration_secs.unwrap_or(schedule::DEFAULT_DURATION_SECS);
+            let max_age = schedule::max_age(
+                expiry::unix_secs(room.created_at),
+                starts_at,
+                duration_secs,
+                state.config().rooms.max_age_secs,
+            );
+            match max_age {
+                Ok(max_age) => {
+                    room.settings.starts_at = Some(starts_at);
+                    room.settings.max_age_secs = Some(max_age);
+                    Some((starts_at, starts_at + duration_secs))
+                }
+                Err(err) => return json_response(StatusCode::BAD_REQUEST, json!({"error": err})),
+            }
+        }
+    };
+    let stored = match state.store.load_room(&id).await {
+        Ok(stored) => stored.is_some(),
+        Err(err) => return json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({"error": err})),
+    };
+    let shortcode = shortlink::fresh(state).await;
+    let token = room.issue_creator_token();
+    room.shortcode = Some(shortcode.clone());
+    room.configure(&state.config());
+    let opened = !stored && state.rooms.open(room, store_opened(state));
+    if !opened {
+        return json_response(
+            StatusCode::CONFLICT,
+            json!({"error": "A room with this id already exists."}),
+        );
+    }
+    info!("Room {} created by the operator", id);
+    state.audit.record(AuditEvent::Admin {
+        action: "roomCreated".to_string(),
+        detail: Some(id.clone()),
+    });
+    let mut reply = json!({"room": id, "shortcode": shortcode, "creatorToken": token});
+    if let Some((starts_at, ends_at)) = window {
+        reply["startsAt"] = json!(starts_at);
+        reply["endsAt"] = json!(ends_at);
+        reply["calendar"] = json!(format!("/api/rooms/{}/calendar.ics", id));
+    }
+    json_response(StatusCode::OK, reply)
+}
+
+/// Shadow-bans `participant` in room `room_id`, or lifts the ban: their key
+/// presses are still taken and shown back to them, but no one else sees
+/// them and they aren't kept in the room's history.
+async fn shadow_ban(
+    state: &SharedState,
+    room_id: &str,
+    participant: &str,
+    banned: bool,
+) -> Response<Body> {
+    let Some(room) = state.rooms.get(room_id) else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({"error": "No such room, or it isn't open."}),
+        );
+    };
+    let writer = state.store_writer.clone();
+    let target = participant.to_string();
+    let result = room
+        .call(move |room| {
+            if room.shadow_ban(&target, banned)? {
+                writer.append_history(room.id.clone(), room.take_history());
+                room.notify_participants();
+            }
+            let mut shadow_banned: Vec<String> = room.shadow_banned.keys().cloned().collect();
+            shadow_banned.sort_unstable();
+            Ok::<_, String>(shadow_banned)
+        })
+        .await;
+    match result {
+        Some(Ok(shadow_banned)) => {
+            let action = if banned {
+                "shadowBan"
+            } else {
+                "shadowBanLifted"
+            };
+            info!("{} of {} in room {}", action, participant, room_id);
+            state.audit.record(AuditEvent::Admin {
+                action: action.to_string(),
+                detail: Some(format!("{} in {}", participant, room_id)),
+            });
+            json_response(StatusCode::OK, json!({"shadowBanned": shadow_banned}))
+        }
+        Some(Err(err)) => json_response(StatusCode::NOT_FOUND, json!({"error": err})),
+        None => json_response(
+            StatusCode::NOT_FOUND,
+            json!({"error": "No such room, or it isn't open."}),
+        ),
+    }
+}
+
+/// Refuses new rooms from now on, tells everyone connected, and shuts the
+/// server down once no room has participants or the deadline passes.
+/// Returns how many rooms are still in use.
+fn start_maintenance(state: &SharedState, message: String, deadline: Option<Instant>) -> usize {
+    let already_draining = state
+        .maintenance
+        .lock()
+        .unwrap()
+        .replace(Maintenance {
+            message: message.clone(),
+            deadline,
+        })
+        .is_some();
+    info!("Maintenance mode enabled: {}", message);
+    state.audit.record(AuditEvent::Admin {
+        action: "maintenance".to_string(),
+        detail: Some(message.clone()),
+    });
+
+    state.announce(message);
+    let active_rooms = state
+        .rooms
+        .published()
+        .iter()
+        .filter(|room| room.connections > 0)
+        .count();
+
+    if !already_draining {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let deadline_passed = match &*state.maintenance.lock().unwrap() {
+                    Some(maintenance) => maintenance
+                        .deadline
+                        .is_some_and(|deadline| deadline <= Instant::now()),
+                    None => return,
+                };
+                let drained = state
+                    .rooms
+                    .published()
+                    .iter()
+                    .all(|room| room.connections == 0);
+                if drained || deadline_passed {
+                    info!(
+                        "Maintenance: {}, shutting down",
+                        if drained {
+                            "all rooms are empty"
+                        } else {
+                            "deadline reached"
+                        }
+                    );
+                    state.request_shutdown();
+                    return;
+                }
+            }
+        });
+    }
+    active_rooms
+}
+
+pub async fn read_json<T: serde::de::DeserializeOwned + Default>(
+    req: Request<Body>,
+) -> Result<T, Response<Body>> {
+    read_json_up_to(req, MAX_REQUEST_BYTES).await
+}
+
+async fn read_json_up_to<T: serde::de::DeserializeOwned + Default>(
+    req: Request<Body>,
+    max_bytes: u64,
+) -> Result<T, Response<Body>> {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.is_some_and(|len| len > max_bytes) {
+        return Err(json_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            json!({"error": "Request body too large."}),
+        ));
+    }
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|_| json_response(StatusCode::BAD_REQUEST, json!({"error": "Bad body."})))?;
+    if body.is_empty() {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(&body)
+        .map_err(|err| json_response(StatusCode::BAD_REQUEST, json!({"error": err.to_string()})))
+}
@@ -0,0 +1,222 @@
+//! A stream for terminals that can't parse JSON: instead of messages, a
+//! connection that joins with `"format": "ansi"` gets VT100 escape sequences
+//! that draw the room and redraw a line as each key press arrives, so piping
+//! the socket to a terminal (`websocat`, say) shows the conversation.
+//! Client messages are still JSON.
+
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use typeto_client_core::apply_key;
+
+use crate::{sound::KeySound, RoomView, ServerMessage, SYSTEM_ID};
+
+/// Lines shown per participant, the last one the line in progress.
+const SHOWN: usize = 3;
+/// Columns a line is clipped to, keeping its end, where typing happens.
+const WIDTH: usize = 80;
+
+const CLEAR: &str = "\x1b[2J\x1b[H";
+const ERASE_LINE: &str = "\x1b[2K";
+const BOLD: &str = "\x1b[1m";
+const PLAIN: &str = "\x1b[0m";
+const BELL: &str = "\x07";
+
+/// What a connection receives, chosen when it joins a room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "lowercase")]
+pub enum StreamFormat {
+    #[default]
+    Json,
+    Ansi,
+}
+
+/// The room as a terminal connection last drew it.
+#[derive(Debug, Default)]
+pub struct Screen {
+    /// Participants in the order drawn, you first, with the lines shown.
+    sections: Vec<(String, Vec<String>)>,
+}
+
+impl Screen {
+    /// The escape sequences that show `message`, if it changes what's drawn.
+    pub fn render(&mut self, message: &ServerMessage) -> Option<String> {
+        match message {
+            ServerMessage::GotRoom { room } => Some(self.room(room)),
+            ServerMessage::KeyPress {
+                key,
+                source,
+                cursor_pos,
+                ..
+            } => self.key_press(source, key, *cursor_pos),
+            ServerMessage::Committed {
+                r#final, source, ..
+            } => self.committed(source, r#final),
+            ServerMessage::Typing { source, line } => self.typed(source, line),
+            // Only sent when the connection asked for sounds.
+            ServerMessage::KeySound {
+                sound: KeySound::Bell,
+                ..
+            } => Some(BELL.to_string()),
+            ServerMessage::Error { message }
+            | ServerMessage::RoomIsCrowded { message }
+            | ServerMessage::ServerNotice { message }
+            | ServerMessage::SessionTakenOver { message } => Some(self.status(message)),
+            ServerMessage::Expiring { seconds_left, .. } => Some(self.status(&format!(
+                "This room expires in {} minute(s).",
+                seconds_left.div_ceil(60)
+            ))),
+            ServerMessage::Mention { source, line, .. } => {
+                Some(self.status(&format!("{} mentioned you: {}", source, line)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Clears the terminal and draws the whole room.
+    fn room(&mut self, room: &RoomView) -> String {
+        let ids = std::iter::once(&room.your_id).chain(&room.other_participant_ids);
+        self.sections = ids
+            .map(|id| {
+                let lines = room.messages.get(id).cloned().unwrap_or_default();
+                let shown = lines[lines.len().saturating_sub(SHOWN)..].to_vec();
+                (id.clone(), shown)
+            })
+            .collect();
+        let event = room.messages.get(SYSTEM_ID).and_then(|lines| {
+            lines
+                .iter()
+                .rev()
+                .find(|line| !line.is_empty())
+                .map(String::as_str)
+        });
+        self.draw(&room.id, event.unwrap_or_default())
+    }
+
+    /// Clears the terminal and draws room `room_id` with nothing said yet by
+    /// `participants`, in that order, for a recording of it.
+    pub fn start(&mut self, room_id: &str, participants: &[String]) -> String {
+        self.sections = participants
+            .iter()
+            .map(|id| (id.clone(), vec![String::new()]))
+            .collect();
+        self.draw(room_id, "")
+    }
+
+    /// The rows drawn on.
+    pub fn rows(&self) -> usize {
+        self.status_row() + 1
+    }
+
+    fn draw(&self, room_id: &str, status: &str) -> String {
+        let mut out = format!("{}{}typeto.me room {}{}", CLEAR, BOLD, clip(room_id), PLAIN);
+        for i in 0..self.sections.len() {
+            self.draw_section(i, &mut out);
+        }
+        out.push_str(&self.status(status));
+        out
+    }
+
+    fn key_press(&mut self, source: &str, key: &str, cursor_pos: Option<usize>) -> Option<String> {
+        let i = self.section(source)?;
+        let line = self.sections[i].1.last_mut()?;
+        let before = line.clone();
+        apply_key(line, key, cursor_pos);
+        if *line == before {
+            return None;
+        }
+        Some(self.redraw_current(i))
+    }
+
+    /// Replaces `source`'s line in progress with `line`.
+    fn typed(&mut self, source: &str, line: &str) -> Option<String> {
+        let i = self.section(source)?;
+        *self.sections[i].1.last_mut()? = line.to_string();
+        Some(self.redraw_current(i))
+    }
+
+    /// Redraws section `i`'s line in progress.
+    fn redraw_current(&self, i: usize) -> String {
+        let lines = &self.sections[i].1;
+        let row = self.first_row(i) + lines.len() - 1;
+        let mut out = String::new();
+        self.draw_line(row, &clip(lines.last().unwrap()), &mut out);
+        self.park(&mut out);
+        out
+    }
+
+    fn committed(&mut self, source: &str, line: &str) -> Option<String> {
+        let i = self.section(source)?;
+        let lines = &mut self.sections[i].1;
+        lines.pop();
+        lines.push(line.to_string());
+        lines.push(String::new());
+        if lines.len() > SHOWN {
+            lines.remove(0);
+        }
+        let mut out = String::new();
+        self.draw_section(i, &mut out);
+        self.park(&mut out);
+        Some(out)
+    }
+
+    /// Shows `message` on the line below the participants.
+    fn status(&self, message: &str) -> String {
+        let mut out = String::new();
+        self.draw_line(self.status_row(), &clip(message), &mut out);
+        self.park(&mut out);
+        out
+    }
+
+    fn section(&self, id: &str) -> Option<usize> {
+        self.sections.iter().position(|(section, _)| section == id)
+    }
+
+    /// The row of section `i`'s first line, below its heading; rows count
+    /// from 1, and the title takes the first.
+    fn first_row(&self, i: usize) -> usize {
+        2 + i * (SHOWN + 1) + 1
+    }
+
+    fn status_row(&self) -> usize {
+        self.first_row(self.sections.len())
+    }
+
+    fn draw_section(&self, i: usize, out: &mut String) {
+        let (id, lines) = &self.sections[i];
+        let heading = format!("{}== {} =={}", BOLD, clip(id), PLAIN);
+        self.draw_line(self.first_row(i) - 1, &heading, out);
+        for row in 0..SHOWN {
+            let line = lines.get(row).map_or("", String::as_str);
+            self.draw_line(self.first_row(i) + row, &clip(line), out);
+        }
+    }
+
+    fn draw_line(&self, row: usize, text: &str, out: &mut String) {
+        let _ = write!(out, "\x1b[{};1H{}{}", row, ERASE_LINE, text);
+    }
+
+    /// Leaves the cursor below everything, where the terminal's own output
+    /// won't land on a line.
+    fn park(&self, out: &mut String) {
+        let _ = write!(out, "\x1b[{};1H", self.status_row() + 1);
+    }
+}
+
+/// `text` as one terminal row: control characters, which could move the
+/// cursor or restyle the terminal, are replaced, and a long line keeps its
+/// end.
+fn clip(text: &str) -> String {
+    let chars: Vec<char> = text
+        .chars()
+        .map(|c| if c.is_control() { '\u{fffd}' } else { c })
+        .collect();
+    if chars.len() <= WIDTH {
+        return chars.into_iter().collect();
+    }
+    std::iter::once('…')
+        .chain(chars[chars.len() - (WIDTH - 1)..].iter().copied())
+        .collect()
+}
@@ -0,0 +1,525 @@
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    admin,
+    audit::AuditEvent,
+    cast,
+    email::{self, InviteEmailRequest},
+    generate_random_string, identity, json_response, report,
+    search::{self, Matcher},
+    security::{self, Event},
+    storage::RoomRecord,
+    ServerMessage, SharedState,
+};
+
+/// Who is calling a room's endpoint, once they're known to be its creator
+/// or the admin, and what of the room exists.
+pub struct Caller {
+    pub is_admin: bool,
+    pub stored: Option<RoomRecord>,
+}
+
+/// The `Authorization: Bearer` token, or an empty string.
+pub fn bearer(req: &Request<Body>) -> &str {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default()
+}
+
+/// Checks the room's creator token or the admin token, either as
+/// `Authorization: Bearer`. A wrong one from `ip` is logged.
+async fn authorize(
+    req: &Request<Body>,
+    state: &SharedState,
+    id: &str,
+    ip: Option<IpAddr>,
+) -> Result<Caller, Response<Body>> {
+    let is_admin = state
+        .admin
+        .as_ref()
+        .is_some_and(|admin| admin.authorized(req));
+    check(state, id, bearer(req), is_admin)
+        .await
+        .map_err(|(status, message)| {
+            if status == StatusCode::UNAUTHORIZED && !bearer(req).is_empty() {
+                let credential = "creator_token";
+                security::log(ip, Event::AuthFailed { credential });
+            }
+            json_response(status, json!({"error": message}))
+        })
+}
+
+/// Whether `bearer` is room `id`'s creator token, or the caller already
+/// proved to be the admin, and that the room exists live or stored.
+pub async fn check(
+    state: &SharedState,
+    id: &str,
+    bearer: &str,
+    is_admin: bool,
+) -> Result<Caller, (StatusCode, &'static str)> {
+    let live_hash = state
+        .rooms
+        .get(id)
+        .map(|room| room.published().creator_token_hash.clone());
+    let stored = match state.store.load_room(id).await {
+        Ok(stored) => stored,
+        Err(err) => {
+            error!("Failed to load room {}: {}", id, err);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Storage unavailable."));
+        }
+    };
+    let creator_hash = match &live_hash {
+        Some(hash) => hash.clone(),
+        None => stored
+            .as_ref()
+            .and_then(|record| record.creator_token_hash.clone()),
+    };
+    let is_creator = !bearer.is_empty()
+        && creator_hash.is_some_and(|hash| {
+            bool::from(
+                hash.as_bytes()
+                    .ct_eq(identity::token_hash(bearer).as_bytes()),
+            )
+        });
+    if !is_admin && !is_creator {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "A creator or admin token is required.",
+        ));
+    }
+    if live_hash.is_none() && stored.is_none() {
+        return Err((StatusCode::NOT_FOUND, "No such room."));
+    }
+    Ok(Caller { is_admin, stored })
+}
+
+/// `GET /api/rooms/:id`: who is in the room, its topic and settings, and
+/// `seq`, how many lines it has finished, so a poller can tell whether
+/// anything happened. The reply carries an `ETag`; with a matching
+/// `If-None-Match` it is an empty 304. Authorized like `DELETE`.
+pub async fn get_room(
+    req: &Request<Body>,
+    state: &SharedState,
+    id: &str,
+    ip: Option<IpAddr>,
+) -> Response<Body> {
+    let Caller { stored, .. } = match authorize(req, state, id, ip).await {
+        Ok(caller) => caller,
+        Err(response) => return response,
+    };
+    let live = state.rooms.get(id).map(|room| {
+        let room = room.published();
+        json!({
+            "id": room.id,
+            "live": true,
+            "participants": room.participants,
+            "ownerId": room.owner_id,
+            "topic": room.settings.topic,
+            "settings": room.settings,
+            "seq": room.seq,
+            "createdAt": room.created_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        })
+    });
+    let body = match (live, stored) {
+        (Some(live), _) => live,
+        (None, Some(record)) => {
+            let seq = match state.store.load_history(id).await {
+                Ok(history) => history.len(),
+                Err(err) => {
+                    error!("Failed to load history of room {}: {}", id, err);
+                    return json_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        json!({"error": "Storage unavailable."}),
+                    );
+                }
+            };
+            json!({
+                "id": record.id,
+                "live": false,
+                "participants": [],
+                "ownerId": record.owner_id,
+                "topic": record.settings.topic,
+                "settings": record.settings,
+                "seq": seq,
+                "createdAt": record.created_at,
+            })
+        }
+        // Deleted since it was authorized.
+        (None, None) => {
+            return json_response(StatusCode::NOT_FOUND, json!({"error": "No such room."}));
+        }
+    };
+
+    let body = body.to_string();
+    let digest = Sha256::digest(body.as_bytes());
+    let etag: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    let etag = format!("\"{}\"", etag);
+    let fresh = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        });
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, "no-cache");
+    if fresh {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+    response
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// `DELETE /api/rooms/:id`: purges a room's live state, its stored record,
+/// its activity rollups and the audit entries about it, and answers once
+/// they are gone. Authorized by the room's creator token or the admin token.
+pub async fn delete_room(
+    req: &Request<Body>,
+    state: &SharedState,
+    id: &str,
+    ip: Option<IpAddr>,
+) -> Response<Body> {
+    let Caller { is_admin, stored } = match authorize(req, state, id, ip).await {
+        Ok(caller) => caller,
+        Err(response) => return response,
+    };
+
+    let live = state.rooms.close(id).await;
+    if let Some(room) = &live {
+        room.broadcast(
+            ServerMessage::Error {
+                message: "This room has been deleted.".to_string(),
+            },
+            None,
+        );
+    }
+    if let Err(err) = state.store_writer.purge(id.to_string()).await {
+        error!("Failed to delete the stored data of {}: {}", id, err);
+        return json_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({"error": "Room closed, but its stored data could not be deleted; try again."}),
+        );
+    }
+    let audit_entries = match state.audit.purge_room(id).await {
+        Ok(count) => count,
+        Err(err) => {
+            error!("Failed to purge audit entries for {}: {}", id, err);
+            return json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": "Room deleted, but its audit entries could not be purged."}),
+            );
+        }
+    };
+
+    let receipt = generate_random_string(16);
+    state.audit.record(AuditEvent::Admin {
+        action: "roomDeleted".to_string(),
+        detail: Some(format!(
+            "receipt {} by {}",
+            receipt,
+            if is_admin { "admin" } else { "creator" }
+        )),
+    });
+    info!("Deleted room {} (receipt {})", id, receipt);
+    json_response(
+        StatusCode::OK,
+        json!({
+            "receipt": receipt,
+            "room": id,
+            "deletedAt": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            "purged": {
+                "liveRoom": live.is_some(),
+                "storedRecord": stored.is_some(),
+                "auditEntries": audit_entries,
+            },
+        }),
+    )
+}
+
+/// `GET /api/rooms/:id/search?q=…`: the room's lines containing `q`, or
+/// matching it as a regex with `regex=true`, as `{ hits, truncated }`.
+/// Authorized like `DELETE`.
+pub async fn search_room(
+    req: &Request<Body>,
+    state: &SharedState,
+    id: &str,
+    ip: Option<IpAddr>,
+) -> Response<Body> {
+    let caller = match authorize(req, state, id, ip).await {
+        Ok(caller) => caller,
+        Err(response) => return response,
+    };
+    let mut query = String::new();
+    let mut regex = false;
+    for (key, value) in
+        url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+    {
+        match &*key {
+            "q" => query = value.into_owned(),
+            "regex" => regex = value == "true",
+            _ => {}
+        }
+    }
+    let matcher = match Matcher::new(&query, regex) {
+        Ok(matcher) => matcher,
+        Err(err) => return json_response(StatusCode::BAD_REQUEST, json!({"error": err})),
+    };
+    match search::room(state, id, &matcher).await {
+        Ok(results) => {
+            info!(
+                "Searched room {} ({} hits, by {})",
+                id,
+                results.hits.len(),
+                if caller.is_admin { "admin" } else { "creator" }
+            );
+            json_response(StatusCode::OK, json!(results))
+        }
+        Err(err) => {
+            error!("Failed to search room {}: {}", id, err);
+            json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": "Storage unavailable."}),
+            )
+        }
+    }
+}
+
+/// `POST /api/rooms/:id/invite-email`: emails a link to the room, as
+/// `[email]` configures; 404 without it. Authorized like `DELETE`.
+pub async fn invite_email(
+    req: Request<Body>,
+    state: &SharedState,
+    id: &str,
+    ip: Option<IpAddr>,
+) -> Response<Body> {
+    if let Err(response) = authorize(&req, state, id, ip).await {
+        return response;
+    }
+    let config = state.config();
+    let Some(email) = &config.email else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({"error": "This server doesn't send email."}),
+        );
+    };
+    let request: InviteEmailRequest = match admin::read_json(req).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    match email::invite(state, email, id, ip, request).await {
+        Ok(()) => json_response(StatusCode::OK, json!({"sent": true})),
+        Err((status, message)) => json_response(status, json!({"error": message})),
+    }
+}
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptRequest {
+    /// Pinned above everyone's lines; none, or an empty one, unpins it.
+    prompt: Option<String>,
+}
+
+/// `POST /api/report`: reports a room to the operator, as `report` does on
+/// the WebSocket, for those who aren't in it or can't use the GUI.
+pub async fn report(req: Request<Body>, state: &SharedState, ip: Option<IpAddr>) -> Response<Body> {
+    let request: report::ReportRequest = match admin::read_json(req).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    if let Err(message) = report::valid_reason(&request.reason) {
+        return json_response(StatusCode::BAD_REQUEST, json!({"error": message}));
+    }
+    // Before looking the room up, so reports can't probe for open rooms.
+    if let Err(message) = state.reports.admit(ip, "") {
+        return json_response(StatusCode::TOO_MANY_REQUESTS, json!({"error": message}));
+    }
+    let view = match state.rooms.get(&request.room) {
+        Some(room) => room.call(|room| room.render("")).await,
+        None => None,
+    };
+    let Some(view) = view else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({"error": "The room isn't open."}),
+        );
+    };
+    warn!("Room {} reported over the API", request.room);
+    state.audit.record(AuditEvent::Reported {
+        room: request.room,
+        participant: None,
+        ip,
+        reason: request.reason,
+        snapshot: report::Snapshot::of(&view),
+    });
+    json_response(StatusCode::OK, json!({"reported": true}))
+}
+
+/// `PUT /api/rooms/:id/prompt`: pins a prompt in the room, as its owner can
+/// with `setPrompt`, for bots and scripts that run exercises.
+pub async fn set_prompt(
+    req: Request<Body>,
+    state: &SharedState,
+    id: &str,
+    ip: Option<IpAddr>,
+) -> Response<Body> {
+    if let Err(response) = authorize(&req, state, id, ip).await {
+        return response;
+    }
+    let request: PromptRequest = match admin::read_json(req).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let set = match state.rooms.get(id) {
+        Some(room) => {
+            let state = state.clone();
+            room.call(move |room| {
+                room.set_prompt(None, request.prompt)?;
+                room.save_settings(&state);
+                Ok::<_, String>(room.settings.prompt.clone())
+            })
+            .await
+        }
+        None => None,
+    };
+    match set {
+        Some(Ok(prompt)) => {
+            info!("Prompt of room {} set over the API", id);
+            json_response(StatusCode::OK, json!({"prompt": prompt}))
+        }
+        Some(Err(message)) => json_response(StatusCode::BAD_REQUEST, json!({"error": message})),
+        None => json_response(
+            StatusCode::NOT_FOUND,
+            json!({"error": "The room isn't open."}),
+        ),
+    }
+}
+
+/// `GET /api/rooms/:id/cast?speed=15`: the conversation as an asciinema
+/// cast. For the creator, a room in interview mode is played back with its
+/// recorded timing; otherwise its finished lines are typed out at `speed`
+/// characters a second.
+pub async fn export_cast(
+    req: &Request<Body>,
+    state: &SharedState,
+    id: &str,
+    ip: Option<IpAddr>,
+) -> Response<Body> {
+    let caller = match authorize(req, state, id, ip).await {
+        Ok(caller) => caller,
+        Err(response) => return response,
+    };
+    let speed = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "speed")
+        .map(|(_, value)| value.parse::<u32>());
+    let speed = match speed {
+        None => cast::DEFAULT_SPEED,
+        Some(Ok(speed)) if (1..=cast::MAX_SPEED).contains(&speed) => speed,
+        Some(_) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({"error": format!("speed is 1 to {} characters per second.", cast::MAX_SPEED)}),
+            )
+        }
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    // Key timing is for the creator only, as with the keystrokes export.
+    let log = match state.rooms.get(id) {
+        Some(room) if !caller.is_admin => room.call(|room| room.keystrokes.clone()).await,
+        _ => None,
+    };
+    let recording = match log {
+        Some(log) if log.iter().next().is_some() => cast::recorded(id, &log, timestamp),
+        _ => match search::lines(state, id).await {
+            Ok(lines) => cast::paced(id, &lines, speed, timestamp),
+            Err(err) => {
+                error!("Failed to load the history of room {}: {}", id, err);
+                return json_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    json!({"error": "The room's history could not be loaded."}),
+                );
+            }
+        },
+    };
+    info!("Room {} exported as a cast", id);
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-asciicast")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.cast\"", id),
+        )
+        .body(Body::from(recording))
+        .unwrap()
+}
+
+/// `GET /api/rooms/:id/keystrokes?format=csv`: what interview mode recorded
+/// in the room, as JSON or, with `format=csv`, CSV. Only the creator's
+/// token will do, and only while the room is open, since the log is kept
+/// in memory.
+pub async fn export_keystrokes(
+    req: &Request<Body>,
+    state: &SharedState,
+    id: &str,
+    ip: Option<IpAddr>,
+) -> Response<Body> {
+    let caller = match authorize(req, state, id, ip).await {
+        Ok(caller) => caller,
+        Err(response) => return response,
+    };
+    if caller.is_admin {
+        return json_response(
+            StatusCode::FORBIDDEN,
+            json!({"error": "Only the room's creator can export its keystrokes."}),
+        );
+    }
+    let log = match state.rooms.get(id) {
+        Some(room) => room.call(|room| room.keystrokes.clone()).await,
+        None => None,
+    };
+    let Some(log) = log else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({"error": "The room isn't open; keystrokes are kept only while it is."}),
+        );
+    };
+    let csv = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .any(|(key, value)| key == "format" && value == "csv");
+    info!("Keystrokes of room {} exported", id);
+    state.audit.record(AuditEvent::Admin {
+        action: "keystrokesExported".to_string(),
+        detail: Some(id.to_string()),
+    });
+    if !csv {
+        return json_response(StatusCode::OK, json!(log));
+    }
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/csv; charset=utf-8")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}-keystrokes.csv\"", id),
+        )
+        .body(Body::from(log.csv()))
+        .unwrap()
+}
@@ -0,0 +1,301 @@
+use hmac::{Hmac, Mac};
+use hyper::{Body, Method, Request};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info};
+
+use crate::{
+    audit::AuditEvent, config::ArchiveConfig, http_client::HttpClient, locale::LocaleHint, Room,
+    RoomSettings, SharedState, SYSTEM_ID,
+};
+
+/// A room's conversation as it stood when the room expired.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transcript {
+    room: String,
+    /// Unix seconds.
+    created_at: u64,
+    archived_at: u64,
+    settings: RoomSettings,
+    /// Each participant's lines, without the empty line in progress.
+    messages: BTreeMap<String, Vec<String>>,
+    /// Each participant's color, as the room showed it.
+    colors: BTreeMap<String, String>,
+    /// The language hints participants joined with.
+    locales: BTreeMap<String, LocaleHint>,
+}
+
+impl Transcript {
+    pub fn new(room: &Room) -> Self {
+        let messages = room
+            .messages
+            .iter()
+            .map(|(id, lines)| {
+                let mut lines = lines.clone();
+                if lines.last().is_some_and(String::is_empty) {
+                    lines.pop();
+                }
+                (id.clone(), lines)
+            })
+            .collect();
+        Self {
+            room: room.id.clone(),
+            created_at: unix_secs(room.created_at),
+            archived_at: unix_secs(SystemTime::now()),
+            settings: room.settings.clone(),
+            messages,
+            colors: room.colors.clone(),
+            locales: room.locales.clone(),
+        }
+    }
+
+    /// Plain text, one section per participant and one for room events.
+    /// Headings carry the participant's language, where they gave one.
+    fn text(&self) -> String {
+        let mut text = format!(
+            "typeto.me room {}\ncreated {}\narchived {}\n",
+            self.room,
+            format_time(self.created_at),
+            format_time(self.archived_at)
+        );
+        for (id, lines) in &self.messages {
+            let heading = if id == SYSTEM_ID { "room events" } else { id };
+            let locale = self.locales.get(id).and_then(|hint| hint.locale.as_deref());
+            match locale {
+                Some(locale) => {
+                    let _ = write!(text, "\n== {} ({}) ==\n", heading, locale);
+                }
+                None => {
+                    let _ = write!(text, "\n== {} ==\n", heading);
+                }
+            }
+            for line in lines.iter().filter(|line| !line.is_empty()) {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+        text
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn format_time(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .unwrap()
+        .format("%Y-%m-%d %H:%M:%SZ")
+        .to_string()
+}
+
+/// Uploads the transcripts of expired rooms to an S3-compatible bucket,
+/// addressed path-style so MinIO, R2 and the like work as well as AWS.
+pub struct Archive {
+    config: ArchiveConfig,
+    client: HttpClient,
+}
+
+impl Archive {
+    pub fn new(config: ArchiveConfig, client: HttpClient) -> Self {
+        Self { config, client }
+    }
+
+    /// Writes `<prefix><room>-<archived at>.json` and `.txt`, and returns
+    /// the keys.
+    async fn upload(&self, transcript: &Transcript) -> Result<Vec<String>, String> {
+        // Room ids are chosen by clients; keep them from adding path segments.
+        let room: String = transcript
+            .room
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let base = format!("{}{}-{}", self.config.prefix, room, transcript.archived_at);
+        let json = serde_json::to_vec(transcript).map_err(|err| err.to_string())?;
+        let objects = [
+            (format!("{}.json", base), json, "application/json"),
+            (
+                format!("{}.txt", base),
+                transcript.text().into_bytes(),
+                "text/plain; charset=utf-8",
+            ),
+        ];
+        let mut keys = Vec::new();
+        for (key, body, content_type) in objects {
+            self.put(&key, body, content_type).await?;
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), String> {
+        let url = format!(
+            "{}/{}/{}",
+            self.config.endpoint.trim_end_matches('/'),
+            uri_encode(&self.config.bucket),
+            uri_encode(key)
+        );
+        let uri: hyper::Uri = url.parse().map_err(|err| format!("{}: {}", url, err))?;
+        let host = uri
+            .authority()
+            .ok_or_else(|| format!("{} has no host", url))?
+            .to_string();
+
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex(&Sha256::digest(&body));
+        // The whole path is signed, a path in `endpoint` included.
+        let authorization = authorization(
+            &self.config,
+            "PUT",
+            uri.path(),
+            &[
+                ("host", &host),
+                ("x-amz-content-sha256", &payload_hash),
+                ("x-amz-date", &amz_date),
+            ],
+            &payload_hash,
+        );
+
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(uri)
+            .header("host", &host)
+            .header("content-type", content_type)
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("x-amz-date", &amz_date)
+            .header("authorization", authorization)
+            .body(Body::from(body))
+            .map_err(|err| err.to_string())?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|err| format!("Upload of {} failed: {}", key, err))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .unwrap_or_default();
+            return Err(format!(
+                "Upload of {} returned {}: {}",
+                key,
+                status,
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// AWS Signature Version 4 for an S3 request to `path`, already
+/// URI-encoded, with no query string. `headers` are the ones signed, named
+/// in lowercase and sorted, `x-amz-date` among them.
+pub(crate) fn authorization(
+    config: &ArchiveConfig,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+) -> String {
+    let amz_date = headers
+        .iter()
+        .find(|(name, _)| *name == "x-amz-date")
+        .map_or("", |(_, value)| value);
+    let date = amz_date.get(..8).unwrap_or_default();
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method, path, canonical_headers, signed_headers, payload_hash
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let secret = format!("AWS4{}", config.secret_access_key);
+    let key = [date, &config.region, "s3", "aws4_request"]
+        .iter()
+        .fold(secret.into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        config.access_key_id,
+        scope,
+        signed_headers,
+        hex(&hmac(&key, string_to_sign.as_bytes()))
+    )
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Percent-encodes everything but unreserved characters and `/`, as S3
+/// expects in a canonical path.
+fn uri_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+/// Archives an expired room's transcript in the background, if `[archive]`
+/// is configured, and notes the object keys in the audit log.
+pub fn spawn(state: &SharedState, room: &Room) {
+    if state.archive.is_none() {
+        return;
+    }
+    let transcript = Transcript::new(room);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let Some(archive) = &state.archive else {
+            return;
+        };
+        match archive.upload(&transcript).await {
+            Ok(keys) => {
+                info!("Archived room {} as {}", transcript.room, keys.join(", "));
+                state.audit.record(AuditEvent::RoomArchived {
+                    room: transcript.room,
+                    keys,
+                });
+            }
+            Err(err) => error!("Failed to archive room {}: {}", transcript.room, err),
+        }
+    });
+}
@@ -0,0 +1,100 @@
+//! Expired rooms kept for `[rooms] archive_grace_secs` instead of being
+//! deleted outright. An archived room is stored with its lines as they
+//! stood: fetching it shows them read-only, and `reviveRoom` brings it back
+//! as a live room whose maximum age starts afresh. Once the grace period is
+//! over the room is deleted as before.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{expiry, now_utc, storage::RoomRecord, store_opened, Room, SharedState};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Archived {
+    /// Unix seconds.
+    pub at: u64,
+    /// Each participant's lines, as the room held them.
+    pub messages: HashMap<String, Vec<String>>,
+}
+
+/// An archived room as `fetchRoom` shows it.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivedView {
+    id: String,
+    archived_at: u64,
+    /// When the room is deleted unless it is revived first.
+    deleted_at: u64,
+    messages: HashMap<String, Vec<String>>,
+    colors: BTreeMap<String, String>,
+}
+
+/// How `record` shows, if it is archived.
+pub fn view(record: &RoomRecord, grace_secs: u64) -> Option<ArchivedView> {
+    let archived = record.archived.as_ref()?;
+    Some(ArchivedView {
+        id: record.id.clone(),
+        archived_at: archived.at,
+        deleted_at: archived.at + grace_secs,
+        messages: archived.messages.clone(),
+        colors: record.colors.clone(),
+    })
+}
+
+/// The record that keeps `room` archived as of `now`.
+pub fn record(room: &Room, now: SystemTime) -> RoomRecord {
+    let mut record = room.record();
+    record.archived = Some(Archived {
+        at: expiry::unix_secs(now),
+        messages: room.messages.clone(),
+    });
+    record
+}
+
+/// Brings archived room `id` back into memory, with its lines as they were
+/// archived and a new maximum age. The error is for the client.
+pub async fn revive(state: &SharedState, id: &str) -> Result<(), String> {
+    let record = state.store.load_room(id).await.map_err(|err| {
+        error!("Failed to load room {}: {}", id, err);
+        "Storage unavailable.".to_string()
+    })?;
+    let Some(mut record) = record.filter(|record| record.archived.is_some()) else {
+        return Err("This room isn't archived.".to_string());
+    };
+    let archived = record.archived.take().unwrap();
+    record.created_at = expiry::unix_secs(SystemTime::now());
+    let mut room = Room::from_record(record, Vec::new());
+    room.messages = archived.messages;
+    room.configure(&state.config());
+    room.system_line(format!("The room was revived at {}Z", now_utc()));
+
+    // Unless someone else revived it first.
+    if state.rooms.open(room, store_opened(state)) {
+        info!("Revived archived room {}", id);
+    }
+    Ok(())
+}
+
+/// Deletes the archived rooms whose grace period is over, in the background.
+pub fn purge(state: &SharedState) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let grace = state.config().rooms.archive_grace_secs;
+        let before = expiry::unix_secs(SystemTime::now()).saturating_sub(grace);
+        match state.store.archived_before(before).await {
+            Ok(ids) => {
+                for id in ids {
+                    info!("Deleted archived room {}", id);
+                    state.store_writer.delete(id);
+                }
+            }
+            Err(err) => error!("Failed to list archived rooms: {}", err),
+        }
+    });
+}
@@ -0,0 +1,148 @@
+//! The frontend's files, from `[gui] dir`. A path under `/gui/`, or whose
+//! last segment has an extension, asks for a file and gets it or a 404;
+//! any other path is a room, or the page that creates one, and gets `index`
+//! so the frontend can route it.
+//!
+//! `[gui.bundles]` adds more frontends, each served under `/gui/<name>/`.
+//! The page comes from `default_bundle` unless the visitor asked for another
+//! with `?gui=<name>`, which a cookie remembers.
+
+use std::path::{Path, PathBuf};
+
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Serialize;
+
+use crate::{config::GuiConfig, oidc::cookie};
+
+const BUNDLE_COOKIE: &str = "typeto_gui";
+
+/// The frontends an instance offers, as room views list them.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+pub struct GuiBundles {
+    default: String,
+    available: Vec<String>,
+}
+
+impl GuiBundles {
+    /// `None` unless `[gui.bundles]` has some.
+    pub fn new(config: &GuiConfig) -> Option<Self> {
+        Some(Self {
+            default: config.default_bundle.clone()?,
+            available: config.bundles.keys().cloned().collect(),
+        })
+    }
+}
+
+/// The file `path` asks for, relative to `[gui] dir`, if it asks for one.
+/// Room ids never have a dot, so one with an extension is a file.
+pub fn file_name(path: &str) -> Option<&str> {
+    if let Some(name) = path.strip_prefix("/gui/") {
+        return Some(name);
+    }
+    let name = path.strip_prefix('/')?;
+    name.rsplit('/')
+        .next()
+        .is_some_and(|last| last.contains('.'))
+        .then_some(name)
+}
+
+/// `name` under `[gui] dir`, cached for `max_age_secs`, or a 404 if there
+/// is no such file or it would be outside the directory.
+pub async fn file(config: &GuiConfig, name: &str) -> Response<Body> {
+    let bundle = name
+        .split_once('/')
+        .and_then(|(bundle, rest)| Some((config.bundles.get(bundle)?, rest)));
+    let path = match bundle {
+        Some((dir, rest)) => resolve(dir, rest),
+        None => resolve(&config.dir, name),
+    };
+    let content = match path {
+        Some(path) => tokio::fs::read(path).await.ok(),
+        None => None,
+    };
+    let Some(content) = content else {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap();
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type(name))
+        .header(
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", config.max_age_secs),
+        )
+        .body(Body::from(content))
+        .unwrap()
+}
+
+/// The page for every path that isn't a file, from the bundle `req` asks
+/// for or the default, or `None` if it is missing.
+pub async fn index(config: &GuiConfig, req: &Request<Body>) -> Option<Response<Body>> {
+    let asked = req.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "gui")
+            .map(|(_, value)| value.into_owned())
+    });
+    let asked = asked.filter(|name| config.bundles.contains_key(name));
+    let chosen = asked
+        .clone()
+        .or_else(|| cookie(req, BUNDLE_COOKIE).filter(|name| config.bundles.contains_key(name)))
+        .or_else(|| config.default_bundle.clone());
+    let dir = chosen
+        .as_ref()
+        .and_then(|name| config.bundles.get(name))
+        .unwrap_or(&config.dir);
+    let content = tokio::fs::read(resolve(dir, &config.index)?).await.ok()?;
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-cache");
+    if let Some(name) = asked {
+        response = response.header(
+            header::SET_COOKIE,
+            format!(
+                "{}={}; Path=/; SameSite=Lax; Max-Age=31536000",
+                BUNDLE_COOKIE, name
+            ),
+        );
+    }
+    Some(response.body(Body::from(content)).unwrap())
+}
+
+/// `name` under `dir`, unless it tries to climb out of it or hide.
+fn resolve(dir: &Path, name: &str) -> Option<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for segment in name.split('/') {
+        if segment.is_empty() || segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map_or("", |(_, extension)| extension);
+    match extension.to_ascii_lowercase().as_str() {
+        "html" => "text/html; charset=utf-8",
+        "js" | "mjs" => "application/javascript",
+        "css" => "text/css",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "txt" | "ts" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wav" => "audio/wav",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
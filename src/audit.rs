@@ -0,0 +1,311 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    sync::{broadcast, mpsc, oneshot},
+};
+use tracing::error;
+
+use crate::{config::AuditConfig, report::Snapshot};
+
+/// How many entries are kept in memory when no audit file is configured.
+const MEMORY_ENTRIES: usize = 10_000;
+/// Entries a subscriber may fall behind by before it misses some.
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum AuditEvent {
+    RoomCreated {
+        room: String,
+        participant: String,
+        ip: Option<IpAddr>,
+    },
+    Joined {
+        room: String,
+        participant: String,
+        ip: Option<IpAddr>,
+    },
+    SettingsChanged {
+        room: String,
+        participant: String,
+    },
+    RoomExpired {
+        room: String,
+    },
+    /// The expired room's transcript was uploaded under `keys`.
+    RoomArchived {
+        room: String,
+        keys: Vec<String>,
+    },
+    /// An archived room was brought back.
+    RoomRevived {
+        room: String,
+        ip: Option<IpAddr>,
+    },
+    /// A room was created from an uploaded transcript of `lines` lines.
+    RoomImported {
+        room: String,
+        lines: usize,
+        ip: Option<IpAddr>,
+    },
+    /// `ip` was banned for `secs` after too many strikes, the last for
+    /// `reason`.
+    Banned {
+        ip: IpAddr,
+        secs: u64,
+        reason: String,
+    },
+    /// `ip` fetched decoy room `room`.
+    HoneypotTouched {
+        room: String,
+        ip: Option<IpAddr>,
+    },
+    Admin {
+        action: String,
+        detail: Option<String>,
+    },
+    /// Someone reported the room, with what it looked like to them; over
+    /// the API, `participant` is unknown.
+    Reported {
+        room: String,
+        participant: Option<String>,
+        ip: Option<IpAddr>,
+        reason: String,
+        snapshot: Snapshot,
+    },
+}
+
+impl AuditEvent {
+    pub fn room(&self) -> Option<&str> {
+        match self {
+            Self::RoomCreated { room, .. }
+            | Self::Joined { room, .. }
+            | Self::SettingsChanged { room, .. }
+            | Self::RoomExpired { room }
+            | Self::RoomArchived { room, .. }
+            | Self::RoomRevived { room, .. }
+            | Self::RoomImported { room, .. }
+            | Self::HoneypotTouched { room, .. }
+            | Self::Reported { room, .. } => Some(room),
+            Self::Banned { .. } | Self::Admin { .. } => None,
+        }
+    }
+
+    /// Whether deleting `room` drops this entry. Abuse reports and honeypot
+    /// hits stay for the operator, whoever deletes the room.
+    fn purged_with(&self, room: &str) -> bool {
+        !matches!(self, Self::Reported { .. } | Self::HoneypotTouched { .. })
+            && self.room() == Some(room)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix seconds.
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+enum AuditOp {
+    Append(AuditEntry),
+    /// Rewrites the file without the entries about one room.
+    PurgeRoom(String, oneshot::Sender<Result<usize, String>>),
+}
+
+/// Which entries a query returns; the newest `limit` matches are kept.
+pub struct AuditQuery {
+    pub room: Option<String>,
+    pub since: Option<u64>,
+    pub limit: usize,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.since.is_none_or(|since| entry.time >= since)
+            && self
+                .room
+                .as_deref()
+                .is_none_or(|room| entry.event.room() == Some(room))
+    }
+}
+
+/// Append-only record of room lifecycle and operator actions. Entries go to
+/// a JSON-lines file through a single writer task when one is configured,
+/// and are otherwise only kept in memory.
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    tx: Option<mpsc::UnboundedSender<AuditOp>>,
+    recent: Mutex<VecDeque<AuditEntry>>,
+    /// Every entry as it is recorded, for webhooks.
+    entries: broadcast::Sender<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn spawn(config: Option<&AuditConfig>) -> Self {
+        let path = config.map(|config| config.path.clone());
+        let tx = path.clone().map(|path| {
+            let (tx, mut rx) = mpsc::unbounded_channel::<AuditOp>();
+            tokio::spawn(async move {
+                let mut file = match open_append(&path).await {
+                    Ok(file) => file,
+                    Err(err) => {
+                        error!("Failed to open audit log {}: {}", path.display(), err);
+                        return;
+                    }
+                };
+                while let Some(op) = rx.recv().await {
+                    match op {
+                        AuditOp::Append(entry) => {
+                            let mut line = serde_json::to_string(&entry).unwrap_or_default();
+                            line.push('\n');
+                            let written = match file.write_all(line.as_bytes()).await {
+                                Ok(()) => file.flush().await,
+                                Err(err) => Err(err),
+                            };
+                            if let Err(err) = written {
+                                error!("Audit log write failed: {}", err);
+                            }
+                        }
+                        AuditOp::PurgeRoom(room, done) => {
+                            let result = purge_file(&path, &room).await;
+                            match open_append(&path).await {
+                                Ok(reopened) => file = reopened,
+                                Err(err) => error!("Failed to reopen audit log: {}", err),
+                            }
+                            let _ = done.send(result);
+                        }
+                    }
+                }
+            });
+            tx
+        });
+        Self {
+            path,
+            tx,
+            recent: Mutex::new(VecDeque::new()),
+            entries: broadcast::channel(SUBSCRIBER_BACKLOG).0,
+        }
+    }
+
+    /// Entries recorded from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<AuditEntry> {
+        self.entries.subscribe()
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let entry = AuditEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            event,
+        };
+        let _ = self.entries.send(entry.clone());
+        match &self.tx {
+            Some(tx) => {
+                let _ = tx.send(AuditOp::Append(entry));
+            }
+            None => {
+                let mut recent = self.recent.lock().unwrap();
+                if recent.len() == MEMORY_ENTRIES {
+                    recent.pop_front();
+                }
+                recent.push_back(entry);
+            }
+        }
+    }
+
+    pub async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, String> {
+        let mut entries: VecDeque<AuditEntry> = VecDeque::new();
+        let mut keep = |entry: AuditEntry| {
+            if query.matches(&entry) {
+                if entries.len() == query.limit {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+            }
+        };
+        match &self.path {
+            Some(path) => {
+                let text = match tokio::fs::read_to_string(path).await {
+                    Ok(text) => text,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                    Err(err) => return Err(format!("Failed to read audit log: {}", err)),
+                };
+                text.lines()
+                    .filter_map(|line| serde_json::from_str(line).ok())
+                    .for_each(&mut keep);
+            }
+            None => self.recent.lock().unwrap().iter().cloned().for_each(keep),
+        }
+        Ok(entries.into())
+    }
+
+    /// Drops the entries about `room` that go with it, and returns how many
+    /// there were.
+    pub async fn purge_room(&self, room: &str) -> Result<usize, String> {
+        match &self.tx {
+            Some(tx) => {
+                let (done, result) = oneshot::channel();
+                tx.send(AuditOp::PurgeRoom(room.to_string(), done))
+                    .map_err(|_| "Audit log writer is gone.".to_string())?;
+                result
+                    .await
+                    .map_err(|_| "Audit log writer is gone.".to_string())?
+            }
+            None => {
+                let mut recent = self.recent.lock().unwrap();
+                let before = recent.len();
+                recent.retain(|entry| !entry.event.purged_with(room));
+                Ok(before - recent.len())
+            }
+        }
+    }
+}
+
+async fn open_append(path: &Path) -> std::io::Result<File> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// Writes the surviving lines to a sibling file and renames it over the log,
+/// so a crash mid-purge leaves either the old or the new log intact.
+async fn purge_file(path: &Path, room: &str) -> Result<usize, String> {
+    let text = match tokio::fs::read_to_string(path).await {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(format!("Failed to read audit log: {}", err)),
+    };
+    let mut purged = 0;
+    let mut kept = String::with_capacity(text.len());
+    for line in text.lines() {
+        let about_room = serde_json::from_str::<AuditEntry>(line)
+            .is_ok_and(|entry| entry.event.purged_with(room));
+        if about_room {
+            purged += 1;
+        } else {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    let temp = path.with_extension("purge");
+    tokio::fs::write(&temp, kept)
+        .await
+        .map_err(|err| format!("Failed to write audit log: {}", err))?;
+    tokio::fs::rename(&temp, path)
+        .await
+        .map_err(|err| format!("Failed to replace audit log: {}", err))?;
+    Ok(purged)
+}
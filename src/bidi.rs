@@ -0,0 +1,30 @@
+//! Lines are kept in logical order, the order they were typed in, whatever
+//! direction they are read in. Cursor positions count characters (Unicode
+//! scalar values) into that order, so an edit to Hebrew or Arabic text, or to
+//! a line mixing it with Latin, lands where it was typed and never splits a
+//! character. Each line's direction is sent alongside it for clients that
+//! can't work it out themselves.
+
+use serde::Serialize;
+
+/// The base direction of a line, named as HTML's `dir` attribute names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Ltr,
+    Rtl,
+    /// No strongly directional letters yet, e.g. an empty line or digits;
+    /// follows whatever surrounds it.
+    Auto,
+}
+
+/// The direction of the first strongly directional character, skipping
+/// isolated runs, as in rules P2 and P3 of the Unicode bidi algorithm.
+pub fn direction(line: &str) -> Direction {
+    match typeto_client_core::direction(line) {
+        "ltr" => Direction::Ltr,
+        "rtl" => Direction::Rtl,
+        _ => Direction::Auto,
+    }
+}
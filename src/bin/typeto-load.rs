@@ -0,0 +1,247 @@
+//! Opens simulated clients against a running server and reports how long
+//! key presses take to reach the other participants.
+//!
+//! ```text
+//! typeto-load --url ws://127.0.0.1:8090/ws --clients 200 --rooms 100 --wpm 60 --duration 60
+//! ```
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+/// Characters per typed line before the simulated user presses Enter.
+const LINE_LEN: usize = 40;
+/// Default room cap; more clients than this per room would be turned away.
+const MAX_PARTICIPANTS: usize = 4;
+
+struct Options {
+    url: String,
+    clients: usize,
+    rooms: usize,
+    wpm: f64,
+    duration: Duration,
+}
+
+impl Options {
+    fn from_args() -> Result<Self, String> {
+        let mut options = Self {
+            url: "ws://127.0.0.1:8090/ws".to_string(),
+            clients: 10,
+            rooms: 5,
+            wpm: 40.0,
+            duration: Duration::from_secs(30),
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || {
+                value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", name))
+            };
+            let number = |text: String| {
+                text.parse::<f64>()
+                    .ok()
+                    .filter(|n| *n > 0.0)
+                    .ok_or_else(|| format!("{} must be a positive number", name))
+            };
+            match name.as_str() {
+                "--url" => options.url = value()?,
+                "--clients" => options.clients = number(value()?)? as usize,
+                "--rooms" => options.rooms = number(value()?)? as usize,
+                "--wpm" => options.wpm = number(value()?)?,
+                "--duration" => options.duration = Duration::from_secs_f64(number(value()?)?),
+                "--help" | "-h" => {
+                    return Err("usage: typeto-load [--url ws://host/ws] [--clients N] \
+                                [--rooms M] [--wpm WPM] [--duration SECS]"
+                        .to_string())
+                }
+                _ => return Err(format!("Unknown argument: {}", name)),
+            }
+        }
+        if options.clients == 0 || options.rooms == 0 {
+            return Err("--clients and --rooms must be at least 1".to_string());
+        }
+        Ok(options)
+    }
+}
+
+/// Shared between all simulated clients.
+#[derive(Default)]
+struct Stats {
+    /// When each (participant, cursor position) on its current line was
+    /// sent; receivers look their key presses up here.
+    sent_at: Mutex<HashMap<(String, u64), Instant>>,
+    latencies: Mutex<Vec<Duration>>,
+    keys_sent: AtomicU64,
+    errors: AtomicU64,
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    if options.clients.div_ceil(options.rooms) > MAX_PARTICIPANTS {
+        eprintln!(
+            "warning: more than {} clients per room; the extra ones will be refused",
+            MAX_PARTICIPANTS
+        );
+    }
+    println!(
+        "{} clients in {} rooms typing at {} wpm for {:?} against {}",
+        options.clients, options.rooms, options.wpm, options.duration, options.url
+    );
+
+    let stats = Arc::new(Stats::default());
+    let run = format!("{:x}", rand::random::<u32>());
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    // Five characters per word.
+    let interval = Duration::from_secs_f64(60.0 / (options.wpm * 5.0));
+    let clients: Vec<_> = (0..options.clients)
+        .map(|i| {
+            let room = format!("load-{}-{}", run, i % options.rooms);
+            let id = format!("load-{}-{}", run, i);
+            let client = run_client(
+                options.url.clone(),
+                room,
+                id,
+                interval,
+                deadline,
+                stats.clone(),
+            );
+            tokio::spawn(client)
+        })
+        .collect();
+    for client in clients {
+        let _ = client.await;
+    }
+
+    let mut latencies = stats.latencies.lock().unwrap().clone();
+    latencies.sort();
+    let keys_sent = stats.keys_sent.load(Ordering::Relaxed);
+    let elapsed = started.elapsed().as_secs_f64();
+    println!(
+        "sent {} key presses ({:.0}/s), {} deliveries, {} errors",
+        keys_sent,
+        keys_sent as f64 / elapsed,
+        latencies.len(),
+        stats.errors.load(Ordering::Relaxed)
+    );
+    if latencies.is_empty() {
+        println!("no key presses were delivered");
+        std::process::exit(1);
+    }
+    for (label, quantile) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999)] {
+        let index = ((latencies.len() as f64 * quantile) as usize).min(latencies.len() - 1);
+        println!("{:>6} {:?}", label, latencies[index]);
+    }
+    println!("{:>6} {:?}", "max", latencies[latencies.len() - 1]);
+}
+
+async fn run_client(
+    url: String,
+    room: String,
+    id: String,
+    interval: Duration,
+    deadline: Instant,
+    stats: Arc<Stats>,
+) {
+    let (socket, _) = match connect_async(url.as_str()).await {
+        Ok(connected) => connected,
+        Err(err) => {
+            eprintln!("{}: connect failed: {}", id, err);
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    let (mut sender, mut receiver) = socket.split();
+    let join = json!({"type": "fetchRoom", "id": room, "socketId": id});
+    if sender.send(Message::Text(join.to_string())).await.is_err() {
+        stats.errors.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let reader_stats = stats.clone();
+    let reader = tokio::spawn(async move {
+        while let Some(Ok(message)) = receiver.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let Ok(message) = serde_json::from_str::<Value>(&text) else {
+                continue;
+            };
+            match message["type"].as_str() {
+                Some("keyPress") => {
+                    let source = message["source"].as_str().unwrap_or_default().to_string();
+                    let pos = message["cursorPos"].as_u64().unwrap_or_default();
+                    let sent = reader_stats
+                        .sent_at
+                        .lock()
+                        .unwrap()
+                        .get(&(source, pos))
+                        .copied();
+                    if let Some(sent) = sent {
+                        reader_stats.latencies.lock().unwrap().push(sent.elapsed());
+                    }
+                }
+                Some("error") | Some("room-is-crowded") => {
+                    eprintln!("server: {}", message["message"]);
+                    reader_stats.errors.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+        }
+    });
+
+    // Spread the clients' keystrokes out instead of sending in lockstep.
+    tokio::time::sleep(interval.mul_f64(rand::random::<f64>())).await;
+    let mut ticker = tokio::time::interval(interval);
+    let mut pos = 0;
+    while Instant::now() < deadline {
+        ticker.tick().await;
+        let message = if pos == LINE_LEN {
+            pos = 0;
+            json!({"type": "keyPress", "key": "Enter"})
+        } else {
+            let key = char::from(b'a' + (pos % 26) as u8).to_string();
+            stats
+                .sent_at
+                .lock()
+                .unwrap()
+                .insert((id.clone(), pos as u64), Instant::now());
+            pos += 1;
+            json!({"type": "keyPress", "key": key, "cursorPos": pos - 1})
+        };
+        if sender
+            .send(Message::Text(message.to_string()))
+            .await
+            .is_err()
+        {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+            break;
+        }
+        stats.keys_sent.fetch_add(1, Ordering::Relaxed);
+    }
+    // Let the last key presses arrive before hanging up.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let _ = sender.close().await;
+    reader.abort();
+}
@@ -0,0 +1,64 @@
+//! What a client can expect of this instance, advertised in `helloAck` as a
+//! bitmap so that it can hide what the instance has turned off instead of
+//! failing at first use. Some bits follow from other settings, such as
+//! `[rooms] creation`; the rest are switched by `[capabilities]`.
+
+use crate::config::{Config, CreationPolicy, DuplicatePolicy};
+
+/// One bit of `capabilities`. Bits are never reused; a client should ignore
+/// those it doesn't know.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+pub enum Capability {
+    /// `newroom`, and fetching a room that doesn't exist, create it.
+    CreateRooms = 1 << 0,
+    /// A dropped connection can resume with `lastSeq`.
+    Resume = 1 << 1,
+    /// Expired rooms are archived and `reviveRoom` brings them back.
+    Revive = 1 << 2,
+    /// `searchHistory`.
+    Search = 1 << 3,
+    /// `sounds` when joining.
+    KeySounds = 1 << 4,
+    /// `granularity` of `word` or `line` when joining.
+    LineMode = 1 << 5,
+    /// `pushUrl` in prefs gets mentions pushed to it.
+    PushMentions = 1 << 6,
+    /// Anyone, not only a verified identity, can be connected from several
+    /// devices at once.
+    Mirror = 1 << 7,
+}
+
+impl Capability {
+    const ALL: [Self; 8] = [
+        Self::CreateRooms,
+        Self::Resume,
+        Self::Revive,
+        Self::Search,
+        Self::KeySounds,
+        Self::LineMode,
+        Self::PushMentions,
+        Self::Mirror,
+    ];
+
+    pub fn enabled(self, config: &Config) -> bool {
+        match self {
+            Self::CreateRooms => config.rooms.creation == CreationPolicy::Open,
+            Self::Resume => config.rooms.resume_grace_secs > 0,
+            Self::Revive => config.rooms.archive_grace_secs > 0,
+            Self::Search => config.capabilities.search,
+            Self::KeySounds => config.capabilities.key_sounds,
+            Self::LineMode => config.capabilities.line_mode,
+            Self::PushMentions => !config.mentions.push_hosts.is_empty(),
+            Self::Mirror => config.rooms.duplicate_connections == DuplicatePolicy::Mirror,
+        }
+    }
+}
+
+/// The bitmap of what `config` enables.
+pub fn advertised(config: &Config) -> u32 {
+    Capability::ALL
+        .iter()
+        .filter(|capability| capability.enabled(config))
+        .fold(0, |bits, capability| bits | *capability as u32)
+}
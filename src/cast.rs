@@ -0,0 +1,155 @@
+//! `GET /api/rooms/<id>/cast`: a conversation as an asciinema recording
+//! (asciicast v2), drawn the way a terminal that joined with `"format":
+//! "ansi"` sees it, so the live typing can be played back or embedded where
+//! typeto.me isn't. Interview mode's key presses give the real timing;
+//! otherwise the finished lines are typed out at a steady speed. Renderers
+//! such as `agg` turn a cast into a GIF.
+
+use std::{collections::HashMap, fmt::Write};
+
+use serde_json::json;
+use typeto_client_core::apply_key;
+
+use crate::{
+    ansi::Screen, keystrokes::KeystrokeLog, storage::HistoryLine, ServerMessage, SYSTEM_ID,
+};
+
+/// Characters a second when typing out finished lines, unless asked.
+pub const DEFAULT_SPEED: u32 = 15;
+pub const MAX_SPEED: u32 = 1000;
+/// Between one finished line and the next.
+const LINE_PAUSE_MS: u64 = 500;
+/// Players skip pauses longer than this, in seconds, in recorded timing.
+const IDLE_TIME_LIMIT: f64 = 2.0;
+
+/// Something to draw, `at_ms` from the start.
+enum Step {
+    Key {
+        at_ms: u64,
+        participant: String,
+        key: String,
+        cursor_pos: Option<usize>,
+    },
+    Notice {
+        at_ms: u64,
+        message: String,
+    },
+}
+
+/// The cast of `lines`, typed out at `speed` characters a second. Join and
+/// leave lines are shown below the participants.
+pub fn paced(room_id: &str, lines: &[HistoryLine], speed: u32, timestamp: u64) -> String {
+    let per_key = 1000 / u64::from(speed.clamp(1, MAX_SPEED));
+    let mut steps = Vec::new();
+    let mut at_ms = 0;
+    for line in lines {
+        if line.participant == SYSTEM_ID {
+            steps.push(Step::Notice {
+                at_ms,
+                message: line.text.clone(),
+            });
+            at_ms += LINE_PAUSE_MS;
+            continue;
+        }
+        for (pos, c) in line.text.chars().enumerate() {
+            at_ms += per_key;
+            steps.push(Step::Key {
+                at_ms,
+                participant: line.participant.clone(),
+                key: if c == ' ' {
+                    "Space".to_string()
+                } else {
+                    c.to_string()
+                },
+                cursor_pos: Some(pos),
+            });
+        }
+        at_ms += per_key;
+        steps.push(Step::Key {
+            at_ms,
+            participant: line.participant.clone(),
+            key: "Enter".to_string(),
+            cursor_pos: None,
+        });
+        at_ms += LINE_PAUSE_MS;
+    }
+    render(room_id, steps, timestamp, None)
+}
+
+/// The cast of what interview mode recorded, with its timing.
+pub fn recorded(room_id: &str, log: &KeystrokeLog, timestamp: u64) -> String {
+    let start = log.iter().next().map_or(0, |(at_ms, ..)| at_ms);
+    let steps = log
+        .iter()
+        .map(|(at_ms, participant, key, cursor_pos)| Step::Key {
+            at_ms: at_ms.saturating_sub(start),
+            participant: participant.to_string(),
+            key: key.to_string(),
+            cursor_pos,
+        })
+        .collect();
+    render(room_id, steps, timestamp, Some(IDLE_TIME_LIMIT))
+}
+
+fn render(room_id: &str, steps: Vec<Step>, timestamp: u64, idle_limit: Option<f64>) -> String {
+    let mut participants: Vec<String> = Vec::new();
+    for step in &steps {
+        if let Step::Key { participant, .. } = step {
+            if !participants.contains(participant) {
+                participants.push(participant.clone());
+            }
+        }
+    }
+    let mut screen = Screen::default();
+    let first = screen.start(room_id, &participants);
+    let mut header = json!({
+        "version": 2,
+        "width": 80,
+        "height": screen.rows(),
+        "timestamp": timestamp,
+        "title": format!("typeto.me room {}", room_id),
+    });
+    if let Some(limit) = idle_limit {
+        header["idle_time_limit"] = json!(limit);
+    }
+    let mut cast = format!("{}\n", header);
+    let mut event = |at_ms: u64, output: String| {
+        let _ = writeln!(cast, "{}", json!([at_ms as f64 / 1000.0, "o", output]));
+    };
+    event(0, first);
+
+    let mut lines: HashMap<String, String> = HashMap::new();
+    for step in steps {
+        let (at_ms, message) = match step {
+            Step::Notice { at_ms, message } => (at_ms, ServerMessage::ServerNotice { message }),
+            Step::Key {
+                at_ms,
+                participant,
+                key,
+                cursor_pos,
+            } => {
+                let line = lines.entry(participant.clone()).or_default();
+                let message = if key == "Enter" {
+                    ServerMessage::Committed {
+                        r#final: std::mem::take(line).into(),
+                        source: participant.into(),
+                        rev: None,
+                    }
+                } else {
+                    apply_key(line, &key, cursor_pos);
+                    ServerMessage::KeyPress {
+                        key: key.into(),
+                        source: participant.into(),
+                        cursor_pos,
+                        rev: None,
+                    }
+                };
+                (at_ms, message)
+            }
+        };
+        if let Some(output) = screen.render(&message) {
+            event(at_ms, output);
+        }
+    }
+    cast
+}
@@ -0,0 +1,219 @@
+//! Several instances serving rooms together without shared storage. Each
+//! room belongs to one node, picked by rendezvous hashing of its id over the
+//! nodes `[cluster]` lists that answer their health checks, so a node coming
+//! or going only moves the rooms that hash to it. Every connection to a room
+//! ends up on its owner, which keeps ordering within a room as simple as on a
+//! single instance: an upgrade with `?room=<id>` for a room another node owns
+//! is passed on to that node whole, and the two sockets are joined byte for
+//! byte. New rooms get ids the node serving the connection owns.
+
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use hyper::{header, Body, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+
+use crate::{config::ClusterConfig, gate::Pass, generate_random_string, SharedState};
+
+/// Carries `[cluster] secret` on a connection one node passes to another.
+const PEER_HEADER: &str = "x-typeto-cluster";
+const HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Cluster {
+    node: String,
+    nodes: Vec<Node>,
+    secret: String,
+}
+
+struct Node {
+    url: String,
+    /// As of the last health check; this node always is.
+    alive: AtomicBool,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig) -> Result<Self, String> {
+        if config.secret.is_empty() {
+            return Err("[cluster] secret must not be empty.".to_string());
+        }
+        let node = base_url(&config.node)?;
+        let mut nodes = Vec::new();
+        for url in &config.nodes {
+            let url = base_url(url)?;
+            if nodes.iter().any(|node: &Node| node.url == url) {
+                return Err(format!("[cluster] nodes lists {} twice.", url));
+            }
+            nodes.push(Node {
+                url,
+                alive: AtomicBool::new(true),
+            });
+        }
+        if !nodes.iter().any(|other| other.url == node) {
+            return Err(format!("[cluster] nodes must include node, {}.", node));
+        }
+        Ok(Self {
+            node,
+            nodes,
+            secret: config.secret.clone(),
+        })
+    }
+
+    /// The base URL of the node that serves room `id`.
+    pub fn owner(&self, id: &str) -> &str {
+        self.nodes
+            .iter()
+            .filter(|node| node.url == self.node || node.alive.load(Ordering::Relaxed))
+            .max_by_key(|node| weight(&node.url, id))
+            .map_or(&self.node, |node| &node.url)
+    }
+
+    pub fn owns(&self, id: &str) -> bool {
+        self.owner(id) == self.node
+    }
+
+    /// Whether `req` was passed on by another node.
+    pub fn is_peer(&self, req: &Request<Body>) -> bool {
+        req.headers()
+            .get(PEER_HEADER)
+            .is_some_and(|value| bool::from(value.as_bytes().ct_eq(self.secret.as_bytes())))
+    }
+
+    /// The node to pass a WebSocket upgrade on to: the owner of its `?room=`,
+    /// unless that is this node or the upgrade already was passed on.
+    pub fn forward_to(&self, req: &Request<Body>) -> Option<String> {
+        if self.is_peer(req) {
+            return None;
+        }
+        let room = req.uri().query().and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "room")
+                .map(|(_, value)| value.into_owned())
+        })?;
+        let owner = self.owner(&room);
+        (owner != self.node).then(|| owner.to_string())
+    }
+
+    /// Passes the upgrade `req` from `ip` on to `owner` and, once it is
+    /// accepted there, joins the two connections until either closes. The
+    /// connection keeps its place here, `pass`, meanwhile.
+    pub async fn proxy(
+        &self,
+        state: &SharedState,
+        mut req: Request<Body>,
+        owner: &str,
+        ip: Option<IpAddr>,
+        pass: Pass,
+    ) -> Response<Body> {
+        let path = req
+            .uri()
+            .path_and_query()
+            .map_or("/ws", |path| path.as_str());
+        let mut forwarded = Request::get(format!("{}{}", owner, path));
+        for (name, value) in req.headers() {
+            if name != header::HOST && name != "x-forwarded-for" && name != PEER_HEADER {
+                forwarded = forwarded.header(name, value);
+            }
+        }
+        if let Some(ip) = ip {
+            forwarded = forwarded.header("x-forwarded-for", ip.to_string());
+        }
+        let forwarded = forwarded
+            .header(PEER_HEADER, &self.secret)
+            .body(Body::empty())
+            .unwrap();
+        let mut response = match state.http_client.request(forwarded).await {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("Passing a connection on to {} failed: {}", owner, err);
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::from("The room's node is unreachable."))
+                    .unwrap();
+            }
+        };
+        // A refusal is the client's to see, as if it had come from here.
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return response;
+        }
+        let from_client = hyper::upgrade::on(&mut req);
+        let from_owner = hyper::upgrade::on(&mut response);
+        tokio::spawn(async move {
+            let _pass = pass;
+            let (Ok(mut client), Ok(mut owner)) = (from_client.await, from_owner.await) else {
+                return;
+            };
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut owner).await;
+        });
+        let mut reply = Response::builder().status(StatusCode::SWITCHING_PROTOCOLS);
+        for (name, value) in response.headers() {
+            reply = reply.header(name, value);
+        }
+        reply.body(Body::empty()).unwrap()
+    }
+
+    /// Checks the other nodes' health every few seconds, for as long as the
+    /// server runs, and places rooms on those that answer.
+    pub async fn watch(state: SharedState) {
+        let Some(cluster) = &state.cluster else {
+            return;
+        };
+        let mut interval = tokio::time::interval(HEALTH_INTERVAL);
+        loop {
+            interval.tick().await;
+            for node in cluster.nodes.iter().filter(|node| node.url != cluster.node) {
+                let url = format!("{}/healthz", node.url);
+                let check = state.http_client.get(url.parse().unwrap());
+                let alive = matches!(
+                    tokio::time::timeout(HEALTH_TIMEOUT, check).await,
+                    Ok(Ok(response)) if response.status().is_success()
+                );
+                if node.alive.swap(alive, Ordering::Relaxed) != alive {
+                    if alive {
+                        info!("Cluster node {} is back", node.url);
+                    } else {
+                        warn!("Cluster node {} is down; its rooms go elsewhere", node.url);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A fresh id for a room created here; one that hashes to this node, in a
+/// cluster.
+pub fn new_room_id(cluster: Option<&Cluster>) -> String {
+    loop {
+        let id = generate_random_string(6);
+        if cluster.is_none_or(|cluster| cluster.owns(&id)) {
+            return id;
+        }
+    }
+}
+
+/// `url` without a trailing slash, if it is an HTTP one.
+fn base_url(url: &str) -> Result<String, String> {
+    let url = url.trim_end_matches('/');
+    let parsed: hyper::Uri = url
+        .parse()
+        .map_err(|_| format!("[cluster] {:?} is not a URL.", url))?;
+    match parsed.scheme_str() {
+        Some("http" | "https") if parsed.host().is_some() => Ok(url.to_string()),
+        _ => Err(format!("[cluster] {:?} is not an http(s) URL.", url)),
+    }
+}
+
+/// Node `url`'s claim on room `id`; the highest claim wins.
+fn weight(url: &str, id: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(url)
+        .chain_update([0])
+        .chain_update(id)
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
@@ -0,0 +1,103 @@
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+
+use crate::config::CorsConfig;
+
+/// Cross-origin policy: CORS headers for `/api/` routes, and an `Origin`
+/// check on WebSocket upgrades so other sites can't open sockets that ride
+/// on a visitor's cookies.
+#[derive(Debug, Default)]
+pub struct Cors {
+    allowed_origins: Vec<String>,
+    allow_any: bool,
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> Self {
+        Self {
+            allow_any: config.allowed_origins.iter().any(|origin| origin == "*"),
+            allowed_origins: config
+                .allowed_origins
+                .iter()
+                .map(|origin| origin.trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    fn listed(&self, origin: &str) -> bool {
+        self.allow_any
+            || self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    /// Requests without an `Origin` header come from non-browser clients and
+    /// are always allowed; browsers must be same-origin or listed.
+    pub fn upgrade_allowed(&self, req: &Request<Body>) -> bool {
+        let Some(origin) = origin(req) else {
+            return true;
+        };
+        if self.listed(origin) {
+            return true;
+        }
+        let origin_host = origin.split_once("://").map(|(_, host)| host);
+        let request_hosts = [header::HOST.as_str(), "x-forwarded-host"]
+            .into_iter()
+            .filter_map(|name| req.headers().get(name))
+            .filter_map(|value| value.to_str().ok());
+        origin_host.is_some_and(|origin_host| {
+            request_hosts
+                .into_iter()
+                .any(|host| host.eq_ignore_ascii_case(origin_host))
+        })
+    }
+
+    /// Answers a CORS preflight for an API route.
+    pub fn preflight(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if req.method() != Method::OPTIONS || !req.uri().path().starts_with("/api/") {
+            return None;
+        }
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        if self.decorate(origin(req), &mut response) {
+            let headers = response.headers_mut();
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                header::HeaderValue::from_static("GET, POST, PUT, DELETE, OPTIONS"),
+            );
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                header::HeaderValue::from_static("authorization, content-type"),
+            );
+            headers.insert(
+                header::ACCESS_CONTROL_MAX_AGE,
+                header::HeaderValue::from_static("600"),
+            );
+        }
+        Some(response)
+    }
+
+    /// Adds CORS headers for a listed origin; returns whether it did.
+    pub fn decorate(&self, origin: Option<&str>, response: &mut Response<Body>) -> bool {
+        response
+            .headers_mut()
+            .append(header::VARY, header::HeaderValue::from_static("Origin"));
+        let Some(origin) = origin.filter(|origin| self.listed(origin)) else {
+            return false;
+        };
+        if let Ok(value) = header::HeaderValue::from_str(origin) {
+            response
+                .headers_mut()
+                .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        }
+        true
+    }
+}
+
+pub fn origin(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(header::ORIGIN)
+        .and_then(|value| value.to_str().ok())
+}
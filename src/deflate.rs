@@ -0,0 +1,391 @@
+//! permessage-deflate (RFC 7692) for the WebSocket. tungstenite doesn't
+//! implement extensions, so compressed messages are handled around it:
+//! [`Inflate`] sits between the socket and tungstenite and rewrites incoming
+//! compressed messages as plain frames, and [`Deflate`] builds outgoing
+//! compressed frames that tungstenite writes as they are.
+//!
+//! Both sides are asked not to take over the compression context between
+//! messages, so a connection holds no compressor state while idle.
+
+use flate2::{write::DeflateEncoder, Compression, Decompress, FlushDecompress, Status};
+use hyper::{
+    header::{self, HeaderMap},
+    upgrade::{OnUpgrade, Upgraded},
+    Body, Request, Response, StatusCode,
+};
+use hyper_tungstenite::tungstenite::{
+    handshake::derive_accept_key,
+    protocol::{
+        frame::{
+            coding::{Data, OpCode},
+            Frame, FrameHeader,
+        },
+        Role, WebSocketConfig,
+    },
+    Message,
+};
+use std::{
+    io::{self, Cursor, Write},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::config::CompressionConfig;
+
+/// What the server answers to an acceptable offer.
+pub const RESPONSE: &str =
+    "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// Every compressed message ends with this empty stored block, which the
+/// sender strips and the receiver puts back.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Whether any `permessage-deflate` offer in `headers` can be accepted. Offers
+/// that would have the server use a smaller window than zlib's default, or
+/// that carry parameters this module doesn't know, are declined.
+pub fn negotiate(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            params.next() == Some("permessage-deflate")
+                && params.all(|param| {
+                    let (name, value) = match param.split_once('=') {
+                        Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                        None => (param, None),
+                    };
+                    match name {
+                        "server_no_context_takeover" | "client_no_context_takeover" => {
+                            value.is_none()
+                        }
+                        "client_max_window_bits" => true,
+                        "server_max_window_bits" => value == Some("15"),
+                        _ => false,
+                    }
+                })
+        })
+}
+
+/// Answers a WebSocket upgrade request, accepting compression when the
+/// client offers it and `[compression]` allows it. Messages and frames from
+/// the client are limited to `max_message_bytes`, inflated.
+pub fn upgrade(
+    req: &mut Request<Body>,
+    config: &CompressionConfig,
+    max_message_bytes: usize,
+) -> Result<(Response<Body>, Upgrade), String> {
+    let key = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .ok_or("Missing Sec-WebSocket-Key")?;
+    if req
+        .headers()
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .map(|v| v.as_bytes())
+        != Some(b"13")
+    {
+        return Err("Unsupported Sec-WebSocket-Version".to_string());
+    }
+    let deflate = (config.enabled && negotiate(req.headers())).then_some(Deflate {
+        threshold: config.threshold,
+    });
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(
+            header::SEC_WEBSOCKET_ACCEPT,
+            derive_accept_key(key.as_bytes()),
+        );
+    if deflate.is_some() {
+        response = response.header(header::SEC_WEBSOCKET_EXTENSIONS, RESPONSE);
+    }
+    let response = response.body(Body::empty()).unwrap();
+    Ok((
+        response,
+        Upgrade {
+            on_upgrade: hyper::upgrade::on(req),
+            deflate,
+            limits: WebSocketConfig {
+                max_message_size: Some(max_message_bytes),
+                max_frame_size: Some(max_message_bytes),
+                ..WebSocketConfig::default()
+            },
+        },
+    ))
+}
+
+/// The connection once the `101 Switching Protocols` has been sent.
+pub struct Upgrade {
+    on_upgrade: OnUpgrade,
+    deflate: Option<Deflate>,
+    limits: WebSocketConfig,
+}
+
+impl Upgrade {
+    pub async fn accept(
+        self,
+    ) -> Result<(WebSocketStream<Inflate<Upgraded>>, Option<Deflate>), hyper::Error> {
+        let upgraded = self.on_upgrade.await?;
+        let mut stream = Inflate::new(upgraded, self.deflate.is_some());
+        stream.limits = self.limits;
+        let socket =
+            WebSocketStream::from_raw_socket(stream, Role::Server, Some(self.limits)).await;
+        Ok((socket, self.deflate))
+    }
+}
+
+/// Compresses outgoing text messages of at least `threshold` bytes; smaller
+/// ones, like relayed key presses, gain nothing and go out as they are.
+#[derive(Debug, Clone, Copy)]
+pub struct Deflate {
+    pub threshold: usize,
+}
+
+impl Deflate {
+    pub fn message(&self, text: String) -> Message {
+        if text.len() < self.threshold {
+            return Message::Text(text);
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        // A sync flush ends the data on a byte boundary with the empty block
+        // the receiver expects to add back itself.
+        if encoder
+            .write_all(text.as_bytes())
+            .and_then(|()| encoder.flush())
+            .is_err()
+        {
+            return Message::Text(text);
+        }
+        let mut data = std::mem::take(encoder.get_mut());
+        if data.ends_with(&TAIL) {
+            data.truncate(data.len() - TAIL.len());
+        }
+        let mut frame = Frame::message(data, OpCode::Data(Data::Text), true);
+        frame.header_mut().rsv1 = true;
+        Message::Frame(frame)
+    }
+}
+
+/// A compressed message whose continuation frames are still arriving.
+struct Partial {
+    header: FrameHeader,
+    payload: Vec<u8>,
+}
+
+/// Reads WebSocket frames from `inner` and hands them on unchanged, except
+/// that compressed messages are inflated into a single uncompressed frame.
+/// Works for either end of the connection; writes pass straight through.
+pub struct Inflate<S> {
+    inner: S,
+    /// Off until the handshake has agreed on compression.
+    pub enabled: bool,
+    /// Compressed messages inflated so far.
+    pub inflated: usize,
+    /// Bytes read from `inner` that don't yet make up a whole frame.
+    input: Vec<u8>,
+    /// Frames ready for tungstenite, and how far it has read into them.
+    output: Vec<u8>,
+    read: usize,
+    partial: Option<Partial>,
+    eof: bool,
+    /// The sizes past which a compressed message is refused.
+    pub limits: WebSocketConfig,
+}
+
+impl<S> Inflate<S> {
+    pub fn new(inner: S, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            inflated: 0,
+            input: Vec::new(),
+            output: Vec::new(),
+            read: 0,
+            partial: None,
+            eof: false,
+            limits: WebSocketConfig::default(),
+        }
+    }
+
+    /// Moves each complete frame in `input` to `output`, inflating any
+    /// compressed message once its last frame is in.
+    fn process(&mut self) -> io::Result<()> {
+        loop {
+            let mut cursor = Cursor::new(&self.input);
+            let Some((mut header, len)) = FrameHeader::parse(&mut cursor).map_err(invalid)? else {
+                return Ok(());
+            };
+            if self
+                .limits
+                .max_frame_size
+                .is_some_and(|max| len > max as u64)
+            {
+                return Err(too_large("frame"));
+            }
+            let start = cursor.position() as usize;
+            let end = start + len as usize;
+            if self.input.len() < end {
+                return Ok(());
+            }
+            let compressed =
+                header.rsv1 && matches!(header.opcode, OpCode::Data(Data::Text | Data::Binary));
+            let continued = self.partial.is_some() && header.opcode == OpCode::Data(Data::Continue);
+            if !compressed && !continued {
+                self.output.extend_from_slice(&self.input[..end]);
+                self.input.drain(..end);
+                continue;
+            }
+            let mut payload = self.input[start..end].to_vec();
+            self.input.drain(..end);
+            if let Some(mask) = header.mask {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+            }
+            let partial = match self.partial.take() {
+                Some(mut partial) => {
+                    partial.payload.extend_from_slice(&payload);
+                    partial.header.is_final = header.is_final;
+                    partial
+                }
+                None => Partial {
+                    header: header.clone(),
+                    payload,
+                },
+            };
+            if self
+                .limits
+                .max_message_size
+                .is_some_and(|max| partial.payload.len() > max)
+            {
+                return Err(too_large("message"));
+            }
+            if !partial.header.is_final {
+                self.partial = Some(partial);
+                continue;
+            }
+            let data = self.inflate(partial.payload)?;
+            header = partial.header;
+            header.rsv1 = false;
+            // The data is already unmasked; a zero mask keeps the frame
+            // valid for a server without changing it.
+            header.mask = header.mask.map(|_| [0; 4]);
+            header
+                .format(data.len() as u64, &mut self.output)
+                .map_err(invalid)?;
+            self.output.extend_from_slice(&data);
+            self.inflated += 1;
+        }
+    }
+
+    fn inflate(&self, mut payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        payload.extend_from_slice(&TAIL);
+        let max = self.limits.max_message_size.unwrap_or(usize::MAX);
+        let mut decompress = Decompress::new(false);
+        let mut data = Vec::with_capacity(payload.len() * 4);
+        loop {
+            let consumed = decompress.total_in() as usize;
+            let status = decompress
+                .decompress_vec(&payload[consumed..], &mut data, FlushDecompress::Sync)
+                .map_err(invalid)?;
+            if data.len() > max {
+                return Err(too_large("message"));
+            }
+            let done = decompress.total_in() as usize == payload.len();
+            if status == Status::StreamEnd || (done && data.len() < data.capacity()) {
+                return Ok(data);
+            }
+            data.reserve(data.capacity().max(1024));
+        }
+    }
+}
+
+/// A frame or message over the limits, told apart from other refusals by
+/// [`is_too_large`].
+#[derive(Debug)]
+struct TooLarge(&'static str);
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} too large", self.0)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+fn too_large(what: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, TooLarge(what))
+}
+
+/// Whether `err` is `Inflate` refusing a frame or message over the limits.
+pub fn is_too_large(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<TooLarge>())
+}
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Inflate<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.enabled {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if this.read < this.output.len() {
+                let n = buf.remaining().min(this.output.len() - this.read);
+                buf.put_slice(&this.output[this.read..this.read + n]);
+                this.read += n;
+                if this.read == this.output.len() {
+                    this.output.clear();
+                    this.read = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0; 8192];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // Whatever is left is an incomplete frame; tungstenite
+                // reports it.
+                this.eof = true;
+                this.output.append(&mut this.input);
+                continue;
+            }
+            this.input.extend_from_slice(chunk.filled());
+            this.process()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Inflate<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
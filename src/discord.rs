@@ -0,0 +1,514 @@
+//! `[discord]`, in a binary built with the `discord` feature: a bot that
+//! bridges rooms and Discord channels. The operator bridges a room with
+//! `POST /admin/bridge/discord`; the bot then joins the room as the
+//! participant `discord`, posts each line the others finish to the channel,
+//! and types what is said in the channel into the room as
+//! `<author>: <message>`, a line at a time. Messages from bots, the bridge's
+//! own included, are not passed on, so two bridges can't feed each other.
+//!
+//! The bot listens on Discord's gateway over a WebSocket it opens through
+//! the shared HTTP client, and posts through the REST API. Bridges are kept
+//! in memory; one ends when its room does.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::{header, upgrade::Upgraded, Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_tungstenite::{
+    tungstenite::{handshake::client::generate_key, protocol::Role, Message},
+    WebSocketStream,
+};
+use tracing::{info, warn};
+
+use crate::{
+    audit::AuditEvent, config::DiscordConfig, depart, embed::valid_room_id, enter_room,
+    is_valid_key, json_response, press_key, retransmit::Retransmit, Connection, Counters,
+    ServerMessage, SharedState, SYSTEM_ID,
+};
+
+/// The bridge's participant id in the rooms it is in.
+const PARTICIPANT: &str = "discord";
+/// `GUILD_MESSAGES` and `MESSAGE_CONTENT`.
+const INTENTS: u64 = 1 << 9 | 1 << 15;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often a bridge checks that its room is still there.
+const ROOM_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// The most of a Discord message typed into a room.
+const MAX_TYPED_LINES: usize = 10;
+const MAX_TYPED_CHARS: usize = 500;
+/// Discord's limit on a message.
+const MAX_POSTED_CHARS: usize = 2000;
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BridgeRequest {
+    room: String,
+    /// The channel's id.
+    channel: String,
+}
+
+#[derive(Clone, Serialize)]
+struct Listed {
+    room: String,
+    channel: String,
+}
+
+struct Bridge {
+    channel: String,
+    /// Messages said in the channel, as `(author, content)`.
+    incoming: mpsc::UnboundedSender<(String, String)>,
+    stop: oneshot::Sender<()>,
+}
+
+pub struct Discord {
+    config: DiscordConfig,
+    /// By room id.
+    bridges: Mutex<HashMap<String, Bridge>>,
+}
+
+impl Discord {
+    pub fn new(config: &DiscordConfig) -> Result<Self, String> {
+        if config.token.is_empty() {
+            return Err("[discord] token must not be empty.".to_string());
+        }
+        Ok(Self {
+            config: config.clone(),
+            bridges: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Passes a message said in a channel on to the room bridged with it.
+    fn heard(&self, message: &Value) {
+        if message["author"]["bot"].as_bool() == Some(true) {
+            return;
+        }
+        let (Some(channel), Some(content)) =
+            (message["channel_id"].as_str(), message["content"].as_str())
+        else {
+            return;
+        };
+        let author = message["author"]["global_name"]
+            .as_str()
+            .or(message["author"]["username"].as_str())
+            .unwrap_or("someone");
+        let bridges = self.bridges.lock().unwrap();
+        if let Some(bridge) = bridges.values().find(|bridge| bridge.channel == channel) {
+            let _ = bridge
+                .incoming
+                .send((author.to_string(), content.to_string()));
+        }
+    }
+}
+
+/// Handles `GET /admin/bridge/discord`.
+pub fn list(state: &SharedState) -> Response<Body> {
+    let Some(discord) = &state.discord else {
+        return not_configured();
+    };
+    let mut bridges: Vec<Listed> = discord
+        .bridges
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(room, bridge)| Listed {
+            room: room.clone(),
+            channel: bridge.channel.clone(),
+        })
+        .collect();
+    bridges.sort_by(|a, b| a.room.cmp(&b.room));
+    json_response(StatusCode::OK, json!({"bridges": bridges}))
+}
+
+/// Handles `POST /admin/bridge/discord`.
+pub fn add(state: &SharedState, request: BridgeRequest) -> Response<Body> {
+    let Some(discord) = &state.discord else {
+        return not_configured();
+    };
+    if !valid_room_id(&request.room) {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({"error": "Room ids are 1 to 64 letters, digits, '-' or '_'."}),
+        );
+    }
+    if request.channel.is_empty() || !request.channel.bytes().all(|b| b.is_ascii_digit()) {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({"error": "channel must be a Discord channel id."}),
+        );
+    }
+    let (incoming, incoming_rx) = mpsc::unbounded_channel();
+    let (stop, stop_rx) = oneshot::channel();
+    {
+        let mut bridges = discord.bridges.lock().unwrap();
+        if bridges.contains_key(&request.room) {
+            return json_response(
+                StatusCode::CONFLICT,
+                json!({"error": "The room is already bridged."}),
+            );
+        }
+        if bridges
+            .values()
+            .any(|bridge| bridge.channel == request.channel)
+        {
+            return json_response(
+                StatusCode::CONFLICT,
+                json!({"error": "The channel is already bridged to a room."}),
+            );
+        }
+        bridges.insert(
+            request.room.clone(),
+            Bridge {
+                channel: request.channel.clone(),
+                incoming,
+                stop,
+            },
+        );
+    }
+    info!(
+        "Bridging room {} with Discord channel {}",
+        request.room, request.channel
+    );
+    state.audit.record(AuditEvent::Admin {
+        action: "discordBridged".to_string(),
+        detail: Some(format!("{} {}", request.room, request.channel)),
+    });
+    tokio::spawn(bridge(
+        state.clone(),
+        request.room.clone(),
+        request.channel.clone(),
+        incoming_rx,
+        stop_rx,
+    ));
+    json_response(
+        StatusCode::OK,
+        json!({"room": request.room, "channel": request.channel}),
+    )
+}
+
+/// Handles `DELETE /admin/bridge/discord/{room}`.
+pub fn remove(state: &SharedState, room: &str) -> Response<Body> {
+    let Some(discord) = &state.discord else {
+        return not_configured();
+    };
+    let Some(bridge) = discord.bridges.lock().unwrap().remove(room) else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({"error": "The room isn't bridged."}),
+        );
+    };
+    let _ = bridge.stop.send(());
+    info!("Unbridged room {}", room);
+    state.audit.record(AuditEvent::Admin {
+        action: "discordUnbridged".to_string(),
+        detail: Some(room.to_string()),
+    });
+    json_response(StatusCode::OK, json!({"removed": room}))
+}
+
+fn not_configured() -> Response<Body> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        json!({"error": "[discord] is not configured."}),
+    )
+}
+
+/// Keeps room `room_id` and Discord channel `channel` in step until the
+/// bridge is removed or the room ends.
+async fn bridge(
+    state: SharedState,
+    room_id: String,
+    channel: String,
+    mut incoming: mpsc::UnboundedReceiver<(String, String)>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let (sender, mut events) = broadcast::channel(64);
+    let connection = Connection {
+        sender,
+        traffic: Arc::new(Counters::default()),
+        retransmit: Arc::new(Mutex::new(Retransmit::default())),
+        ip: None,
+    };
+    let entered = enter_room(&state, &connection, &room_id, PARTICIPANT, true, None, None).await;
+    if !entered {
+        warn!("The Discord bridge couldn't join room {}", room_id);
+        forget(&state, &room_id);
+        return;
+    }
+    let mut room_check = tokio::time::interval(ROOM_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(ServerMessage::Committed { r#final, source, .. })
+                    if &*source != PARTICIPANT && &*source != SYSTEM_ID && !r#final.is_empty() =>
+                {
+                    post(&state, &channel, &source, &r#final).await;
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => {
+                    forget(&state, &room_id);
+                    break;
+                }
+            },
+            Some((author, content)) = incoming.recv() => {
+                if !type_message(&state, &connection, &room_id, &author, &content).await {
+                    info!("Room {} is gone; ending its Discord bridge", room_id);
+                    forget(&state, &room_id);
+                    return;
+                }
+            }
+            _ = room_check.tick() => {
+                if !state.rooms.contains(&room_id) {
+                    info!("Room {} is gone; ending its Discord bridge", room_id);
+                    forget(&state, &room_id);
+                    return;
+                }
+            }
+            _ = &mut stop => break,
+        }
+    }
+    if let Some(room) = state.rooms.get(&room_id) {
+        let state = state.clone();
+        room.cast(move |room| depart(&state, room, PARTICIPANT, &connection.sender));
+    }
+}
+
+/// Drops the bridge of `room_id` from the list, once it has ended by itself.
+fn forget(state: &SharedState, room_id: &str) {
+    if let Some(discord) = &state.discord {
+        discord.bridges.lock().unwrap().remove(room_id);
+    }
+}
+
+/// Types what `author` said into the room, a line at a time; false if the
+/// bridge is no longer in the room.
+async fn type_message(
+    state: &SharedState,
+    connection: &Connection,
+    room_id: &str,
+    author: &str,
+    content: &str,
+) -> bool {
+    for line in content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .take(MAX_TYPED_LINES)
+    {
+        let text = format!("{}: {}", author, line);
+        let keys = text
+            .chars()
+            .take(MAX_TYPED_CHARS)
+            .map(|c| {
+                if c == ' ' {
+                    "Space".to_string()
+                } else {
+                    c.to_string()
+                }
+            })
+            .filter(|key| is_valid_key(key))
+            .chain(["Enter".to_string()]);
+        // Keys need the cursor, which stays at the end of the line.
+        for (pos, key) in keys.enumerate() {
+            let typed = press_key(
+                state,
+                &connection.sender,
+                room_id,
+                PARTICIPANT,
+                &key,
+                Some(pos),
+                None,
+            )
+            .await;
+            if !typed {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Posts `source`'s line to `channel`.
+async fn post(state: &SharedState, channel: &str, source: &str, line: &str) {
+    let Some(discord) = &state.discord else {
+        return;
+    };
+    let content: String = format!("**{}**: {}", escape(source), escape(line))
+        .chars()
+        .take(MAX_POSTED_CHARS)
+        .collect();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!(
+            "{}/channels/{}/messages",
+            discord.config.api_url.trim_end_matches('/'),
+            channel
+        ))
+        .header(
+            header::AUTHORIZATION,
+            format!("Bot {}", discord.config.token),
+        )
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            json!({"content": content, "allowed_mentions": {"parse": []}}).to_string(),
+        ));
+    let Ok(request) = request else {
+        warn!(
+            "[discord] api_url {:?} is not a URL",
+            discord.config.api_url
+        );
+        return;
+    };
+    match state.http_client.request(request).await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => warn!(
+            "Posting to Discord channel {} returned {}",
+            channel,
+            response.status()
+        ),
+        Err(err) => warn!("Posting to Discord channel {} failed: {}", channel, err),
+    }
+}
+
+/// `text` with Discord's markdown characters escaped.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\*_~`|>".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Listens on the gateway for as long as the server runs, reconnecting with
+/// a growing delay while that fails.
+pub async fn run(state: SharedState) {
+    let Some(discord) = &state.discord else {
+        return;
+    };
+    let listen = async {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match listen(&state, discord).await {
+                Ok(()) => backoff = MIN_BACKOFF,
+                Err(err) => {
+                    warn!("Discord gateway: {}", err);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+            tokio::time::sleep(backoff).await;
+        }
+    };
+    tokio::select! {
+        () = listen => {}
+        () = state.shutdown.clone() => {}
+    }
+}
+
+/// One gateway session, until Discord asks for a new one (`Ok`) or it fails.
+async fn listen(state: &SharedState, discord: &Discord) -> Result<(), String> {
+    let mut socket = connect(state, &discord.config.gateway_url).await?;
+    let hello = read_payload(&mut socket).await?;
+    let interval = hello["d"]["heartbeat_interval"]
+        .as_u64()
+        .filter(|_| hello["op"] == 10)
+        .ok_or("the gateway didn't say hello")?;
+    let identify = json!({
+        "op": 2,
+        "d": {
+            "token": discord.config.token,
+            "intents": INTENTS,
+            "properties": {"os": std::env::consts::OS, "browser": "typeto", "device": "typeto"},
+        },
+    });
+    send(&mut socket, &identify).await?;
+    let mut heartbeat = tokio::time::interval(Duration::from_millis(interval));
+    heartbeat.tick().await;
+    let mut sequence = Value::Null;
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                send(&mut socket, &json!({"op": 1, "d": sequence})).await?;
+            }
+            payload = read_payload(&mut socket) => {
+                let payload = payload?;
+                if !payload["s"].is_null() {
+                    sequence = payload["s"].clone();
+                }
+                match payload["op"].as_u64() {
+                    Some(0) if payload["t"] == "READY" => {
+                        info!("Discord bot {} is connected", payload["d"]["user"]["username"]);
+                    }
+                    Some(0) if payload["t"] == "MESSAGE_CREATE" => discord.heard(&payload["d"]),
+                    Some(1) => send(&mut socket, &json!({"op": 1, "d": sequence})).await?,
+                    Some(7) => return Ok(()),
+                    Some(9) => return Err("the session was invalidated".to_string()),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Opens a WebSocket to `url`, `wss://` or `ws://`.
+async fn connect(state: &SharedState, url: &str) -> Result<WebSocketStream<Upgraded>, String> {
+    let http_url = url
+        .replacen("wss://", "https://", 1)
+        .replacen("ws://", "http://", 1);
+    let request = Request::get(&http_url)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_VERSION, "13")
+        .header(header::SEC_WEBSOCKET_KEY, generate_key())
+        .body(Body::empty())
+        .map_err(|_| format!("{:?} is not a URL", url))?;
+    let response = state
+        .http_client
+        .request(request)
+        .await
+        .map_err(|err| format!("connecting failed: {}", err))?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(format!("connecting returned {}", response.status()));
+    }
+    let upgraded = hyper::upgrade::on(response)
+        .await
+        .map_err(|err| format!("connecting failed: {}", err))?;
+    Ok(WebSocketStream::from_raw_socket(upgraded, Role::Client, None).await)
+}
+
+async fn read_payload(socket: &mut WebSocketStream<Upgraded>) -> Result<Value, String> {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => {
+                return serde_json::from_str(&text).map_err(|err| format!("bad payload: {}", err))
+            }
+            Some(Ok(Message::Close(frame))) => {
+                return Err(format!(
+                    "closed{}",
+                    frame.map_or_else(String::new, |frame| format!(
+                        " with {}: {}",
+                        frame.code, frame.reason
+                    ))
+                ))
+            }
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err.to_string()),
+            None => return Err("closed".to_string()),
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocketStream<Upgraded>, payload: &Value) -> Result<(), String> {
+    socket
+        .send(Message::Text(payload.to_string()))
+        .await
+        .map_err(|err| err.to_string())
+}
@@ -0,0 +1,362 @@
+//! `[email]`: invitations to a room sent over SMTP, for invitees who
+//! wouldn't know what to do with a pasted URL. A room's creator, or the
+//! admin, names an address and the server mails it a link to the room,
+//! carrying an invitation if one is given, through the templates the
+//! operator configured. Each room may send `max_per_hour` of them, each
+//! client address `max_per_ip_per_hour` whatever the room, and the instance
+//! `max_total_per_hour` altogether, so that fresh rooms can't make it a
+//! relay.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hyper::StatusCode;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    audit::AuditEvent,
+    config::{EmailConfig, SmtpTls},
+    embed::valid_room_id,
+    generate_random_string, SharedState,
+};
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_NOTE_CHARS: usize = 500;
+const MAX_INVITE_CHARS: usize = 64;
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct InviteEmailRequest {
+    /// The invitee's address.
+    to: String,
+    /// An invitation from `POST /admin/invites`, added to the link so the
+    /// invitee can bring the room back if it has expired by then.
+    invite: Option<String>,
+    /// A few words from whoever invites, filling in `{note}`.
+    note: Option<String>,
+}
+
+/// Who an invitation counts against.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Sender {
+    Room(String),
+    Ip(Option<IpAddr>),
+    Instance,
+}
+
+/// When each room, client address and the instance sent invitations in the
+/// last hour.
+#[derive(Default)]
+pub struct Outbox(Mutex<HashMap<Sender, VecDeque<Instant>>>);
+
+impl Outbox {
+    /// Counts an invitation against each of `limits`, the most each sender
+    /// may send in an hour, unless one has sent that many already; returns
+    /// the first that has.
+    fn admit<'a>(&self, limits: &'a [(Sender, usize)]) -> Result<(), &'a Sender> {
+        let now = Instant::now();
+        let hour = Duration::from_secs(3600);
+        let mut sent = self.0.lock().unwrap();
+        sent.retain(|_, times| {
+            times.retain(|&at| now.duration_since(at) < hour);
+            !times.is_empty()
+        });
+        for (sender, max_per_hour) in limits {
+            if sent.get(sender).map_or(0, VecDeque::len) >= *max_per_hour {
+                return Err(sender);
+            }
+        }
+        for (sender, _) in limits {
+            sent.entry(sender.clone()).or_default().push_back(now);
+        }
+        Ok(())
+    }
+}
+
+/// Emails the invitation `request` describes to room `room`, asked for from
+/// `ip`. The error is for the caller.
+pub async fn invite(
+    state: &SharedState,
+    config: &EmailConfig,
+    room: &str,
+    ip: Option<IpAddr>,
+    request: InviteEmailRequest,
+) -> Result<(), (StatusCode, String)> {
+    let bad_request = |message: &str| (StatusCode::BAD_REQUEST, message.to_string());
+    // Goes into the link and the templates as it is.
+    if !valid_room_id(room) {
+        return Err(bad_request(
+            "Only rooms named with letters, digits, - and _ can send invitations.",
+        ));
+    }
+    if !valid_address(&request.to) {
+        return Err(bad_request("to must be one email address."));
+    }
+    let note = request.note.unwrap_or_default();
+    if note.chars().count() > MAX_NOTE_CHARS || note.chars().any(|c| c.is_control() && c != '\n') {
+        return Err(bad_request(&format!(
+            "note must be at most {} characters, without control characters.",
+            MAX_NOTE_CHARS
+        )));
+    }
+    let mut url = format!("{}/{}", config.public_url.trim_end_matches('/'), room);
+    if let Some(invite) = &request.invite {
+        if invite.is_empty()
+            || invite.len() > MAX_INVITE_CHARS
+            || !invite.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(bad_request("invite is not an invitation."));
+        }
+        url.push_str(&format!("?invite={}", invite));
+    }
+    let limits = [
+        (Sender::Room(room.to_string()), config.max_per_hour),
+        (Sender::Ip(ip), config.max_per_ip_per_hour),
+        (Sender::Instance, config.max_total_per_hour),
+    ];
+    if let Err(sender) = state.outbox.admit(&limits) {
+        let message = match sender {
+            Sender::Room(_) => format!(
+                "A room can send {} invitations an hour; try again later.",
+                config.max_per_hour
+            ),
+            Sender::Ip(_) => format!(
+                "You can send {} invitations an hour; try again later.",
+                config.max_per_ip_per_hour
+            ),
+            Sender::Instance => {
+                "This server has sent all the invitations it may this hour; try again later."
+                    .to_string()
+            }
+        };
+        return Err((StatusCode::TOO_MANY_REQUESTS, message));
+    }
+
+    let fill = |template: &str| {
+        let template = if note.is_empty() {
+            template.replace("{note}\n\n", "")
+        } else {
+            template.to_string()
+        };
+        template
+            .replace("{room}", room)
+            .replace("{url}", &url)
+            .replace("{note}", note.trim())
+    };
+    let subject = fill(&config.subject).replace(['\r', '\n'], " ");
+    let message = compose(config, &request.to, &subject, &fill(&config.body));
+    match tokio::time::timeout(SEND_TIMEOUT, send(config, &request.to, &message)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => {
+            warn!("Invitation from room {} not sent: {}", room, err);
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                "The email could not be sent.".to_string(),
+            ));
+        }
+        Err(_) => {
+            warn!("Invitation from room {} timed out", room);
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                "The email could not be sent.".to_string(),
+            ));
+        }
+    }
+    info!("Room {} emailed an invitation", room);
+    state.audit.record(AuditEvent::Admin {
+        action: "invitationEmailed".to_string(),
+        detail: Some(room.to_string()),
+    });
+    Ok(())
+}
+
+/// Whether `address` is a single plain address, nothing a header or an SMTP
+/// command could be smuggled in.
+fn valid_address(address: &str) -> bool {
+    let Some((local, domain)) = address.split_once('@') else {
+        return false;
+    };
+    address.len() <= 254
+        && !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains('@')
+        && address
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"<>()[],;:\\\"".contains(c))
+}
+
+/// The address in `mailbox`, which may be `Name <address>`.
+fn address_of(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// The message as it goes after `DATA`, text base64-encoded so that no line
+/// is too long or starts with a dot.
+fn compose(config: &EmailConfig, to: &str, subject: &str, text: &str) -> String {
+    let from = address_of(&config.from);
+    let domain = from
+        .rsplit_once('@')
+        .map_or("localhost", |(_, domain)| domain);
+    let subject = if subject.is_ascii() {
+        subject.to_string()
+    } else {
+        format!("=?utf-8?B?{}?=", BASE64.encode(subject))
+    };
+    let text = text.replace("\r\n", "\n").replace('\n', "\r\n");
+    let encoded = BASE64.encode(text);
+    let mut message = format!(
+        "From: {}\r\nTo: <{}>\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: base64\r\n\r\n",
+        config.from,
+        to,
+        subject,
+        chrono::Utc::now().to_rfc2822(),
+        generate_random_string(24),
+        domain,
+    );
+    for line in encoded.as_bytes().chunks(76) {
+        message.push_str(std::str::from_utf8(line).unwrap());
+        message.push_str("\r\n");
+    }
+    message
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Session {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl Session {
+    /// The server's next reply, failing unless its code is in the hundreds
+    /// of `class`, as 2 for 2xx.
+    async fn reply(&mut self, class: u16) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            let read = self
+                .stream
+                .read_line(&mut line)
+                .await
+                .map_err(|err| err.to_string())?;
+            if read == 0 {
+                return Err("the server hung up".to_string());
+            }
+            text.push_str(&line);
+            // `250-…` continues a reply, `250 …` ends it.
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+        let code: u16 = text
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0);
+        if code / 100 != class {
+            return Err(format!("the server replied {:?}", text.trim_end()));
+        }
+        Ok(text)
+    }
+
+    async fn command(&mut self, command: &str, class: u16) -> Result<String, String> {
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await
+            .map_err(|err| err.to_string())?;
+        stream.flush().await.map_err(|err| err.to_string())?;
+        self.reply(class).await
+    }
+}
+
+async fn tls(host: &str, stream: Box<dyn Stream>) -> Result<Box<dyn Stream>, String> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let tls_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from(host).map_err(|err| err.to_string())?;
+    let stream = TlsConnector::from(Arc::new(tls_config))
+        .connect(name, stream)
+        .await
+        .map_err(|err| format!("TLS with {} failed: {}", host, err))?;
+    Ok(Box::new(stream))
+}
+
+/// Hands `message` for `to` to the configured SMTP server.
+async fn send(config: &EmailConfig, to: &str, message: &str) -> Result<(), String> {
+    let from = address_of(&config.from);
+    let hello = format!(
+        "EHLO {}",
+        from.rsplit_once('@')
+            .map_or("localhost", |(_, domain)| domain)
+    );
+    let tcp = TcpStream::connect((config.host.as_str(), config.port()))
+        .await
+        .map_err(|err| format!("connecting to {} failed: {}", config.host, err))?;
+    let stream: Box<dyn Stream> = match config.tls {
+        SmtpTls::Implicit => tls(&config.host, Box::new(tcp)).await?,
+        SmtpTls::Starttls | SmtpTls::None => Box::new(tcp),
+    };
+    let mut session = Session {
+        stream: BufReader::new(stream),
+    };
+    session.reply(2).await?;
+    let mut extensions = session.command(&hello, 2).await?;
+    if config.tls == SmtpTls::Starttls {
+        if !extensions.to_ascii_uppercase().contains("STARTTLS") {
+            return Err(format!("{} doesn't offer STARTTLS", config.host));
+        }
+        session.command("STARTTLS", 2).await?;
+        let stream = tls(&config.host, session.stream.into_inner()).await?;
+        session = Session {
+            stream: BufReader::new(stream),
+        };
+        extensions = session.command(&hello, 2).await?;
+    }
+    if let Some(username) = &config.username {
+        if !extensions.to_ascii_uppercase().contains("AUTH") {
+            return Err(format!("{} doesn't offer AUTH", config.host));
+        }
+        let password = config.password.as_deref().unwrap_or_default();
+        let credentials = BASE64.encode(format!("\0{}\0{}", username, password));
+        session
+            .command(&format!("AUTH PLAIN {}", credentials), 2)
+            .await?;
+    }
+    session.command(&format!("MAIL FROM:<{}>", from), 2).await?;
+    session.command(&format!("RCPT TO:<{}>", to), 2).await?;
+    session.command("DATA", 3).await?;
+    session.command(&format!("{}.", message), 2).await?;
+    let _ = session.command("QUIT", 2).await;
+    Ok(())
+}
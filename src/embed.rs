@@ -0,0 +1,165 @@
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+use utoipa::ToSchema;
+
+use crate::{
+    config::EmbedConfig,
+    generate_random_string, json_response,
+    security::{self, Event},
+};
+
+const EMBED_AUDIENCE: &str = "typeto-embed";
+const MAX_REQUEST_BYTES: u64 = 4096;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbedClaims {
+    pub room: String,
+    pub origin: String,
+    exp: u64,
+    aud: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct EmbedRequest {
+    /// The room to frame; a new random one if left out.
+    room: Option<String>,
+}
+
+/// Issues and checks short-lived tokens that let a partner site frame a
+/// single room. Partners call `POST /api/embed` server-side with their API
+/// key; the resulting token rides along as `?embed=` on the room URL.
+pub struct Embed {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    config: EmbedConfig,
+}
+
+impl Embed {
+    pub fn new(config: EmbedConfig) -> Self {
+        Self {
+            encoding_key: EncodingKey::from_secret(config.secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(config.secret.as_bytes()),
+            config,
+        }
+    }
+
+    pub async fn issue(&self, req: Request<Body>, ip: Option<IpAddr>) -> Response<Body> {
+        if req.method() != Method::POST {
+            return json_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                json!({"error": "Use POST."}),
+            );
+        }
+
+        let api_key = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        let Some(site) = self.config.sites.iter().find(|site| {
+            !api_key.is_empty() && bool::from(site.api_key.as_bytes().ct_eq(api_key.as_bytes()))
+        }) else {
+            if !api_key.is_empty() {
+                let credential = "embed_api_key";
+                security::log(ip, Event::AuthFailed { credential });
+            }
+            return json_response(
+                StatusCode::UNAUTHORIZED,
+                json!({"error": "Unknown API key."}),
+            );
+        };
+
+        let too_large = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .is_none_or(|len| len > MAX_REQUEST_BYTES);
+        if too_large {
+            return json_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                json!({"error": "Request body missing or too large."}),
+            );
+        }
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => body,
+            Err(_) => {
+                return json_response(StatusCode::BAD_REQUEST, json!({"error": "Bad body."}));
+            }
+        };
+        let request: EmbedRequest = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(err) => {
+                return json_response(StatusCode::BAD_REQUEST, json!({"error": err.to_string()}));
+            }
+        };
+
+        let room = request.room.unwrap_or_else(|| generate_random_string(6));
+        if !valid_room_id(&room) {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({"error": "Invalid room id."}),
+            );
+        }
+
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + self.config.token_ttl_secs;
+        let claims = EmbedClaims {
+            room: room.clone(),
+            origin: site.origin.clone(),
+            exp,
+            aud: EMBED_AUDIENCE.to_string(),
+        };
+        match encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key) {
+            Ok(token) => json_response(
+                StatusCode::OK,
+                json!({
+                    "token": token,
+                    "room": room,
+                    "path": format!("/{}?embed={}", room, token),
+                    "expiresAt": exp,
+                }),
+            ),
+            Err(err) => json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": err.to_string()}),
+            ),
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<EmbedClaims, String> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_audience(&[EMBED_AUDIENCE]);
+        decode::<EmbedClaims>(token, &self.decoding_key, &validation)
+            .map(|data| data.claims)
+            .map_err(|err| format!("Invalid embed token: {}", err))
+    }
+}
+
+/// The `embed` query parameter of a request, if any.
+pub fn token_from(req: &Request<Body>) -> Option<String> {
+    req.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "embed")
+            .map(|(_, value)| value.into_owned())
+    })
+}
+
+pub fn valid_room_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -0,0 +1,74 @@
+//! When a room will be cleaned up, shown to its participants so a room
+//! doesn't vanish mid-conversation: `RoomView` carries the times, and an
+//! `expiring` message warns as the end of a room's maximum age nears.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::RoomsConfig;
+
+/// How long before a room's maximum age runs out its participants are
+/// warned, furthest first.
+const WARNINGS: [u64; 3] = [3600, 600, 60];
+
+/// The `[rooms]` lifetimes a room was last checked against.
+#[derive(Debug, Clone, Copy)]
+pub struct Lifetimes {
+    max_age_secs: Option<u64>,
+    idle_ttl_secs: u64,
+}
+
+impl From<&RoomsConfig> for Lifetimes {
+    fn from(rooms: &RoomsConfig) -> Self {
+        Self {
+            max_age_secs: rooms.max_age_secs,
+            idle_ttl_secs: rooms.idle_ttl_secs,
+        }
+    }
+}
+
+impl Default for Lifetimes {
+    fn default() -> Self {
+        Self::from(&RoomsConfig::default())
+    }
+}
+
+impl Lifetimes {
+    /// The maximum age of a room that chose `own`, if it has one.
+    pub fn max_age(&self, own: Option<u64>) -> Option<Duration> {
+        let secs = match (own, self.max_age_secs) {
+            (Some(own), Some(cap)) => Some(own.min(cap)),
+            (own, cap) => own.or(cap),
+        };
+        secs.map(Duration::from_secs)
+    }
+
+    /// How long a room that chose `own` is kept once empty.
+    pub fn idle_ttl(&self, own: Option<u64>) -> Duration {
+        Duration::from_secs(own.unwrap_or(self.idle_ttl_secs))
+    }
+}
+
+/// Whether to warn now, with `left` until expiry and the nearest warning
+/// sent so far in `warned`; each is sent once, unless the room's maximum age
+/// is raised past it.
+pub fn warn(left: Duration, warned: &mut Option<u64>) -> bool {
+    let Some(&due) = WARNINGS
+        .iter()
+        .rev()
+        .find(|&&warning| left.as_secs() < warning)
+    else {
+        *warned = None;
+        return false;
+    };
+    if warned.is_some_and(|warned| warned <= due) {
+        return false;
+    }
+    *warned = Some(due);
+    true
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,149 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`. They run client input
+//! through the same parsing and room code as a live socket, minus the
+//! network and the shared state.
+
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use crate::{
+    claim_participant_id, identity, is_valid_key, report, search, ClientMessage, Counters, Room,
+    ServerMessage, UserPrefs, MAX_HISTORY,
+};
+
+/// Two participants in one room, fed frames as if from the first of them.
+pub struct Session {
+    room: Room,
+    participant_id: String,
+    sender: broadcast::Sender<ServerMessage>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        let mut room = Room::new("fuzz".to_string());
+        let (sender, _) = broadcast::channel(32);
+        for id in ["alice", "bob"] {
+            room.join(
+                id.to_string(),
+                sender.clone(),
+                Arc::new(Counters::default()),
+                Arc::default(),
+            )
+            .unwrap();
+        }
+        Self {
+            room,
+            participant_id: "alice".to_string(),
+            sender,
+        }
+    }
+}
+
+impl Session {
+    /// Handles one WebSocket text frame. Frames that don't parse are ignored,
+    /// as the server does; the rest are applied to the room where they would
+    /// change it.
+    pub fn frame(&mut self, text: &str) {
+        let Ok(message) = serde_json::from_str::<ClientMessage>(text) else {
+            return;
+        };
+        match message {
+            ClientMessage::NewRoom { socket_id, .. }
+            | ClientMessage::FetchRoom { socket_id, .. } => {
+                let Ok(id) = claim_participant_id(socket_id, None) else {
+                    return;
+                };
+                self.room.leave(&self.participant_id);
+                if self
+                    .room
+                    .join(
+                        id.clone(),
+                        self.sender.clone(),
+                        Arc::new(Counters::default()),
+                        Arc::default(),
+                    )
+                    .is_ok()
+                {
+                    self.participant_id = id;
+                }
+                self.room.notify_participants();
+            }
+            ClientMessage::KeyPress {
+                key, cursor_pos, ..
+            } => {
+                if !is_valid_key(&key) {
+                    return;
+                }
+                self.room
+                    .handle_keypress(&self.participant_id, &key, cursor_pos);
+                self.room.flush_outbox();
+            }
+            ClientMessage::UpdateRoomSettings { settings } => {
+                if self.room.update_settings(&self.sender, settings).is_ok() {
+                    self.room.notify_participants();
+                }
+            }
+            ClientMessage::StartTimer { seconds, lock } => {
+                let _ = self.room.start_timer(&self.sender, seconds, lock);
+            }
+            ClientMessage::Freeze { socket_id } => {
+                let _ = self.room.freeze(&self.sender, &socket_id, true);
+            }
+            ClientMessage::Unfreeze { socket_id } => {
+                let _ = self.room.freeze(&self.sender, &socket_id, false);
+            }
+            ClientMessage::SetPrompt { prompt } => {
+                let _ = self.room.set_prompt(Some(&self.sender), prompt);
+            }
+            ClientMessage::StartRace { text } => {
+                let _ = self.room.start_race(&self.sender, text);
+            }
+            ClientMessage::EndRace => {
+                let _ = self.room.end_race(Some(&self.sender));
+            }
+            ClientMessage::SetPrefs { prefs, .. } => {
+                let _ = UserPrefs::default().apply(prefs);
+            }
+            ClientMessage::Authenticate {
+                public_key,
+                signature,
+            } => {
+                let _ = identity::verify("fuzz", &public_key, &signature);
+            }
+            ClientMessage::SearchHistory { query, regex } => {
+                if let Ok(matcher) = search::Matcher::new(&query, regex) {
+                    let lines = self.room.messages.iter().flat_map(|(id, lines)| {
+                        lines.iter().map(move |line| (id.as_str(), line.as_str()))
+                    });
+                    search::find(lines, &matcher);
+                }
+            }
+            ClientMessage::ClaimOwner { token } => {
+                let _ = self.room.claim_owner(&self.sender, &token);
+            }
+            ClientMessage::Report { reason } => {
+                if report::valid_reason(&reason).is_ok() {
+                    let view = self.room.render(&self.participant_id);
+                    serde_json::to_string(&report::Snapshot::of(&view))
+                        .expect("report snapshot failed to serialize");
+                }
+            }
+            ClientMessage::GetPrefs
+            | ClientMessage::GetChallenge
+            | ClientMessage::Hello
+            | ClientMessage::Ack { .. }
+            | ClientMessage::ReviveRoom { .. } => {}
+        }
+    }
+
+    /// Checks what must hold however the room got here, and that every view
+    /// of it still serializes.
+    pub fn check(&self) {
+        for lines in self.room.messages.values() {
+            assert!(lines.len() <= MAX_HISTORY, "history grew past the cap");
+        }
+        for participant in &self.room.participants {
+            let view = self.room.render(&participant.id);
+            serde_json::to_string(&view).expect("room view failed to serialize");
+        }
+    }
+}
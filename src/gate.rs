@@ -0,0 +1,38 @@
+//! Counting the WebSocket connections being served, for `[limits]
+//! max_connections`. A connection takes its place when its upgrade is
+//! accepted, not once it is set up, so a burst of upgrades can't all slip in
+//! under the limit before any of them is counted.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+#[derive(Default)]
+pub struct ConnectionGate(Arc<AtomicUsize>);
+
+impl ConnectionGate {
+    /// A place for one more connection, unless `limit` are already served.
+    pub fn enter(&self, limit: Option<usize>) -> Option<Pass> {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                limit.is_none_or(|limit| open < limit).then_some(open + 1)
+            })
+            .ok()?;
+        Some(Pass(self.0.clone()))
+    }
+
+    /// Connections being served.
+    pub fn open(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// A connection's place, given back when it is dropped.
+pub struct Pass(Arc<AtomicUsize>);
+
+impl Drop for Pass {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+use crate::{config::LimitsConfig, Room, ServerMessage, SharedState, MAX_PARTICIPANTS};
+
+const TICK: Duration = Duration::from_millis(10);
+/// Events a room earns per round-robin turn; enough for one relay to a full
+/// room, so no broadcast is ever too expensive to send.
+const QUANTUM: u64 = MAX_PARTICIPANTS as u64;
+
+/// A broadcast waiting in a room's outbox.
+#[derive(Debug)]
+pub enum Outbound {
+    Relay {
+        message: Box<ServerMessage>,
+        exclude: Option<String>,
+    },
+    /// A fresh `gotRoom` for everyone, standing in for the deltas it replaced.
+    Snapshot,
+}
+
+/// Shares `limits.broadcast_events_per_sec` fairly between rooms. Key presses
+/// and commits are queued in their room's outbox, and a scheduler task sends
+/// them round-robin across rooms within the ceiling.
+#[derive(Default)]
+pub struct Governor {
+    /// Rooms with queued broadcasts, in round-robin order.
+    active: Mutex<VecDeque<String>>,
+}
+
+impl Governor {
+    /// Called after a room queued broadcasts. Without a ceiling they are sent
+    /// right away; otherwise the room joins the round-robin.
+    pub fn schedule(&self, room: &mut Room, limits: &LimitsConfig) {
+        if limits.broadcast_events_per_sec.is_none() {
+            room.flush_outbox();
+        } else if !room.outbox.is_empty() && !room.scheduled {
+            room.scheduled = true;
+            self.active.lock().unwrap().push_back(room.id.clone());
+        }
+    }
+}
+
+pub async fn run(state: SharedState) {
+    let mut interval = tokio::time::interval(TICK);
+    let mut tokens = 0.0;
+    let mut last = Instant::now();
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        tokens = tick(&state, tokens, now - last).await;
+        last = now;
+    }
+}
+
+/// Spends the events earned over `elapsed` and returns what is left over.
+async fn tick(state: &SharedState, tokens: f64, elapsed: Duration) -> f64 {
+    let limit = state.config().limits.broadcast_events_per_sec;
+    let tokens = limit.map_or(0.0, |rate| {
+        (tokens + elapsed.as_secs_f64() * f64::from(rate)).min(burst(rate))
+    });
+    // Rooms scheduled while this tick waits on others queue up behind it.
+    let mut active = std::mem::take(&mut *state.governor.active.lock().unwrap());
+    if active.is_empty() {
+        return tokens;
+    }
+    let Some(rate) = limit else {
+        // The ceiling was lifted by a reload; nothing is held back anymore.
+        for id in active {
+            if let Some(room) = state.rooms.get(&id) {
+                room.cast(|room| {
+                    room.flush_outbox();
+                    room.scheduled = false;
+                });
+            }
+        }
+        return tokens;
+    };
+    coalesce(state, &active, f64::from(rate)).await;
+    let tokens = serve(state, &mut active, tokens).await;
+    let mut queued = state.governor.active.lock().unwrap();
+    active.append(&mut queued);
+    *queued = active;
+    tokens
+}
+
+/// Events that can be saved up while there is nothing to send.
+fn burst(rate: u32) -> f64 {
+    (f64::from(rate) / 10.0).max(QUANTUM as f64)
+}
+
+/// When more than a second's worth of events is queued, replaces the deltas
+/// of the rooms with the longest backlogs by one snapshot each until the
+/// rest fits.
+async fn coalesce(state: &SharedState, active: &VecDeque<String>, rate: f64) {
+    let mut backlogs = Vec::new();
+    for id in active {
+        let Some(room) = state.rooms.get(id) else {
+            continue;
+        };
+        if let Some(backlog) = room.call(|room| room.backlog()).await {
+            backlogs.push((backlog, room));
+        }
+    }
+    let mut total: u64 = backlogs.iter().map(|(backlog, _)| backlog).sum();
+    if total as f64 <= rate {
+        return;
+    }
+    backlogs.sort_by_key(|(backlog, _)| Reverse(*backlog));
+    for (backlog, room) in backlogs {
+        if total as f64 <= rate {
+            break;
+        }
+        let coalesced = room
+            .call(move |room| {
+                let snapshot = room.participants.len() as u64;
+                if backlog <= snapshot {
+                    return None;
+                }
+                room.outbox.clear();
+                room.outbox.push_back(Outbound::Snapshot);
+                debug!(
+                    "Coalesced {} queued events in room {} into a snapshot",
+                    backlog, room.id
+                );
+                Some(backlog - snapshot)
+            })
+            .await
+            .flatten();
+        total -= coalesced.unwrap_or(0);
+    }
+}
+
+/// How a room's round-robin turn went.
+enum Turn {
+    /// It had nothing queued and leaves the round-robin.
+    Empty,
+    /// The ceiling's budget ran out before its next broadcast.
+    OutOfBudget,
+    /// It sent what its credit covered, or nothing, and has `more` queued.
+    Served { sent: bool, more: bool },
+}
+
+/// Deficit round robin: each turn a room earns `QUANTUM` events of credit
+/// and spends it on its oldest broadcasts, so rooms get equal shares of the
+/// ceiling however much each of them queues.
+async fn serve(state: &SharedState, active: &mut VecDeque<String>, mut tokens: f64) -> f64 {
+    let mut idle_turns = 0;
+    while idle_turns < active.len() {
+        let Some(id) = active.pop_front() else {
+            break;
+        };
+        let Some(room) = state.rooms.get(&id) else {
+            continue;
+        };
+        let Some((turn, left)) = room.call(move |room| take_turn(room, tokens)).await else {
+            continue;
+        };
+        tokens = left;
+        match turn {
+            Turn::Empty => {}
+            Turn::OutOfBudget => {
+                // This room goes first next tick.
+                active.push_front(id);
+                break;
+            }
+            Turn::Served { sent, more } => {
+                if more {
+                    active.push_back(id);
+                }
+                idle_turns = if sent { 0 } else { idle_turns + 1 };
+            }
+        }
+    }
+    tokens
+}
+
+/// Spends what `room`'s turn allows of `tokens` and returns the rest.
+fn take_turn(room: &mut Room, mut tokens: f64) -> (Turn, f64) {
+    let Some(cost) = room.outbox.front().map(|outbound| room.cost(outbound)) else {
+        room.scheduled = false;
+        room.deficit = 0;
+        return (Turn::Empty, tokens);
+    };
+    if cost as f64 > tokens {
+        return (Turn::OutOfBudget, tokens);
+    }
+
+    room.deficit += QUANTUM;
+    let mut sent = false;
+    while let Some(cost) = room.outbox.front().map(|outbound| room.cost(outbound)) {
+        if cost > room.deficit || cost as f64 > tokens {
+            break;
+        }
+        room.deficit -= cost;
+        tokens -= cost as f64;
+        let outbound = room.outbox.pop_front().unwrap();
+        room.deliver(outbound);
+        sent = true;
+    }
+    let more = !room.outbox.is_empty();
+    if !more {
+        room.scheduled = false;
+        room.deficit = 0;
+    }
+    (Turn::Served { sent, more }, tokens)
+}
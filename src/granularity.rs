@@ -0,0 +1,130 @@
+//! Coarser relaying for connections that ask for it, such as a screen
+//! reader's, which would otherwise read out every character as it arrives.
+//! At `word` granularity another participant's key presses are folded into
+//! their line, which is sent whole in a `typing` message once they finish a
+//! word or pause; at `line` granularity only finished lines are sent.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use typeto_client_core::apply_key;
+
+use crate::ServerMessage;
+
+/// How long a participant stops typing before their line is sent anyway.
+const PAUSE: Duration = Duration::from_millis(1500);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    /// Every key press, as typed.
+    #[default]
+    Key,
+    /// Lines in progress after each word or pause.
+    Word,
+    /// Only finished lines.
+    Line,
+}
+
+/// Holds back one connection's key presses from others and works out the
+/// `typing` messages to send in their place.
+#[derive(Debug, Default)]
+pub struct Summarizer {
+    granularity: Granularity,
+    /// Each other participant's line in progress.
+    lines: HashMap<String, String>,
+    /// The line last sent for each participant.
+    sent: HashMap<String, String>,
+    /// When each participant's changed line is due to be sent.
+    due: HashMap<String, Instant>,
+}
+
+impl Summarizer {
+    pub fn new(granularity: Granularity) -> Self {
+        Self {
+            granularity,
+            ..Self::default()
+        }
+    }
+
+    /// What to send now in place of `message`.
+    pub fn pass(&mut self, message: ServerMessage, now: Instant) -> Vec<ServerMessage> {
+        if self.granularity == Granularity::Key {
+            return vec![message];
+        }
+        match &message {
+            ServerMessage::GotRoom { room } => {
+                self.lines = room
+                    .messages
+                    .iter()
+                    .filter(|(id, _)| **id != room.your_id)
+                    .map(|(id, lines)| (id.clone(), lines.last().cloned().unwrap_or_default()))
+                    .collect();
+                self.sent = self.lines.clone();
+                self.due.clear();
+            }
+            // Presses with a `rev` are the connection's own, from its other
+            // devices, and are needed as they are.
+            ServerMessage::KeyPress {
+                key,
+                source,
+                cursor_pos,
+                rev: None,
+            } => {
+                if self.granularity == Granularity::Line {
+                    return Vec::new();
+                }
+                let line = self.lines.entry(source.to_string()).or_default();
+                apply_key(line, key, *cursor_pos);
+                if &**key == "Space" {
+                    return self.flush(source).into_iter().collect();
+                }
+                self.due.insert(source.to_string(), now + PAUSE);
+                return Vec::new();
+            }
+            ServerMessage::Committed {
+                source, rev: None, ..
+            } => {
+                self.lines.insert(source.to_string(), String::new());
+                self.sent.insert(source.to_string(), String::new());
+                self.due.remove(&**source);
+            }
+            _ => {}
+        }
+        vec![message]
+    }
+
+    /// When the next held-back line is due, if any is.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.due.values().min().copied()
+    }
+
+    /// The lines due by `now`.
+    pub fn flush_due(&mut self, now: Instant) -> Vec<ServerMessage> {
+        let due: Vec<String> = self
+            .due
+            .iter()
+            .filter(|(_, due)| **due <= now)
+            .map(|(source, _)| source.clone())
+            .collect();
+        due.iter().filter_map(|source| self.flush(source)).collect()
+    }
+
+    fn flush(&mut self, source: &str) -> Option<ServerMessage> {
+        self.due.remove(source);
+        let line = self.lines.get(source)?;
+        if self.sent.get(source) == Some(line) {
+            return None;
+        }
+        self.sent.insert(source.to_string(), line.clone());
+        Some(ServerMessage::Typing {
+            source: source.to_string(),
+            line: line.clone(),
+        })
+    }
+}
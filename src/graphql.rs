@@ -0,0 +1,318 @@
+//! `/graphql`, for integrators who'd rather use GraphQL tooling than the
+//! WebSocket protocol: queries for rooms and their transcripts, and a
+//! subscription to the lines a room finishes, over `graphql-transport-ws` or
+//! the older `graphql-ws`. Authorized like `/api/rooms/:id`, by the room's
+//! creator token or the admin token as `Authorization: Bearer`, or for a
+//! subscription as `token` in the `connection_init` payload.
+
+use async_graphql::{
+    http::{WebSocket, WebSocketProtocols, WsMessage},
+    Context, Data, EmptyMutation, Error, Object, Result, Schema, SimpleObject, Subscription,
+};
+use futures_util::{future, stream, SinkExt, Stream, StreamExt};
+use hyper::{
+    header::{self, HeaderValue},
+    Body, Method, Request, Response, StatusCode,
+};
+use serde_json::json;
+use std::time::UNIX_EPOCH;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::tungstenite::{protocol::CloseFrame, Message};
+use tracing::{debug, warn};
+
+use crate::{
+    actor::Published, api, config::GraphqlConfig, json_response, search, storage::HistoryLine,
+    RoomMode, SharedState,
+};
+
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+
+type TypetoSchema = Schema<Query, EmptyMutation, RoomEvents>;
+
+/// The caller's credentials.
+struct Access {
+    bearer: String,
+    is_admin: bool,
+}
+
+impl Access {
+    fn new(state: &SharedState, bearer: &str) -> Self {
+        Self {
+            bearer: bearer.to_string(),
+            is_admin: state
+                .admin
+                .as_ref()
+                .is_some_and(|admin| admin.accepts(bearer)),
+        }
+    }
+}
+
+fn schema(config: &GraphqlConfig) -> TypetoSchema {
+    Schema::build(Query, EmptyMutation, RoomEvents)
+        .limit_depth(config.max_depth)
+        .limit_complexity(config.max_complexity)
+        .finish()
+}
+
+/// Fails unless the caller may see room `id`.
+async fn authorize(ctx: &Context<'_>, id: &str) -> Result<()> {
+    let state = ctx.data::<SharedState>()?;
+    let access = ctx.data::<Access>()?;
+    api::check(state, id, &access.bearer, access.is_admin)
+        .await
+        .map(|_| ())
+        .map_err(|(_, message)| Error::new(message))
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Room")]
+struct RoomInfo {
+    id: String,
+    /// Whether the room is in memory; the rest of a stored room is its
+    /// record.
+    live: bool,
+    /// Who is connected, without repeats.
+    participants: Vec<String>,
+    owner_id: Option<String>,
+    mode: RoomMode,
+    topic: Option<String>,
+    /// Unix seconds.
+    created_at: u64,
+}
+
+#[derive(SimpleObject)]
+struct Line {
+    /// `_system` for joins, leaves and topic changes.
+    participant: String,
+    text: String,
+}
+
+impl From<HistoryLine> for Line {
+    fn from(line: HistoryLine) -> Self {
+        Self {
+            participant: line.participant,
+            text: line.text,
+        }
+    }
+}
+
+fn live_room(room: &Published) -> RoomInfo {
+    RoomInfo {
+        id: room.id.clone(),
+        live: true,
+        participants: room.participants.clone(),
+        owner_id: room.owner_id.clone(),
+        mode: room.settings.mode,
+        topic: room.settings.topic.clone(),
+        created_at: room
+            .created_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    }
+}
+
+struct Query;
+
+#[Object]
+impl Query {
+    /// Every room in memory; needs the admin token.
+    async fn rooms(&self, ctx: &Context<'_>) -> Result<Vec<RoomInfo>> {
+        let state = ctx.data::<SharedState>()?;
+        if !ctx.data::<Access>()?.is_admin {
+            return Err(Error::new("The admin token is required."));
+        }
+        let mut rooms: Vec<RoomInfo> = state
+            .rooms
+            .published()
+            .iter()
+            .map(|room| live_room(room))
+            .collect();
+        rooms.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(rooms)
+    }
+
+    async fn room(&self, ctx: &Context<'_>, id: String) -> Result<RoomInfo> {
+        authorize(ctx, &id).await?;
+        let state = ctx.data::<SharedState>()?;
+        if let Some(room) = state.rooms.get(&id) {
+            return Ok(live_room(&room.published()));
+        }
+        let record = state
+            .store
+            .load_room(&id)
+            .await
+            .map_err(|_| Error::new("Storage unavailable."))?
+            .ok_or_else(|| Error::new("No such room."))?;
+        Ok(RoomInfo {
+            id: record.id,
+            live: false,
+            participants: Vec::new(),
+            owner_id: record.owner_id,
+            mode: record.settings.mode,
+            topic: record.settings.topic,
+            created_at: record.created_at,
+        })
+    }
+
+    /// The room's finished lines, oldest first: all of its stored history
+    /// with sled or PostgreSQL, otherwise what the room holds in memory.
+    async fn transcript(&self, ctx: &Context<'_>, id: String) -> Result<Vec<Line>> {
+        authorize(ctx, &id).await?;
+        let state = ctx.data::<SharedState>()?;
+        let lines = search::lines(state, &id)
+            .await
+            .map_err(|_| Error::new("Storage unavailable."))?;
+        Ok(lines.into_iter().map(Line::from).collect())
+    }
+}
+
+struct RoomEvents;
+
+#[Subscription]
+impl RoomEvents {
+    /// Each line the room finishes from now on, until it expires or is
+    /// deleted. A subscriber that falls far behind skips what it missed.
+    async fn room_lines(&self, ctx: &Context<'_>, id: String) -> Result<impl Stream<Item = Line>> {
+        authorize(ctx, &id).await?;
+        let state = ctx.data::<SharedState>()?;
+        let watched = match state.rooms.get(&id) {
+            Some(room) => room.call(|room| room.watch()).await,
+            None => None,
+        };
+        let receiver = watched.ok_or_else(|| Error::new("The room isn't open."))?;
+        Ok(stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(line) => return Some((Line::from(line), receiver)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+}
+
+/// Runs a query posted as JSON, or takes a WebSocket upgrade for
+/// subscriptions.
+pub async fn handle(
+    mut req: Request<Body>,
+    state: &SharedState,
+    config: &GraphqlConfig,
+) -> Response<Body> {
+    let access = Access::new(state, api::bearer(&req));
+    if hyper_tungstenite::is_upgrade_request(&req) {
+        return subscribe(&mut req, state, config, access);
+    }
+    if req.method() != Method::POST {
+        return json_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({"error": "POST a query, or connect a WebSocket for subscriptions."}),
+        );
+    }
+    let too_large = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_none_or(|len| len > MAX_REQUEST_BYTES);
+    if too_large {
+        return json_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            json!({"error": "Request body missing or too large."}),
+        );
+    }
+    let request: async_graphql::Request = match hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|err| err.to_string())
+        .and_then(|body| serde_json::from_slice(&body).map_err(|err| err.to_string()))
+    {
+        Ok(request) => request,
+        Err(err) => return json_response(StatusCode::BAD_REQUEST, json!({"error": err})),
+    };
+    let response = schema(config)
+        .execute(request.data(state.clone()).data(access))
+        .await;
+    json_response(StatusCode::OK, json!(response))
+}
+
+fn subscribe(
+    req: &mut Request<Body>,
+    state: &SharedState,
+    config: &GraphqlConfig,
+    access: Access,
+) -> Response<Body> {
+    let protocol = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .split(',')
+                .find_map(|protocol| protocol.trim().parse::<WebSocketProtocols>().ok())
+        });
+    let Some(protocol) = protocol else {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({"error": "Offer graphql-transport-ws or graphql-ws."}),
+        );
+    };
+    let (mut response, websocket) = match hyper_tungstenite::upgrade(req, None) {
+        Ok(upgrade) => upgrade,
+        Err(err) => {
+            return json_response(StatusCode::BAD_REQUEST, json!({"error": err.to_string()}))
+        }
+    };
+    response.headers_mut().insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(protocol.sec_websocket_protocol()),
+    );
+
+    let schema = schema(config);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let websocket = match websocket.await {
+            Ok(websocket) => websocket,
+            Err(err) => {
+                warn!("GraphQL WebSocket upgrade failed: {}", err);
+                return;
+            }
+        };
+        let (mut sink, incoming) = websocket.split();
+        let incoming = incoming
+            .take_while(|message| future::ready(message.is_ok()))
+            .filter_map(|message| {
+                future::ready(match message {
+                    Ok(Message::Text(text)) => Some(text.into_bytes()),
+                    Ok(Message::Binary(bytes)) => Some(bytes),
+                    _ => None,
+                })
+            });
+        let mut outgoing = WebSocket::new(schema, incoming, protocol).on_connection_init(
+            move |payload| async move {
+                let access = match payload.get("token").and_then(|token| token.as_str()) {
+                    Some(token) => Access::new(&state, token),
+                    None => access,
+                };
+                let mut data = Data::default();
+                data.insert(state);
+                data.insert(access);
+                Ok(data)
+            },
+        );
+        while let Some(message) = outgoing.next().await {
+            let message = match message {
+                WsMessage::Text(text) => Message::Text(text),
+                WsMessage::Close(code, reason) => Message::Close(Some(CloseFrame {
+                    code: code.into(),
+                    reason: reason.into(),
+                })),
+            };
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+        debug!("GraphQL WebSocket closed");
+    });
+    response
+}
@@ -0,0 +1,551 @@
+//! The gRPC API from `proto/typeto.proto`, for native and backend clients
+//! that would rather not speak the WebSocket protocol, served on a port of
+//! its own from `[grpc]`. `JoinRoom` opens a session on a room, which counts
+//! as one connection, `StreamEvents` streams what the session is sent, and
+//! `SendKeypress` types on it. With `[jwt]` every `JoinRoom` needs a token,
+//! as `authorization: Bearer` metadata.
+
+use futures_util::{stream, Stream};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::broadcast};
+use tonic::{service::Interceptor, transport::server::TcpIncoming, Request, Response, Status};
+use tracing::info;
+
+use crate::{
+    bidi::Direction,
+    claim_participant_id,
+    config::{DuplicatePolicy, GrpcConfig},
+    depart, enter_room, generate_random_string, is_valid_key,
+    locale::LocaleHint,
+    press_key, Connection, Counters, KeyRate, Retransmit, RoomMode, RoomView, ServerMessage,
+    SharedState,
+};
+
+/// Generated from `proto/typeto.proto`, for clients written in Rust.
+#[allow(clippy::large_enum_variant)]
+pub mod proto {
+    tonic::include_proto!("typeto.v1");
+}
+
+use proto::{
+    event::Event as Kind,
+    typeto_server::{Typeto, TypetoServer},
+    Event, JoinRoomReply, JoinRoomRequest, SendKeypressReply, SendKeypressRequest,
+    StreamEventsRequest,
+};
+
+/// How long a session waits for `StreamEvents` before it is closed.
+const UNCLAIMED_TTL: Duration = Duration::from_secs(30);
+
+/// A participant's connection to a room over gRPC.
+struct Session {
+    room_id: String,
+    participant_id: String,
+    connection: Connection,
+    /// What the room has sent so far, until `StreamEvents` takes it.
+    events: Option<broadcast::Receiver<ServerMessage>>,
+    key_rate: KeyRate,
+}
+
+type Sessions = Arc<Mutex<HashMap<String, Session>>>;
+
+struct Service {
+    state: SharedState,
+    sessions: Sessions,
+}
+
+/// Serves the gRPC API on `config.bind` until shutdown.
+pub(crate) async fn run(config: &GrpcConfig, state: SharedState) -> Result<(), String> {
+    let listener = TcpListener::bind(config.bind)
+        .await
+        .map_err(|err| format!("Failed to bind gRPC on {}: {}", config.bind, err))?;
+    info!("Serving gRPC on {}", config.bind);
+    serve(listener, state).await
+}
+
+/// Serves the gRPC API on `listener` until shutdown.
+pub(crate) async fn serve(listener: TcpListener, state: SharedState) -> Result<(), String> {
+    let incoming = TcpIncoming::from_listener(listener, true, None)
+        .map_err(|err| format!("gRPC listener failed: {}", err))?;
+    let shutdown = state.shutdown.clone();
+    let service = Service {
+        state: state.clone(),
+        sessions: Arc::default(),
+    };
+    tonic::transport::Server::builder()
+        .add_service(TypetoServer::with_interceptor(service, Blocklist(state)))
+        .serve_with_incoming_shutdown(incoming, shutdown)
+        .await
+        .map_err(|err| format!("gRPC server failed: {}", err))
+}
+
+/// Refuses calls from addresses in `[blocklist]`, as the HTTP server does.
+#[derive(Clone)]
+struct Blocklist(SharedState);
+
+impl Interceptor for Blocklist {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let blocked = request
+            .remote_addr()
+            .is_some_and(|addr| self.0.config().blocklist.blocks(addr.ip()));
+        if blocked {
+            return Err(Status::permission_denied("Blocked."));
+        }
+        Ok(request)
+    }
+}
+
+impl Service {
+    /// Closes `session`, taking its participant out of the room if it was
+    /// their last connection.
+    fn close(&self, session: &str) {
+        let Some(session) = self.sessions.lock().unwrap().remove(session) else {
+            return;
+        };
+        if let Some(room) = self.state.rooms.get(&session.room_id) {
+            let state = self.state.clone();
+            room.cast(move |room| {
+                depart(
+                    &state,
+                    room,
+                    &session.participant_id,
+                    &session.connection.sender,
+                );
+            });
+        }
+    }
+}
+
+/// Closes the session when its event stream is dropped.
+struct Departure {
+    service: Service,
+    session: String,
+}
+
+impl Drop for Departure {
+    fn drop(&mut self) {
+        self.service.close(&self.session);
+    }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<Event, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Typeto for Service {
+    async fn join_room(
+        &self,
+        request: Request<JoinRoomRequest>,
+    ) -> Result<Response<JoinRoomReply>, Status> {
+        let grant = match &self.state.jwt {
+            Some(jwt) => {
+                let token = request
+                    .metadata()
+                    .get("authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .ok_or_else(|| Status::unauthenticated("Missing token."))?;
+                Some(jwt.verify(token).map_err(Status::unauthenticated)?)
+            }
+            None => None,
+        };
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        let JoinRoomRequest {
+            room_id,
+            participant_id,
+            invite,
+            locale,
+            layout,
+        } = request.into_inner();
+        if room_id.is_empty() {
+            return Err(Status::invalid_argument("Name a room to join."));
+        }
+        if grant.as_ref().is_some_and(|grant| !grant.allows(&room_id)) {
+            return Err(Status::permission_denied(
+                "Your token does not allow joining this room.",
+            ));
+        }
+        let verified_id = grant.as_ref().and_then(|grant| grant.identity.clone());
+        let requested = Some(participant_id).filter(|id| !id.is_empty());
+        let participant_id = claim_participant_id(requested, verified_id.as_deref())
+            .map_err(Status::invalid_argument)?;
+        let locale =
+            LocaleHint::new(Some(locale), Some(layout)).map_err(Status::invalid_argument)?;
+
+        // There is no taking over a session, so a second connection joins only
+        // where connections are mirrored.
+        let config = self.state.config();
+        let policy = if verified_id.is_some() {
+            DuplicatePolicy::Mirror
+        } else {
+            config.rooms.duplicate_connections
+        };
+        if let Some(room) = self.state.rooms.get(&room_id) {
+            let participant = participant_id.clone();
+            let present = room
+                .call(move |room| (room.connected(&participant), room.connections(&participant)))
+                .await;
+            if let Some((connected, connections)) = present {
+                if connected && policy != DuplicatePolicy::Mirror {
+                    return Err(Status::already_exists(
+                        "You're already in this room from somewhere else.",
+                    ));
+                }
+                if connections >= config.rooms.max_devices {
+                    return Err(Status::already_exists(format!(
+                        "You're already in this room on {} devices.",
+                        config.rooms.max_devices
+                    )));
+                }
+            }
+        }
+
+        let (sender, mut events) = broadcast::channel(32);
+        let connection = Connection {
+            sender,
+            traffic: Arc::new(Counters::default()),
+            retransmit: Arc::new(Mutex::new(Retransmit::default())),
+            ip,
+        };
+        let may_create = grant.as_ref().is_none_or(|grant| grant.can_create);
+        if !enter_room(
+            &self.state,
+            &connection,
+            &room_id,
+            &participant_id,
+            may_create,
+            Some(invite.as_str()).filter(|invite| !invite.is_empty()),
+            locale,
+        )
+        .await
+        {
+            let mut refusal = "Could not join the room.".to_string();
+            while let Ok(message) = events.try_recv() {
+                match message {
+                    ServerMessage::Error { message } | ServerMessage::RoomIsCrowded { message } => {
+                        refusal = message
+                    }
+                    ServerMessage::Archived { .. } => {
+                        refusal = "The room has expired and is archived.".to_string()
+                    }
+                    ServerMessage::NotStarted { seconds_left, .. } => {
+                        refusal = format!("The room opens in {} seconds.", seconds_left)
+                    }
+                    _ => {}
+                }
+            }
+            return Err(Status::failed_precondition(refusal));
+        }
+
+        let session = generate_random_string(24);
+        self.sessions.lock().unwrap().insert(
+            session.clone(),
+            Session {
+                room_id,
+                participant_id: participant_id.clone(),
+                connection,
+                events: Some(events),
+                key_rate: KeyRate::new(),
+            },
+        );
+        let unclaimed = Service {
+            state: self.state.clone(),
+            sessions: self.sessions.clone(),
+        };
+        let id = session.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(UNCLAIMED_TTL).await;
+            let waiting = unclaimed
+                .sessions
+                .lock()
+                .unwrap()
+                .get(&id)
+                .is_some_and(|session| session.events.is_some());
+            if waiting {
+                unclaimed.close(&id);
+            }
+        });
+        Ok(Response::new(JoinRoomReply {
+            session,
+            participant_id,
+        }))
+    }
+
+    type StreamEventsStream = EventStream;
+
+    async fn stream_events(
+        &self,
+        request: Request<StreamEventsRequest>,
+    ) -> Result<Response<EventStream>, Status> {
+        let session = request.into_inner().session;
+        let (events, traffic) = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let found = sessions
+                .get_mut(&session)
+                .ok_or_else(|| Status::not_found("No such session."))?;
+            let events = found
+                .events
+                .take()
+                .ok_or_else(|| Status::failed_precondition("The session is already streaming."))?;
+            (events, found.connection.traffic.clone())
+        };
+        let feed = Feed {
+            events,
+            notices: self.state.notices.subscribe(),
+            traffic,
+            ended: false,
+            departure: Departure {
+                service: Service {
+                    state: self.state.clone(),
+                    sessions: self.sessions.clone(),
+                },
+                session,
+            },
+        };
+        let stream = stream::unfold(feed, |mut feed| async move {
+            let event = feed.next().await?;
+            Some((Ok(event), feed))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn send_keypress(
+        &self,
+        request: Request<SendKeypressRequest>,
+    ) -> Result<Response<SendKeypressReply>, Status> {
+        let request = request.into_inner();
+        let (sender, room_id, participant_id) = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions
+                .get_mut(&request.session)
+                .ok_or_else(|| Status::not_found("No such session."))?;
+            let size = prost::Message::encoded_len(&request);
+            session.connection.traffic.received(size);
+            self.state.traffic.received(size);
+            if !is_valid_key(&request.key) {
+                return Err(Status::invalid_argument(
+                    "Keys are a named key or one character.",
+                ));
+            }
+            if !session.key_rate.allow(&self.state.config().limits) {
+                return Err(Status::resource_exhausted(
+                    "You're typing too fast; some keys were dropped.",
+                ));
+            }
+            (
+                session.connection.sender.clone(),
+                session.room_id.clone(),
+                session.participant_id.clone(),
+            )
+        };
+        let typed = press_key(
+            &self.state,
+            &sender,
+            &room_id,
+            &participant_id,
+            &request.key,
+            request.cursor_pos.map(|pos| pos as usize),
+            request.rev,
+        )
+        .await;
+        if !typed {
+            return Err(Status::failed_precondition(
+                "The session is no longer in the room.",
+            ));
+        }
+        Ok(Response::new(SendKeypressReply {}))
+    }
+}
+
+/// A session's events and operator notices, in the order they come.
+struct Feed {
+    events: broadcast::Receiver<ServerMessage>,
+    notices: broadcast::Receiver<ServerMessage>,
+    traffic: Arc<Counters>,
+    /// Set once the session was taken over; nothing more is sent.
+    ended: bool,
+    departure: Departure,
+}
+
+impl Feed {
+    async fn next(&mut self) -> Option<Event> {
+        loop {
+            if self.ended {
+                return None;
+            }
+            let message = tokio::select! {
+                biased;
+                message = self.events.recv() => match message {
+                    Ok(message) => message,
+                    // The room notices and sends a snapshot once the queue
+                    // has drained.
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        self.traffic.dropped(missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+                notice = self.notices.recv() => match notice {
+                    Ok(notice) => notice,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+            };
+            self.ended = matches!(message, ServerMessage::SessionTakenOver { .. });
+            let Some(kind) = event(message) else {
+                continue;
+            };
+            let event = Event { event: Some(kind) };
+            let size = prost::Message::encoded_len(&event);
+            self.traffic.sent(size);
+            self.departure.service.state.traffic.sent(size);
+            return Some(event);
+        }
+    }
+}
+
+/// The event for `message`; replies to requests gRPC has no call for are
+/// left out.
+fn event(message: ServerMessage) -> Option<Kind> {
+    Some(match message {
+        ServerMessage::GotRoom { room } => Kind::Room((*room).into()),
+        ServerMessage::KeyPress {
+            key,
+            source,
+            cursor_pos,
+            rev,
+        } => Kind::KeyPress(proto::KeyPress {
+            key: key.to_string(),
+            source: source.to_string(),
+            cursor_pos: cursor_pos.map(|pos| pos as u64),
+            rev,
+        }),
+        ServerMessage::Committed {
+            r#final,
+            source,
+            rev,
+        } => Kind::Committed(proto::Committed {
+            r#final: r#final.to_string(),
+            source: source.to_string(),
+            rev,
+        }),
+        ServerMessage::Error { message } | ServerMessage::RoomIsCrowded { message } => {
+            Kind::Error(proto::Message { message })
+        }
+        ServerMessage::ServerNotice { message } | ServerMessage::SessionTakenOver { message } => {
+            Kind::Notice(proto::Message { message })
+        }
+        ServerMessage::Degraded { reason } => Kind::Notice(proto::Message {
+            message: reason.message().to_string(),
+        }),
+        ServerMessage::ServerShutdown { .. } => Kind::Notice(proto::Message {
+            message: "The server is restarting.".to_string(),
+        }),
+        ServerMessage::Timer { .. } => Kind::Notice(proto::Message {
+            message: "The owner started a timer.".to_string(),
+        }),
+        ServerMessage::TimerEnded { locked } => Kind::Notice(proto::Message {
+            message: if locked {
+                "Time's up; typing is locked.".to_string()
+            } else {
+                "The timer ended.".to_string()
+            },
+        }),
+        ServerMessage::Turn { participant, .. } => Kind::Notice(proto::Message {
+            message: format!("It's {}'s turn.", participant),
+        }),
+        ServerMessage::Race { race } => Kind::Notice(proto::Message {
+            message: format!("A typing race started; type: {}", race.text()),
+        }),
+        ServerMessage::RaceEnded { standings } => Kind::Notice(proto::Message {
+            message: match standings.first() {
+                Some(winner) if winner.place().is_some() => {
+                    format!("The race is over; {} won.", winner.participant())
+                }
+                _ => "The race is over.".to_string(),
+            },
+        }),
+        ServerMessage::MoveTo { url, .. } => Kind::Notice(proto::Message {
+            message: format!("The room moved to {}.", url),
+        }),
+        ServerMessage::CreatorToken { room, token } => {
+            Kind::CreatorToken(proto::CreatorToken { room, token })
+        }
+        ServerMessage::Mention { room, source, line } => {
+            Kind::Mention(proto::Mention { room, source, line })
+        }
+        ServerMessage::Prefs { .. }
+        | ServerMessage::Challenge { .. }
+        | ServerMessage::Authenticated { .. }
+        | ServerMessage::HelloAck { .. }
+        | ServerMessage::ResumeSecret { .. }
+        | ServerMessage::SearchResults { .. }
+        | ServerMessage::Typing { .. }
+        | ServerMessage::KeySound { .. }
+        | ServerMessage::Expiring { .. }
+        | ServerMessage::Archived { .. }
+        | ServerMessage::Revived { .. }
+        | ServerMessage::NotStarted { .. }
+        | ServerMessage::RaceProgress { .. } => return None,
+    })
+}
+
+impl From<RoomView> for proto::Room {
+    fn from(room: RoomView) -> Self {
+        Self {
+            id: room.id,
+            messages: room
+                .messages
+                .into_iter()
+                .map(|(participant, lines)| {
+                    let directions = room
+                        .directions
+                        .get(&participant)
+                        .into_iter()
+                        .flatten()
+                        .map(|direction| {
+                            match direction {
+                                Direction::Ltr => "ltr",
+                                Direction::Rtl => "rtl",
+                                Direction::Auto => "auto",
+                            }
+                            .to_string()
+                        })
+                        .collect();
+                    (participant, proto::Lines { lines, directions })
+                })
+                .collect(),
+            participants: room.participants as u64,
+            your_id: room.your_id,
+            other_participant_ids: room.other_participant_ids,
+            owner_id: room.owner_id,
+            topic: room.settings.topic,
+            prompt: room.settings.prompt,
+            mode: match room.settings.mode {
+                RoomMode::Live => "live",
+                RoomMode::Line => "line",
+                RoomMode::Turns => "turns",
+            }
+            .to_string(),
+            colors: room.colors.into_iter().collect(),
+            your_rev: room.your_rev,
+            shortcode: room.shortcode,
+            locales: room
+                .locales
+                .into_iter()
+                .map(|(participant, hint)| {
+                    let hint = proto::LocaleHint {
+                        locale: hint.locale,
+                        layout: hint.layout,
+                        rtl: hint.rtl,
+                    };
+                    (participant, hint)
+                })
+                .collect(),
+        }
+    }
+}
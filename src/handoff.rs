@@ -0,0 +1,163 @@
+//! Moving live rooms from one instance to another, for a blue/green deploy
+//! that doesn't end long-lived rooms. `POST /admin/handoff/export` on the old
+//! instance returns the rooms asked for as a snapshot saves them, unfinished
+//! lines included; with `moveTo`, the URL of the new instance's WebSocket,
+//! the rooms are also taken out of the old instance and everyone in them is
+//! sent `moveTo` and closed, to reconnect there once their `reconnect` hint
+//! says. What the export returned goes as it is to `POST
+//! /admin/handoff/import` on the new instance, which opens the rooms.
+
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::{
+    actor::finished_lines,
+    audit::AuditEvent,
+    embed::valid_room_id,
+    json_response,
+    reconnect::{self, ReconnectHint},
+    snapshot::SavedRoom,
+    Room, ServerMessage, SharedState,
+};
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportRequest {
+    /// Ids of the rooms to export.
+    rooms: Vec<String>,
+    /// Where their participants should reconnect, as `ws://` or `wss://`;
+    /// unset to copy the rooms and leave them running here.
+    move_to: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, ToSchema)]
+#[serde(default)]
+pub struct Handoff {
+    /// The rooms, as `[snapshot]` saves them.
+    #[schema(value_type = Vec<Object>)]
+    rooms: Vec<SavedRoom>,
+    /// Ids asked for that aren't in memory here; ignored by the import.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    missing: Vec<String>,
+}
+
+/// Handles `POST /admin/handoff/export`.
+pub async fn export(state: &SharedState, request: ExportRequest) -> Response<Body> {
+    if let Some(url) = &request.move_to {
+        if !url.starts_with("ws://") && !url.starts_with("wss://") {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({"error": "moveTo must be a ws:// or wss:// URL."}),
+            );
+        }
+    }
+    let mut handoff = Handoff::default();
+    for id in request.rooms {
+        let saved = match &request.move_to {
+            Some(url) => state.rooms.close(&id).await.map(|mut room| {
+                let saved = SavedRoom::new(&room);
+                move_participants(state, &mut room, url);
+                saved
+            }),
+            None => match state.rooms.get(&id) {
+                Some(handle) => handle.call(|room| SavedRoom::new(room)).await,
+                None => None,
+            },
+        };
+        match saved {
+            Some(saved) => handoff.rooms.push(saved),
+            None => handoff.missing.push(id),
+        }
+    }
+    let ids: Vec<&str> = handoff.rooms.iter().map(SavedRoom::id).collect();
+    info!(
+        "Exported room(s) {:?}{}",
+        ids,
+        request
+            .move_to
+            .as_ref()
+            .map_or_else(String::new, |url| format!(", moving them to {}", url))
+    );
+    state.audit.record(AuditEvent::Admin {
+        action: "handoffExport".to_string(),
+        detail: Some(ids.join(" ")),
+    });
+    json_response(StatusCode::OK, json!(handoff))
+}
+
+/// Saves what `room` has that the store doesn't yet, then tells everyone in
+/// it to reconnect to `url`, which closes their connections.
+fn move_participants(state: &SharedState, room: &mut Room, url: &str) {
+    state.store_writer.save(room.record());
+    state
+        .store_writer
+        .append_history(room.id.clone(), room.take_history());
+    let config = state.config();
+    for participant in &room.participants {
+        let resume_token = reconnect::resume_token(&room.id, &participant.id);
+        let _ = participant.sender.send(ServerMessage::MoveTo {
+            url: url.to_string(),
+            reconnect: ReconnectHint::new(&config.reconnect, 0, Some(resume_token)),
+        });
+    }
+}
+
+/// Handles `POST /admin/handoff/import`. A room already in memory here is
+/// left as it is and reported as a conflict.
+pub async fn import(state: &SharedState, handoff: Handoff) -> Response<Body> {
+    let mut imported = Vec::new();
+    let mut conflicts = Vec::new();
+    for saved in handoff.rooms {
+        let id = saved.id().to_string();
+        if !valid_room_id(&id) {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({"error": format!("{:?} is not a room id.", id)}),
+            );
+        }
+        // With a store of its own, this instance hasn't seen the lines.
+        let stored = match state.store.load_room(&id).await {
+            Ok(stored) => stored.is_some(),
+            Err(err) => {
+                error!("Failed to load room {}: {}", id, err);
+                return json_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    json!({"error": "Storage unavailable."}),
+                );
+            }
+        };
+        let mut room = saved.into_room();
+        room.configure(&state.config());
+        let history = if stored {
+            Vec::new()
+        } else {
+            finished_lines(&room)
+        };
+        let opened = state.rooms.open(room, {
+            let state = state.clone();
+            move |room| {
+                state.store_writer.save(room.record());
+                if !history.is_empty() {
+                    state.store_writer.append_history(room.id.clone(), history);
+                }
+            }
+        });
+        if opened {
+            imported.push(id);
+        } else {
+            conflicts.push(id);
+        }
+    }
+    info!("Imported room(s) {:?}", imported);
+    state.audit.record(AuditEvent::Admin {
+        action: "handoffImport".to_string(),
+        detail: Some(imported.join(" ")),
+    });
+    json_response(
+        StatusCode::OK,
+        json!({"imported": imported, "conflicts": conflicts}),
+    )
+}
@@ -0,0 +1,127 @@
+//! Probes for load balancers and orchestrators, open without credentials.
+//! `/healthz` says the process is up and answering. `/readyz` says whether
+//! it should be sent more clients, with the figures behind the answer: it is
+//! unready, with a 503, while storage doesn't answer, while every connection
+//! `[limits] max_connections` allows is taken, and once shutdown has begun.
+
+use std::time::{Duration, SystemTime};
+
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{config::StorageConfig, expiry, json_response, SharedState};
+
+/// How long storage has to answer before it is reported down.
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Readiness {
+    ready: bool,
+    storage: StorageStatus,
+    connections: Capacity,
+    /// Rooms in memory.
+    rooms: usize,
+    gc: GcBacklog,
+    /// New rooms are refused, as `POST /admin/maintenance` started.
+    maintenance: bool,
+    /// Shutdown has begun and connections are draining.
+    draining: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct StorageStatus {
+    /// `memory`, `sled` or `postgres`.
+    backend: &'static str,
+    ok: bool,
+    /// Why storage is down.
+    error: Option<String>,
+    /// Writes queued for the backend.
+    pending_writes: usize,
+}
+
+/// Open WebSocket connections and how many are allowed.
+#[derive(Serialize, ToSchema)]
+struct Capacity {
+    active: usize,
+    /// Unlimited if null.
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct GcBacklog {
+    /// Rooms in memory past their age or idle time, for the next sweep.
+    expired_rooms: usize,
+    /// Archived rooms past `archive_grace_secs`, for the next sweep; null
+    /// while storage is down.
+    archived_rooms_due: Option<usize>,
+}
+
+/// Handles `/healthz`, and `/health` as before.
+pub fn liveness() -> Response<Body> {
+    json_response(StatusCode::OK, json!({"status": "ok"}))
+}
+
+/// Handles `/readyz`.
+pub async fn readiness(state: &SharedState) -> Response<Body> {
+    let config = state.config();
+    let now = SystemTime::now();
+    let due = archived_due(state, now).await;
+    let rooms = state.rooms.published();
+    let active_rooms = rooms.len();
+    let expired_rooms = rooms.iter().filter(|room| room.is_expired(now)).count();
+    let sockets = state.connections.open();
+    let limit = config.limits.max_connections;
+    let draining = state.shutdown.peek().is_some();
+
+    let readiness = Readiness {
+        ready: due.is_ok() && limit.is_none_or(|limit| sockets < limit) && !draining,
+        storage: StorageStatus {
+            backend: backend(&config.storage),
+            ok: due.is_ok(),
+            error: due.as_ref().err().cloned(),
+            pending_writes: state.store_writer.pending(),
+        },
+        connections: Capacity {
+            active: sockets,
+            limit,
+        },
+        rooms: active_rooms,
+        gc: GcBacklog {
+            expired_rooms,
+            archived_rooms_due: due.ok(),
+        },
+        maintenance: state.maintenance_message().is_some(),
+        draining,
+    };
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    json_response(status, json!(readiness))
+}
+
+/// Archived rooms past `archive_grace_secs`, or why storage didn't say.
+/// Listing what the sweep will purge doubles as the storage check.
+pub async fn archived_due(state: &SharedState, now: SystemTime) -> Result<usize, String> {
+    let grace = state.config().rooms.archive_grace_secs;
+    let before = expiry::unix_secs(now).saturating_sub(grace);
+    match tokio::time::timeout(STORAGE_TIMEOUT, state.store.archived_before(before)).await {
+        Ok(Ok(ids)) => Ok(ids.len()),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Err("Timed out.".to_string()),
+    }
+}
+
+pub fn backend(config: &StorageConfig) -> &'static str {
+    match config {
+        StorageConfig::Memory => "memory",
+        StorageConfig::Sled { .. } => "sled",
+        StorageConfig::Postgres(_) => "postgres",
+    }
+}
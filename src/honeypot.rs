@@ -0,0 +1,42 @@
+//! Decoy rooms, from `[honeypot] rooms`: ids that aren't given to anyone, so
+//! whoever fetches one is guessing, as scanners do. The address is flagged
+//! for `flag_secs`, which the tarpit treats as a head start on strikes, and
+//! with `ban` it is banned outright as well.
+
+use std::{net::IpAddr, time::Duration};
+
+use crate::{
+    audit::AuditEvent,
+    config::Config,
+    security::{self, Event},
+    tarpit, SharedState,
+};
+
+pub fn is_decoy(config: &Config, id: &str) -> bool {
+    config.honeypot.rooms.iter().any(|room| room == id)
+}
+
+/// Records `ip` fetching decoy room `room`. Returns whether the connection
+/// should be closed.
+pub fn touched(state: &SharedState, ip: Option<IpAddr>, room: &str) -> bool {
+    let config = state.config();
+    security::log(
+        ip,
+        Event::Honeypot {
+            room: room.to_string(),
+        },
+    );
+    state.audit.record(AuditEvent::HoneypotTouched {
+        room: room.to_string(),
+        ip,
+    });
+    if let Some(ip) = ip {
+        state
+            .bans
+            .flag(ip, Duration::from_secs(config.honeypot.flag_secs));
+    }
+    if config.honeypot.ban {
+        tarpit::ban(state, ip, "honeypot");
+    }
+    config.honeypot.ban
+}
@@ -0,0 +1,70 @@
+use hyper::{client::HttpConnector, Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use serde::de::DeserializeOwned;
+
+/// Client for outbound calls to identity providers and other integrations.
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+pub fn new() -> HttpClient {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+/// Sends `request` and decodes a JSON response, treating any non-2xx status
+/// as an error.
+pub async fn fetch_json<T: DeserializeOwned>(
+    client: &HttpClient,
+    request: Request<Body>,
+) -> Result<T, String> {
+    let uri = request.uri().clone();
+    let response = client
+        .request(request)
+        .await
+        .map_err(|err| format!("Request to {} failed: {}", uri, err))?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|err| format!("Reading response from {} failed: {}", uri, err))?;
+    if !status.is_success() {
+        return Err(format!("{} returned {}", uri, status));
+    }
+    serde_json::from_slice(&body).map_err(|err| format!("Invalid JSON from {}: {}", uri, err))
+}
+
+pub async fn get_json<T: DeserializeOwned>(
+    client: &HttpClient,
+    url: &str,
+    bearer: Option<&str>,
+) -> Result<T, String> {
+    let mut builder = Request::builder().method(Method::GET).uri(url);
+    if let Some(token) = bearer {
+        builder = builder.header("authorization", format!("Bearer {}", token));
+    }
+    let request = builder
+        .header("accept", "application/json")
+        .body(Body::empty())
+        .map_err(|err| err.to_string())?;
+    fetch_json(client, request).await
+}
+
+pub async fn post_form<T: DeserializeOwned>(
+    client: &HttpClient,
+    url: &str,
+    fields: &[(&str, &str)],
+) -> Result<T, String> {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(fields)
+        .finish();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("content-type", "application/x-www-form-urlencoded")
+        .header("accept", "application/json")
+        .body(Body::from(body))
+        .map_err(|err| err.to_string())?;
+    fetch_json(client, request).await
+}
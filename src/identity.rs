@@ -0,0 +1,72 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Participant ids derived from a public key carry this prefix. Random
+/// socketIds are alphanumeric, so they can never collide with one.
+pub const IDENTITY_PREFIX: &str = "k-";
+/// Prefix for ids mapped from a subject asserted by an external issuer (an
+/// OpenID Connect provider or a JWT signer).
+pub const SUBJECT_PREFIX: &str = "o-";
+
+/// Prepended to the challenge before signing so a signature made for typeto
+/// can't be replayed as anything else.
+const SIGNING_CONTEXT: &str = "typeto-auth:";
+
+pub fn new_challenge() -> String {
+    let mut nonce = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    URL_SAFE_NO_PAD.encode(nonce)
+}
+
+pub fn is_identity(id: &str) -> bool {
+    id.starts_with(IDENTITY_PREFIX) || id.starts_with(SUBJECT_PREFIX)
+}
+
+/// Checks that `signature` is an Ed25519 signature over the challenge made
+/// with `public_key` (both base64url), and returns the participant id the
+/// key maps to.
+pub fn verify(challenge: &str, public_key: &str, signature: &str) -> Result<String, String> {
+    let key_bytes: [u8; 32] = URL_SAFE_NO_PAD
+        .decode(public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| "Malformed public key.".to_string())?;
+    let signature_bytes: [u8; 64] = URL_SAFE_NO_PAD
+        .decode(signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| "Malformed signature.".to_string())?;
+
+    let key =
+        VerifyingKey::from_bytes(&key_bytes).map_err(|_| "Invalid public key.".to_string())?;
+    let message = format!("{}{}", SIGNING_CONTEXT, challenge);
+    key.verify(message.as_bytes(), &Signature::from_bytes(&signature_bytes))
+        .map_err(|_| "Signature verification failed.".to_string())?;
+
+    Ok(identity_for(&key_bytes))
+}
+
+/// Stable participant id for a subject at an external issuer.
+pub fn for_subject(issuer: &str, subject: &str) -> String {
+    let digest = Sha256::digest(format!("{}\0{}", issuer, subject));
+    format!("{}{}", SUBJECT_PREFIX, short_hex(&digest))
+}
+
+/// Hex SHA-256 of a bearer secret, so only the digest needs to be stored.
+pub fn token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn identity_for(key_bytes: &[u8; 32]) -> String {
+    let digest = Sha256::digest(key_bytes);
+    format!("{}{}", IDENTITY_PREFIX, short_hex(&digest))
+}
+
+fn short_hex(digest: &[u8]) -> String {
+    digest[..10].iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,306 @@
+//! Creating a room from an exported conversation, for demos, bug reports
+//! and carrying an old conversation over to a new server. `POST /api/import`
+//! takes a transcript as `[archive]` uploads it, or an op log as GraphQL's
+//! `transcript(id)` returns it, and creates a room holding its lines; with
+//! `?replay=<characters per second>` the room starts empty and the lines are
+//! typed into it at that speed instead, for whoever joins to watch.
+
+use std::{collections::BTreeMap, net::IpAddr, time::Duration};
+
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::{
+    audit::AuditEvent, generate_random_string, invite, is_valid_key, json_response,
+    locale::LocaleHint, sanitize, shortlink, storage::HistoryLine, Room, SharedState, SYSTEM_ID,
+};
+
+/// Enough for a room's worth of history and then some.
+const MAX_IMPORT_BYTES: u64 = 1024 * 1024;
+/// The fastest replay, in characters per second.
+const MAX_REPLAY_SPEED: u32 = 1000;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Upload {
+    /// Finished lines in the order they were finished.
+    Log(Vec<HistoryLine>),
+    /// Each participant's lines, as an archived transcript has them.
+    Transcript {
+        messages: BTreeMap<String, Vec<String>>,
+        #[serde(default)]
+        colors: BTreeMap<String, String>,
+        #[serde(default)]
+        locales: BTreeMap<String, LocaleHint>,
+    },
+}
+
+impl Upload {
+    /// The lines in the order to replay them, with the colors and language
+    /// hints that go with them. A transcript doesn't say how participants'
+    /// lines interleaved, so they take turns.
+    fn into_parts(
+        self,
+    ) -> (
+        Vec<HistoryLine>,
+        BTreeMap<String, String>,
+        BTreeMap<String, LocaleHint>,
+    ) {
+        match self {
+            Self::Log(lines) => (lines, BTreeMap::new(), BTreeMap::new()),
+            Self::Transcript {
+                messages,
+                colors,
+                locales,
+            } => {
+                let longest = messages.values().map(Vec::len).max().unwrap_or_default();
+                let lines = (0..longest)
+                    .flat_map(|i| {
+                        messages.iter().filter_map(move |(participant, lines)| {
+                            Some(HistoryLine {
+                                participant: participant.clone(),
+                                text: lines.get(i)?.clone(),
+                            })
+                        })
+                    })
+                    .collect();
+                (lines, colors, locales)
+            }
+        }
+    }
+}
+
+/// Handles `POST /api/import`, which creates rooms as `[rooms] creation`
+/// allows, taking an invitation as `?invite=`, or with the admin token.
+pub async fn handle(req: Request<Body>, state: &SharedState, ip: Option<IpAddr>) -> Response<Body> {
+    let is_admin = state
+        .admin
+        .as_ref()
+        .is_some_and(|admin| admin.authorized(&req));
+    let query = |name: &str| {
+        req.uri().query().and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        })
+    };
+    let invitation = query("invite");
+    let replay = match query("replay").map(|speed| speed.parse::<u32>()) {
+        None => None,
+        Some(Ok(speed)) if (1..=MAX_REPLAY_SPEED).contains(&speed) => Some(speed),
+        Some(_) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({"error": format!(
+                    "replay is 1 to {} characters per second.",
+                    MAX_REPLAY_SPEED
+                )}),
+            );
+        }
+    };
+    if let Some(message) = state.maintenance_message() {
+        return json_response(StatusCode::SERVICE_UNAVAILABLE, json!({"error": message}));
+    }
+
+    let too_large = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_none_or(|len| len > MAX_IMPORT_BYTES);
+    if too_large {
+        return json_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            json!({"error": "Request body missing or too large."}),
+        );
+    }
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => {
+            return json_response(StatusCode::BAD_REQUEST, json!({"error": "Bad body."}));
+        }
+    };
+    let upload: Upload = match serde_json::from_slice(&body) {
+        Ok(upload) => upload,
+        Err(_) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({"error": "Expected a transcript or a list of lines."}),
+            );
+        }
+    };
+    let (mut lines, colors, locales) = upload.into_parts();
+    let text = state.config().text;
+    for line in &mut lines {
+        line.text = sanitize::clean(&line.text, &text);
+    }
+    if lines.is_empty() {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({"error": "There are no lines to import."}),
+        );
+    }
+    if lines.iter().any(|line| line.participant.is_empty()) {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({"error": "Every line needs a participant."}),
+        );
+    }
+
+    // Checked last, so a bad upload doesn't use up an invitation.
+    if !is_admin {
+        if let Err(message) = invite::admit(state, invitation.as_deref()) {
+            return json_response(StatusCode::FORBIDDEN, json!({"error": message}));
+        }
+    }
+
+    let id = generate_random_string(6);
+    let stored = match state.store.load_room(&id).await {
+        Ok(stored) => stored.is_some(),
+        Err(err) => {
+            error!("Failed to load room {}: {}", id, err);
+            return json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": "Storage unavailable."}),
+            );
+        }
+    };
+    let shortcode = shortlink::fresh(state).await;
+    let mut room = Room::new(id.clone());
+    let token = room.issue_creator_token();
+    room.shortcode = Some(shortcode.clone());
+    room.colors = colors;
+    room.locales = locales;
+    if replay.is_none() {
+        room = Room::from_record(room.record(), lines.clone());
+    }
+    room.configure(&state.config());
+    let history = if replay.is_none() {
+        lines.clone()
+    } else {
+        Vec::new()
+    };
+    let opened = !stored
+        && state.rooms.open(room, {
+            let state = state.clone();
+            move |room| {
+                state.store_writer.save(room.record());
+                if !history.is_empty() {
+                    state.store_writer.append_history(room.id.clone(), history);
+                }
+            }
+        });
+    if !opened {
+        return json_response(
+            StatusCode::CONFLICT,
+            json!({"error": "A room with this id already exists; try again."}),
+        );
+    }
+    info!("Room {} imported with {} line(s)", id, lines.len());
+    state.audit.record(AuditEvent::RoomImported {
+        room: id.clone(),
+        lines: lines.len(),
+        ip,
+    });
+    if let Some(speed) = replay {
+        let per_key = Duration::from_secs(1) / speed;
+        tokio::spawn(replay_lines(state.clone(), id.clone(), lines, per_key));
+    }
+    json_response(
+        StatusCode::OK,
+        json!({"room": id, "shortcode": shortcode, "creatorToken": token}),
+    )
+}
+
+/// Types `lines` into room `id`, a key every `per_key`, until they are done
+/// or the room is gone.
+async fn replay_lines(state: SharedState, id: String, lines: Vec<HistoryLine>, per_key: Duration) {
+    for line in lines {
+        let participant = line.participant;
+        if participant == SYSTEM_ID {
+            let said = update(&state, &id, move |room| {
+                room.system_line(line.text);
+                room.notify_participants();
+            });
+            if !said.await {
+                return;
+            }
+            continue;
+        }
+        let started = update(&state, &id, {
+            let participant = participant.clone();
+            move |room| {
+                room.messages
+                    .entry(participant.clone())
+                    .or_insert_with(|| vec![String::new()]);
+                if !room.replaying.contains(&participant) {
+                    room.replaying.push(participant);
+                    room.notify_participants();
+                }
+            }
+        });
+        if !started.await {
+            return;
+        }
+        let mut pos = 0;
+        for ch in line.text.chars() {
+            let key = if ch == ' ' {
+                "Space".to_string()
+            } else {
+                ch.to_string()
+            };
+            // Typed as a client could have typed it.
+            if !is_valid_key(&key) {
+                continue;
+            }
+            tokio::time::sleep(per_key).await;
+            let participant = participant.clone();
+            let typed = update(&state, &id, move |room| {
+                room.handle_keypress(&participant, &key, Some(pos))
+            });
+            if !typed.await {
+                return;
+            }
+            pos += 1;
+        }
+        tokio::time::sleep(per_key).await;
+        let finished = update(&state, &id, move |room| {
+            room.handle_keypress(&participant, "Enter", None)
+        });
+        if !finished.await {
+            return;
+        }
+    }
+    update(&state, &id, |room| {
+        room.replaying.clear();
+        room.notify_participants();
+    })
+    .await;
+    info!("Finished replaying into room {}", id);
+}
+
+/// Applies `change` to room `id` and sends and stores what it did. Returns
+/// false if the room is gone.
+async fn update(
+    state: &SharedState,
+    id: &str,
+    change: impl FnOnce(&mut Room) + Send + 'static,
+) -> bool {
+    let Some(room) = state.rooms.get(id) else {
+        return false;
+    };
+    let state = state.clone();
+    room.call(move |room| {
+        change(room);
+        state.governor.schedule(room, &state.config().limits);
+        state
+            .store_writer
+            .append_history(room.id.clone(), room.take_history());
+        // Replayed lines don't mention anyone anew.
+        room.take_mentions();
+    })
+    .await
+    .is_some()
+}
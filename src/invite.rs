@@ -0,0 +1,58 @@
+//! Who may create rooms, under `[rooms] creation`, and the invitations that
+//! let someone create one when creation is by invitation. Invitations are
+//! kept in memory, by digest, and don't survive a restart.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config::CreationPolicy, generate_random_string, identity, SharedState};
+
+/// How long an invitation lasts unless the operator says otherwise.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+
+#[derive(Default)]
+pub struct Invites {
+    /// Expiry of each unused invitation, by token digest.
+    pending: Mutex<HashMap<String, Instant>>,
+}
+
+impl Invites {
+    /// A new invitation, good for one room until `ttl` has passed.
+    pub fn issue(&self, ttl: Duration) -> String {
+        let token = generate_random_string(24);
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, expiry| *expiry > now);
+        pending.insert(identity::token_hash(&token), now + ttl);
+        token
+    }
+
+    /// Uses up `token`; false if it was never issued, has expired, or was
+    /// used already.
+    fn redeem(&self, token: &str) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&identity::token_hash(token))
+            .is_some_and(|expiry| expiry > Instant::now())
+    }
+}
+
+/// Whether a connection holding `invite` may create a room now, using the
+/// invitation up if creating takes one. The error is for the client.
+pub fn admit(state: &SharedState, invite: Option<&str>) -> Result<(), String> {
+    match state.config().rooms.creation {
+        CreationPolicy::Open => Ok(()),
+        CreationPolicy::Invite => match invite {
+            Some(token) if state.invites.redeem(token) => Ok(()),
+            Some(_) => Err("That invitation has expired or was already used.".to_string()),
+            None => Err("Creating a room on this server takes an invitation.".to_string()),
+        },
+        CreationPolicy::Admin => Err("Rooms on this server are created by its operators; \
+             check the link you were given."
+            .to_string()),
+    }
+}
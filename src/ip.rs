@@ -0,0 +1,89 @@
+use hyper::{Body, Request};
+use std::net::IpAddr;
+
+/// A single address or a CIDR block such as `198.51.100.0/24`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value.as_str(), None),
+        };
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid IP address: {}", value))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("Invalid prefix length: {}", value))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                masked(u32::from(network).into(), 32, self.prefix)
+                    == masked(u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                masked(network.into(), 128, self.prefix) == masked(ip.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn masked(bits: u128, width: u32, prefix: u32) -> u128 {
+    match width - prefix {
+        0 => bits,
+        host_bits if host_bits >= 128 => 0,
+        host_bits => bits >> host_bits,
+    }
+}
+
+/// IPv4 clients reaching a dual-stack socket show up as `::ffff:a.b.c.d`.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+/// The address a request came from. Connections over a Unix socket or from
+/// loopback are assumed to come through a local reverse proxy, whose
+/// `X-Forwarded-For` (last hop) or `X-Real-IP` is used instead.
+pub fn client_ip(req: &Request<Body>, peer: Option<IpAddr>) -> Option<IpAddr> {
+    if peer.is_some_and(|ip| !canonical(ip).is_loopback()) {
+        return peer.map(canonical);
+    }
+    forwarded(req).or(peer)
+}
+
+/// The address a proxy in front says the request came from.
+pub fn forwarded(req: &Request<Body>) -> Option<IpAddr> {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    header("x-forwarded-for")
+        .and_then(|value| value.rsplit(',').next())
+        .or_else(|| header("x-real-ip"))
+        .and_then(|value| value.trim().parse().ok())
+        .map(canonical)
+}
@@ -0,0 +1,141 @@
+use hyper::{header, Body, Request};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::{config::JwtConfig, identity};
+
+/// Claims typeto understands; anything else in the token is ignored.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    /// Room ids the bearer may join. A trailing `*` matches any suffix.
+    /// Absent means any room.
+    rooms: Option<Vec<String>>,
+    /// Whether the bearer may bring new rooms into existence.
+    #[serde(default)]
+    create: bool,
+}
+
+/// What a verified token allows its bearer to do.
+#[derive(Debug, Clone)]
+pub struct RoomGrant {
+    pub identity: Option<String>,
+    rooms: Option<Vec<String>>,
+    pub can_create: bool,
+}
+
+impl RoomGrant {
+    /// Grant for exactly one room, which the bearer may also create.
+    pub fn for_room(room_id: &str) -> Self {
+        Self {
+            identity: None,
+            rooms: Some(vec![room_id.to_string()]),
+            can_create: true,
+        }
+    }
+
+    pub fn allows(&self, room_id: &str) -> bool {
+        match &self.rooms {
+            None => true,
+            Some(patterns) => patterns
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => room_id.starts_with(prefix),
+                    None => pattern == room_id,
+                }),
+        }
+    }
+}
+
+/// Verifies signed tokens presented on `/ws` upgrades and gRPC calls, for
+/// deployments that embed typeto behind another product's login.
+pub struct JwtGate {
+    issuer_name: String,
+    keys: Vec<(Algorithm, DecodingKey)>,
+    validation: Validation,
+}
+
+impl JwtGate {
+    pub fn new(config: &JwtConfig) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        if let Some(secret) = &config.hs256_secret {
+            keys.push((
+                Algorithm::HS256,
+                DecodingKey::from_secret(secret.as_bytes()),
+            ));
+        }
+        if let Some(path) = &config.rs256_public_key_file {
+            let pem = std::fs::read(path)
+                .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+            let key = DecodingKey::from_rsa_pem(&pem)
+                .map_err(|err| format!("Invalid RSA key {}: {}", path.display(), err))?;
+            keys.push((Algorithm::RS256, key));
+        }
+        if keys.is_empty() {
+            return Err("[jwt] needs hs256_secret or rs256_public_key_file".to_string());
+        }
+
+        let mut validation = Validation::new(keys[0].0);
+        validation.algorithms = keys.iter().map(|(alg, _)| *alg).collect();
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        Ok(Self {
+            issuer_name: config.issuer.clone().unwrap_or_else(|| "jwt".to_string()),
+            keys,
+            validation,
+        })
+    }
+
+    /// Validates the token from the `token` query parameter or the
+    /// `Authorization: Bearer` header.
+    pub fn check(&self, req: &Request<Body>) -> Result<RoomGrant, String> {
+        let token = token_from(req).ok_or_else(|| "Missing token.".to_string())?;
+        self.verify(&token)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<RoomGrant, String> {
+        let alg = decode_header(token)
+            .map_err(|err| format!("Malformed token: {}", err))?
+            .alg;
+        let (_, key) = self
+            .keys
+            .iter()
+            .find(|(key_alg, _)| *key_alg == alg)
+            .ok_or_else(|| format!("Unsupported token algorithm {:?}.", alg))?;
+        let claims = decode::<Claims>(token, key, &self.validation)
+            .map_err(|err| format!("Invalid token: {}", err))?
+            .claims;
+
+        Ok(RoomGrant {
+            identity: claims
+                .sub
+                .map(|sub| identity::for_subject(&self.issuer_name, &sub)),
+            rooms: claims.rooms,
+            can_create: claims.create,
+        })
+    }
+}
+
+/// The token from `?token=` or `Authorization: Bearer`, if either is there.
+pub fn token_from(req: &Request<Body>) -> Option<String> {
+    let from_query = req.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .collect::<HashMap<_, _>>()
+            .remove("token")
+            .map(|token| token.into_owned())
+    });
+    from_query.or_else(|| {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string)
+    })
+}
@@ -0,0 +1,83 @@
+//! Interview mode: with `recordKeystrokes` on, a room keeps every key press
+//! with its time, for typing tests, teaching and user research, where how a
+//! line was typed matters as much as the line. Everyone in the room is told
+//! when recording starts and stops. The log is kept in memory with the room
+//! and only its creator can export it, as JSON or CSV.
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Key presses one room keeps at most; later ones aren't recorded.
+const MAX_KEYSTROKES: usize = 200_000;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Keystroke {
+    /// Unix milliseconds.
+    at_ms: u64,
+    participant: String,
+    key: String,
+    cursor_pos: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct KeystrokeLog {
+    keystrokes: Vec<Keystroke>,
+    /// Whether the room stopped recording at its limit.
+    truncated: bool,
+}
+
+impl KeystrokeLog {
+    pub fn record(&mut self, at_ms: u64, participant: &str, key: &str, cursor_pos: Option<usize>) {
+        if self.keystrokes.len() >= MAX_KEYSTROKES {
+            self.truncated = true;
+            return;
+        }
+        self.keystrokes.push(Keystroke {
+            at_ms,
+            participant: participant.to_string(),
+            key: key.to_string(),
+            cursor_pos,
+        });
+    }
+
+    /// Each key press, oldest first: when, by whom, the key and the cursor
+    /// position.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &str, &str, Option<usize>)> {
+        self.keystrokes.iter().map(|keystroke| {
+            (
+                keystroke.at_ms,
+                keystroke.participant.as_str(),
+                keystroke.key.as_str(),
+                keystroke.cursor_pos,
+            )
+        })
+    }
+
+    /// The log as CSV with a header line, one key press a line.
+    pub fn csv(&self) -> String {
+        let mut csv = String::from("at_ms,participant,key,cursor_pos\r\n");
+        for keystroke in &self.keystrokes {
+            csv.push_str(&format!(
+                "{},{},{},{}\r\n",
+                keystroke.at_ms,
+                field(&keystroke.participant),
+                field(&keystroke.key),
+                keystroke
+                    .cursor_pos
+                    .map(|pos| pos.to_string())
+                    .unwrap_or_default(),
+            ));
+        }
+        csv
+    }
+}
+
+/// `value` as a CSV field, quoted if it has to be.
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,466 @@
+//! `--lan`, or `[lan]`: finding other instances on the local network with
+//! no internet and no URL to pass around. The server advertises itself over
+//! mDNS as a `_typeto._tcp` DNS-SD service, answering queries and announcing
+//! itself every half minute, and keeps track of the instances it hears from
+//! for `GET /api/lan-peers`. Only what that needs of DNS is spoken: PTR,
+//! SRV, TXT and A records over IPv4 multicast.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{config::LanConfig, generate_random_string, json_response, SharedState};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_typeto._tcp.local";
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long our records are good for, and how long a peer is listed after it
+/// was last heard from.
+const TTL_SECS: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on the class of records that replace what was cached for the name.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// This instance as advertised, and the others it has heard from.
+pub struct Lan {
+    name: String,
+    /// Told apart from the others by this, in its TXT record, rather than by
+    /// a name two laptops may share.
+    id: String,
+    peers: Mutex<HashMap<String, Peer>>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Peer {
+    /// The instance's name, usually its host's.
+    name: String,
+    /// Where its GUI is, e.g. `http://192.168.1.20:8090`.
+    url: String,
+    #[serde(skip)]
+    seen: Instant,
+}
+
+impl Lan {
+    pub fn new(config: &LanConfig) -> Self {
+        let name = config
+            .name
+            .clone()
+            .or_else(hostname)
+            .unwrap_or_else(|| "typeto".to_string());
+        Self {
+            name,
+            id: generate_random_string(8),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The instances heard from lately, by name.
+    pub fn peers(&self) -> Vec<Peer> {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, peer| peer.seen.elapsed() < Duration::from_secs(TTL_SECS.into()));
+        let mut listed: Vec<Peer> = peers.values().cloned().collect();
+        listed.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.url.cmp(&b.url)));
+        listed
+    }
+
+    /// The records that advertise this instance on `port`, reachable at `ip`
+    /// if it is known; with a TTL of 0 they take it back.
+    fn announcement(&self, port: u16, ip: Option<Ipv4Addr>, ttl: u32) -> Vec<u8> {
+        let instance = format!("{}.{}", self.name.replace('.', " "), SERVICE);
+        let host = format!("{}.local", host_label(&self.name));
+        let mut records = vec![
+            Record::new(SERVICE, TYPE_PTR, CLASS_IN, ttl, name_bytes(&instance)),
+            Record::new(
+                &instance,
+                TYPE_SRV,
+                CLASS_IN | CACHE_FLUSH,
+                ttl,
+                [&[0, 0, 0, 0][..], &port.to_be_bytes(), &name_bytes(&host)].concat(),
+            ),
+            Record::new(
+                &instance,
+                TYPE_TXT,
+                CLASS_IN | CACHE_FLUSH,
+                ttl,
+                txt(&[&format!("id={}", self.id), "path=/"]),
+            ),
+        ];
+        if let Some(ip) = ip {
+            records.push(Record::new(
+                &host,
+                TYPE_A,
+                CLASS_IN | CACHE_FLUSH,
+                ttl,
+                ip.octets().to_vec(),
+            ));
+        }
+        packet(0x8400, &[], &records)
+    }
+
+    /// Takes in a response from `from`, noting the instances it advertises.
+    fn heard(&self, message: &Message, from: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        let instances = message
+            .records
+            .iter()
+            .filter(|record| record.rtype == TYPE_PTR && record.name.eq_ignore_ascii_case(SERVICE))
+            .filter_map(|record| record.target.as_deref());
+        for instance in instances {
+            let find = |rtype: u16| {
+                message
+                    .records
+                    .iter()
+                    .find(|record| record.rtype == rtype && record.name == instance)
+            };
+            let Some(srv) = find(TYPE_SRV) else {
+                continue;
+            };
+            let id = find(TYPE_TXT).and_then(|txt| txt.id.as_deref());
+            if id == Some(self.id.as_str()) {
+                continue;
+            }
+            if srv.ttl == 0 {
+                peers.remove(instance);
+                continue;
+            }
+            let ip = message
+                .records
+                .iter()
+                .find(|record| record.rtype == TYPE_A && Some(&record.name) == srv.target.as_ref())
+                .and_then(|record| record.ip)
+                .map_or(from.ip(), IpAddr::V4);
+            let name = instance
+                .strip_suffix(SERVICE)
+                .and_then(|name| name.strip_suffix('.'))
+                .unwrap_or(instance);
+            let peer = Peer {
+                name: name.to_string(),
+                url: format!("http://{}", SocketAddr::new(ip, srv.port)),
+                seen: Instant::now(),
+            };
+            if !peers.contains_key(instance) {
+                info!("Found {} on the LAN at {}", peer.name, peer.url);
+            }
+            peers.insert(instance.to_string(), peer);
+        }
+    }
+}
+
+/// Handles `GET /api/lan-peers`.
+pub fn peers(state: &SharedState) -> Response<Body> {
+    match &state.lan {
+        Some(lan) => json_response(StatusCode::OK, json!({"peers": lan.peers()})),
+        None => json_response(
+            StatusCode::NOT_FOUND,
+            json!({"error": "LAN discovery is off."}),
+        ),
+    }
+}
+
+/// Advertises the server listening on `addr` and listens for others, until
+/// the server stops. A server only on localhost can't be reached by them, so
+/// it just listens.
+pub async fn run(state: SharedState, addr: SocketAddr) {
+    let Some(lan) = &state.lan else {
+        return;
+    };
+    let socket = match bind() {
+        Ok(socket) => socket,
+        Err(err) => {
+            warn!("LAN discovery is off: can't listen for mDNS: {}", err);
+            return;
+        }
+    };
+    let group = SocketAddr::from(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT));
+    let port = addr.port();
+    let advertised = !addr.ip().is_loopback();
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() && !ip.is_loopback() => Some(ip),
+        _ => local_ip(),
+    };
+    if advertised {
+        info!(
+            "Advertising {:?} on the LAN at {}",
+            lan.name,
+            ip.map_or_else(|| format!("port {}", port), |ip| format!("{}:{}", ip, port))
+        );
+    } else {
+        info!(
+            "Looking for others on the LAN; listening on {} only, this server isn't advertised",
+            addr
+        );
+    }
+    let query = packet(0, &[(SERVICE, TYPE_PTR)], &[]);
+    let _ = socket.send_to(&query, group).await;
+    let mut announce = tokio::time::interval(ANNOUNCE_INTERVAL);
+    let mut shutdown = state.shutdown.clone();
+    let mut buffer = [0; 9000];
+    loop {
+        tokio::select! {
+            _ = announce.tick(), if advertised => {
+                let _ = socket.send_to(&lan.announcement(port, ip, TTL_SECS), group).await;
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let Ok((len, from)) = received else {
+                    continue;
+                };
+                let Some(message) = parse(&buffer[..len]) else {
+                    continue;
+                };
+                if !message.response {
+                    let asked = message.questions.iter().any(|(name, qtype)| {
+                        name.eq_ignore_ascii_case(SERVICE) && matches!(*qtype, TYPE_PTR | TYPE_ANY)
+                    });
+                    if asked && advertised {
+                        let _ = socket.send_to(&lan.announcement(port, ip, TTL_SECS), group).await;
+                    }
+                } else {
+                    lan.heard(&message, from);
+                }
+            }
+            () = &mut shutdown => {
+                if advertised {
+                    let _ = socket.send_to(&lan.announcement(port, ip, 0), group).await;
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// A socket on the mDNS port, shared with any other responder on the host,
+/// in the mDNS group.
+fn bind() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// The address multicast goes out from, which is the one the LAN knows us by.
+pub fn local_ip() -> Option<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_GROUP, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+fn hostname() -> Option<String> {
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    Some(name.trim().to_string()).filter(|name| !name.is_empty())
+}
+
+/// `name` as a single host label: letters, digits and hyphens.
+fn host_label(name: &str) -> String {
+    let label: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    if label.is_empty() {
+        "typeto".to_string()
+    } else {
+        label
+    }
+}
+
+struct Record {
+    name: String,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn new(name: &str, rtype: u16, class: u16, ttl: u32, data: Vec<u8>) -> Self {
+        Self {
+            name: name.to_string(),
+            rtype,
+            class,
+            ttl,
+            data,
+        }
+    }
+}
+
+/// A DNS message with `flags`, asking `questions` and carrying `answers`,
+/// with names written out in full.
+fn packet(flags: u16, questions: &[(&str, u16)], answers: &[Record]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for field in [0, flags, questions.len() as u16, answers.len() as u16, 0, 0] {
+        out.extend(field.to_be_bytes());
+    }
+    for (name, qtype) in questions {
+        out.extend(name_bytes(name));
+        out.extend(qtype.to_be_bytes());
+        out.extend(CLASS_IN.to_be_bytes());
+    }
+    for record in answers {
+        out.extend(name_bytes(&record.name));
+        out.extend(record.rtype.to_be_bytes());
+        out.extend(record.class.to_be_bytes());
+        out.extend(record.ttl.to_be_bytes());
+        out.extend((record.data.len() as u16).to_be_bytes());
+        out.extend(&record.data);
+    }
+    out
+}
+
+/// `name` as length-prefixed labels. The first label of an instance name
+/// may hold dots of its own, so only the service's labels are split.
+fn name_bytes(name: &str) -> Vec<u8> {
+    let (first, rest) = match name.strip_suffix(SERVICE) {
+        Some(instance) if !instance.is_empty() => (Some(instance.trim_end_matches('.')), SERVICE),
+        _ => (None, name),
+    };
+    let mut out = Vec::new();
+    for label in first.into_iter().chain(rest.split('.')) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend(label);
+    }
+    out.push(0);
+    out
+}
+
+fn txt(entries: &[&str]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for entry in entries {
+        out.push(entry.len() as u8);
+        out.extend(entry.as_bytes());
+    }
+    out
+}
+
+/// What is read of a DNS message.
+struct Message {
+    response: bool,
+    questions: Vec<(String, u16)>,
+    records: Vec<ParsedRecord>,
+}
+
+struct ParsedRecord {
+    name: String,
+    rtype: u16,
+    ttl: u32,
+    /// Of a PTR or SRV record.
+    target: Option<String>,
+    /// Of an SRV record.
+    port: u16,
+    /// Of an A record.
+    ip: Option<Ipv4Addr>,
+    /// The `id=` of a TXT record.
+    id: Option<String>,
+}
+
+fn parse(packet: &[u8]) -> Option<Message> {
+    let field = |at: usize| Some(u16::from_be_bytes(packet.get(at..at + 2)?.try_into().ok()?));
+    let flags = field(2)?;
+    let counts = [field(4)?, field(6)?, field(8)?, field(10)?];
+    let mut at = 12;
+    let mut questions = Vec::new();
+    for _ in 0..counts[0] {
+        let (name, next) = read_name(packet, at)?;
+        questions.push((name, field(next)?));
+        at = next + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..counts[1] + counts[2] + counts[3] {
+        let (name, next) = read_name(packet, at)?;
+        let rtype = field(next)?;
+        let ttl = u32::from_be_bytes(packet.get(next + 4..next + 8)?.try_into().ok()?);
+        let len = usize::from(field(next + 8)?);
+        let start = next + 10;
+        let data = packet.get(start..start + len)?;
+        let mut record = ParsedRecord {
+            name,
+            rtype,
+            ttl,
+            target: None,
+            port: 0,
+            ip: None,
+            id: None,
+        };
+        match rtype {
+            TYPE_PTR => record.target = Some(read_name(packet, start)?.0),
+            TYPE_SRV if len >= 6 => {
+                record.port = field(start + 4)?;
+                record.target = Some(read_name(packet, start + 6)?.0);
+            }
+            TYPE_A if len == 4 => {
+                record.ip = Some(Ipv4Addr::new(data[0], data[1], data[2], data[3]))
+            }
+            TYPE_TXT => {
+                let mut entry = 0;
+                while let Some(&entry_len) = data.get(entry) {
+                    let text = data.get(entry + 1..entry + 1 + usize::from(entry_len))?;
+                    if let Some(id) = text.strip_prefix(b"id=") {
+                        record.id = Some(String::from_utf8_lossy(id).into_owned());
+                    }
+                    entry += 1 + usize::from(entry_len);
+                }
+            }
+            _ => {}
+        }
+        records.push(record);
+        at = start + len;
+    }
+    Some(Message {
+        response: flags & 0x8000 != 0,
+        questions,
+        records,
+    })
+}
+
+/// The name at `at`, following compression pointers, and where what follows
+/// it starts.
+fn read_name(packet: &[u8], mut at: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // A pointer only ever points back, so this many jumps means a loop.
+    for _ in 0..64 {
+        let len = *packet.get(at)?;
+        match len {
+            0 => {
+                let name = labels.join(".");
+                return Some((name, end.unwrap_or(at + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = usize::from(u16::from_be_bytes([len & 0x3f, *packet.get(at + 1)?]));
+                end.get_or_insert(at + 2);
+                at = pointer;
+            }
+            len => {
+                let label = packet.get(at + 1..at + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + usize::from(len);
+            }
+        }
+    }
+    None
+}
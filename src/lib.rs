@@ -0,0 +1,4617 @@
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt, SinkExt, StreamExt,
+};
+use hyper::{
+    server::accept::Accept, service::service_fn, Body, Method, Request, Response, Server,
+    StatusCode,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    net::IpAddr,
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{broadcast, mpsc, watch, Notify},
+    time::interval,
+};
+use tokio_tungstenite::tungstenite::{
+    self,
+    error::ProtocolError,
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use tracing_subscriber::{
+    filter::EnvFilter, layer::SubscriberExt, reload, util::SubscriberInitExt, Registry,
+};
+use typeto_client_core::{apply_key, is_character, is_non_event};
+
+mod access;
+#[cfg(feature = "acme")]
+mod acme;
+mod actor;
+mod admin;
+mod ansi;
+mod api;
+mod archive;
+mod archived;
+mod assets;
+mod audit;
+mod bidi;
+mod capability;
+mod cast;
+mod cluster;
+mod config;
+mod cors;
+mod deflate;
+#[cfg(feature = "discord")]
+mod discord;
+mod email;
+mod embed;
+mod expiry;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod gate;
+mod governor;
+mod granularity;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod handoff;
+mod health;
+mod honeypot;
+mod http_client;
+mod identity;
+mod import;
+mod invite;
+mod ip;
+mod jwt;
+mod keystrokes;
+mod lan;
+mod link;
+mod listener;
+mod local;
+mod locale;
+mod mention;
+mod merge;
+mod metrics;
+mod oidc;
+mod openapi;
+mod qr;
+mod race;
+mod reconnect;
+mod report;
+mod retransmit;
+mod rollup;
+mod sanitize;
+mod schedule;
+mod search;
+mod security;
+mod security_headers;
+mod selftest;
+mod server_info;
+mod shortlink;
+mod slack;
+mod snapshot;
+mod sound;
+mod status;
+mod storage;
+mod systemd;
+mod tarpit;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod timer;
+mod trace;
+mod turns;
+#[cfg(feature = "typescript")]
+pub mod typescript;
+mod webhook;
+
+use access::AccessGate;
+use actor::{panic_message, Rooms};
+use admin::{Admin, Maintenance};
+use ansi::StreamFormat;
+use archive::Archive;
+use archived::ArchivedView;
+use assets::GuiBundles;
+use audit::{AuditEvent, AuditLog};
+use cluster::Cluster;
+use config::{
+    CapabilitiesConfig, Config, CreationPolicy, DuplicatePolicy, RuntimeConfig, TextConfig,
+};
+use cors::Cors;
+use email::Outbox;
+use embed::Embed;
+use expiry::Lifetimes;
+use gate::{ConnectionGate, Pass};
+use governor::{Governor, Outbound};
+use granularity::{Granularity, Summarizer};
+use http_client::HttpClient;
+use invite::Invites;
+use jwt::{JwtGate, RoomGrant};
+use keystrokes::KeystrokeLog;
+use lan::Lan;
+use link::{DegradedReason, Link};
+use listener::{Bind, PeerAddr};
+use locale::LocaleHint;
+use mention::Mention;
+use merge::EditLog;
+use metrics::{CloseCause, Closes, Degradations, RoomMemory};
+pub use metrics::{Counters, Traffic};
+use oidc::Oidc;
+use race::{Race, RaceProgress, RaceView};
+use reconnect::ReconnectHint;
+use report::Reports;
+pub use retransmit::Retransmit;
+use retransmit::{Reference, Resume};
+use rollup::Activity;
+use security_headers::SecurityHeaders;
+use sound::KeySound;
+use storage::{HistoryLine, RoomRecord, RoomStore, StoreWriter};
+use tarpit::{Bans, Strikes};
+use timer::{Timer, TimerView};
+use webhook::Webhooks;
+
+/// Version of the WebSocket protocol, as `gui/protocol.d.ts` describes it;
+/// raised when a change would break existing clients.
+const PROTOCOL_VERSION: u32 = 1;
+const MAX_HISTORY: usize = 500;
+const MAX_PARTICIPANTS: usize = 4;
+const CLEANUP_INTERVAL_SECS: u64 = 60;
+const MAX_ROOM_TTL_SECS: u64 = 7 * 24 * 3600;
+const MAX_TOPIC_LEN: usize = 200;
+const MAX_PROMPT_LEN: usize = 500;
+const MAX_THEME_LEN: usize = 32;
+const MAX_PUSH_URL_LEN: usize = 300;
+const PREFS_NEED_IDENTITY: &str = "Preferences need a verified identity; authenticate first.";
+/// Protocol violations, such as binary messages, a connection may commit
+/// before it is closed; a message over the size limit closes it at once.
+const MAX_VIOLATIONS: u32 = 3;
+/// How long a connection being closed has to send its close frame, and the
+/// server, shutting down, to let its connections send theirs.
+const CLOSE_WAIT: Duration = Duration::from_secs(2);
+/// Joins, leaves and topic changes are recorded as this participant's lines.
+/// Random socketIds are alphanumeric, and clients can't claim this one.
+const SYSTEM_ID: &str = "_system";
+/// Finished lines kept for a watcher that falls behind.
+#[cfg(feature = "graphql")]
+const WATCH_BUFFER: usize = 64;
+/// Participant colors, handed out in join order and picked to read well on
+/// the GUI's black background; the first is the GUI's own amber.
+const PALETTE: [&str; 8] = [
+    "#ffb000", "#33ff66", "#33ccff", "#ff66cc", "#e0e0e0", "#ff5555", "#ffff55", "#aa88ff",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+enum RoomMode {
+    /// Every keystroke is relayed as it happens.
+    #[default]
+    Live,
+    /// Others only see a line once it is committed with Enter.
+    Line,
+    /// Live, but only the participant whose turn it is may type.
+    Turns,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+struct RoomSettings {
+    mode: RoomMode,
+    /// How long an empty room is kept before it is cleaned up; the instance
+    /// default when unset.
+    idle_ttl_secs: Option<u64>,
+    /// Hard lifetime counted from creation, regardless of activity.
+    max_age_secs: Option<u64>,
+    max_participants: usize,
+    listed: bool,
+    topic: Option<String>,
+    /// The language the room is held in, if the owner set one.
+    #[serde(default)]
+    locale: Option<LocaleHint>,
+    /// Unix seconds before which joins are refused, for a room scheduled
+    /// with `POST /admin/rooms`.
+    #[serde(default)]
+    starts_at: Option<u64>,
+    /// Interview mode: every key press is recorded with its time, for the
+    /// creator to export.
+    #[serde(default)]
+    record_keystrokes: bool,
+    /// Pinned above everyone's lines with `setPrompt`, say an icebreaker or
+    /// an exercise.
+    #[serde(default)]
+    prompt: Option<String>,
+    /// In mode `turns`, how long each turn lasts at most.
+    #[serde(default)]
+    turn_secs: Option<u64>,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            mode: RoomMode::default(),
+            idle_ttl_secs: None,
+            max_age_secs: None,
+            max_participants: MAX_PARTICIPANTS,
+            listed: false,
+            topic: None,
+            locale: None,
+            starts_at: None,
+            record_keystrokes: false,
+            prompt: None,
+            turn_secs: None,
+        }
+    }
+}
+
+/// Partial update sent by the room owner; absent fields are left unchanged.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[cfg_attr(feature = "typescript", ts(optional_fields = nullable))]
+#[serde(rename_all = "camelCase", default)]
+struct RoomSettingsUpdate {
+    mode: Option<RoomMode>,
+    /// `0` goes back to the instance default.
+    idle_ttl_secs: Option<u64>,
+    /// `0` clears the limit.
+    max_age_secs: Option<u64>,
+    max_participants: Option<usize>,
+    listed: Option<bool>,
+    /// An empty string clears the topic.
+    topic: Option<String>,
+    /// The room's language tag and keyboard layout, replacing both; empty
+    /// strings clear them.
+    locale: Option<String>,
+    layout: Option<String>,
+    record_keystrokes: Option<bool>,
+    /// `0` clears the limit.
+    turn_secs: Option<u64>,
+}
+
+impl RoomSettings {
+    fn apply(&mut self, update: RoomSettingsUpdate) -> Result<(), String> {
+        if let Some(ttl) = update.idle_ttl_secs {
+            if ttl > MAX_ROOM_TTL_SECS {
+                return Err(format!(
+                    "Idle TTL must be at most {} seconds.",
+                    MAX_ROOM_TTL_SECS
+                ));
+            }
+        }
+        if let Some(age) = update.max_age_secs {
+            if age > MAX_ROOM_TTL_SECS {
+                return Err(format!(
+                    "Max age must be at most {} seconds.",
+                    MAX_ROOM_TTL_SECS
+                ));
+            }
+        }
+        if let Some(max) = update.max_participants {
+            if !(2..=MAX_PARTICIPANTS).contains(&max) {
+                return Err(format!(
+                    "Max participants must be between 2 and {}.",
+                    MAX_PARTICIPANTS
+                ));
+            }
+        }
+        if let Some(topic) = &update.topic {
+            if topic.chars().count() > MAX_TOPIC_LEN {
+                return Err(format!(
+                    "Topic must be at most {} characters.",
+                    MAX_TOPIC_LEN
+                ));
+            }
+        }
+        if let Some(secs) = update.turn_secs {
+            if secs > turns::MAX_TURN_SECS {
+                return Err(format!(
+                    "Turns last at most {} seconds.",
+                    turns::MAX_TURN_SECS
+                ));
+            }
+        }
+        let locale = if update.locale.is_some() || update.layout.is_some() {
+            Some(LocaleHint::new(update.locale, update.layout)?)
+        } else {
+            None
+        };
+
+        if let Some(mode) = update.mode {
+            self.mode = mode;
+        }
+        if let Some(ttl) = update.idle_ttl_secs {
+            self.idle_ttl_secs = (ttl > 0).then_some(ttl);
+        }
+        if let Some(age) = update.max_age_secs {
+            self.max_age_secs = (age > 0).then_some(age);
+        }
+        if let Some(max) = update.max_participants {
+            self.max_participants = max;
+        }
+        if let Some(listed) = update.listed {
+            self.listed = listed;
+        }
+        if let Some(topic) = update.topic {
+            let topic = topic.trim().to_string();
+            self.topic = (!topic.is_empty()).then_some(topic);
+        }
+        if let Some(locale) = locale {
+            self.locale = locale;
+        }
+        if let Some(record) = update.record_keystrokes {
+            self.record_keystrokes = record;
+        }
+        if let Some(secs) = update.turn_secs {
+            self.turn_secs = (secs > 0).then_some(secs);
+        }
+        Ok(())
+    }
+}
+
+/// Small per-user preferences, stored server-side so they follow the user's
+/// identity across devices.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[cfg_attr(feature = "typescript", ts(optional_fields = nullable))]
+#[serde(rename_all = "camelCase", default)]
+pub struct UserPrefs {
+    theme: Option<String>,
+    font_size: Option<u8>,
+    sound: Option<bool>,
+    /// Receives a POST when someone mentions this user, e.g. an ntfy topic.
+    push_url: Option<String>,
+    /// Do not disturb: mentions of this user are neither shown to them nor
+    /// pushed.
+    dnd: Option<bool>,
+}
+
+impl UserPrefs {
+    fn apply(&mut self, update: UserPrefs) -> Result<(), String> {
+        if let Some(theme) = &update.theme {
+            if theme.chars().count() > MAX_THEME_LEN {
+                return Err(format!(
+                    "Theme must be at most {} characters.",
+                    MAX_THEME_LEN
+                ));
+            }
+        }
+        if let Some(size) = update.font_size {
+            if !(8..=48).contains(&size) {
+                return Err("Font size must be between 8 and 48.".to_string());
+            }
+        }
+        if let Some(url) = update.push_url.as_deref().filter(|url| !url.is_empty()) {
+            let valid = url.len() <= MAX_PUSH_URL_LEN
+                && url.parse::<hyper::Uri>().is_ok_and(|uri| {
+                    matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some()
+                });
+            if !valid {
+                return Err("Push URL must be an http(s) URL.".to_string());
+            }
+        }
+
+        if let Some(theme) = update.theme {
+            self.theme = (!theme.is_empty()).then_some(theme);
+        }
+        if update.font_size.is_some() {
+            self.font_size = update.font_size;
+        }
+        if update.sound.is_some() {
+            self.sound = update.sound;
+        }
+        if let Some(url) = update.push_url {
+            self.push_url = (!url.is_empty()).then_some(url);
+        }
+        if update.dnd.is_some() {
+            self.dnd = update.dnd;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[cfg_attr(feature = "typescript", ts(optional_fields = nullable))]
+#[serde(tag = "type")]
+enum ClientMessage {
+    #[serde(rename = "newroom")]
+    NewRoom {
+        #[serde(rename = "socketId")]
+        socket_id: Option<String>,
+        /// The joiner's language tag, e.g. `he-IL`, and keyboard layout.
+        #[serde(default)]
+        locale: Option<String>,
+        #[serde(default)]
+        layout: Option<String>,
+        /// `ansi` to be sent terminal escape sequences instead of messages.
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(as = "Option<StreamFormat>", optional))]
+        format: StreamFormat,
+        /// `word` or `line` to be sent others' typing a word or a line at a
+        /// time, e.g. for a screen reader.
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(as = "Option<Granularity>", optional))]
+        granularity: Granularity,
+        /// To be sent a `keySound` after each of others' key presses.
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(as = "Option<bool>", optional))]
+        sounds: bool,
+        /// The client's own id for this session, quoted in `error`s and the
+        /// server's logs.
+        #[serde(rename = "traceId", default)]
+        trace_id: Option<String>,
+    },
+    #[serde(rename = "fetchRoom")]
+    FetchRoom {
+        /// May be left out with a `resumeToken`.
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(as = "Option<String>", optional))]
+        id: String,
+        #[serde(rename = "socketId")]
+        socket_id: Option<String>,
+        /// From a `reconnect` hint; stands for `id` and `socketId`.
+        #[serde(rename = "resumeToken", default)]
+        resume_token: Option<String>,
+        /// The last `seq` seen on a dropped connection, to resume from.
+        #[serde(rename = "lastSeq", default)]
+        last_seq: Option<u64>,
+        /// The dropped connection's `resumeSecret`, without which it isn't
+        /// resumed.
+        #[serde(rename = "resumeSecret", default)]
+        resume_secret: Option<String>,
+        #[serde(default)]
+        locale: Option<String>,
+        #[serde(default)]
+        layout: Option<String>,
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(as = "Option<StreamFormat>", optional))]
+        format: StreamFormat,
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(as = "Option<Granularity>", optional))]
+        granularity: Granularity,
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(as = "Option<bool>", optional))]
+        sounds: bool,
+        #[serde(rename = "traceId", default)]
+        trace_id: Option<String>,
+    },
+    #[serde(rename = "keyPress")]
+    KeyPress {
+        key: String,
+        #[serde(rename = "cursorPos")]
+        cursor_pos: Option<usize>,
+        /// The revision of the typist's line `cursor_pos` was taken against.
+        #[serde(default)]
+        rev: Option<u64>,
+    },
+    #[serde(rename = "updateRoomSettings")]
+    UpdateRoomSettings { settings: RoomSettingsUpdate },
+    /// From the room's owner: a countdown of `seconds` for everyone, locking
+    /// typing when it runs out if `lock` is set; `0` cancels it and unlocks.
+    #[serde(rename = "startTimer")]
+    StartTimer {
+        seconds: u64,
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(as = "Option<bool>", optional))]
+        lock: bool,
+    },
+    /// From the room's owner, back on a new connection: takes back the
+    /// owner's rights with the room's creator token.
+    #[serde(rename = "claimOwner")]
+    ClaimOwner { token: String },
+    /// Reports the room to the operator, with `reason`.
+    #[serde(rename = "report")]
+    Report { reason: String },
+    /// From the room's owner: key presses from participant `socketId` are
+    /// dropped until `unfreeze`.
+    #[serde(rename = "freeze")]
+    Freeze {
+        #[serde(rename = "socketId")]
+        socket_id: String,
+    },
+    #[serde(rename = "unfreeze")]
+    Unfreeze {
+        #[serde(rename = "socketId")]
+        socket_id: String,
+    },
+    /// From the room's owner: pins `prompt` above everyone's lines; none, or
+    /// an empty one, unpins it.
+    #[serde(rename = "setPrompt")]
+    SetPrompt {
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(optional))]
+        prompt: Option<String>,
+    },
+    /// From the room's owner: a typing race on `text`, or on a built-in
+    /// passage.
+    #[serde(rename = "startRace")]
+    StartRace {
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(optional))]
+        text: Option<String>,
+    },
+    /// From the room's owner: ends the race before everyone has finished.
+    #[serde(rename = "endRace")]
+    EndRace,
+    /// The preferences of the connection's verified identity.
+    #[serde(rename = "getPrefs")]
+    GetPrefs,
+    #[serde(rename = "setPrefs")]
+    SetPrefs { prefs: UserPrefs },
+    /// Looks for `query` in the room's lines, as a regex if `regex` is set.
+    #[serde(rename = "searchHistory")]
+    SearchHistory {
+        query: String,
+        #[serde(default)]
+        #[cfg_attr(feature = "typescript", ts(as = "Option<bool>", optional))]
+        regex: bool,
+    },
+    /// Brings back an archived room; fetch it again once `revived` comes.
+    #[serde(rename = "reviveRoom")]
+    ReviveRoom { id: String },
+    #[serde(rename = "getChallenge")]
+    GetChallenge,
+    /// Asks what the instance supports, before joining; answered with
+    /// `helloAck`.
+    #[serde(rename = "hello")]
+    Hello,
+    /// Everything up to `seq` arrived; it needn't be kept for a resume.
+    #[serde(rename = "ack")]
+    Ack { seq: u64 },
+    /// Proves ownership of an Ed25519 key by signing `typeto-auth:<challenge>`.
+    #[serde(rename = "authenticate")]
+    Authenticate {
+        #[serde(rename = "publicKey")]
+        public_key: String,
+        signature: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(tag = "type")]
+pub enum ServerMessage {
+    #[serde(rename = "gotRoom")]
+    GotRoom { room: Box<RoomView> },
+    #[serde(rename = "room-is-crowded")]
+    RoomIsCrowded { message: String },
+    /// `rev`, like on `keyPress`, is only sent to the typist's other
+    /// connections: the revision of their line once this edit is made.
+    #[serde(rename = "committed")]
+    Committed {
+        r#final: Arc<str>,
+        source: Arc<str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "typescript", ts(optional))]
+        rev: Option<u64>,
+    },
+    #[serde(rename = "keyPress")]
+    KeyPress {
+        key: Arc<str>,
+        source: Arc<str>,
+        #[serde(rename = "cursorPos")]
+        cursor_pos: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[cfg_attr(feature = "typescript", ts(optional))]
+        rev: Option<u64>,
+    },
+    #[serde(rename = "error")]
+    Error { message: String },
+    #[serde(rename = "prefs")]
+    Prefs { prefs: UserPrefs },
+    #[serde(rename = "challenge")]
+    Challenge { challenge: String },
+    #[serde(rename = "authenticated")]
+    Authenticated { identity: String },
+    /// The reply to `hello`.
+    #[serde(rename = "helloAck")]
+    HelloAck {
+        #[serde(rename = "protocolVersion")]
+        protocol_version: u32,
+        /// A bit for each optional capability the instance has on:
+        /// 1 creating rooms, 2 resuming, 4 reviving archived rooms,
+        /// 8 `searchHistory`, 16 `sounds`, 32 `granularity` other than
+        /// `key`, 64 push notifications for mentions, 128 anyone on several
+        /// devices at once. Unknown bits are to be ignored.
+        capabilities: u32,
+    },
+    /// Sent only to a room's creator; authorizes `DELETE /api/rooms/:id`.
+    #[serde(rename = "creatorToken")]
+    CreatorToken { room: String, token: String },
+    /// Sent to a participant named with `@` in `source`'s finished `line`.
+    #[serde(rename = "mention")]
+    Mention {
+        room: String,
+        source: String,
+        line: String,
+    },
+    /// Operator message shown as a system line, e.g. ahead of a restart.
+    #[serde(rename = "serverNotice")]
+    ServerNotice { message: String },
+    /// The participant connected again elsewhere; this connection is closed
+    /// right after.
+    #[serde(rename = "sessionTakenOver")]
+    SessionTakenOver { message: String },
+    /// Sent only to the connection that just joined a room, or took over a
+    /// participant's place in it: what to send back as `resumeSecret` to
+    /// resume that place from another connection.
+    #[serde(rename = "resumeSecret")]
+    ResumeSecret { secret: String },
+    /// At `word` or `line` granularity: `source`'s line in progress as it
+    /// stands once they finish a word or pause, in place of their key presses.
+    #[serde(rename = "typing")]
+    Typing { source: String, line: String },
+    /// To connections that asked for sounds: what `source`'s last key press
+    /// or commit sounds like.
+    #[serde(rename = "keySound")]
+    KeySound { source: Arc<str>, sound: KeySound },
+    /// The reply to `fetchRoom` for a room that expired but is archived:
+    /// its lines, to read, until `deletedAt`. `reviveRoom` brings it back.
+    #[serde(rename = "archived")]
+    Archived { room: ArchivedView },
+    #[serde(rename = "revived")]
+    Revived { room: String },
+    /// The room reaches its maximum age at `expiresAt`, `secondsLeft` from
+    /// now; sent an hour, ten minutes and a minute ahead.
+    #[serde(rename = "expiring")]
+    Expiring {
+        #[serde(rename = "expiresAt")]
+        expires_at: u64,
+        #[serde(rename = "secondsLeft")]
+        seconds_left: u64,
+    },
+    /// The reply to joining a scheduled room before it starts at `startsAt`,
+    /// `secondsLeft` from now.
+    #[serde(rename = "notStarted")]
+    NotStarted {
+        #[serde(rename = "startsAt")]
+        starts_at: u64,
+        #[serde(rename = "secondsLeft")]
+        seconds_left: u64,
+    },
+    /// The room's owner started a countdown.
+    #[serde(rename = "timer")]
+    Timer { timer: TimerView },
+    /// The countdown ran out, or was cancelled; if `locked`, nobody can type
+    /// until another one starts.
+    #[serde(rename = "timerEnded")]
+    TimerEnded { locked: bool },
+    /// In mode `turns`, it is `participant`'s turn, until `endsAtMs` if
+    /// turns are limited.
+    #[serde(rename = "turn")]
+    Turn {
+        participant: String,
+        #[serde(rename = "endsAtMs")]
+        ends_at_ms: Option<u64>,
+    },
+    /// The room's owner started a typing race.
+    #[serde(rename = "race")]
+    Race { race: RaceView },
+    /// Someone typed in the race.
+    #[serde(rename = "raceProgress")]
+    RaceProgress { progress: RaceProgress },
+    /// Everyone finished, or the owner ended the race.
+    #[serde(rename = "raceEnded")]
+    RaceEnded { standings: Vec<RaceProgress> },
+    /// The server is shutting down, e.g. for a deploy, and closes this
+    /// connection right after.
+    #[serde(rename = "serverShutdown")]
+    ServerShutdown { reconnect: ReconnectHint },
+    /// The room moved to another instance, whose WebSocket is at `url`;
+    /// this connection is closed right after.
+    #[serde(rename = "moveTo")]
+    MoveTo {
+        url: String,
+        reconnect: ReconnectHint,
+    },
+    /// The connection fell behind and gets fewer updates for now.
+    #[serde(rename = "degraded")]
+    Degraded { reason: DegradedReason },
+    /// The reply to `searchHistory`.
+    #[serde(rename = "searchResults")]
+    SearchResults {
+        query: String,
+        #[serde(flatten)]
+        results: search::Results,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+pub struct RoomView {
+    messages: HashMap<String, Vec<String>>,
+    participants: usize,
+    id: String,
+    #[serde(rename = "yourId")]
+    your_id: String,
+    #[serde(rename = "theirId")]
+    their_id: Option<String>,
+    #[serde(rename = "otherParticipantIds")]
+    other_participant_ids: Vec<String>,
+    #[serde(rename = "ownerId")]
+    owner_id: Option<String>,
+    settings: RoomSettings,
+    /// The revision of your line, for `keyPress`.
+    #[serde(rename = "yourRev")]
+    your_rev: u64,
+    /// Everyone's color, by participant id, including those who left.
+    colors: BTreeMap<String, String>,
+    /// The code of the room's short link, `/j/<code>`. Rooms created before
+    /// short links have none.
+    shortcode: Option<String>,
+    /// The language and keyboard layout of those who said, by participant id.
+    locales: BTreeMap<String, LocaleHint>,
+    /// The direction of each line in `messages`, line for line.
+    directions: HashMap<String, Vec<bidi::Direction>>,
+    /// When the room's maximum age runs out, in seconds since the epoch.
+    #[serde(rename = "expiresAt")]
+    expires_at: Option<u64>,
+    /// When the room goes if everyone leaves now and nobody comes back.
+    #[serde(rename = "idleTimeoutAt")]
+    idle_timeout_at: u64,
+    /// The frontends the instance offers, if it has more than one.
+    gui: Option<GuiBundles>,
+    /// The countdown running, if the owner started one.
+    timer: Option<TimerView>,
+    /// Whether a countdown with `lock` ran out, so that nobody can type.
+    #[serde(rename = "typingLocked")]
+    typing_locked: bool,
+    /// The typing race being run, if any.
+    race: Option<RaceView>,
+    /// Participants whose input the owner froze.
+    frozen: Vec<String>,
+    /// In mode `turns`, whose turn it is.
+    turn: Option<String>,
+}
+
+#[derive(Debug)]
+struct Participant {
+    id: String,
+    sender: broadcast::Sender<ServerMessage>,
+    /// The connection's counters, and their values when it joined this room.
+    traffic: Arc<Counters>,
+    traffic_at_join: Traffic,
+    link: Link,
+    /// The connection's numbered messages, kept for a resume.
+    retransmit: Arc<Mutex<Retransmit>>,
+    /// Set while the connection is gone but may still resume; messages for
+    /// it go straight to `retransmit` until it does.
+    parked: bool,
+    /// Tells apart the connections of a participant connected more than once.
+    device: u64,
+    /// Whether this connection holds the owner's rights: it joined first, or
+    /// as the owner's verified identity, or claimed them with the creator
+    /// token. The owner's id alone, being public, isn't enough.
+    owner: bool,
+    /// Sent to this connection alone; resuming it from another takes it,
+    /// as the id is public.
+    resume_secret: String,
+}
+
+impl Participant {
+    /// Adds this connection's traffic so far to `departed`, and starts
+    /// counting afresh.
+    fn fold_traffic(&mut self, departed: &mut HashMap<String, Traffic>) {
+        departed
+            .entry(self.id.clone())
+            .or_default()
+            .add(self.traffic.snapshot().since(self.traffic_at_join));
+        self.traffic_at_join = self.traffic.snapshot();
+    }
+
+    /// Moves this participant onto a new connection.
+    fn hand_over(
+        &mut self,
+        sender: broadcast::Sender<ServerMessage>,
+        traffic: Arc<Counters>,
+        retransmit: Arc<Mutex<Retransmit>>,
+        departed: &mut HashMap<String, Traffic>,
+    ) {
+        if !self.parked {
+            self.fold_traffic(departed);
+        }
+        self.sender = sender;
+        self.traffic_at_join = traffic.snapshot();
+        self.traffic = traffic;
+        self.retransmit = retransmit;
+        self.parked = false;
+        self.link = Link::default();
+        // Whoever resumes or takes over an id proves ownership afresh.
+        self.owner &= identity::is_identity(&self.id);
+    }
+
+    /// Whether `secret` is this connection's resume secret.
+    fn holds(&self, secret: &str) -> bool {
+        bool::from(self.resume_secret.as_bytes().ct_eq(secret.as_bytes()))
+    }
+
+    /// Gives the connection a new resume secret, and sends it.
+    fn renew_secret(&mut self) {
+        self.resume_secret = generate_random_string(32);
+        self.send(ServerMessage::ResumeSecret {
+            secret: self.resume_secret.clone(),
+        });
+    }
+
+    /// Tells the connection if it just started getting fewer updates.
+    fn report_degraded(&mut self) {
+        if let Some(reason) = self.link.take_degraded() {
+            self.send(ServerMessage::Degraded { reason });
+        }
+    }
+
+    fn send(&self, message: ServerMessage) {
+        if self.parked {
+            self.retransmit.lock().unwrap().stamp(&message, None);
+        } else {
+            let _ = self.sender.send(message);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Room {
+    id: String,
+    participants: Vec<Participant>,
+    messages: HashMap<String, Vec<String>>,
+    last_update: SystemTime,
+    created_at: SystemTime,
+    owner_id: Option<String>,
+    settings: RoomSettings,
+    creator_token_hash: Option<String>,
+    /// Traffic of participants who have since left, by participant id.
+    departed_traffic: HashMap<String, Traffic>,
+    /// Relayed key presses and commits not yet sent, and the governor's
+    /// bookkeeping for this room.
+    outbox: VecDeque<Outbound>,
+    deficit: u64,
+    scheduled: bool,
+    /// Finished lines not yet handed to the store.
+    unsaved_history: Vec<HistoryLine>,
+    /// Whose each finished line is, in the order they were finished, so the
+    /// lines can be put back into conversation order.
+    line_order: VecDeque<String>,
+    /// Recent edits to each participant's line, for merging key presses
+    /// from their several connections.
+    edits: HashMap<String, EditLog>,
+    next_device: u64,
+    /// Each participant's color, kept for as long as the room.
+    colors: BTreeMap<String, String>,
+    shortcode: Option<String>,
+    /// Each participant's language hint, from when they last joined with one.
+    locales: BTreeMap<String, LocaleHint>,
+    /// Mentions not yet handed to `mention::notify`.
+    unsent_mentions: Vec<Mention>,
+    /// Activity not yet added to the daily rollups.
+    activity: Activity,
+    /// Subscribers to finished lines, once there have been any.
+    watchers: Option<broadcast::Sender<HistoryLine>>,
+    lifetimes: Lifetimes,
+    /// The nearest `expiring` warning sent so far.
+    expiry_warned: Option<u64>,
+    /// Imported participants whose lines are being replayed, shown as if
+    /// they were here.
+    replaying: Vec<String>,
+    /// Lines finished so far, counting those it was loaded with.
+    seq: u64,
+    /// How finished lines are cleaned up.
+    text: TextConfig,
+    /// The frontends the instance offers, for room views.
+    gui: Option<GuiBundles>,
+    /// `[rooms] max_memory_bytes`.
+    memory_limit: Option<usize>,
+    /// Key presses recorded while `record_keystrokes` was on.
+    keystrokes: KeystrokeLog,
+    timer: Option<Timer>,
+    /// Countdowns started so far, to number them.
+    timers_started: u64,
+    typing_locked: bool,
+    race: Option<Race>,
+    /// Participants whose key presses are dropped, by the owner's `freeze`.
+    frozen: BTreeSet<String>,
+    /// In mode `turns`, whose turn it is.
+    turn: Option<String>,
+    /// Turns passed so far, to number them.
+    turns_taken: u64,
+    /// The time limit of the turn just passed, for `turns::schedule`.
+    turn_timer: Option<(u64, Duration)>,
+    /// Participants the admin shadow-banned, with their lines as only they
+    /// see them.
+    shadow_banned: HashMap<String, Vec<String>>,
+}
+
+impl Room {
+    pub fn new(id: String) -> Self {
+        Self {
+            id,
+            participants: Vec::new(),
+            messages: HashMap::new(),
+            last_update: SystemTime::now(),
+            created_at: SystemTime::now(),
+            owner_id: None,
+            settings: RoomSettings::default(),
+            creator_token_hash: None,
+            departed_traffic: HashMap::new(),
+            outbox: VecDeque::new(),
+            deficit: 0,
+            scheduled: false,
+            unsaved_history: Vec::new(),
+            line_order: VecDeque::new(),
+            edits: HashMap::new(),
+            next_device: 0,
+            colors: BTreeMap::new(),
+            shortcode: None,
+            locales: BTreeMap::new(),
+            unsent_mentions: Vec::new(),
+            activity: Activity::default(),
+            watchers: None,
+            lifetimes: Lifetimes::default(),
+            expiry_warned: None,
+            replaying: Vec::new(),
+            seq: 0,
+            text: TextConfig::default(),
+            gui: None,
+            memory_limit: None,
+            keystrokes: KeystrokeLog::default(),
+            timer: None,
+            timers_started: 0,
+            typing_locked: false,
+            race: None,
+            frozen: BTreeSet::new(),
+            turn: None,
+            turns_taken: 0,
+            turn_timer: None,
+            shadow_banned: HashMap::new(),
+        }
+    }
+
+    /// Follows `config`'s room lifetimes, text rules and memory limit from
+    /// now on.
+    fn configure(&mut self, config: &Config) {
+        self.lifetimes = Lifetimes::from(&config.rooms);
+        self.text = config.text;
+        self.gui = GuiBundles::new(&config.gui);
+        self.memory_limit = config.rooms.max_memory_bytes;
+    }
+
+    /// Creates the secret that lets whoever brought this room into existence
+    /// delete it later; only its digest is kept.
+    fn issue_creator_token(&mut self) -> String {
+        let token = generate_random_string(32);
+        self.creator_token_hash = Some(identity::token_hash(&token));
+        token
+    }
+
+    fn from_record(record: RoomRecord, history: Vec<HistoryLine>) -> Self {
+        let mut room = Self::new(record.id);
+        room.created_at = UNIX_EPOCH + Duration::from_secs(record.created_at);
+        room.owner_id = record.owner_id;
+        room.settings = record.settings;
+        room.creator_token_hash = record.creator_token_hash;
+        room.colors = record.colors;
+        room.shortcode = record.shortcode;
+        room.locales = record.locales;
+        room.seq = history.len() as u64;
+        for line in history {
+            room.line_order.push_back(line.participant.clone());
+            room.messages
+                .entry(line.participant)
+                .or_default()
+                .push(line.text);
+        }
+        let ids: Vec<String> = room.messages.keys().cloned().collect();
+        for id in ids {
+            room.messages.get_mut(&id).unwrap().push(String::new());
+            room.prune_history(&id);
+        }
+        room
+    }
+
+    fn record(&self) -> RoomRecord {
+        RoomRecord {
+            id: self.id.clone(),
+            owner_id: self.owner_id.clone(),
+            settings: self.settings.clone(),
+            created_at: self
+                .created_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            creator_token_hash: self.creator_token_hash.clone(),
+            colors: self.colors.clone(),
+            shortcode: self.shortcode.clone(),
+            locales: self.locales.clone(),
+            archived: None,
+        }
+    }
+
+    /// Records the language hint `participant` joined with; false if it
+    /// changed nothing. Joining without one keeps the last.
+    fn set_locale(&mut self, participant: &str, hint: Option<LocaleHint>) -> bool {
+        match hint {
+            Some(hint) if self.locales.get(participant) != Some(&hint) => {
+                self.locales.insert(participant.to_string(), hint);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn join(
+        &mut self,
+        participant_id: String,
+        sender: broadcast::Sender<ServerMessage>,
+        traffic: Arc<Counters>,
+        retransmit: Arc<Mutex<Retransmit>>,
+    ) -> Result<(), String> {
+        // Another connection of someone already here doesn't take a place.
+        let present = self.participants.iter().any(|p| p.id == participant_id);
+        if !present && self.headcount() >= self.settings.max_participants {
+            return Err(format!(
+                "Room is full (max {} participants).",
+                self.settings.max_participants
+            ));
+        }
+
+        let owner = match &self.owner_id {
+            Some(owner_id) => *owner_id == participant_id && identity::is_identity(owner_id),
+            None => true,
+        };
+        if self.owner_id.is_none() {
+            self.owner_id = Some(participant_id.clone());
+        }
+        if !self.colors.contains_key(&participant_id) {
+            let color = self.next_color();
+            self.colors.insert(participant_id.clone(), color);
+        }
+
+        info!(
+            "Socket {} joining room {}, {} participants already connected",
+            participant_id,
+            self.id,
+            self.participants.len()
+        );
+
+        let mut participant = Participant {
+            id: participant_id.clone(),
+            sender,
+            traffic_at_join: traffic.snapshot(),
+            traffic,
+            link: Link::default(),
+            retransmit,
+            parked: false,
+            device: self.next_device,
+            owner,
+            resume_secret: String::new(),
+        };
+        participant.renew_secret();
+        self.participants.push(participant);
+        self.next_device += 1;
+
+        if self.participants.len() == 2 {
+            info!("Room {} started chatting", self.id);
+        }
+
+        if !self.messages.contains_key(&participant_id) {
+            self.messages
+                .insert(participant_id.clone(), vec![String::new()]);
+        }
+
+        let joined = format!("{} has joined", short_id(&participant_id));
+        let recent_join = self
+            .messages
+            .get(SYSTEM_ID)
+            .and_then(|lines| lines.iter().rev().nth(1))
+            .is_some_and(|line| line.starts_with(&joined));
+
+        if !present && !recent_join {
+            self.finish_line(&participant_id);
+            self.system_line(format!("{} at {}Z", joined, now_utc()));
+        }
+
+        self.last_update = SystemTime::now();
+        self.activity.present(self.headcount(), self.last_update);
+        Ok(())
+    }
+
+    /// Keeps the participant whose connection sends to `sender` in the room
+    /// after that connection dropped, collecting what is sent to it for a
+    /// resume. Returns false if it isn't here.
+    fn park(&mut self, sender: &broadcast::Sender<ServerMessage>) -> bool {
+        let Some(participant) = self
+            .participants
+            .iter_mut()
+            .find(|p| !p.parked && p.sender.same_channel(sender))
+        else {
+            return false;
+        };
+        participant.fold_traffic(&mut self.departed_traffic);
+        participant.parked = true;
+        true
+    }
+
+    /// Whether `participant_id` is still parked with `retransmit`, rather
+    /// than having resumed or left.
+    fn is_parked(&self, participant_id: &str, retransmit: &Arc<Mutex<Retransmit>>) -> bool {
+        self.participants
+            .iter()
+            .any(|p| p.id == participant_id && p.parked && Arc::ptr_eq(&p.retransmit, retransmit))
+    }
+
+    /// Hands the connection of `participant_id` whose resume secret is
+    /// `secret` to a new connection, if it is parked or, when the client
+    /// says where it left off, if it hasn't been noticed dropping yet. The
+    /// new connection's `retransmit` continues the old numbering, and the
+    /// secret stays the same.
+    fn resume(
+        &mut self,
+        participant_id: &str,
+        secret: Option<&str>,
+        last_seq: Option<u64>,
+        sender: broadcast::Sender<ServerMessage>,
+        traffic: Arc<Counters>,
+        retransmit: Arc<Mutex<Retransmit>>,
+    ) -> Option<Resume> {
+        let secret = secret?;
+        let index = self.participants.iter().position(|p| {
+            p.id == participant_id && (p.parked || last_seq.is_some()) && p.holds(secret)
+        })?;
+        let participant = &mut self.participants[index];
+        // Messages still queued for the old connection were never numbered.
+        let unsent = !participant.parked && !participant.sender.is_empty();
+        let kept = std::mem::take(&mut *participant.retransmit.lock().unwrap());
+        let replay = last_seq
+            .filter(|_| !unsent)
+            .and_then(|last_seq| kept.since(last_seq));
+        *retransmit.lock().unwrap() = kept;
+        participant.hand_over(sender, traffic, retransmit, &mut self.departed_traffic);
+        Some(match replay {
+            Some(frames) => Resume::Replay(frames),
+            None => Resume::Reload,
+        })
+    }
+
+    /// Whether `participant_id` has a live connection here.
+    fn connected(&self, participant_id: &str) -> bool {
+        self.participants
+            .iter()
+            .any(|p| p.id == participant_id && !p.parked)
+    }
+
+    /// The participant connected on `sender`, if any.
+    fn participant_on(&self, sender: &broadcast::Sender<ServerMessage>) -> Option<String> {
+        self.participants
+            .iter()
+            .find(|p| p.sender.same_channel(sender))
+            .map(|p| p.id.clone())
+    }
+
+    /// The owner's id, if the connection on `sender` holds the owner's rights.
+    fn owner_on(&self, sender: &broadcast::Sender<ServerMessage>) -> Option<String> {
+        self.participants
+            .iter()
+            .find(|p| p.sender.same_channel(sender) && p.owner)
+            .map(|p| p.id.clone())
+    }
+
+    /// Gives the connection on `sender`, joined as the owner's id, the
+    /// owner's rights if `token` is the room's creator token.
+    fn claim_owner(
+        &mut self,
+        sender: &broadcast::Sender<ServerMessage>,
+        token: &str,
+    ) -> Result<(), String> {
+        let valid = self.creator_token_hash.as_deref().is_some_and(|hash| {
+            bool::from(
+                identity::token_hash(token)
+                    .as_bytes()
+                    .ct_eq(hash.as_bytes()),
+            )
+        });
+        let owner_id = self.owner_id.as_deref();
+        let Some(participant) = self
+            .participants
+            .iter_mut()
+            .find(|p| p.sender.same_channel(sender))
+        else {
+            return Err("Join the room first.".to_string());
+        };
+        if !valid || owner_id != Some(participant.id.as_str()) {
+            return Err("That isn't the creator token of the room you own.".to_string());
+        }
+        participant.owner = true;
+        Ok(())
+    }
+
+    fn has_connection(&self, sender: &broadcast::Sender<ServerMessage>) -> bool {
+        self.participants
+            .iter()
+            .any(|p| p.sender.same_channel(sender))
+    }
+
+    /// Moves `participant_id` from their live connection to a new one, and
+    /// tells the old one it was replaced.
+    fn take_over(
+        &mut self,
+        participant_id: &str,
+        sender: broadcast::Sender<ServerMessage>,
+        traffic: Arc<Counters>,
+        retransmit: Arc<Mutex<Retransmit>>,
+    ) {
+        let Some(participant) = self
+            .participants
+            .iter_mut()
+            .find(|p| p.id == participant_id && !p.parked)
+        else {
+            return;
+        };
+        participant.send(ServerMessage::SessionTakenOver {
+            message: "You joined this room from somewhere else.".to_string(),
+        });
+        participant.hand_over(sender, traffic, retransmit, &mut self.departed_traffic);
+        participant.renew_secret();
+    }
+
+    /// How many connections `participant_id` has here, parked ones included.
+    fn connections(&self, participant_id: &str) -> usize {
+        self.participants
+            .iter()
+            .filter(|p| p.id == participant_id)
+            .count()
+    }
+
+    /// Applies a key press from the connection sending to `sender`, typed
+    /// against revision `rev` of the participant's line, and passes it on to
+    /// their other connections. If it had to be merged with edits from those,
+    /// they are all sent the room afresh instead.
+    fn type_from(
+        &mut self,
+        sender: &broadcast::Sender<ServerMessage>,
+        participant_id: &str,
+        key: &str,
+        cursor_pos: Option<usize>,
+        rev: Option<u64>,
+    ) {
+        let Some(device) = self
+            .participants
+            .iter()
+            .find(|p| p.sender.same_channel(sender))
+            .map(|p| p.device)
+        else {
+            return;
+        };
+        if self.typing_locked {
+            let _ = sender.send(ServerMessage::Error {
+                message: "Time's up; typing is locked until the owner starts another timer."
+                    .to_string(),
+            });
+            return;
+        }
+        if self.frozen.contains(participant_id) {
+            let _ = sender.send(ServerMessage::Error {
+                message: "The room owner froze your input.".to_string(),
+            });
+            return;
+        }
+        if self.settings.mode == RoomMode::Turns {
+            let holder = self.turn_holder();
+            if holder.as_deref() != Some(participant_id) {
+                let _ = sender.send(ServerMessage::ServerNotice {
+                    message: format!(
+                        "It's {}'s turn.",
+                        holder.as_deref().map_or("someone else", short_id)
+                    ),
+                });
+                return;
+            }
+        }
+        // With one connection there is nothing to merge.
+        let rev = rev.filter(|_| self.connections(participant_id) > 1);
+        let log = self.edits.entry(participant_id.to_string()).or_default();
+        let (cursor_pos, merged) = log.merge(device, rev, key, cursor_pos);
+        let rev = Some(log.rev());
+        let shadow_banned = self.shadow_banned.contains_key(participant_id);
+        if self.settings.record_keystrokes && !shadow_banned {
+            let at_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            self.keystrokes
+                .record(at_ms, participant_id, key, cursor_pos);
+        }
+        let echo = if key == "Enter" {
+            // A shadow-banned participant's other devices see their own lines.
+            let lines = match self.shadow_banned.get(participant_id) {
+                Some(lines) => Some(lines),
+                None => self.messages.get(participant_id),
+            };
+            ServerMessage::Committed {
+                r#final: lines
+                    .and_then(|lines| lines.last())
+                    .map_or_else(|| Arc::from(""), |line| Arc::from(line.as_str())),
+                source: Arc::from(participant_id),
+                rev,
+            }
+        } else {
+            ServerMessage::KeyPress {
+                key: Arc::from(key),
+                source: Arc::from(participant_id),
+                cursor_pos,
+                rev,
+            }
+        };
+        self.handle_keypress(participant_id, key, cursor_pos);
+        if key != "Enter" {
+            if !shadow_banned {
+                self.score_race(participant_id, key);
+            }
+        } else if self.settings.mode == RoomMode::Turns {
+            self.pass_turn();
+        }
+
+        let now = Instant::now();
+        for participant in &mut self.participants {
+            if participant.id != participant_id {
+                continue;
+            }
+            if merged {
+                participant.link.missed();
+                continue;
+            }
+            if participant.device == device {
+                continue;
+            }
+            let queued = participant.sender.len();
+            let dropped = participant.traffic.dropped_total();
+            if participant.parked || participant.link.accepts_delta(queued, dropped, now) {
+                participant.send(echo.clone());
+            }
+            participant.report_degraded();
+        }
+    }
+
+    /// Drops the connection that sends to `sender`. Returns true if it was
+    /// `participant_id`'s last one here, so they have left the room.
+    fn disconnect(
+        &mut self,
+        participant_id: &str,
+        sender: &broadcast::Sender<ServerMessage>,
+    ) -> bool {
+        let Some(index) = self
+            .participants
+            .iter()
+            .position(|p| p.sender.same_channel(sender))
+        else {
+            return false;
+        };
+        if self
+            .participants
+            .iter()
+            .filter(|p| p.id == participant_id)
+            .count()
+            == 1
+        {
+            self.leave(participant_id);
+            return true;
+        }
+        let mut participant = self.participants.remove(index);
+        participant.fold_traffic(&mut self.departed_traffic);
+        false
+    }
+
+    /// The first palette color nobody here has, or once all are taken, the
+    /// palette again from the start.
+    fn next_color(&self) -> String {
+        PALETTE
+            .iter()
+            .find(|color| !self.colors.values().any(|taken| taken == *color))
+            .unwrap_or(&PALETTE[self.colors.len() % PALETTE.len()])
+            .to_string()
+    }
+
+    /// Participants in the room; someone connected more than once counts
+    /// once.
+    fn headcount(&self) -> usize {
+        let mut ids: Vec<&str> = self.participants.iter().map(|p| p.id.as_str()).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids.len()
+    }
+
+    fn leave(&mut self, participant_id: &str) {
+        let departed = &mut self.departed_traffic;
+        self.participants.retain(|p| {
+            if p.id != participant_id {
+                return true;
+            }
+            departed
+                .entry(p.id.clone())
+                .or_default()
+                .add(p.traffic.snapshot().since(p.traffic_at_join));
+            false
+        });
+
+        self.finish_line(participant_id);
+        self.system_line(format!(
+            "{} has left at {}Z",
+            short_id(participant_id),
+            now_utc()
+        ));
+
+        if self.participants.len() == 1 {
+            info!("Room {} stopped chatting", self.id);
+        }
+
+        self.last_update = SystemTime::now();
+    }
+
+    fn update_settings(
+        &mut self,
+        sender: &broadcast::Sender<ServerMessage>,
+        update: RoomSettingsUpdate,
+    ) -> Result<(), String> {
+        let Some(participant_id) = self.owner_on(sender) else {
+            return Err("Only the room owner can change settings.".to_string());
+        };
+        let participant_id = participant_id.as_str();
+        let topic = self.settings.topic.clone();
+        let recording = self.settings.record_keystrokes;
+        let mode = self.settings.mode;
+        self.settings.apply(update)?;
+        if self.settings.mode != mode {
+            self.turn = None;
+            if self.settings.mode == RoomMode::Turns {
+                self.pass_turn();
+            }
+        }
+        self.settings.topic = self
+            .settings
+            .topic
+            .take()
+            .map(|topic| sanitize::clean(&topic, &self.text))
+            .filter(|topic| !topic.is_empty());
+        info!("Room {} settings updated: {:?}", self.id, self.settings);
+        if self.settings.topic != topic {
+            let who = short_id(participant_id);
+            self.system_line(match &self.settings.topic {
+                Some(topic) => format!("{} set the topic to \"{}\"", who, topic),
+                None => format!("{} cleared the topic", who),
+            });
+        }
+        if self.settings.record_keystrokes != recording {
+            let who = short_id(participant_id);
+            self.system_line(if recording {
+                format!("{} stopped recording keystrokes", who)
+            } else {
+                format!("{} started recording keystrokes and their timing", who)
+            });
+        }
+        self.last_update = SystemTime::now();
+        Ok(())
+    }
+
+    /// Shadow-bans participant `target`, or lifts their ban. While banned,
+    /// what they type is kept apart, where only they see it. Returns
+    /// whether anything changed.
+    fn shadow_ban(&mut self, target: &str, banned: bool) -> Result<bool, String> {
+        let known =
+            self.messages.contains_key(target) || self.participants.iter().any(|p| p.id == target);
+        if target == SYSTEM_ID || !known {
+            return Err("No such participant in the room.".to_string());
+        }
+        if !banned {
+            return Ok(self.shadow_banned.remove(target).is_some());
+        }
+        if self.shadow_banned.contains_key(target) {
+            return Ok(false);
+        }
+        self.finish_line(target);
+        let lines = self
+            .messages
+            .get(target)
+            .cloned()
+            .unwrap_or_else(|| vec![String::new()]);
+        self.shadow_banned.insert(target.to_string(), lines);
+        Ok(true)
+    }
+
+    /// Freezes or unfreezes the input of participant `target`.
+    fn freeze(
+        &mut self,
+        sender: &broadcast::Sender<ServerMessage>,
+        target: &str,
+        frozen: bool,
+    ) -> Result<(), String> {
+        let Some(participant_id) = self.owner_on(sender) else {
+            return Err("Only the room owner can freeze input.".to_string());
+        };
+        let participant_id = participant_id.as_str();
+        if !frozen {
+            if self.frozen.remove(target) {
+                self.system_line(format!(
+                    "{} unfroze {}'s input",
+                    short_id(participant_id),
+                    short_id(target)
+                ));
+            }
+            return Ok(());
+        }
+        if target == participant_id {
+            return Err("The owner's input can't be frozen.".to_string());
+        }
+        if !self.participants.iter().any(|p| p.id == target) {
+            return Err("No such participant in this room.".to_string());
+        }
+        if self.frozen.insert(target.to_string()) {
+            self.system_line(format!(
+                "{} froze {}'s input",
+                short_id(participant_id),
+                short_id(target)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whose turn it is, passing it on if they have left.
+    fn turn_holder(&mut self) -> Option<String> {
+        let present = |id: &str| self.participants.iter().any(|p| p.id == id);
+        if !self.turn.as_deref().is_some_and(present) {
+            self.pass_turn();
+        }
+        self.turn.clone()
+    }
+
+    /// Gives the turn to whoever is next, telling everyone.
+    fn pass_turn(&mut self) {
+        let mut order: Vec<&str> = Vec::new();
+        for participant in &self.participants {
+            if !order.contains(&participant.id.as_str()) {
+                order.push(&participant.id);
+            }
+        }
+        let Some(next) = turns::next(&order, self.turn.as_deref()).map(str::to_string) else {
+            self.turn = None;
+            return;
+        };
+        self.turns_taken += 1;
+        let limit = self.settings.turn_secs.map(Duration::from_secs);
+        self.turn_timer = limit.map(|limit| (self.turns_taken, limit));
+        let ends_at_ms = limit.map(|limit| {
+            (SystemTime::now() + limit)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        });
+        self.turn = Some(next.clone());
+        self.broadcast(
+            ServerMessage::Turn {
+                participant: next,
+                ends_at_ms,
+            },
+            None,
+        );
+    }
+
+    /// Passes turn `number` on, if it is still the current one. Returns
+    /// whether it was.
+    fn end_turn(&mut self, number: u64) -> bool {
+        if self.settings.mode != RoomMode::Turns || self.turns_taken != number {
+            return false;
+        }
+        self.pass_turn();
+        true
+    }
+
+    fn take_turn_timer(&mut self) -> Option<(u64, Duration)> {
+        self.turn_timer.take()
+    }
+
+    /// Stores the room after its settings changed, and shows everyone.
+    fn save_settings(&mut self, state: &SharedState) {
+        state.store_writer.save(self.record());
+        state
+            .store_writer
+            .append_history(self.id.clone(), self.take_history());
+        self.notify_participants();
+    }
+
+    /// Pins `prompt` for everyone, or unpins it. `sender` is unset when the
+    /// prompt is set over the API.
+    fn set_prompt(
+        &mut self,
+        sender: Option<&broadcast::Sender<ServerMessage>>,
+        prompt: Option<String>,
+    ) -> Result<(), String> {
+        let participant_id = match sender {
+            Some(sender) => match self.owner_on(sender) {
+                Some(id) => Some(id),
+                None => return Err("Only the room owner can set the prompt.".to_string()),
+            },
+            None => None,
+        };
+        let prompt = prompt
+            .map(|prompt| sanitize::clean(prompt.trim(), &self.text))
+            .filter(|prompt| !prompt.is_empty());
+        if let Some(prompt) = &prompt {
+            if prompt.chars().count() > MAX_PROMPT_LEN || prompt.chars().any(char::is_control) {
+                return Err(format!(
+                    "The prompt must be one line of at most {} characters.",
+                    MAX_PROMPT_LEN
+                ));
+            }
+        }
+        if self.settings.prompt == prompt {
+            return Ok(());
+        }
+        let pinned = if prompt.is_some() {
+            "pinned"
+        } else {
+            "unpinned"
+        };
+        self.system_line(match &participant_id {
+            Some(id) => format!("{} {} the prompt", short_id(id), pinned),
+            None => format!("The prompt was {}", pinned),
+        });
+        self.settings.prompt = prompt;
+        self.last_update = SystemTime::now();
+        Ok(())
+    }
+
+    /// Starts a countdown of `seconds` for everyone, replacing any running,
+    /// or with `0` cancels it; either unlocks typing. Returns the new
+    /// countdown, for `timer::run_out`.
+    fn start_timer(
+        &mut self,
+        sender: &broadcast::Sender<ServerMessage>,
+        seconds: u64,
+        lock: bool,
+    ) -> Result<Option<Timer>, String> {
+        if self.owner_on(sender).is_none() {
+            return Err("Only the room owner can start a timer.".to_string());
+        }
+        if seconds > timer::MAX_SECS {
+            return Err(format!(
+                "Timers run for at most {} seconds.",
+                timer::MAX_SECS
+            ));
+        }
+        let running = self.timer.take();
+        self.typing_locked = false;
+        if seconds == 0 {
+            if running.is_some() {
+                self.broadcast(ServerMessage::TimerEnded { locked: false }, None);
+            }
+            return Ok(None);
+        }
+        self.timers_started += 1;
+        let now = SystemTime::now();
+        let started = Timer {
+            ends_at: now + Duration::from_secs(seconds),
+            lock,
+            number: self.timers_started,
+        };
+        self.timer = Some(started);
+        self.broadcast(
+            ServerMessage::Timer {
+                timer: started.view(now),
+            },
+            None,
+        );
+        Ok(Some(started))
+    }
+
+    /// Ends countdown `number`, if it is still the one running.
+    fn end_timer(&mut self, number: u64) {
+        let Some(running) = self.timer.filter(|timer| timer.number == number) else {
+            return;
+        };
+        self.timer = None;
+        self.typing_locked = running.lock;
+        self.broadcast(
+            ServerMessage::TimerEnded {
+                locked: running.lock,
+            },
+            None,
+        );
+    }
+
+    /// Starts a typing race on `text`, replacing any being run.
+    fn start_race(
+        &mut self,
+        sender: &broadcast::Sender<ServerMessage>,
+        text: Option<String>,
+    ) -> Result<(), String> {
+        if self.owner_on(sender).is_none() {
+            return Err("Only the room owner can start a race.".to_string());
+        }
+        let race = Race::new(text, SystemTime::now())?;
+        self.broadcast(ServerMessage::Race { race: race.view() }, None);
+        self.race = Some(race);
+        Ok(())
+    }
+
+    /// Ends the race, sending everyone the standings. `sender` is unset when
+    /// the race ends by itself.
+    fn end_race(
+        &mut self,
+        sender: Option<&broadcast::Sender<ServerMessage>>,
+    ) -> Result<(), String> {
+        if sender.is_some_and(|sender| self.owner_on(sender).is_none()) {
+            return Err("Only the room owner can end a race.".to_string());
+        }
+        let Some(race) = self.race.take() else {
+            return Err("No race is being run.".to_string());
+        };
+        self.broadcast(
+            ServerMessage::RaceEnded {
+                standings: race.standings(SystemTime::now()),
+            },
+            None,
+        );
+        Ok(())
+    }
+
+    /// Scores `key` in the race, if one is being run, and ends it once
+    /// everyone has finished.
+    fn score_race(&mut self, participant_id: &str, key: &str) {
+        let Some(race) = &mut self.race else {
+            return;
+        };
+        let line = self
+            .messages
+            .get(participant_id)
+            .and_then(|lines| lines.last())
+            .map_or("", |line| line.as_str());
+        let Some(progress) = race.typed(participant_id, key, line, SystemTime::now()) else {
+            return;
+        };
+        let done = race.all_finished(self.participants.iter().map(|p| p.id.as_str()));
+        self.broadcast(ServerMessage::RaceProgress { progress }, None);
+        if done {
+            let _ = self.end_race(None);
+        }
+    }
+
+    pub fn broadcast(&self, message: ServerMessage, exclude_id: Option<&str>) {
+        for participant in &self.participants {
+            if let Some(exclude) = exclude_id {
+                if participant.id == exclude {
+                    continue;
+                }
+            }
+            participant.send(message.clone());
+        }
+    }
+
+    /// Sends a relayed key press or commit to everyone but `exclude`, except
+    /// to connections too far behind to take it; they get a snapshot later.
+    fn send_delta(&mut self, message: ServerMessage, exclude: Option<&str>) {
+        let now = Instant::now();
+        for participant in &mut self.participants {
+            if exclude == Some(participant.id.as_str()) {
+                continue;
+            }
+            let queued = participant.sender.len();
+            let dropped = participant.traffic.dropped_total();
+            if participant.parked || participant.link.accepts_delta(queued, dropped, now) {
+                participant.send(message.clone());
+            }
+            participant.report_degraded();
+        }
+    }
+
+    /// Sends the whole room to each connection that missed deltas and has
+    /// since caught up on the rest of its queue.
+    fn resync(&mut self, now: Instant) {
+        for i in 0..self.participants.len() {
+            let participant = &mut self.participants[i];
+            let queued = participant.sender.len();
+            let dropped = participant.traffic.dropped_total();
+            let due = !participant.parked && participant.link.needs_snapshot(queued, dropped, now);
+            participant.report_degraded();
+            if !due {
+                continue;
+            }
+            let participant = &self.participants[i];
+            debug!(
+                "Resyncing {} in room {}{}",
+                participant.id,
+                self.id,
+                if participant.link.snapshots_only(now) {
+                    " (snapshots only)"
+                } else {
+                    ""
+                }
+            );
+            let room_view = self.render(&participant.id);
+            participant.send(ServerMessage::GotRoom {
+                room: Box::new(room_view),
+            });
+        }
+    }
+
+    /// Queues a broadcast for the governor to send.
+    fn relay(&mut self, message: ServerMessage, exclude_id: Option<&str>) {
+        self.outbox.push_back(Outbound::Relay {
+            message: Box::new(message),
+            exclude: exclude_id.map(str::to_string),
+        });
+    }
+
+    fn deliver(&mut self, outbound: Outbound) {
+        match outbound {
+            Outbound::Relay { message, exclude } => self.send_delta(*message, exclude.as_deref()),
+            Outbound::Snapshot => self.notify_participants(),
+        }
+    }
+
+    pub fn flush_outbox(&mut self) {
+        while let Some(outbound) = self.outbox.pop_front() {
+            self.deliver(outbound);
+        }
+    }
+
+    /// How many socket messages sending `outbound` takes.
+    fn cost(&self, outbound: &Outbound) -> u64 {
+        let recipients = match outbound {
+            Outbound::Relay {
+                exclude: Some(exclude),
+                ..
+            } => self
+                .participants
+                .iter()
+                .filter(|p| &p.id != exclude)
+                .count(),
+            _ => self.participants.len(),
+        };
+        recipients as u64
+    }
+
+    fn backlog(&self) -> u64 {
+        self.outbox.iter().map(|outbound| self.cost(outbound)).sum()
+    }
+
+    /// Everything exchanged in this room so far, by participant id.
+    pub fn render(&self, socket_id: &str) -> RoomView {
+        let mut other_ids: Vec<String> = Vec::new();
+        for participant in &self.participants {
+            if participant.id != socket_id && !other_ids.contains(&participant.id) {
+                other_ids.push(participant.id.clone());
+            }
+        }
+        for id in &self.replaying {
+            if id != socket_id && !other_ids.contains(id) {
+                other_ids.push(id.clone());
+            }
+        }
+
+        let mut messages = self.messages.clone();
+        if let Some(lines) = self.shadow_banned.get(socket_id) {
+            messages.insert(socket_id.to_string(), lines.clone());
+        }
+        if self.settings.mode == RoomMode::Line {
+            for (id, lines) in messages.iter_mut() {
+                if id != socket_id {
+                    if let Some(current_line) = lines.last_mut() {
+                        current_line.clear();
+                    }
+                }
+            }
+        }
+
+        let directions = messages
+            .iter()
+            .map(|(id, lines)| {
+                let directions = lines.iter().map(|line| bidi::direction(line)).collect();
+                (id.clone(), directions)
+            })
+            .collect();
+        RoomView {
+            messages,
+            participants: self.headcount(),
+            id: self.id.clone(),
+            your_id: socket_id.to_string(),
+            their_id: other_ids.first().cloned(),
+            other_participant_ids: other_ids,
+            owner_id: self.owner_id.clone(),
+            settings: self.settings.clone(),
+            your_rev: self.edits.get(socket_id).map_or(0, EditLog::rev),
+            colors: self.colors.clone(),
+            shortcode: self.shortcode.clone(),
+            locales: self.locales.clone(),
+            directions,
+            expires_at: self.expires_at().map(expiry::unix_secs),
+            idle_timeout_at: expiry::unix_secs(self.idle_timeout_at()),
+            gui: self.gui.clone(),
+            timer: self.timer.map(|timer| timer.view(SystemTime::now())),
+            typing_locked: self.typing_locked,
+            race: self.race.as_ref().map(Race::view),
+            frozen: self.frozen.iter().cloned().collect(),
+            turn: self.turn.clone(),
+        }
+    }
+
+    /// Sends everyone the full room. Queued relays are dropped, since the
+    /// snapshot already includes them.
+    fn notify_participants(&mut self) {
+        self.outbox.clear();
+        for i in 0..self.participants.len() {
+            let room_view = self.render(&self.participants[i].id);
+            let participant = &mut self.participants[i];
+            participant.send(ServerMessage::GotRoom {
+                room: Box::new(room_view),
+            });
+            participant.link.synced();
+        }
+    }
+
+    pub fn handle_keypress(&mut self, participant_id: &str, key: &str, cursor_pos: Option<usize>) {
+        if let Some(lines) = self.shadow_banned.get_mut(participant_id) {
+            if key == "Enter" {
+                lines.push(String::new());
+                if lines.len() > MAX_HISTORY {
+                    lines.drain(0..lines.len() - MAX_HISTORY);
+                }
+            } else if let Some(current_line) = lines.last_mut() {
+                apply_key(current_line, key, cursor_pos);
+            }
+            return;
+        }
+        let now = SystemTime::now();
+        self.activity.typed(now);
+        if key == "Enter" {
+            self.clean_line(participant_id);
+            if let Some(messages) = self.messages.get(participant_id) {
+                let final_msg = messages.last().unwrap_or(&String::new()).clone();
+                if !final_msg.is_empty() {
+                    self.activity.committed(&final_msg, now);
+                }
+                self.mention(participant_id, &final_msg);
+                self.relay(
+                    ServerMessage::Committed {
+                        r#final: Arc::from(final_msg),
+                        source: Arc::from(participant_id),
+                        rev: None,
+                    },
+                    Some(participant_id),
+                );
+            }
+
+            self.end_line(participant_id, None);
+            return;
+        }
+
+        if self.settings.mode != RoomMode::Line {
+            self.relay(
+                ServerMessage::KeyPress {
+                    key: Arc::from(key),
+                    source: Arc::from(participant_id),
+                    cursor_pos,
+                    rev: None,
+                },
+                Some(participant_id),
+            );
+        }
+
+        if let Some(current_line) = self
+            .messages
+            .get_mut(participant_id)
+            .and_then(|messages| messages.last_mut())
+        {
+            apply_key(current_line, key, cursor_pos);
+        }
+
+        self.last_update = SystemTime::now();
+    }
+
+    /// When the room's maximum age runs out, if it has one.
+    fn expires_at(&self) -> Option<SystemTime> {
+        let max_age = self.lifetimes.max_age(self.settings.max_age_secs)?;
+        Some(self.created_at + max_age)
+    }
+
+    /// When the room goes if it is left empty from now on; a scheduled room
+    /// waits for its start.
+    fn idle_timeout_at(&self) -> SystemTime {
+        let starts_at = UNIX_EPOCH + Duration::from_secs(self.settings.starts_at.unwrap_or(0));
+        self.last_update.max(starts_at) + self.lifetimes.idle_ttl(self.settings.idle_ttl_secs)
+    }
+
+    /// The refusal for joining before a scheduled room starts.
+    fn not_started(&self, now: SystemTime) -> Option<ServerMessage> {
+        let starts_at = self.settings.starts_at?;
+        let seconds_left = starts_at.checked_sub(expiry::unix_secs(now))?;
+        (seconds_left > 0).then_some(ServerMessage::NotStarted {
+            starts_at,
+            seconds_left,
+        })
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        if self.expires_at().is_some_and(|expires_at| expires_at < now) {
+            return true;
+        }
+        self.participants.is_empty() && self.idle_timeout_at() < now
+    }
+
+    /// Warns everyone here as the room's maximum age nears its end.
+    fn warn_of_expiry(&mut self, now: SystemTime) {
+        let Some(expires_at) = self.expires_at() else {
+            self.expiry_warned = None;
+            return;
+        };
+        let left = expires_at.duration_since(now).unwrap_or_default();
+        if expiry::warn(left, &mut self.expiry_warned) {
+            self.broadcast(
+                ServerMessage::Expiring {
+                    expires_at: expiry::unix_secs(expires_at),
+                    seconds_left: left.as_secs(),
+                },
+                None,
+            );
+        }
+    }
+
+    /// Cleans up `participant_id`'s line in progress as it is finished.
+    fn clean_line(&mut self, participant_id: &str) {
+        let text = self.text;
+        if let Some(line) = self
+            .messages
+            .get_mut(participant_id)
+            .and_then(|messages| messages.last_mut())
+        {
+            *line = sanitize::clean(line, &text);
+        }
+    }
+
+    /// Finishes `participant_id`'s line in progress, adds `notice` as a line
+    /// of its own, and starts a new empty line. The finished lines are queued
+    /// for the store's history.
+    fn end_line(&mut self, participant_id: &str, notice: Option<String>) {
+        self.clean_line(participant_id);
+        let Some(messages) = self.messages.get_mut(participant_id) else {
+            return;
+        };
+        let finished: Vec<String> = messages
+            .last()
+            .cloned()
+            .into_iter()
+            .chain(notice.clone())
+            .collect();
+        messages.extend(notice);
+        messages.push(String::new());
+        for text in finished {
+            self.finished(HistoryLine {
+                participant: participant_id.to_string(),
+                text,
+            });
+        }
+        self.prune_history(participant_id);
+    }
+
+    /// Ends `participant_id`'s line in progress, if they had typed any.
+    fn finish_line(&mut self, participant_id: &str) {
+        let typed = self
+            .messages
+            .get(participant_id)
+            .and_then(|lines| lines.last())
+            .is_some_and(|line| !line.is_empty());
+        if typed {
+            self.end_line(participant_id, None);
+        }
+    }
+
+    /// Records a room event as a finished line of the system participant.
+    fn system_line(&mut self, text: String) {
+        let text = sanitize::clean(&text, &self.text);
+        let lines = self
+            .messages
+            .entry(SYSTEM_ID.to_string())
+            .or_insert_with(|| vec![String::new()]);
+        lines.insert(lines.len() - 1, text.clone());
+        self.finished(HistoryLine {
+            participant: SYSTEM_ID.to_string(),
+            text,
+        });
+        self.prune_history(SYSTEM_ID);
+    }
+
+    /// Keeps a finished line for the store and hands it to any watchers.
+    fn finished(&mut self, line: HistoryLine) {
+        self.seq += 1;
+        self.line_order.push_back(line.participant.clone());
+        if let Some(watchers) = &self.watchers {
+            let _ = watchers.send(line.clone());
+        }
+        self.unsaved_history.push(line);
+    }
+
+    /// Lines this room finishes from now on. The stream ends when the room
+    /// is dropped.
+    #[cfg(feature = "graphql")]
+    fn watch(&mut self) -> broadcast::Receiver<HistoryLine> {
+        self.watchers
+            .get_or_insert_with(|| broadcast::channel(WATCH_BUFFER).0)
+            .subscribe()
+    }
+
+    /// Lines finished since the last call, oldest first.
+    fn take_history(&mut self) -> Vec<HistoryLine> {
+        std::mem::take(&mut self.unsaved_history)
+    }
+
+    /// Queues a mention of whoever `source`'s finished `line` names, for
+    /// `mention::notify`.
+    fn mention(&mut self, source: &str, line: &str) {
+        if !line.contains('@') {
+            return;
+        }
+        let ids: Vec<&str> = self
+            .messages
+            .keys()
+            .map(String::as_str)
+            .filter(|id| *id != source && *id != SYSTEM_ID)
+            .collect();
+        for id in mention::find(line, &ids) {
+            self.unsent_mentions.push(Mention {
+                room: self.id.clone(),
+                participant: id.to_string(),
+                source: source.to_string(),
+                line: line.to_string(),
+            });
+        }
+    }
+
+    /// Mentions since the last call.
+    fn take_mentions(&mut self) -> Vec<Mention> {
+        std::mem::take(&mut self.unsent_mentions)
+    }
+
+    /// Sends `message` to each of `participant_id`'s connections here.
+    fn tell(&self, participant_id: &str, message: ServerMessage) {
+        for participant in self.participants.iter().filter(|p| p.id == participant_id) {
+            participant.send(message.clone());
+        }
+    }
+
+    fn prune_history(&mut self, participant_id: &str) {
+        if let Some(messages) = self.messages.get_mut(participant_id) {
+            if messages.len() > MAX_HISTORY {
+                messages.drain(0..messages.len() - MAX_HISTORY);
+            }
+        }
+        self.trim_to_memory_limit();
+        self.trim_line_order();
+    }
+
+    /// Forgets the order of lines since dropped, oldest first.
+    fn trim_line_order(&mut self) {
+        let finished: usize = self
+            .messages
+            .values()
+            .map(|lines| lines.len().saturating_sub(1))
+            .sum();
+        if self.line_order.len() <= finished {
+            return;
+        }
+        let mut dropped: HashMap<&str, usize> = HashMap::new();
+        for participant in &self.line_order {
+            *dropped.entry(participant).or_default() += 1;
+        }
+        for (participant, count) in dropped.iter_mut() {
+            let kept = self
+                .messages
+                .get(*participant)
+                .map_or(0, |lines| lines.len().saturating_sub(1));
+            *count = count.saturating_sub(kept);
+        }
+        let mut dropped: HashMap<String, usize> = dropped
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(participant, count)| (participant.to_string(), count))
+            .collect();
+        self.line_order
+            .retain(|participant| match dropped.get_mut(participant) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    false
+                }
+                _ => true,
+            });
+    }
+
+    /// Drops finished lines, oldest first from whoever has the most, while
+    /// the room holds more than `[rooms] max_memory_bytes`.
+    fn trim_to_memory_limit(&mut self) {
+        let Some(limit) = self.memory_limit else {
+            return;
+        };
+        let rest = self.memory(0).total();
+        let mut finished = self.finished_memory();
+        let mut dropped = 0;
+        while rest + finished > limit {
+            let Some(lines) = self
+                .messages
+                .values_mut()
+                .filter(|lines| lines.len() > 1)
+                .max_by_key(|lines| lines.len())
+            else {
+                break;
+            };
+            finished -= line_memory(&lines.remove(0));
+            dropped += 1;
+        }
+        if dropped > 0 {
+            debug!(
+                "Room {} dropped {} lines to stay under its memory limit",
+                self.id, dropped
+            );
+        }
+    }
+
+    /// Bytes held by finished lines, roughly.
+    fn finished_memory(&self) -> usize {
+        self.messages
+            .iter()
+            .map(|(participant, lines)| {
+                let finished = &lines[..lines.len().saturating_sub(1)];
+                participant.len() + finished.iter().map(|line| line_memory(line)).sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Roughly what the room holds in memory, given what its finished lines
+    /// hold, which is costlier to count.
+    fn memory(&self, finished: usize) -> RoomMemory {
+        let typing: usize = self
+            .messages
+            .values()
+            .filter_map(|lines| lines.last())
+            .map(|line| line_memory(line))
+            .sum();
+        RoomMemory {
+            lines: finished + typing,
+            edits: self.edits.values().map(EditLog::memory).sum(),
+            unacked: self
+                .participants
+                .iter()
+                .map(|p| p.retransmit.lock().unwrap().memory())
+                .sum(),
+            outbox: self.outbox.len()
+                * (std::mem::size_of::<Outbound>() + std::mem::size_of::<ServerMessage>()),
+        }
+    }
+}
+
+fn line_memory(line: &str) -> usize {
+    std::mem::size_of::<String>() + line.len()
+}
+
+/// Whether a client may send `key`: a named key, or a single printable
+/// character. Control characters, such as a null byte or the ESC that starts
+/// an escape sequence, and strings of several characters are refused.
+fn is_valid_key(key: &str) -> bool {
+    if key == "Space" || is_non_event(key) {
+        return true;
+    }
+    is_character(key) && !key.chars().any(char::is_control)
+}
+
+/// The first four characters of a participant id, as shown in join and leave
+/// lines. Ids come from clients, so this must not split a character.
+fn short_id(id: &str) -> &str {
+    id.char_indices().nth(4).map_or(id, |(end, _)| &id[..end])
+}
+
+/// The time now, as shown in join and leave lines.
+fn now_utc() -> String {
+    chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn generate_random_string(length: usize) -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), length)
+}
+
+/// Picks the participant id for a connection joining a room. A verified key
+/// identity always wins; key-derived ids can't be claimed by presenting them
+/// as a bare socketId.
+fn claim_participant_id(
+    socket_id: Option<String>,
+    verified_id: Option<&str>,
+) -> Result<String, String> {
+    if let Some(id) = verified_id {
+        return Ok(id.to_string());
+    }
+    match socket_id {
+        Some(id) if identity::is_identity(&id) => {
+            Err("This identity requires authentication.".to_string())
+        }
+        Some(id) if id == SYSTEM_ID => Err("This id is reserved.".to_string()),
+        Some(id) => Ok(id),
+        None => Ok(generate_random_string(20)),
+    }
+}
+
+struct AppState {
+    rooms: Rooms,
+    store: Arc<dyn RoomStore>,
+    store_writer: StoreWriter,
+    access: Option<AccessGate>,
+    oidc: Option<Oidc>,
+    jwt: Option<JwtGate>,
+    embed: Option<Embed>,
+    cors: Cors,
+    security_headers: SecurityHeaders,
+    shutdown: Shutdown,
+    /// Replaced wholesale on SIGHUP; only the reloadable sections are read
+    /// from here.
+    config: RwLock<Arc<Config>>,
+    log_filter: LogFilter,
+    admin: Option<Admin>,
+    /// From `[cluster]`: which node serves which room.
+    cluster: Option<Cluster>,
+    /// From `[lan]`: this instance as advertised, and the others found.
+    lan: Option<Lan>,
+    maintenance: Mutex<Option<Maintenance>>,
+    shutdown_trigger: Arc<Notify>,
+    /// Reaches every open socket, whether or not it has joined a room.
+    notices: broadcast::Sender<ServerMessage>,
+    /// WebSocket connections being served, against `[limits]
+    /// max_connections`.
+    connections: ConnectionGate,
+    audit: AuditLog,
+    /// WebSocket traffic since startup, across all connections.
+    traffic: Counters,
+    closes: Closes,
+    degradations: Degradations,
+    governor: Governor,
+    /// Where rooms are saved on shutdown, from `[snapshot]`.
+    snapshot: Option<PathBuf>,
+    archive: Option<Archive>,
+    /// For push notifications.
+    http_client: HttpClient,
+    /// Unused invitations to create rooms.
+    invites: Invites,
+    /// Added under `/admin/webhooks`.
+    webhooks: Webhooks,
+    /// Invitations rooms emailed in the last hour, under `[email]`.
+    outbox: Outbox,
+    /// Abuse reports filed in the last hour.
+    reports: Reports,
+    /// From `[discord]`: the bot and the rooms it bridges.
+    #[cfg(feature = "discord")]
+    discord: Option<discord::Discord>,
+    bans: Bans,
+    started: Instant,
+}
+
+impl AppState {
+    fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Sends a `serverNotice` to every connected socket and returns how many
+    /// there are.
+    fn announce(&self, message: String) -> usize {
+        self.notices
+            .send(ServerMessage::ServerNotice { message })
+            .unwrap_or(0)
+    }
+
+    /// Stops the server as if it had received SIGTERM.
+    fn request_shutdown(&self) {
+        self.shutdown_trigger.notify_one();
+    }
+
+    /// The maintenance message while new rooms are being refused.
+    fn maintenance_message(&self) -> Option<String> {
+        self.maintenance
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|maintenance| maintenance.message.clone())
+    }
+}
+
+type LogFilter = reload::Handle<EnvFilter, Registry>;
+
+/// Re-reads the config file and applies its reloadable sections. A file that
+/// fails to parse leaves the running config untouched.
+fn reload_config(state: &AppState) {
+    let config = match Config::load_from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Config reload failed, keeping the old config: {}", err);
+            return;
+        }
+    };
+    if let Some(level) = &config.logging.level {
+        match EnvFilter::try_new(level) {
+            Ok(filter) => {
+                let _ = state.log_filter.reload(filter);
+            }
+            Err(err) => warn!("Ignoring invalid logging.level {:?}: {}", level, err),
+        }
+    }
+    *state.config.write().unwrap() = Arc::new(config);
+    info!("Reloaded config");
+}
+
+/// Token bucket for one connection's key presses.
+struct KeyRate {
+    tokens: f64,
+    last: Instant,
+    warned: bool,
+}
+
+impl KeyRate {
+    fn new() -> Self {
+        Self {
+            tokens: f64::MAX,
+            last: Instant::now(),
+            warned: false,
+        }
+    }
+
+    /// Spends a token if one is available under the current limits.
+    fn allow(&mut self, limits: &config::LimitsConfig) -> bool {
+        let Some(rate) = limits.keypresses_per_sec else {
+            return true;
+        };
+        let capacity = f64::from(rate) + f64::from(limits.keypress_burst);
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * f64::from(rate);
+        self.tokens = (self.tokens + refill).min(capacity);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.warned = false;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What the HTTP upgrade established about a connection before any
+/// WebSocket message was read.
+#[derive(Default)]
+struct UpgradeAuth {
+    identity: Option<String>,
+    grant: Option<RoomGrant>,
+    ip: Option<IpAddr>,
+    /// From `?invite=`, for creating a room under `creation = "invite"`.
+    invite: Option<String>,
+    /// The upgrade request's id, which the connection goes by.
+    request_id: String,
+    /// Passed on by another node of the cluster, which placed it here.
+    from_peer: bool,
+}
+
+type SharedState = Arc<AppState>;
+
+/// Serves one WebSocket, and if its handling panics, logs it and takes the
+/// connection out of its room as if it had closed, so that a bug in one
+/// connection can't leave a room with a participant nobody is serving. Its
+/// place under `[limits] max_connections` is held until it is done.
+async fn handle_websocket(
+    websocket: deflate::Upgrade,
+    state: SharedState,
+    auth: UpgradeAuth,
+    _pass: Pass,
+) {
+    // Also the connection's identity in the room it joins.
+    let (tx, rx) = broadcast::channel::<ServerMessage>(32);
+    let connection = serve_websocket(websocket, state.clone(), auth, tx.clone(), rx);
+    if let Err(panic) = AssertUnwindSafe(connection).catch_unwind().await {
+        error!(
+            "Connection handler panicked: {}",
+            panic_message(panic.as_ref())
+        );
+        state.closes.record(CloseCause::Panic);
+        abandon(&state, &tx).await;
+    }
+}
+
+/// Takes the connection on `sender` out of every room it is in.
+async fn abandon(state: &SharedState, sender: &broadcast::Sender<ServerMessage>) {
+    for (_, room) in state.rooms.all() {
+        let state = state.clone();
+        let sender = sender.clone();
+        room.call(move |room| {
+            if let Some(participant_id) = room.participant_on(&sender) {
+                warn!(
+                    "Removing {} from room {} after a panic",
+                    participant_id, room.id
+                );
+                depart(&state, room, &participant_id, &sender);
+            }
+        })
+        .await;
+    }
+}
+
+async fn serve_websocket(
+    websocket: deflate::Upgrade,
+    state: SharedState,
+    auth: UpgradeAuth,
+    tx: broadcast::Sender<ServerMessage>,
+    mut rx: broadcast::Receiver<ServerMessage>,
+) {
+    let (ws_stream, deflate) = match websocket.accept().await {
+        Ok(accepted) => accepted,
+        Err(_) => return,
+    };
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+    let mut participant_id = String::new();
+    let mut room_id = String::new();
+    let mut challenge: Option<String> = None;
+    let mut verified_id = auth.identity;
+    let grant = auth.grant;
+    let client_ip = auth.ip;
+    let invite = auth.invite;
+    let connection_id = auth.request_id;
+    let mut key_rate = KeyRate::new();
+    let mut violations = 0;
+    let mut abnormal = None;
+    let mut strikes = Strikes::new();
+    if client_ip.is_some_and(|ip| state.bans.is_flagged(ip)) {
+        strikes.suspect(&state.config().tarpit);
+    }
+    let mut closed = false;
+    let traffic = Arc::new(Counters::default());
+    let retransmit = Arc::new(Mutex::new(Retransmit::default()));
+    let connection = Connection {
+        sender: tx.clone(),
+        traffic: traffic.clone(),
+        retransmit: retransmit.clone(),
+        ip: client_ip,
+    };
+    // Messages a resumed connection missed, already numbered.
+    let (replay_tx, mut replay_rx) = mpsc::unbounded_channel::<Vec<String>>();
+    // Chosen when joining.
+    let (delivery, mut sender_delivery) = watch::channel(Delivery::default());
+    // A close frame to end with, when the server closes the connection.
+    let (close_tx, mut close_rx) = mpsc::unbounded_channel::<CloseFrame<'static>>();
+
+    let mut notices = state.notices.subscribe();
+    let sender_traffic = traffic.clone();
+    let sender_state = state.clone();
+    let sender_retransmit = retransmit.clone();
+    let mut sender_task = tokio::spawn(async move {
+        let stamp = |message: &ServerMessage, trace_id: Option<&str>| -> Vec<String> {
+            let reference = matches!(message, ServerMessage::Error { .. }).then_some(Reference {
+                connection_id: &connection_id,
+                trace_id,
+            });
+            sender_retransmit
+                .lock()
+                .unwrap()
+                .stamp(message, reference)
+                .into_iter()
+                .collect()
+        };
+        let mut screen = ansi::Screen::default();
+        let mut render = |messages: Vec<ServerMessage>, delivery: &Delivery| -> Vec<String> {
+            messages
+                .iter()
+                .flat_map(|message| {
+                    if delivery.format == StreamFormat::Ansi {
+                        screen.render(message).into_iter().collect()
+                    } else {
+                        stamp(message, delivery.trace.as_deref())
+                    }
+                })
+                .collect()
+        };
+        let mut summarizer = Summarizer::default();
+        let mut closing = None;
+        'send: loop {
+            let due = summarizer.next_due();
+            let frames = tokio::select! {
+                biased;
+                Some(frames) = replay_rx.recv() => frames,
+                Some(frame) = close_rx.recv() => {
+                    closing = Some(frame);
+                    Vec::new()
+                }
+                message = rx.recv() => match message {
+                    Ok(message) => {
+                        closing = match &message {
+                            ServerMessage::SessionTakenOver { .. } => Some(CloseFrame {
+                                code: CloseCode::Normal,
+                                reason: "Taken over by another connection".into(),
+                            }),
+                            ServerMessage::ServerShutdown { reconnect } => Some(CloseFrame {
+                                code: CloseCode::Restart,
+                                reason: reconnect.close_reason().into(),
+                            }),
+                            ServerMessage::MoveTo { reconnect, .. } => Some(CloseFrame {
+                                code: CloseCode::Away,
+                                reason: reconnect.close_reason().into(),
+                            }),
+                            _ => None,
+                        };
+                        if let ServerMessage::Degraded { reason } = message {
+                            sender_state.degradations.record(reason);
+                        }
+                        let delivery = follow_delivery(&mut sender_delivery, &mut summarizer);
+                        let sound = delivery.sounds.then(|| sound::after(&message)).flatten();
+                        let mut messages = summarizer.pass(message, Instant::now());
+                        messages.extend(sound);
+                        render(messages, &delivery)
+                    }
+                    // The room notices and sends a snapshot once the queue
+                    // has drained.
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        sender_traffic.dropped(missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                notice = notices.recv() => match notice {
+                    Ok(notice) => {
+                        let delivery = follow_delivery(&mut sender_delivery, &mut summarizer);
+                        render(vec![notice], &delivery)
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                () = tokio::time::sleep_until(due.unwrap_or_else(Instant::now).into()),
+                    if due.is_some() => {
+                        let delivery = follow_delivery(&mut sender_delivery, &mut summarizer);
+                        render(summarizer.flush_due(Instant::now()), &delivery)
+                    }
+            };
+            for json in frames {
+                sender_traffic.sent(json.len());
+                sender_state.traffic.sent(json.len());
+                let message = match deflate {
+                    Some(deflate) => deflate.message(json),
+                    None => Message::Text(json),
+                };
+                if ws_sender.send(message).await.is_err() {
+                    break 'send;
+                }
+            }
+            if let Some(frame) = closing.take() {
+                let _ = ws_sender.send(Message::Close(Some(frame))).await;
+                break;
+            }
+        }
+    });
+
+    let mut sending = true;
+    let mut shutdown = state.shutdown.clone();
+    let mut shutting_down = false;
+    loop {
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            () = &mut shutdown, if !shutting_down => {
+                shutting_down = true;
+                let resume_token = (!room_id.is_empty())
+                    .then(|| reconnect::resume_token(&room_id, &participant_id));
+                let reconnect = ReconnectHint::new(&state.config().reconnect, 0, resume_token);
+                let _ = tx.send(ServerMessage::ServerShutdown { reconnect });
+                continue;
+            }
+            sent = &mut sender_task, if sending => {
+                sending = false;
+                match sent {
+                    // Nothing more would reach the client, so it leaves.
+                    Err(err) if err.is_panic() => {
+                        error!(
+                            "Connection sender panicked: {}",
+                            panic_message(err.into_panic().as_ref())
+                        );
+                        abnormal = Some(CloseCause::Panic);
+                        closed = true;
+                        break;
+                    }
+                    _ => continue,
+                }
+            },
+        };
+        if let Some(delay) = strikes.delay(&state.config().tarpit) {
+            tokio::time::sleep(delay).await;
+        }
+        if let Ok(msg @ (Message::Text(_) | Message::Binary(_))) = &msg {
+            traffic.received(msg.len());
+            state.traffic.received(msg.len());
+        }
+        match msg {
+            Ok(Message::Text(text)) => {
+                #[cfg(feature = "testing")]
+                testing::maybe_panic(&state, &room_id, &text);
+                if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
+                    match client_msg {
+                        ClientMessage::NewRoom {
+                            socket_id,
+                            locale,
+                            layout,
+                            format,
+                            granularity,
+                            sounds,
+                            trace_id,
+                        } => {
+                            let config = state.config().capabilities;
+                            delivery.send_replace(Delivery::new(
+                                &config,
+                                format,
+                                granularity,
+                                sounds,
+                                trace_id,
+                            ));
+                            if let Some(message) = state.maintenance_message() {
+                                let _ = tx.send(ServerMessage::Error { message });
+                                continue;
+                            }
+                            let new_id = cluster::new_room_id(state.cluster.as_ref());
+                            if let Some(grant) = &grant {
+                                if !grant.can_create || !grant.allows(&new_id) {
+                                    let _ = tx.send(ServerMessage::Error {
+                                        message: "Your token does not allow creating rooms."
+                                            .to_string(),
+                                    });
+                                    continue;
+                                }
+                            }
+                            participant_id =
+                                match claim_participant_id(socket_id, verified_id.as_deref()) {
+                                    Ok(id) => id,
+                                    Err(err) => {
+                                        let _ = tx.send(ServerMessage::Error { message: err });
+                                        continue;
+                                    }
+                                };
+                            if let Err(message) = invite::admit(&state, invite.as_deref()) {
+                                let _ = tx.send(ServerMessage::Error { message });
+                                continue;
+                            }
+                            room_id = new_id;
+                            let shortcode = shortlink::fresh(&state).await;
+
+                            let mut room = Room::new(room_id.clone());
+                            room.shortcode = Some(shortcode);
+                            room.configure(&state.config());
+                            if let Err(err) = room.join(
+                                participant_id.clone(),
+                                tx.clone(),
+                                traffic.clone(),
+                                retransmit.clone(),
+                            ) {
+                                let _ = tx.send(ServerMessage::RoomIsCrowded { message: err });
+                                continue;
+                            }
+                            room.set_locale(&participant_id, joining_locale(&tx, locale, layout));
+                            let token = room.issue_creator_token();
+
+                            room.notify_participants();
+                            if !state.rooms.open(room, store_opened(&state)) {
+                                error!("Room {} was created twice", room_id);
+                                room_id.clear();
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "Failed to create the room; try again.".to_string(),
+                                });
+                                continue;
+                            }
+                            state.audit.record(AuditEvent::RoomCreated {
+                                room: room_id.clone(),
+                                participant: participant_id.clone(),
+                                ip: client_ip,
+                            });
+                            let _ = tx.send(ServerMessage::CreatorToken {
+                                room: room_id.clone(),
+                                token,
+                            });
+                        }
+                        ClientMessage::FetchRoom {
+                            id,
+                            socket_id,
+                            resume_token,
+                            last_seq,
+                            resume_secret,
+                            locale,
+                            layout,
+                            format,
+                            granularity,
+                            sounds,
+                            trace_id,
+                        } => {
+                            let (id, socket_id) = match resume_token
+                                .as_deref()
+                                .and_then(reconnect::parse_resume_token)
+                            {
+                                Some((id, participant_id)) => (id, Some(participant_id)),
+                                None => (id, socket_id),
+                            };
+                            let config = state.config().capabilities;
+                            delivery.send_replace(Delivery::new(
+                                &config,
+                                format,
+                                granularity,
+                                sounds,
+                                trace_id,
+                            ));
+                            let elsewhere = state
+                                .cluster
+                                .as_ref()
+                                .is_some_and(|cluster| !auth.from_peer && !cluster.owns(&id));
+                            if elsewhere {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: format!(
+                                        "Room {} is served by another node; connect with ?room={}.",
+                                        id, id
+                                    ),
+                                });
+                                continue;
+                            }
+                            if honeypot::is_decoy(&state.config(), &id) {
+                                if honeypot::touched(&state, client_ip, &id) {
+                                    abnormal = Some(CloseCause::Honeypot);
+                                    closed = true;
+                                    break;
+                                }
+                                strikes.suspect(&state.config().tarpit);
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "No such room.".to_string(),
+                                });
+                                continue;
+                            }
+                            // A terminal can't pick up from a `seq` it never saw.
+                            let last_seq = last_seq.filter(|_| format == StreamFormat::Json);
+                            if grant.as_ref().is_some_and(|grant| !grant.allows(&id)) {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "Your token does not allow joining this room."
+                                        .to_string(),
+                                });
+                                continue;
+                            }
+                            participant_id =
+                                match claim_participant_id(socket_id, verified_id.as_deref()) {
+                                    Ok(id) => id,
+                                    Err(err) => {
+                                        let _ = tx.send(ServerMessage::Error { message: err });
+                                        continue;
+                                    }
+                                };
+                            room_id = id;
+
+                            if let Some(room) = state.rooms.get(&room_id) {
+                                let rejoined = room
+                                    .call({
+                                        let state = state.clone();
+                                        let participant_id = participant_id.clone();
+                                        let identified = verified_id.is_some();
+                                        let connection = connection.clone();
+                                        move |room| {
+                                            rejoin(
+                                                &state,
+                                                room,
+                                                &participant_id,
+                                                identified,
+                                                resume_secret.as_deref(),
+                                                last_seq,
+                                                &connection,
+                                            )
+                                        }
+                                    })
+                                    .await;
+                                match rejoined {
+                                    Some(Rejoin::Replay(frames)) => {
+                                        let _ = replay_tx.send(frames);
+                                        continue;
+                                    }
+                                    Some(Rejoin::Rejoined) => continue,
+                                    Some(Rejoin::Refused(message)) => {
+                                        room_id.clear();
+                                        let _ = tx.send(ServerMessage::Error { message });
+                                        continue;
+                                    }
+                                    // Gone since, or joins below as usual.
+                                    Some(Rejoin::Join) | None => {}
+                                }
+                            }
+
+                            let may_create = grant.as_ref().is_none_or(|grant| grant.can_create);
+                            enter_room(
+                                &state,
+                                &connection,
+                                &room_id,
+                                &participant_id,
+                                may_create,
+                                invite.as_deref(),
+                                joining_locale(&tx, locale, layout),
+                            )
+                            .await;
+                        }
+                        ClientMessage::KeyPress {
+                            key,
+                            cursor_pos,
+                            rev,
+                        } => {
+                            if !is_valid_key(&key) {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "Keys are a named key or one character.".to_string(),
+                                });
+                                if tarpit::strike(&state, &mut strikes, client_ip, "invalid_key") {
+                                    abnormal = Some(CloseCause::RateLimit);
+                                    closed = true;
+                                    break;
+                                }
+                                continue;
+                            }
+                            if !key_rate.allow(&state.config().limits) {
+                                if !key_rate.warned {
+                                    key_rate.warned = true;
+                                    let _ = tx.send(ServerMessage::Error {
+                                        message: "You're typing too fast; some keys were dropped."
+                                            .to_string(),
+                                    });
+                                }
+                                if tarpit::strike(&state, &mut strikes, client_ip, "rate_limit") {
+                                    abnormal = Some(CloseCause::RateLimit);
+                                    closed = true;
+                                    break;
+                                }
+                                continue;
+                            }
+                            press_key(
+                                &state,
+                                &tx,
+                                &room_id,
+                                &participant_id,
+                                &key,
+                                cursor_pos,
+                                rev,
+                            )
+                            .await;
+                        }
+                        ClientMessage::UpdateRoomSettings { settings } => {
+                            if let Some(room) = state.rooms.get(&room_id) {
+                                let state = state.clone();
+                                let tx = tx.clone();
+                                let participant_id = participant_id.clone();
+                                room.cast(move |room| match room.update_settings(&tx, settings) {
+                                    Ok(()) => {
+                                        room.save_settings(&state);
+                                        turns::schedule(&state, room);
+                                        state.audit.record(AuditEvent::SettingsChanged {
+                                            room: room.id.clone(),
+                                            participant: participant_id,
+                                        });
+                                    }
+                                    Err(err) => {
+                                        let _ = tx.send(ServerMessage::Error { message: err });
+                                    }
+                                });
+                            }
+                        }
+                        ClientMessage::Freeze {
+                            socket_id: ref target,
+                        }
+                        | ClientMessage::Unfreeze {
+                            socket_id: ref target,
+                        } => {
+                            let frozen = matches!(client_msg, ClientMessage::Freeze { .. });
+                            let target = target.clone();
+                            if let Some(room) = state.rooms.get(&room_id) {
+                                let tx = tx.clone();
+                                room.cast(move |room| match room.freeze(&tx, &target, frozen) {
+                                    Ok(()) => room.notify_participants(),
+                                    Err(message) => {
+                                        let _ = tx.send(ServerMessage::Error { message });
+                                    }
+                                });
+                            }
+                        }
+                        ClientMessage::SetPrompt { prompt } => {
+                            if let Some(room) = state.rooms.get(&room_id) {
+                                let state = state.clone();
+                                let tx = tx.clone();
+                                let participant_id = participant_id.clone();
+                                room.cast(move |room| match room.set_prompt(Some(&tx), prompt) {
+                                    Ok(()) => {
+                                        room.save_settings(&state);
+                                        state.audit.record(AuditEvent::SettingsChanged {
+                                            room: room.id.clone(),
+                                            participant: participant_id,
+                                        });
+                                    }
+                                    Err(message) => {
+                                        let _ = tx.send(ServerMessage::Error { message });
+                                    }
+                                });
+                            }
+                        }
+                        ClientMessage::ClaimOwner { token } => {
+                            if let Some(room) = state.rooms.get(&room_id) {
+                                let tx = tx.clone();
+                                room.cast(move |room| match room.claim_owner(&tx, &token) {
+                                    Ok(()) => {
+                                        let _ = tx.send(ServerMessage::ServerNotice {
+                                            message: "You have the owner's rights again."
+                                                .to_string(),
+                                        });
+                                    }
+                                    Err(message) => {
+                                        let _ = tx.send(ServerMessage::Error { message });
+                                    }
+                                });
+                            }
+                        }
+                        ClientMessage::StartRace { text } => {
+                            if let Some(room) = state.rooms.get(&room_id) {
+                                let tx = tx.clone();
+                                room.cast(move |room| {
+                                    if let Err(message) = room.start_race(&tx, text) {
+                                        let _ = tx.send(ServerMessage::Error { message });
+                                    }
+                                });
+                            }
+                        }
+                        ClientMessage::EndRace => {
+                            if let Some(room) = state.rooms.get(&room_id) {
+                                let tx = tx.clone();
+                                room.cast(move |room| {
+                                    if let Err(message) = room.end_race(Some(&tx)) {
+                                        let _ = tx.send(ServerMessage::Error { message });
+                                    }
+                                });
+                            }
+                        }
+                        ClientMessage::StartTimer { seconds, lock } => {
+                            let Some(room) = state.rooms.get(&room_id) else {
+                                continue;
+                            };
+                            let sender = tx.clone();
+                            let started = room
+                                .call(move |room| room.start_timer(&sender, seconds, lock))
+                                .await;
+                            match started {
+                                Some(Ok(Some(started))) => {
+                                    tokio::spawn(timer::run_out(
+                                        state.clone(),
+                                        room_id.clone(),
+                                        started.number,
+                                        Duration::from_secs(seconds),
+                                    ));
+                                }
+                                Some(Err(message)) => {
+                                    let _ = tx.send(ServerMessage::Error { message });
+                                }
+                                Some(Ok(None)) | None => {}
+                            }
+                        }
+                        ClientMessage::SearchHistory { query, regex } => {
+                            if !state.config().capabilities.search {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "Search is turned off on this server.".to_string(),
+                                });
+                                continue;
+                            }
+                            let joined = match state.rooms.get(&room_id) {
+                                Some(room) => {
+                                    let tx = tx.clone();
+                                    room.call(move |room| room.has_connection(&tx))
+                                        .await
+                                        .unwrap_or(false)
+                                }
+                                None => false,
+                            };
+                            if !joined {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "Join a room to search it.".to_string(),
+                                });
+                                continue;
+                            }
+                            let matcher = match search::Matcher::new(&query, regex) {
+                                Ok(matcher) => matcher,
+                                Err(err) => {
+                                    let _ = tx.send(ServerMessage::Error { message: err });
+                                    continue;
+                                }
+                            };
+                            match search::room(&state, &room_id, &matcher).await {
+                                Ok(results) => {
+                                    let _ =
+                                        tx.send(ServerMessage::SearchResults { query, results });
+                                }
+                                Err(err) => {
+                                    error!("Failed to search room {}: {}", room_id, err);
+                                }
+                            }
+                        }
+                        ClientMessage::Report { reason } => {
+                            if let Err(message) = report::valid_reason(&reason) {
+                                let _ = tx.send(ServerMessage::Error { message });
+                                continue;
+                            }
+                            let view = match state.rooms.get(&room_id) {
+                                Some(room) => {
+                                    let tx = tx.clone();
+                                    let participant_id = participant_id.clone();
+                                    room.call(move |room| {
+                                        room.has_connection(&tx)
+                                            .then(|| room.render(&participant_id))
+                                    })
+                                    .await
+                                    .flatten()
+                                }
+                                None => None,
+                            };
+                            let Some(view) = view else {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "Join a room to report it.".to_string(),
+                                });
+                                continue;
+                            };
+                            if let Err(message) = state.reports.admit(client_ip, &participant_id) {
+                                let _ = tx.send(ServerMessage::Error { message });
+                                continue;
+                            }
+                            warn!("Room {} reported by {}", room_id, participant_id);
+                            state.audit.record(AuditEvent::Reported {
+                                room: room_id.clone(),
+                                participant: Some(participant_id.clone()),
+                                ip: client_ip,
+                                reason,
+                                snapshot: report::Snapshot::of(&view),
+                            });
+                            let _ = tx.send(ServerMessage::ServerNotice {
+                                message: "Thanks; your report was passed on to the operator."
+                                    .to_string(),
+                            });
+                        }
+                        ClientMessage::GetPrefs => {
+                            // A participant id is public; preferences, such as
+                            // where mentions are pushed, take a verified identity.
+                            let Some(identity) = verified_id.clone() else {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: PREFS_NEED_IDENTITY.to_string(),
+                                });
+                                continue;
+                            };
+                            match state.store.load_prefs(&identity).await {
+                                Ok(prefs) => {
+                                    let _ = tx.send(ServerMessage::Prefs {
+                                        prefs: prefs.unwrap_or_default(),
+                                    });
+                                }
+                                Err(err) => {
+                                    error!("Failed to load prefs for {}: {}", identity, err);
+                                }
+                            }
+                        }
+                        ClientMessage::SetPrefs { prefs } => {
+                            let Some(identity) = verified_id.clone() else {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: PREFS_NEED_IDENTITY.to_string(),
+                                });
+                                continue;
+                            };
+                            match state.store_writer.update_prefs(identity, prefs).await {
+                                Ok(prefs) => {
+                                    let _ = tx.send(ServerMessage::Prefs { prefs });
+                                }
+                                Err(message) => {
+                                    let _ = tx.send(ServerMessage::Error { message });
+                                }
+                            }
+                        }
+                        ClientMessage::Ack { seq } => retransmit.lock().unwrap().ack(seq),
+                        ClientMessage::ReviveRoom { id } => {
+                            if grant.as_ref().is_some_and(|grant| !grant.allows(&id)) {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "Your token does not allow joining this room."
+                                        .to_string(),
+                                });
+                                continue;
+                            }
+                            if let Some(message) = state.maintenance_message() {
+                                let _ = tx.send(ServerMessage::Error { message });
+                                continue;
+                            }
+                            match archived::revive(&state, &id).await {
+                                Ok(()) => {
+                                    state.audit.record(AuditEvent::RoomRevived {
+                                        room: id.clone(),
+                                        ip: client_ip,
+                                    });
+                                    let _ = tx.send(ServerMessage::Revived { room: id });
+                                }
+                                Err(message) => {
+                                    let _ = tx.send(ServerMessage::Error { message });
+                                }
+                            }
+                        }
+                        ClientMessage::Hello => {
+                            let _ = tx.send(ServerMessage::HelloAck {
+                                protocol_version: PROTOCOL_VERSION,
+                                capabilities: capability::advertised(&state.config()),
+                            });
+                        }
+                        ClientMessage::GetChallenge => {
+                            let nonce = identity::new_challenge();
+                            challenge = Some(nonce.clone());
+                            let _ = tx.send(ServerMessage::Challenge { challenge: nonce });
+                        }
+                        ClientMessage::Authenticate {
+                            public_key,
+                            signature,
+                        } => {
+                            if !participant_id.is_empty() || verified_id.is_some() {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "Authenticate once, before joining a room."
+                                        .to_string(),
+                                });
+                                continue;
+                            }
+                            // A challenge is good for one attempt only.
+                            let Some(nonce) = challenge.take() else {
+                                let _ = tx.send(ServerMessage::Error {
+                                    message: "Request a challenge first.".to_string(),
+                                });
+                                continue;
+                            };
+                            match identity::verify(&nonce, &public_key, &signature) {
+                                Ok(id) => {
+                                    info!("Connection authenticated as {}", id);
+                                    verified_id = Some(id.clone());
+                                    let _ = tx.send(ServerMessage::Authenticated { identity: id });
+                                }
+                                Err(err) => {
+                                    security::log(
+                                        client_ip,
+                                        security::Event::AuthFailed {
+                                            credential: "signature",
+                                        },
+                                    );
+                                    let _ = tx.send(ServerMessage::Error { message: err });
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    let _ = tx.send(ServerMessage::Error {
+                        message: "That isn't a message this server understands.".to_string(),
+                    });
+                    if tarpit::strike(&state, &mut strikes, client_ip, "malformed_message") {
+                        abnormal = Some(CloseCause::RateLimit);
+                        closed = true;
+                        break;
+                    }
+                }
+            }
+            // No client message is binary.
+            Ok(Message::Binary(_)) => {
+                violations += 1;
+                warn!(
+                    "Connection from {:?} sent a binary message (violation {} of {})",
+                    client_ip, violations, MAX_VIOLATIONS
+                );
+                let _ = tx.send(ServerMessage::Error {
+                    message: "Messages are JSON, sent as text.".to_string(),
+                });
+                if tarpit::strike(&state, &mut strikes, client_ip, "binary_message") {
+                    abnormal = Some(CloseCause::RateLimit);
+                    closed = true;
+                    break;
+                }
+                if violations >= MAX_VIOLATIONS {
+                    let reason = "binary_message";
+                    security::log(client_ip, security::Event::Disconnected { reason });
+                    abnormal = Some(CloseCause::ProtocolError);
+                    closed = true;
+                    break;
+                }
+            }
+            Ok(Message::Close(_)) => {
+                closed = true;
+                break;
+            }
+            Err(err) => {
+                abnormal = close_cause(&err);
+                if matches!(
+                    abnormal,
+                    Some(CloseCause::ProtocolError | CloseCause::Oversize)
+                ) {
+                    violations += 1;
+                    warn!(
+                        "Dropping connection from {:?} after {} violation(s): {}",
+                        client_ip, violations, err
+                    );
+                    let reason = "protocol_violation";
+                    // Logged by the tarpit if it was the last strike.
+                    if !tarpit::strike(&state, &mut strikes, client_ip, reason) {
+                        security::log(client_ip, security::Event::Disconnected { reason });
+                    }
+                    closed = true;
+                }
+                break;
+            }
+            _ => {}
+        }
+    }
+    if let Some(cause) = abnormal {
+        state.closes.record(cause);
+        if let Some(frame) = close_frame(&state, cause, client_ip) {
+            let _ = close_tx.send(frame);
+            if sending {
+                let _ = tokio::time::timeout(CLOSE_WAIT, &mut sender_task).await;
+            }
+        }
+    }
+    // Whoever is here when the server stops keeps their place, for the
+    // snapshot and their resume.
+    if shutting_down {
+        closed = false;
+    }
+
+    {
+        // A connection closed on purpose is leaving; one that dropped may be
+        // back in a moment.
+        let grace = if closed {
+            0
+        } else {
+            state.config().rooms.resume_grace_secs
+        };
+        if let Some(room) = state.rooms.get(&room_id) {
+            let parked = {
+                let state = state.clone();
+                let participant_id = participant_id.clone();
+                let tx = tx.clone();
+                room.call(move |room| {
+                    if grace > 0 && room.park(&tx) {
+                        return true;
+                    }
+                    depart(&state, room, &participant_id, &tx);
+                    false
+                })
+                .await
+                .unwrap_or(false)
+            };
+            if parked {
+                debug!(
+                    "Holding {}'s place in room {} for {} seconds",
+                    participant_id, room_id, grace
+                );
+                let state = state.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_secs(grace)).await;
+                    room.cast(move |room| {
+                        if room.is_parked(&participant_id, &retransmit) {
+                            depart(&state, room, &participant_id, &tx);
+                        }
+                    });
+                });
+            }
+        }
+    }
+
+    sender_task.abort();
+}
+
+/// The close frame for a connection the server closes for `cause`, with
+/// when to come back: not before the address's ban is over, if it has one.
+fn close_frame(
+    state: &AppState,
+    cause: CloseCause,
+    ip: Option<IpAddr>,
+) -> Option<CloseFrame<'static>> {
+    let code = match cause {
+        CloseCause::RateLimit | CloseCause::Honeypot => CloseCode::Policy,
+        CloseCause::ProtocolError => CloseCode::Protocol,
+        CloseCause::Oversize => CloseCode::Size,
+        CloseCause::Timeout | CloseCause::Reset | CloseCause::Panic => return None,
+    };
+    let banned = ip
+        .and_then(|ip| state.bans.remaining(ip))
+        .unwrap_or_default();
+    let at_least_ms = u64::try_from(banned.as_millis()).unwrap_or(u64::MAX);
+    let hint = ReconnectHint::new(&state.config().reconnect, at_least_ms, None);
+    Some(CloseFrame {
+        code,
+        reason: hint.close_reason().into(),
+    })
+}
+
+/// Why reading from a socket failed: the client breaking the protocol, such
+/// as by sending more than `[limits] max_message_bytes`, or the connection
+/// dropping. `None` if it was already closed.
+fn close_cause(err: &tungstenite::Error) -> Option<CloseCause> {
+    use std::io::ErrorKind;
+    match err {
+        tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake) => {
+            Some(CloseCause::Reset)
+        }
+        tungstenite::Error::Capacity(_) => Some(CloseCause::Oversize),
+        tungstenite::Error::Protocol(_) | tungstenite::Error::Utf8 => {
+            Some(CloseCause::ProtocolError)
+        }
+        // What `deflate::Inflate` refuses.
+        tungstenite::Error::Io(err) if deflate::is_too_large(err) => Some(CloseCause::Oversize),
+        tungstenite::Error::Io(err) => match err.kind() {
+            ErrorKind::InvalidData => Some(CloseCause::ProtocolError),
+            ErrorKind::TimedOut => Some(CloseCause::Timeout),
+            _ => Some(CloseCause::Reset),
+        },
+        _ => None,
+    }
+}
+
+/// The language hint a joining client sent. One that doesn't parse is
+/// reported, and the client joins without it.
+fn joining_locale(
+    sender: &broadcast::Sender<ServerMessage>,
+    locale: Option<String>,
+    layout: Option<String>,
+) -> Option<LocaleHint> {
+    LocaleHint::new(locale, layout).unwrap_or_else(|message| {
+        let _ = sender.send(ServerMessage::Error { message });
+        None
+    })
+}
+
+/// How a connection asked to be sent the room when it joined.
+#[derive(Debug, Clone, Default)]
+struct Delivery {
+    format: StreamFormat,
+    granularity: Granularity,
+    sounds: bool,
+    /// The client's `traceId`, quoted in `error`s.
+    trace: Option<String>,
+}
+
+impl Delivery {
+    /// What a joining client asked for, less what `[capabilities]` turns
+    /// off.
+    fn new(
+        config: &CapabilitiesConfig,
+        format: StreamFormat,
+        granularity: Granularity,
+        sounds: bool,
+        trace_id: Option<String>,
+    ) -> Self {
+        Self {
+            format,
+            granularity: if config.line_mode {
+                granularity
+            } else {
+                Granularity::Key
+            },
+            sounds: sounds && config.key_sounds,
+            trace: follow_trace(trace_id),
+        }
+    }
+}
+
+/// The `traceId` a joining client sent, if it is usable, added to the
+/// connection's span so that what is logged from now on carries it.
+fn follow_trace(trace_id: Option<String>) -> Option<String> {
+    let trace_id = trace_id.filter(|id| trace::valid_id(id))?;
+    Span::current().record("trace", trace_id.as_str());
+    Some(trace_id)
+}
+
+/// Starts holding back key presses as the connection last asked to when
+/// joining, if that changed; returns what it asked for.
+fn follow_delivery(
+    delivery: &mut watch::Receiver<Delivery>,
+    summarizer: &mut Summarizer,
+) -> Delivery {
+    if delivery.has_changed().unwrap_or(false) {
+        *summarizer = Summarizer::new(delivery.borrow_and_update().granularity);
+    }
+    delivery.borrow().clone()
+}
+
+/// A connection's channel and counters, as a room holds them.
+#[derive(Clone)]
+struct Connection {
+    sender: broadcast::Sender<ServerMessage>,
+    traffic: Arc<Counters>,
+    retransmit: Arc<Mutex<Retransmit>>,
+    ip: Option<IpAddr>,
+}
+
+/// What became of a connection fetching a room that is in memory.
+enum Rejoin {
+    /// It resumed and has these frames it missed to replay.
+    Replay(Vec<String>),
+    /// It resumed or took over, and was sent the room.
+    Rejoined,
+    /// It is already in the room as many times as it may be.
+    Refused(String),
+    /// It joins the room as anyone would.
+    Join,
+}
+
+/// Lets `participant_id` back into `room` on `connection` if the room held
+/// the place `resume_secret` was sent to, or takes over or refuses their other connection as
+/// `[rooms] duplicate_connections` says. `identified` participants may be
+/// on several devices whatever it says.
+fn rejoin(
+    state: &AppState,
+    room: &mut Room,
+    participant_id: &str,
+    identified: bool,
+    resume_secret: Option<&str>,
+    last_seq: Option<u64>,
+    connection: &Connection,
+) -> Rejoin {
+    let resumed = room.resume(
+        participant_id,
+        resume_secret,
+        last_seq,
+        connection.sender.clone(),
+        connection.traffic.clone(),
+        connection.retransmit.clone(),
+    );
+    match resumed {
+        Some(Resume::Replay(frames)) => {
+            info!(
+                "Socket {} resumed in room {}, replaying {} message(s)",
+                participant_id,
+                room.id,
+                frames.len()
+            );
+            return Rejoin::Replay(frames);
+        }
+        Some(Resume::Reload) => {
+            let room_view = room.render(participant_id);
+            let _ = connection.sender.send(ServerMessage::GotRoom {
+                room: Box::new(room_view),
+            });
+            return Rejoin::Rejoined;
+        }
+        None => {}
+    }
+    let config = state.config();
+    let policy = if identified {
+        DuplicatePolicy::Mirror
+    } else {
+        config.rooms.duplicate_connections
+    };
+    if policy == DuplicatePolicy::Mirror
+        && room.connections(participant_id) >= config.rooms.max_devices
+    {
+        return Rejoin::Refused(format!(
+            "You're already in this room on {} devices.",
+            config.rooms.max_devices
+        ));
+    }
+    if !room.connected(participant_id) {
+        return Rejoin::Join;
+    }
+    match policy {
+        DuplicatePolicy::Reject => {
+            Rejoin::Refused("You're already in this room from somewhere else.".to_string())
+        }
+        DuplicatePolicy::TakeOver => {
+            info!(
+                "Socket {} moved to a new connection in room {}",
+                participant_id, room.id
+            );
+            room.take_over(
+                participant_id,
+                connection.sender.clone(),
+                connection.traffic.clone(),
+                connection.retransmit.clone(),
+            );
+            let room_view = room.render(participant_id);
+            let _ = connection.sender.send(ServerMessage::GotRoom {
+                room: Box::new(room_view),
+            });
+            Rejoin::Rejoined
+        }
+        // Joins as one more connection.
+        DuplicatePolicy::Mirror => Rejoin::Join,
+    }
+}
+
+/// Joins `participant_id` to room `room_id` on `connection`, as one more
+/// connection if they are already there, bringing the room back from the
+/// store or, if `may_create` and `[rooms] creation` allows it with
+/// `invite`, creating it. Refusals are sent to the connection; returns
+/// whether it joined. `locale` is what they said they type in, if anything.
+async fn enter_room(
+    state: &SharedState,
+    connection: &Connection,
+    room_id: &str,
+    participant_id: &str,
+    may_create: bool,
+    invite: Option<&str>,
+    locale: Option<LocaleHint>,
+) -> bool {
+    let in_memory = state.rooms.contains(room_id);
+    let record = if in_memory {
+        None
+    } else {
+        match load_stored_room(state, room_id).await {
+            Ok(record) => record,
+            Err(err) => {
+                error!("Failed to load room {}: {}", room_id, err);
+                None
+            }
+        }
+    };
+    if let Some(room) = record
+        .as_ref()
+        .and_then(|(record, _)| archived::view(record, state.config().rooms.archive_grace_secs))
+    {
+        let _ = connection.sender.send(ServerMessage::Archived { room });
+        return false;
+    }
+    let creating = !in_memory && record.is_none();
+    let maintenance = state.maintenance_message();
+    if let (true, Some(message)) = (creating, &maintenance) {
+        let _ = connection.sender.send(ServerMessage::Error {
+            message: message.clone(),
+        });
+        return false;
+    }
+    if creating && !may_create {
+        let _ = connection.sender.send(ServerMessage::Error {
+            message: "Your token does not allow creating rooms.".to_string(),
+        });
+        return false;
+    }
+    if creating {
+        if let Err(message) = invite::admit(state, invite) {
+            let _ = connection.sender.send(ServerMessage::Error { message });
+            return false;
+        }
+    }
+
+    let shortcode = if creating {
+        Some(shortlink::fresh(state).await)
+    } else {
+        None
+    };
+    let mut creator_token = None;
+    let joined = match state.rooms.get(room_id) {
+        Some(room) => {
+            let state = state.clone();
+            let connection = connection.clone();
+            let participant_id = participant_id.to_string();
+            room.call(move |room| join(&state, room, &participant_id, &connection, locale))
+                .await
+                .unwrap_or_else(|| Err(closed_meanwhile()))
+        }
+        None => {
+            let mut room = match record {
+                Some((record, history)) => Room::from_record(record, history),
+                None => {
+                    let mut room = Room::new(room_id.to_string());
+                    creator_token = Some(room.issue_creator_token());
+                    room.shortcode = shortcode;
+                    room
+                }
+            };
+            room.configure(&state.config());
+            if let Some(refusal) = room.not_started(SystemTime::now()) {
+                let _ = connection.sender.send(refusal);
+                return false;
+            }
+            match room.join(
+                participant_id.to_string(),
+                connection.sender.clone(),
+                connection.traffic.clone(),
+                connection.retransmit.clone(),
+            ) {
+                Ok(()) => {
+                    room.set_locale(participant_id, locale);
+                    room.notify_participants();
+                    if state.rooms.open(room, store_opened(state)) {
+                        Ok(())
+                    } else {
+                        Err(closed_meanwhile())
+                    }
+                }
+                Err(message) => Err(ServerMessage::RoomIsCrowded { message }),
+            }
+        }
+    };
+    if let Err(refusal) = joined {
+        let _ = connection.sender.send(refusal);
+        return false;
+    }
+    state.audit.record(if creating {
+        AuditEvent::RoomCreated {
+            room: room_id.to_string(),
+            participant: participant_id.to_string(),
+            ip: connection.ip,
+        }
+    } else {
+        AuditEvent::Joined {
+            room: room_id.to_string(),
+            participant: participant_id.to_string(),
+            ip: connection.ip,
+        }
+    });
+    if let Some(token) = creator_token {
+        let _ = connection.sender.send(ServerMessage::CreatorToken {
+            room: room_id.to_string(),
+            token,
+        });
+    }
+    if let Some(message) = maintenance {
+        let _ = connection
+            .sender
+            .send(ServerMessage::ServerNotice { message });
+    }
+    true
+}
+
+/// Joins `participant_id` to `room`, which is in memory, on `connection`.
+/// The error is for the connection.
+fn join(
+    state: &AppState,
+    room: &mut Room,
+    participant_id: &str,
+    connection: &Connection,
+    locale: Option<LocaleHint>,
+) -> Result<(), ServerMessage> {
+    if let Some(refusal) = room.not_started(SystemTime::now()) {
+        return Err(refusal);
+    }
+    let colored = room.colors.contains_key(participant_id);
+    room.join(
+        participant_id.to_string(),
+        connection.sender.clone(),
+        connection.traffic.clone(),
+        connection.retransmit.clone(),
+    )
+    .map_err(|message| ServerMessage::RoomIsCrowded { message })?;
+    let relocated = room.set_locale(participant_id, locale);
+    if !colored || relocated {
+        state.store_writer.save(room.record());
+    }
+    state
+        .store_writer
+        .append_history(room.id.clone(), room.take_history());
+    room.notify_participants();
+    Ok(())
+}
+
+/// For a connection whose room was opened or closed by someone else while
+/// it was joining.
+fn closed_meanwhile() -> ServerMessage {
+    ServerMessage::Error {
+        message: "The room changed while you were joining; try again.".to_string(),
+    }
+}
+
+/// Types `key` on `sender`'s connection to room `room_id`. Returns false if
+/// the connection isn't in the room, or no longer types because it was taken
+/// over.
+async fn press_key(
+    state: &SharedState,
+    sender: &broadcast::Sender<ServerMessage>,
+    room_id: &str,
+    participant_id: &str,
+    key: &str,
+    cursor_pos: Option<usize>,
+    rev: Option<u64>,
+) -> bool {
+    let Some(room) = state.rooms.get(room_id) else {
+        return false;
+    };
+    let state = state.clone();
+    let sender = sender.clone();
+    let participant_id = participant_id.to_string();
+    let key = key.to_string();
+    room.call(move |room| {
+        if !room.has_connection(&sender) {
+            return false;
+        }
+        room.type_from(&sender, &participant_id, &key, cursor_pos, rev);
+        turns::schedule(&state, room);
+        state.governor.schedule(room, &state.config().limits);
+        state
+            .store_writer
+            .append_history(room.id.clone(), room.take_history());
+        for mention in room.take_mentions() {
+            mention::notify(&state, mention);
+        }
+        true
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// The first thing a new room's task does: stores the room and what was
+/// said in it so far.
+fn store_opened(state: &SharedState) -> impl FnOnce(&mut Room) + Send + 'static {
+    let state = state.clone();
+    move |room| {
+        state.store_writer.save(room.record());
+        state
+            .store_writer
+            .append_history(room.id.clone(), room.take_history());
+    }
+}
+
+/// Drops a connection that is gone for good, taking its participant out of
+/// the room if it was their last.
+fn depart(
+    state: &AppState,
+    room: &mut Room,
+    participant_id: &str,
+    sender: &broadcast::Sender<ServerMessage>,
+) {
+    if !room.disconnect(participant_id, sender) {
+        return;
+    }
+    state
+        .store_writer
+        .append_history(room.id.clone(), room.take_history());
+    if room.participants.is_empty() {
+        info!(
+            "Room {} is now empty, will be cleaned up in {} seconds",
+            room.id,
+            room.settings
+                .idle_ttl_secs
+                .unwrap_or(state.config().rooms.idle_ttl_secs)
+        );
+        slack::conversation_ended(state, room);
+    } else {
+        room.notify_participants();
+    }
+}
+
+/// Expires rooms that are past their age or idle time, archiving or deleting
+/// them, warns those close to their maximum age, and prunes other state
+/// that times out. Runs every `CLEANUP_INTERVAL_SECS`.
+async fn sweep(state: &SharedState) {
+    let config = state.config();
+    let archiving = config.rooms.archive_grace_secs > 0;
+    let now = SystemTime::now();
+    for (room_id, handle) in state.rooms.all() {
+        let config = config.clone();
+        let expired = handle
+            .close_if(move |room| {
+                room.configure(&config);
+                room.warn_of_expiry(now);
+                room.is_expired(now)
+            })
+            .await;
+        let Some(mut room) = expired else {
+            continue;
+        };
+        state.rooms.remove(&room_id, &handle);
+        rollup::save(state, &room_id, room.activity.take());
+        room.broadcast(
+            ServerMessage::Error {
+                message: "This room has expired.".to_string(),
+            },
+            None,
+        );
+        archive::spawn(state, &room);
+        if archiving {
+            state.store_writer.save(archived::record(&room, now));
+            info!("Archived expired room: {}", room_id);
+        } else {
+            state.store_writer.delete(room_id.clone());
+            info!("Cleaned up abandoned room: {}", room_id);
+        }
+        state
+            .audit
+            .record(AuditEvent::RoomExpired { room: room_id });
+    }
+
+    archived::purge(state);
+
+    if let Some(oidc) = &state.oidc {
+        oidc.prune();
+    }
+    state.bans.prune();
+}
+
+/// A room that isn't in memory but was kept by the store, with its history.
+async fn load_stored_room(
+    state: &AppState,
+    id: &str,
+) -> Result<Option<(RoomRecord, Vec<HistoryLine>)>, String> {
+    let Some(record) = state.store.load_room(id).await? else {
+        return Ok(None);
+    };
+    let history = state.store.load_history(id).await?;
+    Ok(Some((record, history)))
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    state: SharedState,
+    peer: Option<IpAddr>,
+) -> Result<Response<Body>, hyper::Error> {
+    let request_id = trace::request_id(&req);
+    let span = info_span!("request", id = %request_id);
+    let mut response = serve_request(req, state, peer, request_id.clone())
+        .instrument(span)
+        .await?;
+    response
+        .headers_mut()
+        .insert(trace::HEADER, trace::header_value(&request_id));
+    Ok(response)
+}
+
+async fn serve_request(
+    req: Request<Body>,
+    state: SharedState,
+    peer: Option<IpAddr>,
+    request_id: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let mut client_ip = ip::client_ip(&req, peer);
+    if state
+        .cluster
+        .as_ref()
+        .is_some_and(|cluster| cluster.is_peer(&req))
+    {
+        client_ip = ip::forwarded(&req).or(client_ip);
+    }
+    if let Some(ip) = client_ip {
+        if state.config().blocklist.blocks(ip) {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .unwrap());
+        }
+        if let Some(left) = state.bans.remaining(ip) {
+            return Ok(Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(hyper::header::RETRY_AFTER, left.as_secs().max(1))
+                .body(Body::empty())
+                .unwrap());
+        }
+    }
+    if let Some(response) = state.cors.preflight(&req) {
+        return Ok(response);
+    }
+    let origin = cors::origin(&req).map(str::to_string);
+    let is_api = req.uri().path().starts_with("/api/") || req.uri().path() == "/graphql";
+
+    let mut response = route_request(req, state.clone(), client_ip, request_id).await?;
+    if is_api {
+        state.cors.decorate(origin.as_deref(), &mut response);
+    }
+    Ok(response)
+}
+
+async fn route_request(
+    mut req: Request<Body>,
+    state: SharedState,
+    client_ip: Option<IpAddr>,
+    request_id: String,
+) -> Result<Response<Body>, hyper::Error> {
+    // For load balancers and orchestrators, which have no credentials.
+    if req.method() == Method::GET {
+        match req.uri().path() {
+            "/healthz" | "/health" => return Ok(health::liveness()),
+            "/readyz" => return Ok(health::readiness(&state).await),
+            _ => {}
+        }
+    }
+    // The admin API, the room API, GraphQL and embedding have their own
+    // credentials: the admin token, creator tokens, partner API keys to mint
+    // embed tokens, and the embed tokens themselves for the framed page and
+    // its socket.
+    if let Some(id) = req.uri().path().strip_prefix("/api/rooms/") {
+        if req.method() == Method::GET && !id.contains('/') {
+            let id = id.to_string();
+            return Ok(api::get_room(&req, &state, &id, client_ip).await);
+        }
+        if req.method() == Method::DELETE {
+            let id = id.to_string();
+            return Ok(api::delete_room(&req, &state, &id, client_ip).await);
+        }
+        if let Some(id) = id.strip_suffix("/search") {
+            if req.method() == Method::GET {
+                let id = id.to_string();
+                return Ok(api::search_room(&req, &state, &id, client_ip).await);
+            }
+        }
+        if let Some(id) = id.strip_suffix("/cast") {
+            if req.method() == Method::GET {
+                let id = id.to_string();
+                return Ok(api::export_cast(&req, &state, &id, client_ip).await);
+            }
+        }
+        if let Some(id) = id.strip_suffix("/keystrokes") {
+            if req.method() == Method::GET {
+                let id = id.to_string();
+                return Ok(api::export_keystrokes(&req, &state, &id, client_ip).await);
+            }
+        }
+        if let Some(id) = id.strip_suffix("/prompt") {
+            if req.method() == Method::PUT {
+                let id = id.to_string();
+                return Ok(api::set_prompt(req, &state, &id, client_ip).await);
+            }
+        }
+        if let Some(id) = id.strip_suffix("/invite-email") {
+            if req.method() == Method::POST {
+                let id = id.to_string();
+                return Ok(api::invite_email(req, &state, &id, client_ip).await);
+            }
+        }
+    }
+    #[cfg(feature = "graphql")]
+    if req.uri().path() == "/graphql" {
+        if let Some(config) = &state.config().graphql {
+            return Ok(graphql::handle(req, &state, config).await);
+        }
+    }
+    if req.uri().path().starts_with("/admin/") {
+        return Ok(match &state.admin {
+            Some(admin) => admin.route(req, &state, client_ip).await,
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap(),
+        });
+    }
+    if let Some(embed) = &state.embed {
+        if req.uri().path() == "/api/embed" {
+            return Ok(embed.issue(req, client_ip).await);
+        }
+    }
+    let embed_claims = match (&state.embed, embed::token_from(&req)) {
+        (Some(embed), Some(token)) => match embed.verify(&token) {
+            Ok(claims) => Some(claims),
+            Err(err) => {
+                let credential = "embed_token";
+                security::log(client_ip, security::Event::AuthFailed { credential });
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from(err))
+                    .unwrap());
+            }
+        },
+        _ => None,
+    };
+
+    let mut auth = UpgradeAuth {
+        ip: client_ip,
+        request_id,
+        from_peer: state
+            .cluster
+            .as_ref()
+            .is_some_and(|cluster| cluster.is_peer(&req)),
+        ..UpgradeAuth::default()
+    };
+    // An embed token stands in for `[access]` and OIDC only on its room's
+    // page and the socket, which its grant keeps to that room.
+    let embedded = req.method() == Method::GET
+        && embed_claims.as_ref().is_some_and(|claims| {
+            let path = req.uri().path();
+            path == "/ws" || path.strip_prefix('/') == Some(claims.room.as_str())
+        });
+    if !embedded {
+        if let Some(response) = state
+            .access
+            .as_ref()
+            .and_then(|gate| gate.check(&req, client_ip))
+        {
+            return Ok(response);
+        }
+
+        if let Some(oidc) = &state.oidc {
+            if let Some(response) = oidc.route(&req, client_ip).await {
+                return Ok(response);
+            }
+            auth.identity = oidc.authenticate(&req, client_ip).await;
+            if auth.identity.is_none() {
+                return Ok(login_required(&req));
+            }
+        }
+    }
+
+    if req.uri().path() == "/api/import" && req.method() == Method::POST {
+        return Ok(import::handle(req, &state, client_ip).await);
+    }
+    if req.uri().path() == "/api/report" && req.method() == Method::POST {
+        return Ok(api::report(req, &state, client_ip).await);
+    }
+    let uri = req.uri();
+
+    match uri.path() {
+        "/api/openapi.json" => return Ok(openapi::document()),
+        "/api/server-info" => return Ok(server_info::handle(&state)),
+        "/status" => return Ok(status::handle(&req, &state).await),
+        "/api/lan-peers" => return Ok(lan::peers(&state)),
+        "/api/docs" => return Ok(openapi::docs(false)),
+        "/api/docs.js" => return Ok(openapi::docs(true)),
+        _ => {}
+    }
+    if let Some(code) = uri.path().strip_prefix("/j/") {
+        return Ok(shortlink::redirect(&state, code).await);
+    }
+    if let Some(id) = uri
+        .path()
+        .strip_prefix("/api/rooms/")
+        .and_then(|path| path.strip_suffix("/calendar.ics"))
+    {
+        return Ok(schedule::calendar(&req, &state, id).await);
+    }
+
+    if uri.path() == "/ws" {
+        if !state.cors.upgrade_allowed(&req) {
+            info!(
+                "Rejected WebSocket upgrade from origin {:?}",
+                cors::origin(&req)
+            );
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .unwrap());
+        }
+        if let Some(claims) = &embed_claims {
+            auth.grant = Some(RoomGrant::for_room(&claims.room));
+        } else if let Some(jwt) = &state.jwt {
+            match jwt.check(&req) {
+                Ok(grant) => {
+                    auth.identity = auth.identity.or_else(|| grant.identity.clone());
+                    auth.grant = Some(grant);
+                }
+                Err(err) => {
+                    info!("Rejected WebSocket upgrade: {}", err);
+                    if jwt::token_from(&req).is_some() {
+                        let credential = "jwt";
+                        security::log(client_ip, security::Event::AuthFailed { credential });
+                    }
+                    return Ok(Response::builder()
+                        .status(StatusCode::UNAUTHORIZED)
+                        .body(Body::from(err))
+                        .unwrap());
+                }
+            }
+        }
+        auth.invite = req.uri().query().and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "invite")
+                .map(|(_, value)| value.into_owned())
+        });
+        if hyper_tungstenite::is_upgrade_request(&req) {
+            let config = state.config();
+            let Some(pass) = state.connections.enter(config.limits.max_connections) else {
+                info!("Refused WebSocket upgrade: the server is full");
+                return Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(hyper::header::RETRY_AFTER, 30)
+                    .body(Body::from("The server is full."))
+                    .unwrap());
+            };
+            if let Some(cluster) = &state.cluster {
+                if let Some(owner) = cluster.forward_to(&req) {
+                    return Ok(cluster.proxy(&state, req, &owner, client_ip, pass).await);
+                }
+            }
+            match deflate::upgrade(
+                &mut req,
+                &config.compression,
+                config.limits.max_message_bytes,
+            ) {
+                Ok((response, websocket)) => {
+                    let span = info_span!("connection", trace = tracing::field::Empty);
+                    tokio::spawn(handle_websocket(websocket, state, auth, pass).instrument(span));
+                    Ok(response)
+                }
+                Err(err) => Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(err))
+                    .unwrap()),
+            }
+        } else {
+            Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap())
+        }
+    } else if !state.config().gui.serve {
+        Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap())
+    } else if let Some(name) = assets::file_name(uri.path()) {
+        let mut response = assets::file(&state.config().gui, name).await;
+        state.security_headers.apply(&mut response, None);
+        Ok(response)
+    } else {
+        if let Some(claims) = &embed_claims {
+            if uri.path().trim_start_matches('/') != claims.room {
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("Embed token is for a different room."))
+                    .unwrap());
+            }
+        }
+        match assets::index(&state.config().gui, &req).await {
+            Some(mut response) => {
+                let frame_ancestors = embed_claims.as_ref().map(|claims| claims.origin.as_str());
+                state.security_headers.apply(&mut response, frame_ancestors);
+                Ok(response)
+            }
+            None => Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap()),
+        }
+    }
+}
+
+/// Browsers asking for a page are sent through the login flow; everything
+/// else (the WebSocket, assets, API calls) just gets a 401.
+fn login_required(req: &Request<Body>) -> Response<Body> {
+    let path = req.uri().path();
+    if path == "/ws" || path.starts_with("/gui") {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::empty())
+            .unwrap();
+    }
+    let return_to = req
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let location = format!(
+        "/auth/login?{}",
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("return_to", return_to)
+            .finish()
+    );
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header("location", location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Resolves for every clone once SIGINT or SIGTERM arrives, or shutdown is
+/// requested from within.
+type Shutdown = Shared<BoxFuture<'static, ()>>;
+
+async fn shutdown_signal(trigger: Arc<Notify>) {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+        _ = trigger.notified() => {}
+    }
+    info!("Shutting down");
+    systemd::notify("STOPPING=1");
+}
+
+/// Runs the app on every connection `incoming` yields, until shutdown.
+async fn serve<I>(incoming: I, state: SharedState) -> Result<(), hyper::Error>
+where
+    I: Accept,
+    I::Conn: PeerAddr + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let make_service = hyper::service::make_service_fn({
+        let state = state.clone();
+        move |conn: &I::Conn| {
+            let state = state.clone();
+            let peer = conn.peer_ip();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    handle_request(req, state.clone(), peer)
+                }))
+            }
+        }
+    });
+    Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(state.shutdown.clone())
+        .await
+}
+
+/// Everything the routes share, built from `config`. Background work that
+/// belongs to the state itself (the broadcast governor) is started here; the
+/// housekeeping loops are left to [`run`].
+async fn build_state(config: Config, log_filter: LogFilter) -> Result<SharedState, String> {
+    let http_client = http_client::new();
+    let oidc = match config.oidc.clone() {
+        Some(oidc_config) => match Oidc::discover(oidc_config, http_client.clone()).await {
+            Ok(oidc) => {
+                info!("OIDC login required for all routes");
+                Some(oidc)
+            }
+            Err(err) => return Err(format!("OIDC discovery failed: {}", err)),
+        },
+        None => None,
+    };
+
+    let access = config.access.as_ref().map(AccessGate::new).transpose()?;
+
+    let jwt = config.jwt.as_ref().map(JwtGate::new).transpose()?;
+
+    #[cfg(not(feature = "acme"))]
+    if config.acme.is_some() {
+        return Err("[acme] is configured but this build lacks the `acme` feature".to_string());
+    }
+
+    #[cfg(not(feature = "graphql"))]
+    if config.graphql.is_some() {
+        return Err(
+            "[graphql] is configured but this build lacks the `graphql` feature".to_string(),
+        );
+    }
+
+    #[cfg(not(feature = "grpc"))]
+    if config.grpc.is_some() {
+        return Err("[grpc] is configured but this build lacks the `grpc` feature".to_string());
+    }
+    #[cfg(not(feature = "discord"))]
+    if config.discord.is_some() {
+        return Err(
+            "[discord] is configured but this build lacks the `discord` feature".to_string(),
+        );
+    }
+    #[cfg(feature = "discord")]
+    let discord = config
+        .discord
+        .as_ref()
+        .map(discord::Discord::new)
+        .transpose()?;
+
+    let admin = config.admin.as_ref().map(Admin::new).transpose()?;
+    let cluster = config.cluster.as_ref().map(Cluster::new).transpose()?;
+    if admin.is_none() && config.rooms.creation != CreationPolicy::Open {
+        return Err(
+            "[rooms] creation other than \"open\" needs [admin] to create rooms or invite"
+                .to_string(),
+        );
+    }
+
+    let restored = match &config.snapshot {
+        Some(snapshot) => snapshot::load(&snapshot.path)?,
+        None => Vec::new(),
+    };
+
+    let shutdown_trigger = Arc::new(Notify::new());
+    let store = storage::open(&config.storage).await?;
+    let store_writer = StoreWriter::spawn(store.clone());
+    for room in &restored {
+        store_writer.save(room.record());
+    }
+    let state: SharedState = Arc::new(AppState {
+        rooms: Rooms::new(restored),
+        store_writer,
+        store,
+        access,
+        oidc,
+        jwt,
+        embed: config.embed.clone().map(Embed::new),
+        cors: config.cors.as_ref().map(Cors::new).unwrap_or_default(),
+        security_headers: SecurityHeaders::new(&config.security_headers),
+        shutdown: shutdown_signal(shutdown_trigger.clone()).boxed().shared(),
+        config: RwLock::new(Arc::new(config.clone())),
+        log_filter,
+        admin,
+        cluster,
+        lan: config.lan.as_ref().map(Lan::new),
+        maintenance: Mutex::new(None),
+        shutdown_trigger,
+        notices: broadcast::channel(16).0,
+        connections: ConnectionGate::default(),
+        audit: AuditLog::spawn(config.audit.as_ref()),
+        traffic: Counters::default(),
+        closes: Closes::default(),
+        degradations: Degradations::default(),
+        governor: Governor::default(),
+        snapshot: config
+            .snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.path.clone()),
+        archive: config
+            .archive
+            .clone()
+            .map(|archive| Archive::new(archive, http_client.clone())),
+        http_client,
+        invites: Invites::default(),
+        webhooks: Webhooks::default(),
+        outbox: Outbox::default(),
+        reports: Reports::default(),
+        #[cfg(feature = "discord")]
+        discord,
+        bans: Bans::default(),
+        started: Instant::now(),
+    });
+    tokio::spawn(governor::run(state.clone()));
+    tokio::spawn(link::run(state.clone()));
+    tokio::spawn(rollup::run(state.clone()));
+    tokio::spawn(webhook::run(state.clone()));
+    #[cfg(feature = "discord")]
+    tokio::spawn(discord::run(state.clone()));
+    Ok(state)
+}
+
+/// Runs the server, configured from the command line and environment, until
+/// it is shut down, on a runtime with the threads `[runtime]` asks for.
+pub fn run() {
+    let (filter, log_filter) = reload::Layer::new(
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    );
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let mut config = match Config::load_from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if let (Some(level), Err(_)) = (&config.logging.level, std::env::var("RUST_LOG")) {
+        match EnvFilter::try_new(level) {
+            Ok(filter) => {
+                let _ = log_filter.reload(filter);
+            }
+            Err(err) => {
+                error!("Invalid logging.level {:?}: {}", level, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let local = local::from_args();
+    if local {
+        local::configure(&mut config);
+    }
+    let selftest = match selftest::from_args() {
+        Ok(selftest) => selftest,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let runtime = match build_runtime(&config.runtime) {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    runtime.block_on(run_until_shutdown(config, log_filter, local, selftest));
+}
+
+fn build_runtime(config: &RuntimeConfig) -> Result<tokio::runtime::Runtime, String> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    match config.worker_threads {
+        Some(0) => return Err("runtime.worker_threads must be at least 1.".to_string()),
+        Some(threads) => {
+            builder.worker_threads(threads);
+        }
+        None => {}
+    }
+    match config.max_blocking_threads {
+        Some(0) => return Err("runtime.max_blocking_threads must be at least 1.".to_string()),
+        Some(threads) => {
+            builder.max_blocking_threads(threads);
+        }
+        None => {}
+    }
+    builder
+        .build()
+        .map_err(|err| format!("Failed to start the runtime: {}", err))
+}
+
+async fn run_until_shutdown(
+    config: Config,
+    log_filter: LogFilter,
+    local: bool,
+    selftest: Option<usize>,
+) {
+    let state = match build_state(config.clone(), log_filter).await {
+        Ok(state) => state,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let state_cleanup = state.clone();
+    tokio::spawn(Cluster::watch(state.clone()));
+    let lan_addr = config
+        .server
+        .binds(config.acme.is_some())
+        .iter()
+        .find_map(|addr| match Bind::parse(addr) {
+            Ok(Bind::Tcp(addr)) => Some(addr),
+            _ => None,
+        });
+    if let Some(addr) = lan_addr {
+        tokio::spawn(lan::run(state.clone(), addr));
+        if local {
+            local::open_room(&state, addr);
+        }
+    }
+    if let Some(pairs) = selftest {
+        tokio::spawn(selftest::run(state.clone(), pairs));
+    }
+
+    let state_reload = state.clone();
+    tokio::spawn(async move {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("failed to install SIGHUP handler");
+        while hangup.recv().await.is_some() {
+            reload_config(&state_reload);
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(CLEANUP_INTERVAL_SECS));
+
+        loop {
+            interval.tick().await;
+            sweep(&state_cleanup).await;
+        }
+    });
+
+    let binds = config.server.binds(config.acme.is_some());
+    let plain = listener::run(&binds, &config.server, state.clone());
+    #[cfg(feature = "grpc")]
+    let plain = async {
+        match &config.grpc {
+            Some(grpc_config) => {
+                tokio::try_join!(plain, grpc::run(grpc_config, state.clone())).map(|_| ())
+            }
+            None => plain.await,
+        }
+    };
+    #[cfg(feature = "acme")]
+    let result = match &config.acme {
+        Some(acme_config) => {
+            tokio::try_join!(plain, acme::run(acme_config, state.clone())).map(|_| ())
+        }
+        None => plain.await,
+    };
+    #[cfg(not(feature = "acme"))]
+    let result = plain.await;
+
+    // Upgraded connections outlive hyper's graceful shutdown; give them a
+    // moment to tell their clients when to come back.
+    let closing_since = std::time::Instant::now();
+    while state.connections.open() > 0 && closing_since.elapsed() < CLOSE_WAIT {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    rollup::flush(&state).await;
+    state.store_writer.settle().await;
+    snapshot::save_on_shutdown(&state).await;
+    if let Err(err) = result {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+}
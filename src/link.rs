@@ -0,0 +1,155 @@
+//! Per-connection choice between deltas and snapshots. A connection that
+//! falls behind, whether its queue backs up or messages to it are dropped,
+//! stops getting key presses and commits and is sent one full `gotRoom` once
+//! it has caught up. One that keeps falling behind gets only those periodic
+//! snapshots for a while, then is tried on deltas again. Either way the
+//! connection is told with a `degraded` message, so its user knows why
+//! others' typing arrives in jumps.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::SharedState;
+
+/// Messages waiting for a connection at which it stops getting deltas.
+const HIGH_WATER: usize = 16;
+/// A connection that missed deltas is sent a snapshot once no more than
+/// this many messages are waiting for it.
+const LOW_WATER: usize = 4;
+/// How often connections that missed deltas are checked, and so how often
+/// one on snapshots only is refreshed.
+const RESYNC_INTERVAL: Duration = Duration::from_millis(250);
+/// Falling behind this many times within `STRIKE_WINDOW` puts a connection
+/// on snapshots only for `SNAPSHOT_HOLD`.
+const STRIKES: usize = 3;
+const STRIKE_WINDOW: Duration = Duration::from_secs(30);
+const SNAPSHOT_HOLD: Duration = Duration::from_secs(30);
+
+/// Why a connection gets fewer updates than it would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+pub enum DegradedReason {
+    /// It fell behind; deltas are withheld until it is sent a snapshot.
+    FellBehind,
+    /// It kept falling behind, and gets only periodic snapshots for a while.
+    SnapshotsOnly,
+}
+
+impl DegradedReason {
+    pub const ALL: [Self; 2] = [Self::FellBehind, Self::SnapshotsOnly];
+
+    /// For metrics labels.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::FellBehind => "fell_behind",
+            Self::SnapshotsOnly => "snapshots_only",
+        }
+    }
+
+    /// For clients that only show text, such as gRPC's.
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::FellBehind => "Your connection is too slow; catching up.",
+            Self::SnapshotsOnly => "Your connection is too slow; updates are reduced for now.",
+        }
+    }
+}
+
+/// How a connection has been keeping up, as tracked by its room.
+#[derive(Debug, Default)]
+pub struct Link {
+    /// Deltas were dropped or withheld since the last snapshot.
+    stale: bool,
+    /// The connection's dropped-message count when last looked at.
+    dropped_seen: u64,
+    /// When it fell behind recently, oldest first.
+    strikes: VecDeque<Instant>,
+    snapshots_until: Option<Instant>,
+    /// Set when the connection fell behind, until it is told.
+    degraded: Option<DegradedReason>,
+}
+
+impl Link {
+    /// Whether a delta should go to a connection with `queued` messages
+    /// waiting and `dropped` lost so far. If not, it is due a snapshot.
+    pub fn accepts_delta(&mut self, queued: usize, dropped: u64, now: Instant) -> bool {
+        if dropped > self.dropped_seen || queued >= HIGH_WATER {
+            self.fall_behind(dropped, now);
+        } else if self.snapshots_only(now) {
+            self.stale = true;
+        }
+        !self.stale
+    }
+
+    /// Whether the connection missed deltas and has room for a snapshot now.
+    pub fn needs_snapshot(&mut self, queued: usize, dropped: u64, now: Instant) -> bool {
+        if dropped > self.dropped_seen {
+            self.fall_behind(dropped, now);
+        }
+        if !self.stale || queued > LOW_WATER {
+            return false;
+        }
+        self.stale = false;
+        true
+    }
+
+    /// The connection missed a delta without falling behind, so it is due a
+    /// snapshot all the same.
+    pub fn missed(&mut self) {
+        self.stale = true;
+    }
+
+    /// The connection was just sent the whole room.
+    pub fn synced(&mut self) {
+        self.stale = false;
+    }
+
+    /// Why the connection just started getting fewer updates, once.
+    pub fn take_degraded(&mut self) -> Option<DegradedReason> {
+        self.degraded.take()
+    }
+
+    pub fn snapshots_only(&self, now: Instant) -> bool {
+        self.snapshots_until.is_some_and(|until| now < until)
+    }
+
+    fn fall_behind(&mut self, dropped: u64, now: Instant) {
+        self.dropped_seen = dropped;
+        if self.stale || self.snapshots_only(now) {
+            return;
+        }
+        self.stale = true;
+        self.strikes.push_back(now);
+        while self
+            .strikes
+            .front()
+            .is_some_and(|&strike| now - strike > STRIKE_WINDOW)
+        {
+            self.strikes.pop_front();
+        }
+        if self.strikes.len() >= STRIKES {
+            self.strikes.clear();
+            self.snapshots_until = Some(now + SNAPSHOT_HOLD);
+            self.degraded = Some(DegradedReason::SnapshotsOnly);
+        } else {
+            self.degraded = Some(DegradedReason::FellBehind);
+        }
+    }
+}
+
+/// Sends snapshots to the connections that are due one.
+pub async fn run(state: SharedState) {
+    let mut interval = tokio::time::interval(RESYNC_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        for (_, room) in state.rooms.all() {
+            room.cast(move |room| room.resync(now));
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use futures_util::{future::try_join_all, stream};
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{info, warn};
+
+use crate::{
+    config::ServerConfig,
+    serve,
+    systemd::{self, Inherited},
+    SharedState,
+};
+
+/// Connections that know which address the client connected from.
+pub trait PeerAddr {
+    fn peer_ip(&self) -> Option<IpAddr>;
+}
+
+impl PeerAddr for AddrStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        Some(self.remote_addr().ip())
+    }
+}
+
+impl PeerAddr for UnixStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// Where the server accepts connections.
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Bind {
+    pub fn parse(addr: &str) -> Result<Self, String> {
+        match addr.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            Some(_) => Err("unix: bind address needs a path.".to_string()),
+            None => addr
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|err| format!("Invalid bind address {}: {}", addr, err)),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(AddrIncoming),
+    /// `path` is set when the socket file is ours to remove on shutdown.
+    Unix {
+        listener: UnixListener,
+        path: Option<PathBuf>,
+    },
+}
+
+/// Serves the app until a shutdown signal arrives, on the sockets systemd
+/// passed in if there are any and on `binds` otherwise.
+pub async fn run(
+    binds: &[String],
+    config: &ServerConfig,
+    state: SharedState,
+) -> Result<(), String> {
+    let inherited = systemd::listen_fds();
+    let listeners = if inherited.is_empty() {
+        binds
+            .iter()
+            .map(|addr| bind(&Bind::parse(addr)?, config.socket_mode))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        info!("Using {} socket(s) from systemd", inherited.len());
+        inherited
+            .into_iter()
+            .map(adopt)
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    systemd::notify("READY=1");
+
+    try_join_all(
+        listeners
+            .into_iter()
+            .map(|listener| serve_on(listener, state.clone())),
+    )
+    .await?;
+    Ok(())
+}
+
+fn bind(bind: &Bind, socket_mode: u32) -> Result<Listener, String> {
+    match bind {
+        Bind::Tcp(addr) => {
+            let incoming = bind_tcp(*addr)
+                .and_then(|listener| {
+                    AddrIncoming::from_listener(listener).map_err(std::io::Error::other)
+                })
+                .map_err(|err| format!("Failed to bind {}: {}", addr, err))?;
+            Ok(Listener::Tcp(incoming))
+        }
+        Bind::Unix(path) => Ok(Listener::Unix {
+            listener: bind_unix(path, socket_mode)?,
+            path: Some(path.clone()),
+        }),
+    }
+}
+
+fn adopt(inherited: Inherited) -> Result<Listener, String> {
+    let adopt_err = |err: std::io::Error| format!("Unusable socket from systemd: {}", err);
+    match inherited {
+        Inherited::Tcp(listener) => {
+            listener.set_nonblocking(true).map_err(adopt_err)?;
+            let listener = tokio::net::TcpListener::from_std(listener).map_err(adopt_err)?;
+            let incoming = AddrIncoming::from_listener(listener)
+                .map_err(|err| format!("Unusable socket from systemd: {}", err))?;
+            Ok(Listener::Tcp(incoming))
+        }
+        Inherited::Unix(listener) => {
+            listener.set_nonblocking(true).map_err(adopt_err)?;
+            Ok(Listener::Unix {
+                listener: UnixListener::from_std(listener).map_err(adopt_err)?,
+                path: None,
+            })
+        }
+    }
+}
+
+async fn serve_on(listener: Listener, state: SharedState) -> Result<(), String> {
+    match listener {
+        Listener::Tcp(incoming) => {
+            info!("Server running on http://{}", incoming.local_addr());
+            serve(incoming, state)
+                .await
+                .map_err(|err| format!("Server error: {}", err))
+        }
+        Listener::Unix { listener, path } => {
+            if let Some(path) = &path {
+                info!("Server running on unix:{}", path.display());
+            }
+            let result = serve(
+                hyper::server::accept::from_stream(unix_incoming(listener)),
+                state,
+            )
+            .await
+            .map_err(|err| format!("Server error: {}", err));
+            if let Some(path) = path {
+                if let Err(err) = std::fs::remove_file(&path) {
+                    warn!("Failed to remove {}: {}", path.display(), err);
+                }
+            }
+            result
+        }
+    }
+}
+
+/// IPv6 sockets are made v6-only so `0.0.0.0` and `[::]` can both be bound
+/// on the same port.
+fn bind_tcp(addr: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Binds a Unix socket at `path`, replacing a stale socket left behind by a
+/// previous run but refusing to take over one that is still being served.
+fn bind_unix(path: &Path, mode: u32) -> Result<UnixListener, String> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket.", path.display()));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(format!("{} is already in use.", path.display()));
+        }
+        std::fs::remove_file(path)
+            .map_err(|err| format!("Failed to remove stale {}: {}", path.display(), err))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|err| format!("Failed to bind {}: {}", path.display(), err))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .map_err(|err| format!("Failed to set permissions on {}: {}", path.display(), err))?;
+    Ok(listener)
+}
+
+fn unix_incoming(
+    listener: UnixListener,
+) -> impl futures_util::Stream<Item = Result<UnixStream, std::io::Error>> {
+    stream::unfold(listener, |listener| async {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(err) => {
+                    // Usually out of file descriptors; back off rather than spin.
+                    warn!("Accept failed: {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,78 @@
+//! `--local`: typeto as an instant pair-typing tool, with no config to
+//! write. Unless `--bind` says otherwise the server listens on localhost
+//! only, LAN discovery is on (to find others; a localhost server isn't
+//! advertised), and once it is up a room is opened and its URL printed with
+//! a QR code and opened in the browser.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    process::{Command, Stdio},
+};
+
+use tracing::{info, warn};
+
+use crate::{config::Config, generate_random_string, lan, qr, store_opened, Room, SharedState};
+
+const LOCAL_BIND: &str = "127.0.0.1:8090";
+
+/// Whether `--local` was given.
+pub fn from_args() -> bool {
+    std::env::args().skip(1).any(|arg| arg == "--local")
+}
+
+/// Adjusts `config` for `--local`.
+pub fn configure(config: &mut Config) {
+    if config.server.bind.is_empty() {
+        config.server.bind = vec![LOCAL_BIND.to_string()];
+    }
+    config.lan.get_or_insert_default();
+}
+
+/// Opens a room on the server listening on `addr` and shows how to join it.
+pub fn open_room(state: &SharedState, addr: SocketAddr) {
+    let mut room = Room::new(generate_random_string(6));
+    room.configure(&state.config());
+    let id = room.id.clone();
+    if !state.rooms.open(room, store_opened(state)) {
+        warn!("Couldn't open a room for --local");
+        return;
+    }
+    let host = match addr.ip() {
+        ip if ip.is_unspecified() => {
+            lan::local_ip().map_or(IpAddr::from([127, 0, 0, 1]), IpAddr::V4)
+        }
+        ip => ip,
+    };
+    let url = format!("http://{}/{}", SocketAddr::new(host, addr.port()), id);
+    info!("Room {} opened for --local", id);
+    println!("\nJoin the room at {}\n", url);
+    if let Some(code) = qr::render(&url) {
+        println!("{}", code);
+    }
+    open_browser(&url);
+}
+
+/// Opens `url` in the desktop's browser, if there is one.
+fn open_browser(url: &str) {
+    let mut command = if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else {
+        Command::new("xdg-open")
+    };
+    let opened = command
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match opened {
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(_) => info!("No browser to open; go to {} yourself", url),
+    }
+}
@@ -0,0 +1,82 @@
+//! Language and keyboard-layout hints, for a room and for each participant,
+//! so clients can lay out right-to-left text and transcripts can say what
+//! language a line is in. The server only checks their shape; it doesn't
+//! act on them beyond working out the writing direction.
+
+use serde::{Deserialize, Serialize};
+
+const MAX_LOCALE_LEN: usize = 35;
+const MAX_LAYOUT_LEN: usize = 32;
+
+/// Languages written right to left unless a script subtag says otherwise.
+const RTL_LANGUAGES: [&str; 12] = [
+    "ar", "arc", "dv", "fa", "he", "iw", "ku", "ps", "sd", "syr", "ug", "ur",
+];
+const RTL_SCRIPTS: [&str; 7] = ["adlm", "arab", "hebr", "nkoo", "rohg", "syrc", "thaa"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+pub struct LocaleHint {
+    /// A BCP 47 language tag, e.g. `he-IL` or `sr-Latn`.
+    pub locale: Option<String>,
+    /// The keyboard layout typed on, e.g. `dvorak` or `he-standard`.
+    pub layout: Option<String>,
+    /// Whether `locale` is written right to left.
+    pub rtl: bool,
+}
+
+impl LocaleHint {
+    /// A hint from what a client sent, or None if it sent neither part.
+    /// Empty parts count as absent.
+    pub fn new(locale: Option<String>, layout: Option<String>) -> Result<Option<Self>, String> {
+        let locale = locale.filter(|locale| !locale.is_empty());
+        let layout = layout.filter(|layout| !layout.is_empty());
+        if locale.is_none() && layout.is_none() {
+            return Ok(None);
+        }
+        if let Some(locale) = &locale {
+            if !valid_locale(locale) {
+                return Err(format!("{:?} is not a language tag.", locale));
+            }
+        }
+        if let Some(layout) = &layout {
+            if !valid_layout(layout) {
+                return Err(format!(
+                    "Keyboard layouts are up to {} letters, digits, '-', '_' or '.'.",
+                    MAX_LAYOUT_LEN
+                ));
+            }
+        }
+        Ok(Some(Self {
+            rtl: locale.as_deref().is_some_and(is_rtl),
+            locale,
+            layout,
+        }))
+    }
+}
+
+/// Letters and digits in `-`-separated subtags of up to 8, as in BCP 47.
+fn valid_locale(locale: &str) -> bool {
+    locale.len() <= MAX_LOCALE_LEN
+        && locale.split('-').all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+        && locale.as_bytes()[0].is_ascii_alphabetic()
+}
+
+fn valid_layout(layout: &str) -> bool {
+    layout.len() <= MAX_LAYOUT_LEN
+        && layout
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+fn is_rtl(locale: &str) -> bool {
+    let mut subtags = locale.split('-').map(str::to_ascii_lowercase);
+    let language = subtags.next().unwrap_or_default();
+    match subtags.next().filter(|subtag| subtag.len() == 4) {
+        Some(script) => RTL_SCRIPTS.contains(&script.as_str()),
+        None => RTL_LANGUAGES.contains(&language.as_str()),
+    }
+}
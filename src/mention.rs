@@ -0,0 +1,113 @@
+use hyper::{header::HeaderValue, Body, Method, Request};
+use tracing::{debug, warn};
+
+use crate::{short_id, ServerMessage, SharedState, UserPrefs};
+
+/// A participant named with `@` in a finished line.
+#[derive(Debug, Clone)]
+pub struct Mention {
+    pub room: String,
+    pub participant: String,
+    pub source: String,
+    pub line: String,
+}
+
+/// The ids out of `ids` that `line` mentions as `@` followed by the id or
+/// its first four characters, as the GUI shows it, in order and without
+/// repeats.
+pub fn find<'a>(line: &str, ids: &[&'a str]) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    for (at, _) in line.match_indices('@') {
+        // An @ inside a word, as in an email address, isn't a mention.
+        if line[..at]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_alphanumeric)
+        {
+            continue;
+        }
+        let name = line[at + 1..]
+            .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+            .next()
+            .unwrap_or_default();
+        if name.is_empty() {
+            continue;
+        }
+        for &id in ids {
+            if (id == name || short_id(id) == name) && !found.contains(&id) {
+                found.push(id);
+            }
+        }
+    }
+    found
+}
+
+/// Tells the mentioned participant, in the room and by push notification,
+/// in the background, unless they set do-not-disturb.
+pub fn notify(state: &SharedState, mention: Mention) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let prefs = match state.store.load_prefs(&mention.participant).await {
+            Ok(prefs) => prefs.unwrap_or_default(),
+            Err(err) => {
+                warn!("Failed to load prefs for {}: {}", mention.participant, err);
+                UserPrefs::default()
+            }
+        };
+        if prefs.dnd == Some(true) {
+            debug!("Not notifying {}: do not disturb", mention.participant);
+            return;
+        }
+        if let Some(room) = state.rooms.get(&mention.room) {
+            let participant = mention.participant.clone();
+            let message = ServerMessage::Mention {
+                room: mention.room.clone(),
+                source: mention.source.clone(),
+                line: mention.line.clone(),
+            };
+            room.cast(move |room| room.tell(&participant, message));
+        }
+        if let Some(url) = prefs.push_url {
+            push(&state, &mention, &url).await;
+        }
+    });
+}
+
+/// Posts `mention` to `url`, if `[mentions] push_hosts` allows its host.
+async fn push(state: &SharedState, mention: &Mention, url: &str) {
+    let Ok(uri) = url.parse::<hyper::Uri>() else {
+        return;
+    };
+    let allowed = uri.host().is_some_and(|host| {
+        state
+            .config()
+            .mentions
+            .push_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    });
+    if !allowed {
+        debug!("Not pushing to {}: host not in push_hosts", url);
+        return;
+    }
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("content-type", "text/plain; charset=utf-8");
+    // Room ids may hold what a header can't; the push goes without a title.
+    if let Ok(title) = HeaderValue::from_str(&format!("typeto.me room {}", mention.room)) {
+        request = request.header("title", title);
+    }
+    let request = request
+        .body(Body::from(format!(
+            "{}: {}",
+            short_id(&mention.source),
+            mention.line
+        )))
+        .unwrap();
+    match state.http_client.request(request).await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => warn!("Push to {} returned {}", url, response.status()),
+        Err(err) => warn!("Push to {} failed: {}", url, err),
+    }
+}
@@ -0,0 +1,106 @@
+//! Merging key presses when one participant types from several connections
+//! at once, e.g. the same identity on a laptop and a phone. Each edit to a
+//! participant's line bumps its revision, and clients say which revision
+//! their cursor position was taken against. A key press typed before edits
+//! from another device arrived has its position moved past those edits, in
+//! the order the server received them.
+
+use std::collections::VecDeque;
+
+use typeto_client_core::is_character;
+
+/// Edits kept per participant to rebase late key presses against.
+const KEPT_EDITS: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+enum Edit {
+    Insert(usize),
+    Delete(usize),
+    Truncate(usize),
+    Commit,
+}
+
+impl Edit {
+    /// The change `key` at `cursor_pos` makes to a line, as
+    /// `Room::handle_keypress` applies it.
+    fn of(key: &str, cursor_pos: Option<usize>) -> Option<Self> {
+        if key == "Enter" {
+            return Some(Self::Commit);
+        }
+        let pos = cursor_pos?;
+        match key {
+            "CtrlK" => Some(Self::Truncate(pos)),
+            "Delete" | "DeleteAt" => Some(Self::Delete(pos)),
+            "Backspace" => pos.checked_sub(1).map(Self::Delete),
+            "Space" => Some(Self::Insert(pos)),
+            _ if is_character(key) => Some(Self::Insert(pos)),
+            _ => None,
+        }
+    }
+
+    /// Where a cursor at `pos` is once this edit has been made.
+    fn shift(self, pos: usize) -> usize {
+        match self {
+            Self::Insert(at) if at <= pos => pos + 1,
+            Self::Delete(at) if at < pos => pos - 1,
+            Self::Truncate(at) => pos.min(at),
+            // Typing after another device finished the line starts the next.
+            Self::Commit => 0,
+            _ => pos,
+        }
+    }
+}
+
+/// Recent edits to one participant's line, with the connection that made
+/// each.
+#[derive(Debug, Default)]
+pub struct EditLog {
+    rev: u64,
+    /// The latest edits, oldest first; the last one made revision `rev`.
+    recent: VecDeque<(u64, Edit)>,
+}
+
+impl EditLog {
+    pub fn rev(&self) -> u64 {
+        self.rev
+    }
+
+    /// Bytes the log holds, roughly.
+    pub fn memory(&self) -> usize {
+        std::mem::size_of::<Self>() + self.recent.len() * std::mem::size_of::<(u64, Edit)>()
+    }
+
+    /// Records `key` typed by `device` at `cursor_pos` against revision
+    /// `base`, and returns the position to apply it at now. The flag is set
+    /// when edits from other devices had to be merged, or couldn't be because
+    /// `base` is too old; the devices' views may then differ from the room's.
+    pub fn merge(
+        &mut self,
+        device: u64,
+        base: Option<u64>,
+        key: &str,
+        cursor_pos: Option<usize>,
+    ) -> (Option<usize>, bool) {
+        let mut cursor_pos = cursor_pos;
+        let mut merged = false;
+        if let (Some(base), Some(pos)) = (base.filter(|&base| base <= self.rev), &mut cursor_pos) {
+            let oldest = self.rev - self.recent.len() as u64;
+            merged = base < oldest;
+            let since = (base.max(oldest) - oldest) as usize;
+            for &(by, edit) in self.recent.iter().skip(since) {
+                if by != device {
+                    *pos = edit.shift(*pos);
+                    merged = true;
+                }
+            }
+        }
+        if let Some(edit) = Edit::of(key, cursor_pos) {
+            self.rev += 1;
+            self.recent.push_back((device, edit));
+            if self.recent.len() > KEPT_EDITS {
+                self.recent.pop_front();
+            }
+        }
+        (cursor_pos, merged)
+    }
+}
@@ -0,0 +1,378 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use utoipa::ToSchema;
+
+use crate::{link::DegradedReason, AppState};
+
+/// Prometheus name and help text for each [`Traffic`] field.
+const SERIES: [(&str, &str); 4] = [
+    ("bytes_received", "Bytes received from clients."),
+    ("bytes_sent", "Bytes sent to clients."),
+    ("events_received", "Messages received from clients."),
+    ("events_sent", "Messages sent to clients."),
+];
+
+/// Bytes and WebSocket messages in each direction, as seen by the server:
+/// "in" is what clients sent, "out" is what the server sent them.
+#[derive(Debug, Clone, Copy, Default, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Traffic {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub events_in: u64,
+    pub events_out: u64,
+}
+
+impl Traffic {
+    pub fn add(&mut self, other: Traffic) {
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.events_in += other.events_in;
+        self.events_out += other.events_out;
+    }
+
+    /// In the order of [`SERIES`].
+    fn values(&self) -> [u64; 4] {
+        [
+            self.bytes_in,
+            self.bytes_out,
+            self.events_in,
+            self.events_out,
+        ]
+    }
+
+    /// What was counted since `earlier`, a snapshot of the same counters.
+    pub fn since(self, earlier: Traffic) -> Traffic {
+        Traffic {
+            bytes_in: self.bytes_in - earlier.bytes_in,
+            bytes_out: self.bytes_out - earlier.bytes_out,
+            events_in: self.events_in - earlier.events_in,
+            events_out: self.events_out - earlier.events_out,
+        }
+    }
+}
+
+/// Running totals that the socket's reader and sender tasks update without
+/// taking the rooms lock.
+#[derive(Debug, Default)]
+pub struct Counters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    events_in: AtomicU64,
+    events_out: AtomicU64,
+    /// Messages skipped because the connection fell too far behind.
+    dropped: AtomicU64,
+}
+
+impl Counters {
+    pub fn received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.events_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.events_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, messages: u64) {
+        self.dropped.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    pub fn dropped_total(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> Traffic {
+        Traffic {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            events_in: self.events_in.load(Ordering::Relaxed),
+            events_out: self.events_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Why a WebSocket ended, when it wasn't closed by the client or dropped
+/// cleanly.
+#[derive(Debug, Clone, Copy)]
+pub enum CloseCause {
+    /// A malformed frame, invalid UTF-8, or too many binary messages.
+    ProtocolError,
+    /// The connection timed out at the network level.
+    Timeout,
+    /// A frame or message over `[limits] max_message_bytes`.
+    Oversize,
+    /// Closed by the tarpit after too many refused messages.
+    RateLimit,
+    /// Reset or cut off without a closing handshake.
+    Reset,
+    /// Fetched a `[honeypot]` room.
+    Honeypot,
+    /// The server panicked handling the connection.
+    Panic,
+}
+
+impl CloseCause {
+    const ALL: [CloseCause; 7] = [
+        Self::ProtocolError,
+        Self::Timeout,
+        Self::Oversize,
+        Self::RateLimit,
+        Self::Reset,
+        Self::Honeypot,
+        Self::Panic,
+    ];
+
+    /// The Prometheus label.
+    fn label(self) -> &'static str {
+        match self {
+            Self::ProtocolError => "protocol_error",
+            Self::Timeout => "timeout",
+            Self::Oversize => "oversize",
+            Self::RateLimit => "rate_limit",
+            Self::Reset => "reset",
+            Self::Honeypot => "honeypot",
+            Self::Panic => "panic",
+        }
+    }
+}
+
+/// Abnormal WebSocket closes since startup, by cause.
+#[derive(Debug, Default)]
+pub struct Closes([AtomicU64; 7]);
+
+impl Closes {
+    pub fn record(&self, cause: CloseCause) {
+        self.0[cause as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self, cause: CloseCause) -> u64 {
+        self.0[cause as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> AbnormalCloses {
+        AbnormalCloses {
+            protocol_error: self.count(CloseCause::ProtocolError),
+            timeout: self.count(CloseCause::Timeout),
+            oversize: self.count(CloseCause::Oversize),
+            rate_limit: self.count(CloseCause::RateLimit),
+            reset: self.count(CloseCause::Reset),
+            honeypot: self.count(CloseCause::Honeypot),
+            panic: self.count(CloseCause::Panic),
+        }
+    }
+}
+
+/// Connections told they get fewer updates since startup, by reason.
+#[derive(Debug, Default)]
+pub struct Degradations([AtomicU64; 2]);
+
+impl Degradations {
+    pub fn record(&self, reason: DegradedReason) {
+        self.0[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self, reason: DegradedReason) -> u64 {
+        self.0[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> Degraded {
+        Degraded {
+            fell_behind: self.count(DegradedReason::FellBehind),
+            snapshots_only: self.count(DegradedReason::SnapshotsOnly),
+        }
+    }
+}
+
+/// Connections told they get fewer updates since startup, for the admin API.
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Degraded {
+    pub fell_behind: u64,
+    pub snapshots_only: u64,
+}
+
+/// Abnormal WebSocket closes since startup, for the admin API.
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AbnormalCloses {
+    pub protocol_error: u64,
+    pub timeout: u64,
+    pub oversize: u64,
+    pub rate_limit: u64,
+    pub reset: u64,
+    pub honeypot: u64,
+    pub panic: u64,
+}
+
+/// One room's figures for the admin API.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomTraffic {
+    pub id: String,
+    pub participants: usize,
+    pub traffic: Traffic,
+    pub by_participant: BTreeMap<String, Traffic>,
+    pub memory: RoomMemory,
+}
+
+/// Roughly what a room holds in memory, in bytes, by what holds it.
+#[derive(Debug, Clone, Copy, Default, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomMemory {
+    /// Finished lines and lines in progress.
+    pub lines: usize,
+    /// Recent edits kept for merging key presses from several devices.
+    pub edits: usize,
+    /// Messages kept for connections to resume from.
+    pub unacked: usize,
+    /// Relayed events waiting for their turn to be sent.
+    pub outbox: usize,
+}
+
+impl RoomMemory {
+    const KINDS: [&'static str; 4] = ["lines", "edits", "unacked", "outbox"];
+
+    /// In the order of `KINDS`.
+    fn values(&self) -> [usize; 4] {
+        [self.lines, self.edits, self.unacked, self.outbox]
+    }
+
+    pub fn total(&self) -> usize {
+        self.values().iter().sum()
+    }
+}
+
+/// Every room in memory, busiest first by bytes received.
+pub fn rooms(state: &AppState) -> Vec<RoomTraffic> {
+    let mut rooms: Vec<RoomTraffic> = state
+        .rooms
+        .published()
+        .iter()
+        .map(|room| {
+            let by_participant = room.traffic();
+            let mut traffic = Traffic::default();
+            by_participant.values().for_each(|each| traffic.add(*each));
+            RoomTraffic {
+                id: room.id.clone(),
+                participants: room.connections,
+                traffic,
+                by_participant,
+                memory: room.memory,
+            }
+        })
+        .collect();
+    rooms.sort_by_key(|room| std::cmp::Reverse(room.traffic.bytes_in));
+    rooms
+}
+
+/// The Prometheus text exposition format, for `GET /admin/metrics`.
+pub fn prometheus(state: &AppState) -> String {
+    let total = state.traffic.snapshot();
+    let rooms = rooms(state);
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP typeto_rooms Rooms held in memory.");
+    let _ = writeln!(out, "# TYPE typeto_rooms gauge");
+    let _ = writeln!(out, "typeto_rooms {}", rooms.len());
+    let _ = writeln!(out, "# HELP typeto_sockets Open WebSocket connections.");
+    let _ = writeln!(out, "# TYPE typeto_sockets gauge");
+    let _ = writeln!(out, "typeto_sockets {}", state.notices.receiver_count());
+
+    let _ = writeln!(
+        out,
+        "# HELP typeto_room_memory_bytes Approximate memory held by rooms, by what holds it."
+    );
+    let _ = writeln!(out, "# TYPE typeto_room_memory_bytes gauge");
+    let mut memory = [0; 4];
+    for room in &rooms {
+        for (sum, value) in memory.iter_mut().zip(room.memory.values()) {
+            *sum += value;
+        }
+    }
+    for (kind, value) in RoomMemory::KINDS.iter().zip(memory) {
+        let _ = writeln!(
+            out,
+            "typeto_room_memory_bytes{{kind=\"{}\"}} {}",
+            kind, value
+        );
+    }
+
+    for ((name, help), value) in SERIES.iter().zip(total.values()) {
+        let _ = writeln!(out, "# HELP typeto_{}_total {}", name, help);
+        let _ = writeln!(out, "# TYPE typeto_{}_total counter", name);
+        let _ = writeln!(out, "typeto_{}_total {}", name, value);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP typeto_abnormal_closes_total WebSocket connections that ended abnormally, by cause."
+    );
+    let _ = writeln!(out, "# TYPE typeto_abnormal_closes_total counter");
+    for cause in CloseCause::ALL {
+        let _ = writeln!(
+            out,
+            "typeto_abnormal_closes_total{{cause=\"{}\"}} {}",
+            cause.label(),
+            state.closes.count(cause)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP typeto_degraded_total Connections told they fell behind and get fewer updates, by reason."
+    );
+    let _ = writeln!(out, "# TYPE typeto_degraded_total counter");
+    for reason in DegradedReason::ALL {
+        let _ = writeln!(
+            out,
+            "typeto_degraded_total{{reason=\"{}\"}} {}",
+            reason.label(),
+            state.degradations.count(reason)
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP typeto_room_participants Participants in a room."
+    );
+    let _ = writeln!(out, "# TYPE typeto_room_participants gauge");
+    for room in &rooms {
+        let _ = writeln!(
+            out,
+            "typeto_room_participants{{room=\"{}\"}} {}",
+            escape_label(&room.id),
+            room.participants
+        );
+    }
+    for (i, (name, help)) in SERIES.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "# HELP typeto_room_{}_total {} Resets when the room leaves memory.",
+            name, help
+        );
+        let _ = writeln!(out, "# TYPE typeto_room_{}_total counter", name);
+        for room in &rooms {
+            let _ = writeln!(
+                out,
+                "typeto_room_{}_total{{room=\"{}\"}} {}",
+                name,
+                escape_label(&room.id),
+                room.traffic.values()[i]
+            );
+        }
+    }
+    out
+}
+
+/// Room ids come from clients, so they may contain anything.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,385 @@
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use url::Url;
+
+use crate::{
+    config::OidcConfig,
+    generate_random_string,
+    http_client::{self, HttpClient},
+    identity,
+};
+
+pub const SESSION_COOKIE: &str = "typeto_session";
+/// Holds a login's `state` in the browser that started it, so a callback
+/// for someone else's login is refused.
+const LOGIN_COOKIE: &str = "typeto_login";
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+const BEARER_CACHE_TTL: Duration = Duration::from_secs(300);
+const MAX_PENDING_LOGINS: usize = 10_000;
+const MAX_CACHED_BEARERS: usize = 10_000;
+/// Calls to the provider on behalf of one IP address a minute.
+const MAX_LOOKUPS_PER_MINUTE: usize = 30;
+
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    sub: String,
+}
+
+struct PendingLogin {
+    return_to: String,
+    started: Instant,
+}
+
+struct Session {
+    identity: String,
+    expires: Instant,
+}
+
+/// OpenID Connect authorization-code login. Successful logins become
+/// server-side sessions referenced by an opaque cookie; API clients can
+/// instead present the provider's access token as a bearer token.
+pub struct Oidc {
+    config: OidcConfig,
+    client: HttpClient,
+    discovery: Discovery,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+    sessions: Mutex<HashMap<String, Session>>,
+    bearer_cache: Mutex<HashMap<String, Session>>,
+    /// When each IP address last had the provider called for it.
+    lookups: Mutex<HashMap<Option<IpAddr>, VecDeque<Instant>>>,
+}
+
+impl Oidc {
+    pub async fn discover(config: OidcConfig, client: HttpClient) -> Result<Self, String> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let discovery = http_client::get_json(&client, &url, None).await?;
+        Ok(Self {
+            config,
+            client,
+            discovery,
+            pending: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            bearer_cache: Mutex::new(HashMap::new()),
+            lookups: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn session_cookie(&self, session_id: &str) -> String {
+        self.cookie_with(session_id, self.config.session_ttl_secs)
+    }
+
+    pub fn cleared_cookie(&self) -> String {
+        self.cookie_with("", 0)
+    }
+
+    fn cookie_with(&self, value: &str, max_age: u64) -> String {
+        self.named_cookie(SESSION_COOKIE, value, max_age)
+    }
+
+    fn named_cookie(&self, name: &str, value: &str, max_age: u64) -> String {
+        let secure = if self.config.redirect_url.starts_with("https://") {
+            "; Secure"
+        } else {
+            ""
+        };
+        format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}",
+            name, value, max_age, secure
+        )
+    }
+
+    /// Starts a login and returns the provider URL to redirect the browser
+    /// to, and the login's `state`.
+    pub fn login_url(&self, return_to: &str) -> Result<(String, String), String> {
+        let state = generate_random_string(32);
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING_LOGINS {
+            pending.retain(|_, pending| pending.started.elapsed() < LOGIN_TIMEOUT);
+            if pending.len() >= MAX_PENDING_LOGINS {
+                return Err("Too many logins in progress; try again later.".to_string());
+            }
+        }
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                return_to: safe_return_path(return_to),
+                started: Instant::now(),
+            },
+        );
+        drop(pending);
+
+        let mut url = Url::parse(&self.discovery.authorization_endpoint)
+            .map_err(|err| format!("Bad authorization endpoint: {}", err))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &state);
+        Ok((url.into(), state))
+    }
+
+    /// Finishes a login started by `login_url` in the browser that sent
+    /// `login_cookie`, returning the new session id and the path the user
+    /// was originally headed to.
+    pub async fn complete_login(
+        &self,
+        code: &str,
+        state: &str,
+        login_cookie: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> Result<(String, String), String> {
+        let same_browser = login_cookie
+            .is_some_and(|cookie| bool::from(cookie.as_bytes().ct_eq(state.as_bytes())));
+        if !same_browser {
+            return Err("This login was started in another browser.".to_string());
+        }
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|pending| pending.started.elapsed() < LOGIN_TIMEOUT)
+            .ok_or_else(|| "Unknown or expired login attempt.".to_string())?;
+        self.admit_lookup(ip)?;
+
+        let token: TokenResponse = http_client::post_form(
+            &self.client,
+            &self.discovery.token_endpoint,
+            &[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+            ],
+        )
+        .await?;
+        let identity = self.identity_for_token(&token.access_token).await?;
+
+        let session_id = generate_random_string(40);
+        self.sessions.lock().unwrap().insert(
+            session_id.clone(),
+            Session {
+                identity,
+                expires: Instant::now() + Duration::from_secs(self.config.session_ttl_secs),
+            },
+        );
+        Ok((session_id, pending.return_to))
+    }
+
+    pub fn logout(&self, req: &Request<Body>) {
+        if let Some(session_id) = cookie(req, SESSION_COOKIE) {
+            self.sessions.lock().unwrap().remove(&session_id);
+        }
+    }
+
+    /// Identity of the caller from the session cookie or a bearer token.
+    /// Bearer tokens not seen lately are checked with the provider, at most
+    /// `MAX_LOOKUPS_PER_MINUTE` times a minute for each IP address.
+    pub async fn authenticate(&self, req: &Request<Body>, ip: Option<IpAddr>) -> Option<String> {
+        if let Some(session_id) = cookie(req, SESSION_COOKIE) {
+            let sessions = self.sessions.lock().unwrap();
+            if let Some(session) = sessions.get(&session_id) {
+                if session.expires > Instant::now() {
+                    return Some(session.identity.clone());
+                }
+            }
+        }
+
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))?
+            .to_string();
+        if let Some(session) = self.bearer_cache.lock().unwrap().get(&token) {
+            if session.expires > Instant::now() {
+                return Some(session.identity.clone());
+            }
+        }
+        self.admit_lookup(ip).ok()?;
+        let identity = self.identity_for_token(&token).await.ok()?;
+        let mut cache = self.bearer_cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED_BEARERS {
+            let now = Instant::now();
+            cache.retain(|_, session| session.expires > now);
+        }
+        if cache.len() >= MAX_CACHED_BEARERS {
+            let soonest = cache
+                .iter()
+                .min_by_key(|(_, session)| session.expires)
+                .map(|(token, _)| token.clone());
+            if let Some(soonest) = soonest {
+                cache.remove(&soonest);
+            }
+        }
+        cache.insert(
+            token,
+            Session {
+                identity: identity.clone(),
+                expires: Instant::now() + BEARER_CACHE_TTL,
+            },
+        );
+        Some(identity)
+    }
+
+    /// Counts a call to the provider for `ip`, unless it has had
+    /// `MAX_LOOKUPS_PER_MINUTE` in the last minute.
+    fn admit_lookup(&self, ip: Option<IpAddr>) -> Result<(), String> {
+        let now = Instant::now();
+        let minute = Duration::from_secs(60);
+        let mut lookups = self.lookups.lock().unwrap();
+        let times = lookups.entry(ip).or_default();
+        times.retain(|&at| now.duration_since(at) < minute);
+        if times.len() >= MAX_LOOKUPS_PER_MINUTE {
+            return Err("Too many login attempts; try again later.".to_string());
+        }
+        times.push_back(now);
+        Ok(())
+    }
+
+    async fn identity_for_token(&self, access_token: &str) -> Result<String, String> {
+        let info: UserInfo = http_client::get_json(
+            &self.client,
+            &self.discovery.userinfo_endpoint,
+            Some(access_token),
+        )
+        .await?;
+        Ok(identity::for_subject(&self.config.issuer, &info.sub))
+    }
+
+    /// Drops expired sessions, cached bearer tokens, abandoned logins and
+    /// lookups over a minute old.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.lookups.lock().unwrap().retain(|_, times| {
+            times.retain(|&at| now.duration_since(at) < Duration::from_secs(60));
+            !times.is_empty()
+        });
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, session| session.expires > now);
+        self.bearer_cache
+            .lock()
+            .unwrap()
+            .retain(|_, session| session.expires > now);
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|_, pending| pending.started.elapsed() < LOGIN_TIMEOUT);
+    }
+
+    /// Serves `/auth/login`, `/auth/callback` and `/auth/logout`; returns
+    /// `None` for any other path.
+    pub async fn route(&self, req: &Request<Body>, ip: Option<IpAddr>) -> Option<Response<Body>> {
+        let params: HashMap<String, String> = req
+            .uri()
+            .query()
+            .map(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .into_owned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let response = match req.uri().path() {
+            "/auth/login" => {
+                let return_to = params.get("return_to").map(String::as_str).unwrap_or("/");
+                match self.login_url(return_to) {
+                    Ok((url, state)) => redirect(
+                        &url,
+                        &[self.named_cookie(LOGIN_COOKIE, &state, LOGIN_TIMEOUT.as_secs())],
+                    ),
+                    Err(err) => error_response(StatusCode::SERVICE_UNAVAILABLE, &err),
+                }
+            }
+            "/auth/callback" => match (params.get("code"), params.get("state")) {
+                (Some(code), Some(state)) => {
+                    let login_cookie = cookie(req, LOGIN_COOKIE);
+                    match self
+                        .complete_login(code, state, login_cookie.as_deref(), ip)
+                        .await
+                    {
+                        Ok((session_id, return_to)) => redirect(
+                            &return_to,
+                            &[
+                                self.session_cookie(&session_id),
+                                self.named_cookie(LOGIN_COOKIE, "", 0),
+                            ],
+                        ),
+                        Err(err) => error_response(StatusCode::UNAUTHORIZED, &err),
+                    }
+                }
+                _ => error_response(StatusCode::BAD_REQUEST, "Missing code or state."),
+            },
+            "/auth/logout" => {
+                self.logout(req);
+                redirect("/", &[self.cleared_cookie()])
+            }
+            _ => return None,
+        };
+        Some(response)
+    }
+}
+
+fn redirect(location: &str, set_cookies: &[String]) -> Response<Body> {
+    let mut builder = Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location);
+    for cookie in set_cookies {
+        builder = builder.header(header::SET_COOKIE, cookie);
+    }
+    builder.body(Body::empty()).unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
+
+pub fn cookie(req: &Request<Body>, name: &str) -> Option<String> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Only same-origin paths are allowed as post-login destinations.
+fn safe_return_path(path: &str) -> String {
+    if path.starts_with('/') && !path.starts_with("//") && !path.contains('\\') {
+        path.to_string()
+    } else {
+        "/".to_string()
+    }
+}
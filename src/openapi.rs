@@ -0,0 +1,867 @@
+//! The OpenAPI document for the HTTP API, served at `/api/openapi.json`, and
+//! a Swagger UI page for it at `/api/docs`. Most routes are arms of a `match`
+//! rather than functions of their own, so each is described here by a
+//! stand-in function, next to the shapes of the replies that handlers build
+//! with `json!`.
+
+#![allow(dead_code)]
+
+use hyper::{header, Body, Response, StatusCode};
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+use crate::{
+    admin::{AnnounceRequest, CreateRoomRequest, InviteRequest, MaintenanceRequest},
+    api::PromptRequest,
+    email::InviteEmailRequest,
+    embed::EmbedRequest,
+    handoff::{ExportRequest, Handoff},
+    health::Readiness,
+    keystrokes::KeystrokeLog,
+    lan::Peer,
+    metrics::{AbnormalCloses, Degraded, RoomTraffic, Traffic},
+    report::ReportRequest,
+    rollup::DailyRollup,
+    search::Results,
+    server_info::ServerInfo,
+    status::Status,
+    webhook::{Hook, HookRequest},
+};
+
+const DOCS_PAGE: &str = r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>typeto.me API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script src="/api/docs.js"></script>
+</body>
+</html>
+"#;
+
+const DOCS_SCRIPT: &str =
+    "SwaggerUIBundle({ url: \"/api/openapi.json\", dom_id: \"#swagger-ui\" });\n";
+
+/// Swagger UI comes from unpkg, like the GUI's `cre` module, and styles
+/// itself inline.
+const DOCS_CSP: &str = "default-src 'self'; script-src 'self' https://unpkg.com; \
+     style-src 'unsafe-inline' https://unpkg.com; img-src 'self' data:; \
+     connect-src 'self'; base-uri 'none'; frame-ancestors 'none'";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "typeto.me",
+        description = "Rooms, embedding and operator endpoints. Chat itself \
+            runs over the WebSocket at `/ws`."
+    ),
+    paths(
+        liveness,
+        readiness,
+        server_info,
+        status,
+        report,
+        lan_peers,
+        short_link,
+        room_calendar,
+        get_room,
+        delete_room,
+        search_room,
+        invite_email,
+        set_prompt,
+        export_cast,
+        export_keystrokes,
+        import_room,
+        issue_embed,
+        start_maintenance,
+        cancel_maintenance,
+        announce,
+        audit,
+        invite,
+        create_room,
+        rooms,
+        room,
+        shadow_ban,
+        lift_shadow_ban,
+        connections,
+        metrics,
+        rollups,
+        handoff_export,
+        handoff_import,
+        webhooks,
+        add_webhook,
+        remove_webhook,
+        rotate_webhook_secret,
+    ),
+    modifiers(&Details),
+    tags(
+        (name = "health", description = "Open to anyone, credentials or not."),
+        (name = "server", description = "Open to anyone who may see the GUI."),
+        (name = "links", description = "Open to anyone who may see the GUI."),
+        (name = "import", description = "Open to whoever `[rooms] creation` lets create rooms, or the admin token."),
+        (name = "rooms", description = "Authorized by a room's creator token or the admin token."),
+        (name = "embed", description = "Authorized by a partner's API key; needs `[embed]`."),
+        (name = "admin", description = "Authorized by the admin token; needs `[admin]`."),
+    )
+)]
+struct ApiDoc;
+
+/// What the derive can't express: routes take their credential as
+/// `Authorization: Bearer`, and the crate declares no license.
+struct Details;
+
+impl Modify for Details {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        openapi.info.license = None;
+    }
+}
+
+pub fn document() -> Response<Body> {
+    match ApiDoc::openapi().to_json() {
+        Ok(json) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(_) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
+/// The Swagger UI page, or its script.
+pub fn docs(script: bool) -> Response<Body> {
+    let (content_type, content) = if script {
+        ("application/javascript", DOCS_SCRIPT)
+    } else {
+        ("text/html", DOCS_PAGE)
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_SECURITY_POLICY, DOCS_CSP)
+        .body(Body::from(content))
+        .unwrap()
+}
+
+#[derive(Serialize, ToSchema)]
+struct LivenessReply {
+    /// Always `ok`.
+    status: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ErrorReply {
+    error: String,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct RoomReply {
+    id: String,
+    /// Whether the room is in memory; a stored room has no participants.
+    live: bool,
+    /// Who is connected, without repeats.
+    participants: Vec<String>,
+    owner_id: Option<String>,
+    topic: Option<String>,
+    /// As the owner last set them.
+    #[schema(value_type = Object)]
+    settings: serde_json::Value,
+    /// How many lines the room has finished, joins and leaves included.
+    seq: u64,
+    /// Unix seconds.
+    created_at: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct DeleteReceipt {
+    receipt: String,
+    room: String,
+    /// Unix seconds.
+    deleted_at: u64,
+    purged: Purged,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Purged {
+    live_room: bool,
+    stored_record: bool,
+    audit_entries: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct EmbedToken {
+    token: String,
+    room: String,
+    /// The room's URL with the token, to frame.
+    path: String,
+    /// Unix seconds.
+    expires_at: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct MaintenanceReply {
+    maintenance: bool,
+    /// Rooms that still have participants; only when maintenance starts.
+    active_rooms: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct AnnounceReply {
+    /// Connections the announcement was sent to.
+    sockets: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct AuditReply {
+    /// Oldest first, each with `time` in Unix seconds and an `event` kind.
+    #[schema(value_type = Vec<Object>)]
+    entries: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct InviteReply {
+    /// Passed as `?invite=` on the page or WebSocket URL, or as `invite` in
+    /// gRPC's `JoinRoom`.
+    invite: String,
+    expires_in_secs: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct CreatedRoom {
+    room: String,
+    shortcode: String,
+    /// Authorizes `DELETE /api/rooms/{id}` and search.
+    creator_token: String,
+    /// For a scheduled room, the window in Unix seconds and the path of its
+    /// calendar event.
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+    calendar: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct RoomsReply {
+    total: Traffic,
+    /// Busiest first.
+    rooms: Vec<RoomTraffic>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct ConnectionsReply {
+    /// Open WebSocket connections.
+    sockets: usize,
+    abnormal_closes: AbnormalCloses,
+    /// Connections told they fell behind and get fewer updates.
+    degraded: Degraded,
+}
+
+#[derive(Serialize, ToSchema)]
+struct RollupsReply {
+    days: Vec<DailyRollup>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ImportReply {
+    imported: Vec<String>,
+    /// Rooms already in memory here, left as they were.
+    conflicts: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct WebhooksReply {
+    webhooks: Vec<Hook>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct AddedWebhook {
+    #[serde(flatten)]
+    hook: Hook,
+    /// Shown only here; deliveries carry `X-Typeto-Signature: sha256=<hex
+    /// HMAC-SHA256 of the body with it>`.
+    secret: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct WebhookSecret {
+    id: String,
+    secret: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct RemovedWebhook {
+    removed: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct EmailSent {
+    sent: bool,
+}
+
+/// Whether the process is up and answering; also at `/health`.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses((status = 200, description = "Up", body = LivenessReply))
+)]
+fn liveness() {}
+
+/// Whether the server should be sent more clients, and why.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Ready", body = Readiness),
+        (status = 503, description = "Storage is down, the server is full or it is shutting down", body = Readiness),
+    )
+)]
+fn readiness() {}
+
+/// The version, build, enabled features, uptime and limits of this instance.
+#[utoipa::path(
+    get,
+    path = "/api/server-info",
+    tag = "server",
+    responses((status = 200, description = "About this instance", body = ServerInfo))
+)]
+fn server_info() {}
+
+/// Whether the server is up and taking rooms, and its load against its
+/// limits; HTML unless JSON is asked for.
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "server",
+    params(("format" = Option<String>, Query, description = "`json` for JSON rather than HTML")),
+    responses((status = 200, description = "The server's status", body = Status)),
+)]
+fn status() {}
+
+#[derive(Serialize, ToSchema)]
+struct Reported {
+    reported: bool,
+}
+
+/// Reports an open room to the operator, who finds it in the audit log with
+/// what the room looked like.
+#[utoipa::path(
+    post,
+    path = "/api/report",
+    tag = "server",
+    request_body = ReportRequest,
+    responses(
+        (status = 200, description = "Passed on", body = Reported),
+        (status = 400, description = "No reason, or one too long", body = ErrorReply),
+        (status = 404, description = "No such room, or it isn't open", body = ErrorReply),
+        (status = 429, description = "Five reports already this hour", body = ErrorReply),
+    )
+)]
+fn report() {}
+
+#[derive(Serialize, ToSchema)]
+struct LanPeers {
+    peers: Vec<Peer>,
+}
+
+/// Other instances found on the local network, with `[lan]` or `--lan`.
+#[utoipa::path(
+    get,
+    path = "/api/lan-peers",
+    tag = "server",
+    responses(
+        (status = 200, description = "Instances heard from lately", body = LanPeers),
+        (status = 404, description = "LAN discovery is off"),
+    )
+)]
+fn lan_peers() {}
+
+/// Redirects a room's short link, as given in its `shortcode`, to the room.
+#[utoipa::path(
+    get,
+    path = "/j/{code}",
+    tag = "links",
+    params(("code" = String, Path, description = "The room's shortcode; any case")),
+    responses(
+        (status = 302, description = "To the room's page, in `Location`"),
+        (status = 404, description = "No room has this code"),
+    )
+)]
+fn short_link() {}
+
+/// A scheduled room's window as an iCalendar event, to add to a calendar.
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/calendar.ics",
+    tag = "links",
+    params(("id" = String, Path, description = "Room id")),
+    responses(
+        (status = 200, description = "The event, as an attachment", content_type = "text/calendar"),
+        (status = 404, description = "No such room, or it isn't scheduled"),
+    )
+)]
+fn room_calendar() {}
+
+/// Who is in a room and how far it has got, for polling without a
+/// WebSocket.
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("If-None-Match" = Option<String>, Header, description = "An `ETag` from an earlier reply"),
+    ),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The room, with an `ETag`", body = RoomReply),
+        (status = 304, description = "Unchanged since the `ETag` given"),
+        (status = 401, description = "No creator or admin token", body = ErrorReply),
+        (status = 404, description = "No such room", body = ErrorReply),
+    )
+)]
+fn get_room() {}
+
+/// Purges a room's live state, stored record, activity rollups and audit
+/// entries.
+#[utoipa::path(
+    delete,
+    path = "/api/rooms/{id}",
+    tag = "rooms",
+    params(("id" = String, Path, description = "Room id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Deleted", body = DeleteReceipt),
+        (status = 401, description = "No creator or admin token", body = ErrorReply),
+        (status = 404, description = "No such room", body = ErrorReply),
+    )
+)]
+fn delete_room() {}
+
+/// Searches a room's finished lines, case-insensitively or as a regex.
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/search",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("q" = String, Query, description = "Text to look for, at most 200 characters"),
+        ("regex" = Option<bool>, Query, description = "Treat `q` as a regular expression"),
+    ),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Up to 200 hits, oldest first", body = Results),
+        (status = 400, description = "Empty, too long or invalid query", body = ErrorReply),
+        (status = 401, description = "No creator or admin token", body = ErrorReply),
+        (status = 404, description = "No such room", body = ErrorReply),
+    )
+)]
+fn search_room() {}
+
+/// Emails the invitee a link to the room, filled into `[email]`'s templates.
+#[utoipa::path(
+    post,
+    path = "/api/rooms/{id}/invite-email",
+    tag = "rooms",
+    params(("id" = String, Path, description = "Room id")),
+    request_body = InviteEmailRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Handed to the SMTP server", body = EmailSent),
+        (status = 400, description = "Bad address, note or invitation", body = ErrorReply),
+        (status = 401, description = "No creator or admin token", body = ErrorReply),
+        (status = 404, description = "No such room, or no `[email]`", body = ErrorReply),
+        (status = 429, description = "The room sent `max_per_hour` already", body = ErrorReply),
+        (status = 502, description = "The SMTP server refused it or is unreachable", body = ErrorReply),
+    )
+)]
+fn invite_email() {}
+
+#[derive(Serialize, ToSchema)]
+struct PromptSet {
+    /// As pinned, after trimming; unset if unpinned.
+    prompt: Option<String>,
+}
+
+/// Pins a prompt above everyone's lines in the room, as its owner can with
+/// `setPrompt`.
+#[utoipa::path(
+    put,
+    path = "/api/rooms/{id}/prompt",
+    tag = "rooms",
+    params(("id" = String, Path, description = "Room id")),
+    request_body = PromptRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Pinned, or unpinned", body = PromptSet),
+        (status = 400, description = "Too long, or more than one line", body = ErrorReply),
+        (status = 401, description = "No creator or admin token", body = ErrorReply),
+        (status = 404, description = "No such room, or it isn't open", body = ErrorReply),
+    )
+)]
+fn set_prompt() {}
+
+/// The conversation as an asciinema cast (asciicast v2), as a terminal
+/// would draw it. For the creator, a room in interview mode is played back
+/// with its recorded timing.
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/cast",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("speed" = Option<u32>, Query, description = "Characters a second to type finished lines out at, 1 to 1000; 15 by default"),
+    ),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The cast, `application/x-asciicast`", body = String),
+        (status = 400, description = "Bad speed", body = ErrorReply),
+        (status = 401, description = "No creator or admin token", body = ErrorReply),
+        (status = 404, description = "No such room", body = ErrorReply),
+    )
+)]
+fn export_cast() {}
+
+/// Exports the key presses interview mode recorded in the room, with their
+/// times; the creator's token only.
+#[utoipa::path(
+    get,
+    path = "/api/rooms/{id}/keystrokes",
+    tag = "rooms",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("format" = Option<String>, Query, description = "`csv` for CSV rather than JSON"),
+    ),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The key presses, oldest first", body = KeystrokeLog),
+        (status = 401, description = "No creator token", body = ErrorReply),
+        (status = 403, description = "The admin token, which can't export keystrokes", body = ErrorReply),
+        (status = 404, description = "No such room, or it isn't open", body = ErrorReply),
+    )
+)]
+fn export_keystrokes() {}
+
+/// Creates a room holding an exported conversation, or replays it into the
+/// room a character at a time.
+#[utoipa::path(
+    post,
+    path = "/api/import",
+    tag = "import",
+    params(
+        ("replay" = Option<u32>, Query, description = "Type the lines in at this many characters per second, 1 to 1000"),
+        ("invite" = Option<String>, Query, description = "An invitation, under `creation = \"invite\"`"),
+    ),
+    request_body(
+        content = Object,
+        description = "A transcript as `[archive]` uploads it, or a list of `{participant, text}` lines as GraphQL's `transcript(id)` returns them; at most 1 MiB"
+    ),
+    security((), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Created", body = CreatedRoom),
+        (status = 400, description = "Not a transcript, no lines, or a bad `replay`", body = ErrorReply),
+        (status = 403, description = "Creating rooms takes an invitation or the admin token", body = ErrorReply),
+        (status = 413, description = "Body missing or too large", body = ErrorReply),
+        (status = 503, description = "In maintenance", body = ErrorReply),
+    )
+)]
+fn import_room() {}
+
+/// Issues a short-lived token that lets the partner's site frame one room.
+#[utoipa::path(
+    post,
+    path = "/api/embed",
+    tag = "embed",
+    request_body = EmbedRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Token issued", body = EmbedToken),
+        (status = 400, description = "Bad body or room id", body = ErrorReply),
+        (status = 401, description = "Unknown API key", body = ErrorReply),
+    )
+)]
+fn issue_embed() {}
+
+/// Refuses new rooms, tells everyone connected, and shuts down once rooms
+/// are empty or the deadline passes.
+#[utoipa::path(
+    post,
+    path = "/admin/maintenance",
+    tag = "admin",
+    request_body = MaintenanceRequest,
+    security(("bearer" = [])),
+    responses((status = 200, description = "Draining", body = MaintenanceReply))
+)]
+fn start_maintenance() {}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/maintenance",
+    tag = "admin",
+    security(("bearer" = [])),
+    responses((status = 200, description = "No longer draining", body = MaintenanceReply))
+)]
+fn cancel_maintenance() {}
+
+/// Shows a message to everyone connected.
+#[utoipa::path(
+    post,
+    path = "/admin/announce",
+    tag = "admin",
+    request_body = AnnounceRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Sent", body = AnnounceReply),
+        (status = 400, description = "Empty or longer than 500 characters", body = ErrorReply),
+    )
+)]
+fn announce() {}
+
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    tag = "admin",
+    params(
+        ("room" = Option<String>, Query, description = "Only entries about this room"),
+        ("since" = Option<u64>, Query, description = "Unix seconds"),
+        ("limit" = Option<usize>, Query, description = "1 to 1000, default 100"),
+    ),
+    security(("bearer" = [])),
+    responses((status = 200, description = "Matching entries", body = AuditReply))
+)]
+fn audit() {}
+
+/// Issues an invitation to create one room, for `[rooms] creation =
+/// "invite"`.
+#[utoipa::path(
+    post,
+    path = "/admin/invites",
+    tag = "admin",
+    request_body = InviteRequest,
+    security(("bearer" = [])),
+    responses((status = 200, description = "Issued", body = InviteReply))
+)]
+fn invite() {}
+
+/// Creates an empty room, whatever `[rooms] creation` allows, or schedules
+/// one that opens at `startsAt`.
+#[utoipa::path(
+    post,
+    path = "/admin/rooms",
+    tag = "admin",
+    request_body = CreateRoomRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Created", body = CreatedRoom),
+        (status = 400, description = "Invalid room id or schedule", body = ErrorReply),
+        (status = 409, description = "The room exists", body = ErrorReply),
+    )
+)]
+fn create_room() {}
+
+/// Traffic of the rooms in memory.
+#[utoipa::path(
+    get,
+    path = "/admin/rooms",
+    tag = "admin",
+    security(("bearer" = [])),
+    responses((status = 200, description = "Server-wide and per-room traffic", body = RoomsReply))
+)]
+fn rooms() {}
+
+#[utoipa::path(
+    get,
+    path = "/admin/rooms/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "Room id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The room's traffic", body = RoomTraffic),
+        (status = 404, description = "Not in memory", body = ErrorReply),
+    )
+)]
+fn room() {}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct ShadowBans {
+    /// Everyone shadow-banned in the room.
+    shadow_banned: Vec<String>,
+}
+
+/// Shadow-bans a participant: they still see what they type, but nobody
+/// else does, and it isn't kept.
+#[utoipa::path(
+    put,
+    path = "/admin/rooms/{id}/shadow-bans/{participant}",
+    tag = "admin",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("participant" = String, Path, description = "Participant id"),
+    ),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Banned, or already", body = ShadowBans),
+        (status = 404, description = "No such room or participant, or the room isn't open", body = ErrorReply),
+    )
+)]
+fn shadow_ban() {}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/rooms/{id}/shadow-bans/{participant}",
+    tag = "admin",
+    params(
+        ("id" = String, Path, description = "Room id"),
+        ("participant" = String, Path, description = "Participant id"),
+    ),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Lifted, or never banned", body = ShadowBans),
+        (status = 404, description = "No such room or participant, or the room isn't open", body = ErrorReply),
+    )
+)]
+fn lift_shadow_ban() {}
+
+/// Open sockets, and the connections that ended abnormally since startup by
+/// cause.
+#[utoipa::path(
+    get,
+    path = "/admin/connections",
+    tag = "admin",
+    security(("bearer" = [])),
+    responses((status = 200, description = "Connection counts", body = ConnectionsReply))
+)]
+fn connections() {}
+
+/// The same figures in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/admin/metrics",
+    tag = "admin",
+    security(("bearer" = [])),
+    responses((status = 200, description = "Prometheus exposition", body = String, content_type = "text/plain"))
+)]
+fn metrics() {}
+
+/// Per-room daily activity, by day.
+#[utoipa::path(
+    get,
+    path = "/admin/rollups",
+    tag = "admin",
+    params(
+        ("room" = Option<String>, Query, description = "Only this room"),
+        ("since" = Option<String>, Query, description = "First day, YYYY-MM-DD"),
+        ("until" = Option<String>, Query, description = "Last day, YYYY-MM-DD"),
+    ),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Matching rollups", body = RollupsReply),
+        (status = 400, description = "Malformed day", body = ErrorReply),
+    )
+)]
+fn rollups() {}
+
+/// The rooms' state, to import on another instance; with `moveTo`, the rooms
+/// are taken out of this one and their participants sent there.
+#[utoipa::path(
+    post,
+    path = "/admin/handoff/export",
+    tag = "admin",
+    request_body = ExportRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The rooms found", body = Handoff),
+        (status = 400, description = "moveTo isn't a WebSocket URL", body = ErrorReply),
+    )
+)]
+fn handoff_export() {}
+
+/// Opens rooms exported from another instance.
+#[utoipa::path(
+    post,
+    path = "/admin/handoff/import",
+    tag = "admin",
+    request_body = Handoff,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Imported", body = ImportReply),
+        (status = 400, description = "Malformed rooms", body = ErrorReply),
+    )
+)]
+fn handoff_import() {}
+
+/// The webhooks, without their secrets.
+#[utoipa::path(
+    get,
+    path = "/admin/webhooks",
+    tag = "admin",
+    security(("bearer" = [])),
+    responses((status = 200, description = "Every webhook", body = WebhooksReply))
+)]
+fn webhooks() {}
+
+/// Adds a webhook, which is posted the audit entries of the room events it
+/// asks for, in the rooms whose ids match its globs.
+#[utoipa::path(
+    post,
+    path = "/admin/webhooks",
+    tag = "admin",
+    request_body = HookRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Added", body = AddedWebhook),
+        (status = 400, description = "Bad URL, globs or events", body = ErrorReply),
+    )
+)]
+fn add_webhook() {}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/webhooks/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "Webhook id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Removed", body = RemovedWebhook),
+        (status = 404, description = "No such webhook", body = ErrorReply),
+    )
+)]
+fn remove_webhook() {}
+
+/// Replaces a webhook's secret.
+#[utoipa::path(
+    post,
+    path = "/admin/webhooks/{id}/secret",
+    tag = "admin",
+    params(("id" = String, Path, description = "Webhook id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The new secret", body = WebhookSecret),
+        (status = 404, description = "No such webhook", body = ErrorReply),
+    )
+)]
+fn rotate_webhook_secret() {}
@@ -0,0 +1,232 @@
+//! Just enough of QR codes to show a URL in a terminal: byte mode, error
+//! correction level L, versions 1 to 5 (a single block of codewords, up to
+//! 106 bytes) and always mask 0, which any reader accepts.
+
+/// (data codewords, error correction codewords) of versions 1 to 5 at level L.
+const VERSIONS: [(usize, usize); 5] = [(19, 7), (34, 10), (55, 15), (80, 20), (108, 26)];
+
+/// `text` as a QR code drawn with half blocks, two modules a line, light
+/// modules in the foreground colour as terminals with a dark background want
+/// them; `None` if it is too long.
+pub fn render(text: &str) -> Option<String> {
+    let modules = encode(text.as_bytes())?;
+    let size = modules.len();
+    let quiet = 2;
+    let light = |x: isize, y: isize| {
+        let inside = (0..size as isize).contains(&x) && (0..size as isize).contains(&y);
+        !inside || !modules[y as usize][x as usize]
+    };
+    let mut out = String::new();
+    let span = -quiet..size as isize + quiet;
+    for y in span.clone().step_by(2) {
+        for x in span.clone() {
+            out.push(match (light(x, y), light(x, y + 1)) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        out.push('\n');
+    }
+    Some(out)
+}
+
+/// The modules of the smallest code that holds `data`, `true` for dark, by
+/// row.
+fn encode(data: &[u8]) -> Option<Vec<Vec<bool>>> {
+    let (version, &(data_len, ec_len)) = VERSIONS
+        .iter()
+        .enumerate()
+        .find(|(_, (data_len, _))| data.len() + 2 <= *data_len)?;
+    let version = version + 1;
+
+    let mut bits = Bits::default();
+    bits.push(0b0100, 4);
+    bits.push(data.len() as u32, 8);
+    for &byte in data {
+        bits.push(byte.into(), 8);
+    }
+    let capacity = data_len * 8;
+    bits.push(0, (capacity - bits.len).min(4));
+    bits.push(0, (8 - bits.len % 8) % 8);
+    let mut codewords = bits.bytes;
+    for pad in [0xec, 0x11].into_iter().cycle() {
+        if codewords.len() == data_len {
+            break;
+        }
+        codewords.push(pad);
+    }
+    let ec = reed_solomon(&codewords, ec_len);
+    codewords.extend(ec);
+
+    let mut code = Code::new(17 + 4 * version);
+    code.draw_function_patterns(version);
+    code.draw_codewords(&codewords);
+    Some(code.modules)
+}
+
+#[derive(Default)]
+struct Bits {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bits {
+    /// Appends the low `count` bits of `value`, most significant first.
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if value >> i & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+struct Code {
+    size: usize,
+    modules: Vec<Vec<bool>>,
+    /// Finder, timing, alignment and format modules, which data skips.
+    function: Vec<Vec<bool>>,
+}
+
+impl Code {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            modules: vec![vec![false; size]; size],
+            function: vec![vec![false; size]; size],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y][x] = dark;
+        self.function[y][x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set(6, i, i % 2 == 0);
+            self.set(i, 6, i % 2 == 0);
+        }
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4..=4_isize {
+                for dx in -4..=4_isize {
+                    let (x, y) = (cx as isize + dx, cy as isize + dy);
+                    if (0..size as isize).contains(&x) && (0..size as isize).contains(&y) {
+                        let ring = dx.abs().max(dy.abs());
+                        self.set(x as usize, y as usize, ring != 2 && ring != 4);
+                    }
+                }
+            }
+        }
+        if version > 1 {
+            let center = size - 7;
+            for dy in -2..=2_isize {
+                for dx in -2..=2_isize {
+                    let (x, y) = (center as isize + dx, center as isize + dy);
+                    self.set(x as usize, y as usize, dx.abs().max(dy.abs()) != 1);
+                }
+            }
+        }
+        self.draw_format();
+    }
+
+    /// Level L and mask 0, in both copies, and the dark module.
+    fn draw_format(&mut self) {
+        let data: u32 = 0b01 << 3;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = (data << 10 | rem) ^ 0x5412;
+        let bit = |i: usize| bits >> i & 1 == 1;
+        let size = self.size;
+        for i in 0..6 {
+            self.set(8, i, bit(i));
+        }
+        self.set(8, 7, bit(6));
+        self.set(8, 8, bit(7));
+        self.set(7, 8, bit(8));
+        for i in 9..15 {
+            self.set(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set(8, size - 15 + i, bit(i));
+        }
+        self.set(8, size - 8, true);
+    }
+
+    /// Lays out `codewords` in the zigzag the standard gives, mask 0 applied.
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vert in 0..size {
+                for x in [right, right - 1] {
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vert } else { vert };
+                    if self.function[y][x] {
+                        continue;
+                    }
+                    let dark = i < codewords.len() * 8 && codewords[i / 8] >> (7 - i % 8) & 1 == 1;
+                    self.modules[y][x] = dark ^ ((x + y) % 2 == 0);
+                    i += 1;
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+}
+
+/// The `len` error correction codewords of `data`.
+fn reed_solomon(data: &[u8], len: usize) -> Vec<u8> {
+    // The generator polynomial, highest coefficient (always 1) left out.
+    let mut generator = vec![0u8; len];
+    generator[len - 1] = 1;
+    let mut root = 1;
+    for _ in 0..len {
+        for j in 0..len {
+            generator[j] = multiply(generator[j], root);
+            if j + 1 < len {
+                generator[j] ^= generator[j + 1];
+            }
+        }
+        root = multiply(root, 0x02);
+    }
+    let mut remainder = vec![0u8; len];
+    for &byte in data {
+        let factor = byte ^ remainder[0];
+        remainder.remove(0);
+        remainder.push(0);
+        for (r, g) in remainder.iter_mut().zip(&generator) {
+            *r ^= multiply(*g, factor);
+        }
+    }
+    remainder
+}
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1.
+fn multiply(x: u8, y: u8) -> u8 {
+    let mut product: u32 = 0;
+    for i in (0..8).rev() {
+        product = (product << 1) ^ ((product >> 7) * 0x11d);
+        product ^= (u32::from(y) >> i & 1) * u32::from(x);
+    }
+    product as u8
+}
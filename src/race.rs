@@ -0,0 +1,213 @@
+//! Typing races: the room's owner sends `startRace`, with a text or for one
+//! of the built-in passages, and everyone is sent `race` with it. As each
+//! participant types it out, everyone is sent their `raceProgress`: how much
+//! of the text they have right, their accuracy and their speed. It ends with
+//! `raceEnded` and the standings once everyone in the room has finished, or
+//! when the owner sends `endRace`.
+
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::seq::SliceRandom;
+use serde::Serialize;
+
+/// The longest text a race can be run on.
+pub const MAX_TEXT_CHARS: usize = 1000;
+
+/// Raced when the owner doesn't give a text.
+const PASSAGES: &[&str] = &[
+    "The quick brown fox jumps over the lazy dog.",
+    "Pack my box with five dozen liquor jugs.",
+    "Sphinx of black quartz, judge my vow.",
+    "How vexingly quick daft zebras jump!",
+    "The five boxing wizards jump quickly.",
+    "Jackdaws love my big sphinx of quartz.",
+];
+
+/// A race being run.
+#[derive(Debug, Clone)]
+pub struct Race {
+    text: String,
+    started_at: SystemTime,
+    entrants: BTreeMap<String, Entrant>,
+    finishers: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Entrant {
+    /// Characters typed.
+    keys: u64,
+    /// Those that left the line astray of the text.
+    mistakes: u64,
+    /// Characters of the text typed right, from its start.
+    correct: usize,
+    /// Milliseconds from the start to finishing, and in which place.
+    finished: Option<(u64, usize)>,
+}
+
+/// A race as clients see it.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+pub struct RaceView {
+    text: String,
+    /// Unix milliseconds.
+    started_at_ms: u64,
+}
+
+impl RaceView {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Where one participant stands in a race.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+pub struct RaceProgress {
+    participant: String,
+    /// Characters of the text typed right, out of `length`.
+    correct: usize,
+    length: usize,
+    /// Percent of the characters typed that were right.
+    accuracy: u64,
+    /// Words a minute, a word being five characters typed right.
+    wpm: u64,
+    /// 1 for the first to finish, and so on.
+    place: Option<usize>,
+}
+
+impl RaceProgress {
+    pub fn participant(&self) -> &str {
+        &self.participant
+    }
+
+    pub fn place(&self) -> Option<usize> {
+        self.place
+    }
+}
+
+impl Race {
+    /// A race on `text`, or a built-in passage if none is given.
+    pub fn new(text: Option<String>, now: SystemTime) -> Result<Self, String> {
+        let text = match text {
+            Some(text) => text.trim().to_string(),
+            None => PASSAGES
+                .choose(&mut rand::thread_rng())
+                .unwrap()
+                .to_string(),
+        };
+        if text.is_empty()
+            || text.chars().count() > MAX_TEXT_CHARS
+            || text.chars().any(char::is_control)
+        {
+            return Err(format!(
+                "A race's text must be one line of at most {} characters.",
+                MAX_TEXT_CHARS
+            ));
+        }
+        Ok(Self {
+            text,
+            started_at: now,
+            entrants: BTreeMap::new(),
+            finishers: 0,
+        })
+    }
+
+    pub fn view(&self) -> RaceView {
+        RaceView {
+            text: self.text.clone(),
+            started_at_ms: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        }
+    }
+
+    /// Scores `key` from `participant`, whose line reads `line` after it.
+    /// Returns their progress, unless they had finished already.
+    pub fn typed(
+        &mut self,
+        participant: &str,
+        key: &str,
+        line: &str,
+        now: SystemTime,
+    ) -> Option<RaceProgress> {
+        let elapsed_ms = now
+            .duration_since(self.started_at)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let entrant = self.entrants.entry(participant.to_string()).or_default();
+        if entrant.finished.is_some() {
+            return None;
+        }
+        if key == "Space" || key.chars().count() == 1 {
+            entrant.keys += 1;
+            if !self.text.starts_with(line) {
+                entrant.mistakes += 1;
+            }
+        }
+        entrant.correct = self
+            .text
+            .chars()
+            .zip(line.chars())
+            .take_while(|(want, got)| want == got)
+            .count();
+        if line == self.text {
+            self.finishers += 1;
+            entrant.finished = Some((elapsed_ms, self.finishers));
+        }
+        Some(self.progress(participant, now))
+    }
+
+    /// Whether everyone in `present` has finished.
+    pub fn all_finished<'a>(&self, mut present: impl Iterator<Item = &'a str>) -> bool {
+        present.all(|id| {
+            self.entrants
+                .get(id)
+                .is_some_and(|entrant| entrant.finished.is_some())
+        })
+    }
+
+    /// Everyone who typed, finishers first by place and the rest by how far
+    /// they got.
+    pub fn standings(&self, now: SystemTime) -> Vec<RaceProgress> {
+        let mut standings: Vec<_> = self
+            .entrants
+            .keys()
+            .map(|id| self.progress(id, now))
+            .collect();
+        standings.sort_by_key(|progress| {
+            (
+                progress.place.unwrap_or(usize::MAX),
+                std::cmp::Reverse(progress.correct),
+            )
+        });
+        standings
+    }
+
+    fn progress(&self, participant: &str, now: SystemTime) -> RaceProgress {
+        let entrant = &self.entrants[participant];
+        let elapsed_ms = match entrant.finished {
+            Some((ms, _)) => ms,
+            None => now
+                .duration_since(self.started_at)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+        RaceProgress {
+            participant: participant.to_string(),
+            correct: entrant.correct,
+            length: self.text.chars().count(),
+            accuracy: ((entrant.keys - entrant.mistakes) * 100)
+                .checked_div(entrant.keys)
+                .unwrap_or(100),
+            wpm: entrant.correct as u64 * 60_000 / 5 / elapsed_ms.max(1000),
+            place: entrant.finished.map(|(_, place)| place),
+        }
+    }
+}
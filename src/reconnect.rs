@@ -0,0 +1,73 @@
+//! What a client is told when the server closes its connection, or is about
+//! to shut down: how long to wait before reconnecting, as `[reconnect]`
+//! configures, and how to get its place back. The hint comes in a
+//! `serverShutdown` message and again as JSON in the reason of the close
+//! frame that follows, as it does when the server closes a connection for
+//! misbehaving, so that clients which only see the close frame get it too.
+//!
+//! A resume token names the room and participant a connection was, so a
+//! client that kept nothing else can send it back in `fetchRoom` with its
+//! `lastSeq`. It carries no state of the server's and outlives a restart;
+//! like a `socketId`, it is an identifier and not a credential, so picking
+//! up a place the room still holds takes the `resumeSecret` too.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Serialize;
+
+use crate::config::ReconnectConfig;
+
+/// The most a close frame's reason can hold.
+const MAX_CLOSE_REASON: usize = 123;
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+pub struct ReconnectHint {
+    /// How long to wait before the first attempt.
+    pub retry_after_ms: u64,
+    /// The longest to wait between attempts while they fail.
+    pub max_backoff_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "typescript", ts(optional))]
+    pub resume_token: Option<String>,
+}
+
+impl ReconnectHint {
+    /// A hint to wait as `config` says, at least `at_least_ms`.
+    pub fn new(config: &ReconnectConfig, at_least_ms: u64, resume_token: Option<String>) -> Self {
+        let jitter = rand::random::<u64>() % (config.jitter_ms + 1);
+        let retry_after_ms = (config.retry_after_ms + jitter).max(at_least_ms);
+        Self {
+            retry_after_ms,
+            max_backoff_ms: config.max_backoff_ms.max(retry_after_ms),
+            resume_token,
+        }
+    }
+
+    /// The hint as a close frame's reason, without the resume token if it
+    /// would not fit.
+    pub fn close_reason(&self) -> String {
+        let reason = serde_json::to_string(self).unwrap_or_default();
+        if reason.len() <= MAX_CLOSE_REASON {
+            return reason;
+        }
+        let without_token = Self {
+            resume_token: None,
+            ..self.clone()
+        };
+        serde_json::to_string(&without_token).unwrap_or_default()
+    }
+}
+
+/// The token to resume `participant_id`'s place in `room_id` with.
+pub fn resume_token(room_id: &str, participant_id: &str) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}\n{}", room_id, participant_id))
+}
+
+/// The room and participant `token` names, if it is one.
+pub fn parse_resume_token(token: &str) -> Option<(String, String)> {
+    let bytes = URL_SAFE_NO_PAD.decode(token).ok()?;
+    let text = String::from_utf8(bytes).ok()?;
+    let (room_id, participant_id) = text.split_once('\n')?;
+    Some((room_id.to_string(), participant_id.to_string()))
+}
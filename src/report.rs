@@ -0,0 +1,116 @@
+//! Abuse reports: `report { reason }` from a participant, or `POST
+//! /api/report` from anyone who may see the GUI, files what the room looks
+//! like right then into the audit log as a `reported` entry, for the
+//! operator to review with `GET /admin/audit` or a webhook. The snapshot is
+//! bounded, and each reporter may file a few reports an hour.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::RoomView;
+
+/// Reports one reporter may file an hour.
+const MAX_PER_HOUR: usize = 5;
+pub const MAX_REASON_CHARS: usize = 1000;
+/// Of each participant's lines, the last kept in a snapshot.
+const LINES_KEPT: usize = 20;
+/// Characters kept of each line.
+const LINE_CHARS: usize = 500;
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportRequest {
+    /// The room reported.
+    pub room: String,
+    pub reason: String,
+}
+
+/// The room as the reporter saw it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    topic: Option<String>,
+    participants: Vec<String>,
+    /// Each participant's last lines, the last one still being typed.
+    lines: BTreeMap<String, Vec<String>>,
+    /// Whether lines were left out, or cut short.
+    truncated: bool,
+}
+
+impl Snapshot {
+    pub fn of(view: &RoomView) -> Self {
+        let mut truncated = false;
+        let lines = view
+            .messages
+            .iter()
+            .map(|(id, lines)| {
+                let skipped = lines.len().saturating_sub(LINES_KEPT);
+                truncated |= skipped > 0;
+                let kept = lines[skipped..]
+                    .iter()
+                    .map(|line| {
+                        truncated |= line.chars().count() > LINE_CHARS;
+                        line.chars().take(LINE_CHARS).collect()
+                    })
+                    .collect();
+                (id.clone(), kept)
+            })
+            .collect();
+        let mut participants = vec![view.your_id.clone()];
+        participants.extend(view.other_participant_ids.iter().cloned());
+        participants.retain(|id| !id.is_empty());
+        Self {
+            topic: view.settings.topic.clone(),
+            participants,
+            lines,
+            truncated,
+        }
+    }
+}
+
+/// Whether `reason` says why, in at most `MAX_REASON_CHARS`.
+pub fn valid_reason(reason: &str) -> Result<(), String> {
+    if reason.trim().is_empty() || reason.chars().count() > MAX_REASON_CHARS {
+        return Err(format!(
+            "A report needs a reason of at most {} characters.",
+            MAX_REASON_CHARS
+        ));
+    }
+    Ok(())
+}
+
+/// When each reporter filed their reports in the last hour, by IP address
+/// or, without one, by participant.
+#[derive(Default)]
+pub struct Reports(Mutex<HashMap<String, VecDeque<Instant>>>);
+
+impl Reports {
+    /// Counts a report from `ip` or `participant`, unless they have filed
+    /// `MAX_PER_HOUR` already.
+    pub fn admit(&self, ip: Option<IpAddr>, participant: &str) -> Result<(), String> {
+        let reporter = ip.map_or_else(|| participant.to_string(), |ip| ip.to_string());
+        let now = Instant::now();
+        let hour = Duration::from_secs(3600);
+        let mut filed = self.0.lock().unwrap();
+        filed.retain(|_, times| {
+            times.retain(|&at| now.duration_since(at) < hour);
+            !times.is_empty()
+        });
+        let times = filed.entry(reporter).or_default();
+        if times.len() >= MAX_PER_HOUR {
+            return Err(format!(
+                "You can report {} times an hour; try again later.",
+                MAX_PER_HOUR
+            ));
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use serde::Serialize;
+use std::{collections::VecDeque, io::Write};
+
+use crate::ServerMessage;
+
+/// Bytes of unacknowledged messages kept per connection. A client that was
+/// gone long enough to miss more than this reloads the room instead.
+const WINDOW_BYTES: usize = 128 * 1024;
+
+/// How a resumed connection catches up.
+pub enum Resume {
+    /// Everything it missed, in order, already numbered.
+    Replay(Vec<String>),
+    /// Too much was missed; it gets the whole room.
+    Reload,
+}
+
+/// What an `error` quotes for a bug report: the connection's id, as logged,
+/// and the id the client gave when it joined, if any.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reference<'a> {
+    pub connection_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<&'a str>,
+}
+
+/// Numbers the messages sent on a connection and keeps those the client
+/// hasn't acknowledged with `ack { seq }`, so that after a dropped connection
+/// it can resume from the last one it saw. Clients that never acknowledge
+/// anything have nothing kept for them.
+#[derive(Debug)]
+pub struct Retransmit {
+    next_seq: u64,
+    /// Serialized messages, oldest first.
+    unacked: VecDeque<(u64, String)>,
+    bytes: usize,
+    acking: bool,
+    /// The size of the last message, which the next is written into a
+    /// buffer of; key presses come in long runs of about the same size.
+    last_len: usize,
+}
+
+impl Default for Retransmit {
+    fn default() -> Self {
+        Self {
+            next_seq: 1,
+            unacked: VecDeque::new(),
+            bytes: 0,
+            acking: false,
+            last_len: 0,
+        }
+    }
+}
+
+impl Retransmit {
+    /// Serializes `message` with the next sequence number, and `reference`
+    /// if given.
+    pub fn stamp(
+        &mut self,
+        message: &ServerMessage,
+        reference: Option<Reference>,
+    ) -> Option<String> {
+        let seq = self.next_seq;
+        let json = self.sequenced(seq, message, reference)?;
+        self.next_seq += 1;
+        if self.acking {
+            self.bytes += json.len();
+            self.unacked.push_back((seq, json.clone()));
+            while self.bytes > WINDOW_BYTES {
+                let Some((_, dropped)) = self.unacked.pop_front() else {
+                    break;
+                };
+                self.bytes -= dropped.len();
+            }
+        }
+        Some(json)
+    }
+
+    /// `{"seq":…,` and then `message`'s fields and `reference`'s, written
+    /// straight into one buffer rather than through a flattened wrapper.
+    fn sequenced(
+        &mut self,
+        seq: u64,
+        message: &ServerMessage,
+        reference: Option<Reference>,
+    ) -> Option<String> {
+        let mut json = Vec::with_capacity(self.last_len);
+        write!(json, "{{\"seq\":{},", seq).ok()?;
+        append_fields(&mut json, message)?;
+        if let Some(reference) = reference {
+            json.pop();
+            json.push(b',');
+            append_fields(&mut json, &reference)?;
+        }
+        self.last_len = json.len();
+        String::from_utf8(json).ok()
+    }
+
+    /// Bytes held for a resume, roughly.
+    pub fn memory(&self) -> usize {
+        self.bytes + self.unacked.len() * std::mem::size_of::<(u64, String)>()
+    }
+
+    pub fn ack(&mut self, seq: u64) {
+        self.acking = true;
+        while self.unacked.front().is_some_and(|(kept, _)| *kept <= seq) {
+            let (_, acked) = self.unacked.pop_front().unwrap();
+            self.bytes -= acked.len();
+        }
+    }
+
+    /// Everything sent after `last_seq`, or `None` if some of it is no
+    /// longer kept.
+    pub fn since(&self, last_seq: u64) -> Option<Vec<String>> {
+        let first = self.unacked.front().map_or(self.next_seq, |(seq, _)| *seq);
+        if last_seq >= self.next_seq || last_seq + 1 < first {
+            return None;
+        }
+        Some(
+            self.unacked
+                .iter()
+                .filter(|(seq, _)| *seq > last_seq)
+                .map(|(_, json)| json.clone())
+                .collect(),
+        )
+    }
+}
+
+/// Writes `object`, which serializes as a non-empty JSON object, to `json`
+/// without its opening brace.
+fn append_fields(json: &mut Vec<u8>, object: &impl Serialize) -> Option<()> {
+    let start = json.len();
+    serde_json::to_writer(&mut *json, object).ok()?;
+    (json.get(start) == Some(&b'{')).then(|| {
+        json.remove(start);
+    })
+}
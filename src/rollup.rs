@@ -0,0 +1,138 @@
+//! Per-room daily activity: lines, characters, peak participants and active
+//! minutes. Rooms count it as it happens, and every minute it is added to
+//! the store's totals for the UTC day, which `GET /admin/rollups` lists.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use utoipa::ToSchema;
+
+use crate::SharedState;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One room's activity on one day, or some part of it not yet stored.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Rollup {
+    /// Lines finished by participants; joins and other room events aren't
+    /// counted.
+    pub lines: u64,
+    pub characters: u64,
+    pub peak_participants: u64,
+    /// Minutes in which anyone typed.
+    pub active_minutes: u64,
+}
+
+impl Rollup {
+    /// Folds in more activity from the same day.
+    pub fn add(&mut self, other: &Rollup) {
+        self.lines += other.lines;
+        self.characters += other.characters;
+        self.peak_participants = self.peak_participants.max(other.peak_participants);
+        self.active_minutes += other.active_minutes;
+    }
+}
+
+/// A room's totals for a day, as `YYYY-MM-DD` in UTC.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyRollup {
+    pub room: String,
+    pub day: String,
+    #[serde(flatten)]
+    pub totals: Rollup,
+}
+
+/// Which rollups `GET /admin/rollups` returns; days are inclusive.
+#[derive(Debug, Default)]
+pub struct RollupQuery {
+    pub room: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+impl RollupQuery {
+    pub fn matches(&self, rollup: &DailyRollup) -> bool {
+        self.room.as_ref().is_none_or(|room| *room == rollup.room)
+            && self.since.as_ref().is_none_or(|since| rollup.day >= *since)
+            && self.until.as_ref().is_none_or(|until| rollup.day <= *until)
+    }
+}
+
+/// A room's activity not yet added to the store, by day.
+#[derive(Debug, Default)]
+pub struct Activity {
+    days: BTreeMap<String, Rollup>,
+    /// The Unix minute of the last key press, so that each minute counts
+    /// once however much was typed in it.
+    last_minute: Option<u64>,
+}
+
+impl Activity {
+    fn on(&mut self, now: SystemTime) -> &mut Rollup {
+        let day = chrono::DateTime::<chrono::Utc>::from(now)
+            .format("%Y-%m-%d")
+            .to_string();
+        self.days.entry(day).or_default()
+    }
+
+    pub fn typed(&mut self, now: SystemTime) {
+        let minute = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60;
+        if self.last_minute != Some(minute) {
+            self.last_minute = Some(minute);
+            self.on(now).active_minutes += 1;
+        }
+    }
+
+    pub fn committed(&mut self, line: &str, now: SystemTime) {
+        let today = self.on(now);
+        today.lines += 1;
+        today.characters += line.chars().count() as u64;
+    }
+
+    pub fn present(&mut self, headcount: usize, now: SystemTime) {
+        let today = self.on(now);
+        today.peak_participants = today.peak_participants.max(headcount as u64);
+    }
+
+    pub fn take(&mut self) -> BTreeMap<String, Rollup> {
+        std::mem::take(&mut self.days)
+    }
+}
+
+/// Adds every room's activity to the store now and then.
+pub async fn run(state: SharedState) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        flush(&state).await;
+    }
+}
+
+/// Adds every room's activity so far to the store.
+pub async fn flush(state: &SharedState) {
+    let now = SystemTime::now();
+    let saving = state.clone();
+    state
+        .rooms
+        .each(move |room| {
+            // Rooms people sit in count towards the day's peak even when
+            // nobody joins or types.
+            let headcount = room.headcount();
+            if headcount > 0 {
+                room.activity.present(headcount, now);
+            }
+            save(&saving, &room.id, room.activity.take());
+        })
+        .await;
+}
+
+/// Queues `days` of a room's activity to be added to the store.
+pub fn save(state: &SharedState, room: &str, days: BTreeMap<String, Rollup>) {
+    for (day, totals) in days {
+        state.store_writer.add_rollup(room.to_string(), day, totals);
+    }
+}
@@ -0,0 +1,35 @@
+//! Cleaning up text before a room keeps it, as `[text]` configures: lines
+//! are cleaned as they are finished, and so are topics and imported lines,
+//! so what participants, transcripts and exports see can't be made to read
+//! differently from what it says. A line in progress is left as typed, since
+//! clients count cursor positions into it.
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::TextConfig;
+
+/// `text` with what `config` strips taken out, composed to NFC if it asks.
+pub fn clean(text: &str, config: &TextConfig) -> String {
+    let stripped = |c: char| {
+        (config.strip_bidi_controls && is_bidi_control(c))
+            || (config.strip_zero_width && is_zero_width(c))
+    };
+    let kept = text.chars().filter(|&c| !stripped(c));
+    if config.nfc {
+        kept.nfc().collect()
+    } else {
+        kept.collect()
+    }
+}
+
+/// Embeddings, overrides and isolates. The left-to-right and right-to-left
+/// marks only nudge neutral characters and are kept.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Zero-width characters with no job in text. The zero-width joiner and
+/// non-joiner are kept: emoji sequences and Persian, among others, need them.
+fn is_zero_width(c: char) -> bool {
+    matches!(c, '\u{200b}' | '\u{2060}' | '\u{feff}')
+}
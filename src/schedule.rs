@@ -0,0 +1,175 @@
+//! Rooms scheduled ahead, say for a typing interview: `POST /admin/rooms`
+//! with `startsAt` and `durationSecs` makes a room that refuses joins with
+//! `notStarted` until it starts and reaches its maximum age when the window
+//! ends. `GET /api/rooms/<id>/calendar.ics` is the window as an iCalendar
+//! event, for whoever is invited to add to their calendar.
+
+use hyper::{header, Body, Request, Response, StatusCode};
+use tracing::error;
+
+use crate::{
+    embed::valid_room_id,
+    expiry::{self, Lifetimes},
+    SharedState, MAX_ROOM_TTL_SECS,
+};
+
+/// How far ahead a room can be scheduled.
+const MAX_AHEAD_SECS: u64 = 90 * 24 * 3600;
+/// How long a scheduled room lasts unless asked otherwise.
+pub const DEFAULT_DURATION_SECS: u64 = 3600;
+
+/// The maximum age, counted from `now`, of a room open from `starts_at` for
+/// `duration_secs`, if `rooms_max_age_secs` (`[rooms] max_age_secs`)
+/// allows it.
+pub fn max_age(
+    now: u64,
+    starts_at: u64,
+    duration_secs: u64,
+    rooms_max_age_secs: Option<u64>,
+) -> Result<u64, String> {
+    if !(1..=MAX_ROOM_TTL_SECS).contains(&duration_secs) {
+        return Err(format!(
+            "durationSecs must be between 1 and {}.",
+            MAX_ROOM_TTL_SECS
+        ));
+    }
+    if starts_at > now + MAX_AHEAD_SECS {
+        return Err(format!(
+            "Rooms can be scheduled at most {} days ahead.",
+            MAX_AHEAD_SECS / 86400
+        ));
+    }
+    let ends_at = starts_at + duration_secs;
+    if ends_at <= now {
+        return Err("The scheduled window is over already.".to_string());
+    }
+    let max_age = ends_at - now;
+    if rooms_max_age_secs.is_some_and(|cap| cap < max_age) {
+        return Err(
+            "[rooms] max_age_secs would expire the room before its window ends.".to_string(),
+        );
+    }
+    Ok(max_age)
+}
+
+/// Handles `GET /api/rooms/<id>/calendar.ics`, for scheduled rooms only.
+pub async fn calendar(req: &Request<Body>, state: &SharedState, id: &str) -> Response<Body> {
+    let not_found = || {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap()
+    };
+    if !valid_room_id(id) {
+        return not_found();
+    }
+    let (settings, created_at) = match state.rooms.get(id) {
+        Some(room) => {
+            let room = room.published();
+            (room.settings.clone(), expiry::unix_secs(room.created_at))
+        }
+        None => match state.store.load_room(id).await {
+            Ok(Some(record)) => (record.settings, record.created_at),
+            Ok(None) => return not_found(),
+            Err(err) => {
+                error!("Failed to load room {}: {}", id, err);
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+        },
+    };
+    let lifetimes = Lifetimes::from(&state.config().rooms);
+    let (Some(starts_at), Some(max_age)) =
+        (settings.starts_at, lifetimes.max_age(settings.max_age_secs))
+    else {
+        return not_found();
+    };
+    let ends_at = created_at + max_age.as_secs();
+
+    let host = ["x-forwarded-host", header::HOST.as_str()]
+        .iter()
+        .find_map(|name| req.headers().get(*name)?.to_str().ok());
+    let scheme = req
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or(if state.config().acme.is_some() {
+            "https"
+        } else {
+            "http"
+        });
+    let summary = match &settings.topic {
+        Some(topic) => topic.clone(),
+        None => format!("typeto.me room {}", id),
+    };
+    let mut event = vec![
+        format!("UID:{}-{}@typeto.me", id, created_at),
+        format!(
+            "DTSTAMP:{}",
+            timestamp(expiry::unix_secs(std::time::SystemTime::now()))
+        ),
+        format!("DTSTART:{}", timestamp(starts_at)),
+        format!("DTEND:{}", timestamp(ends_at)),
+        format!("SUMMARY:{}", escape(&summary)),
+    ];
+    if let Some(host) = host {
+        let url = format!("{}://{}/{}", scheme, host, id);
+        event.push(format!("URL:{}", url));
+        event.push(format!("LOCATION:{}", escape(&url)));
+        event.push(format!(
+            "DESCRIPTION:{}",
+            escape(&format!("Join at {}", url))
+        ));
+    }
+    let mut ics = String::from(
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//typeto.me//typeto-server//EN\r\n\
+         METHOD:PUBLISH\r\nBEGIN:VEVENT\r\n",
+    );
+    for line in event {
+        ics.push_str(&fold(&line));
+    }
+    ics.push_str("END:VEVENT\r\nEND:VCALENDAR\r\n");
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/calendar; charset=utf-8")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.ics\"", id),
+        )
+        .body(Body::from(ics))
+        .unwrap()
+}
+
+/// Unix seconds as an iCalendar UTC date-time, `20240131T093000Z`.
+fn timestamp(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+/// `line` ended, and broken into lines of at most 75 bytes as iCalendar
+/// requires, each continuation starting with a space.
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+/// `text` as an iCalendar TEXT value.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
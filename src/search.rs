@@ -0,0 +1,122 @@
+//! Searching a room's finished lines, for `searchHistory` on the socket and
+//! `GET /api/rooms/:id/search`.
+
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{storage::HistoryLine, SharedState};
+
+const MAX_QUERY_LEN: usize = 200;
+/// Hits returned for one search; later ones are left out and the results
+/// marked truncated.
+const MAX_HITS: usize = 200;
+/// Compiled size a `regex` query may take, so a pathological one is refused
+/// rather than built.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// What to look for in each line.
+#[derive(Debug)]
+pub enum Matcher {
+    /// Case-insensitive substring, already lowercased.
+    Text(String),
+    Pattern(Regex),
+}
+
+impl Matcher {
+    pub fn new(query: &str, regex: bool) -> Result<Self, String> {
+        if query.is_empty() {
+            return Err("Search for something.".to_string());
+        }
+        if query.len() > MAX_QUERY_LEN {
+            return Err(format!(
+                "Searches are limited to {} characters.",
+                MAX_QUERY_LEN
+            ));
+        }
+        if !regex {
+            return Ok(Self::Text(query.to_lowercase()));
+        }
+        RegexBuilder::new(query)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .build()
+            .map(Self::Pattern)
+            .map_err(|err| format!("Invalid search pattern: {}", err))
+    }
+
+    fn matches(&self, line: &str) -> bool {
+        match self {
+            Self::Text(text) => line.to_lowercase().contains(text),
+            Self::Pattern(pattern) => pattern.is_match(line),
+        }
+    }
+}
+
+/// A matching line and where it is: `index` counts the participant's lines
+/// from the oldest one searched.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+pub struct Hit {
+    pub participant: String,
+    pub index: usize,
+    pub line: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+pub struct Results {
+    pub hits: Vec<Hit>,
+    /// More lines matched than `hits` holds.
+    pub truncated: bool,
+}
+
+/// The lines matching `matcher`, in the order given, out of
+/// `(participant, line)` pairs.
+pub fn find<'a>(lines: impl IntoIterator<Item = (&'a str, &'a str)>, matcher: &Matcher) -> Results {
+    let mut counts = std::collections::HashMap::new();
+    let mut results = Results::default();
+    for (participant, line) in lines {
+        let count = counts.entry(participant).or_insert(0);
+        let index = *count;
+        *count += 1;
+        if line.is_empty() || !matcher.matches(line) {
+            continue;
+        }
+        if results.hits.len() == MAX_HITS {
+            results.truncated = true;
+            break;
+        }
+        results.hits.push(Hit {
+            participant: participant.to_string(),
+            index,
+            line: line.to_string(),
+        });
+    }
+    results
+}
+
+/// Room `id`'s finished lines: its stored history, which with sled or
+/// PostgreSQL goes back further than the room keeps in memory, or without
+/// one the live room's lines, in the order they were finished.
+pub async fn lines(state: &SharedState, id: &str) -> Result<Vec<HistoryLine>, String> {
+    let stored = state.store.load_history(id).await?;
+    if !stored.is_empty() {
+        return Ok(stored);
+    }
+    Ok(state
+        .rooms
+        .get(id)
+        .map(|room| room.published().lines.to_vec())
+        .unwrap_or_default())
+}
+
+/// Searches room `id`'s finished lines.
+pub async fn room(state: &SharedState, id: &str, matcher: &Matcher) -> Result<Results, String> {
+    let lines = lines(state, id).await?;
+    Ok(find(
+        lines
+            .iter()
+            .map(|line| (line.participant.as_str(), line.text.as_str())),
+        matcher,
+    ))
+}
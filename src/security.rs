@@ -0,0 +1,45 @@
+//! Security events, one line each under the `security` log target, for
+//! fail2ban, CrowdSec and the like to act on. After the target, a line is
+//! `event=<event> ip=<address>` and then that event's fields, always in the
+//! same order, with values that never contain spaces; an unknown address is
+//! `-`. A request that offered no credentials at all isn't a failed attempt
+//! and isn't logged.
+
+use std::net::IpAddr;
+
+use tracing::warn;
+
+pub const TARGET: &str = "security";
+
+pub enum Event {
+    /// `credential` was offered and was wrong.
+    AuthFailed { credential: &'static str },
+    /// The address was banned by the tarpit for `secs`.
+    Banned { secs: u64, reason: &'static str },
+    /// The connection was closed for breaking the protocol.
+    Disconnected { reason: &'static str },
+    /// Decoy room `room` was fetched.
+    Honeypot { room: String },
+}
+
+pub fn log(ip: Option<IpAddr>, event: Event) {
+    let ip = ip.map_or_else(|| "-".to_string(), |ip| ip.to_string());
+    match event {
+        Event::AuthFailed { credential } => warn!(
+            target: TARGET,
+            "event=auth_failed ip={} credential={}", ip, credential
+        ),
+        Event::Banned { secs, reason } => warn!(
+            target: TARGET,
+            "event=banned ip={} secs={} reason={}", ip, secs, reason
+        ),
+        Event::Disconnected { reason } => warn!(
+            target: TARGET,
+            "event=disconnected ip={} reason={}", ip, reason
+        ),
+        Event::Honeypot { room } => warn!(
+            target: TARGET,
+            "event=honeypot ip={} room={}", ip, room
+        ),
+    }
+}
@@ -0,0 +1,66 @@
+use hyper::{header, header::HeaderValue, Body, Response};
+
+use crate::config::SecurityHeadersConfig;
+
+/// Everything the bundled GUI needs: its own modules, the `cre` module from
+/// unpkg, Google Fonts, and its own WebSocket.
+const DEFAULT_CSP: &str = "default-src 'self'; script-src 'self' https://unpkg.com; \
+     style-src 'self' 'unsafe-inline' https://fonts.googleapis.com; \
+     font-src https://fonts.gstatic.com; img-src 'self' data:; connect-src 'self'; \
+     base-uri 'none'; form-action 'self'";
+
+/// Hardening headers for the static GUI, for instances exposed without a
+/// proxy that would otherwise add them.
+pub struct SecurityHeaders {
+    enabled: bool,
+    content_security_policy: String,
+    frame_ancestors: String,
+    referrer_policy: String,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &SecurityHeadersConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            content_security_policy: config
+                .content_security_policy
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CSP.to_string()),
+            frame_ancestors: config.frame_ancestors.join(" "),
+            referrer_policy: config.referrer_policy.clone(),
+        }
+    }
+
+    /// `frame_ancestors` overrides the configured value, e.g. for a page
+    /// framed by an embedding partner.
+    pub fn apply(&self, response: &mut Response<Body>, frame_ancestors: Option<&str>) {
+        let frame_ancestors = frame_ancestors.unwrap_or(&self.frame_ancestors);
+        let headers = response.headers_mut();
+        if !self.enabled {
+            // Embedded pages still need their framing allowance.
+            if frame_ancestors != self.frame_ancestors {
+                if let Ok(value) =
+                    HeaderValue::from_str(&format!("frame-ancestors {}", frame_ancestors))
+                {
+                    headers.insert(header::CONTENT_SECURITY_POLICY, value);
+                }
+            }
+            return;
+        }
+
+        let csp = format!(
+            "{}; frame-ancestors {}",
+            self.content_security_policy, frame_ancestors
+        );
+        if let Ok(value) = HeaderValue::from_str(&csp) {
+            headers.insert(header::CONTENT_SECURITY_POLICY, value);
+        }
+        headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        if let Ok(value) = HeaderValue::from_str(&self.referrer_policy) {
+            headers.insert(header::REFERRER_POLICY, value);
+        }
+    }
+}
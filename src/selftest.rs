@@ -0,0 +1,224 @@
+//! A soak test, started with the hidden `--selftest[=PAIRS]` flag. The
+//! server runs as usual, and next to it pairs of bots join rooms through the
+//! same calls a gRPC session makes and type lines to each other, each pair
+//! moving on to a fresh room every few lines so rooms keep being opened and
+//! left for the sweep. Every ten seconds it logs how many rooms are in memory,
+//! the process's resident memory and how long key presses took to reach the
+//! other bot, so that whatever only ever grows shows up before a release does.
+//! The bots' rooms are stored like any other; run it with a scratch config.
+
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info, warn};
+
+use crate::{
+    depart, enter_room, press_key, retransmit::Retransmit, Connection, Counters, ServerMessage,
+    SharedState,
+};
+
+/// Pairs of bots when the flag doesn't say.
+const DEFAULT_PAIRS: usize = 200;
+/// Between one bot's key presses: about 80 words a minute.
+const KEY_INTERVAL: Duration = Duration::from_millis(150);
+const LINE_LEN: usize = 40;
+/// Lines typed in a room before the pair moves to the next one.
+const LINES_PER_ROOM: usize = 6;
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a key press may take to arrive before it counts as lost.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many pairs of bots `--selftest` asks for, if it was given.
+pub fn from_args() -> Result<Option<usize>, String> {
+    for arg in std::env::args().skip(1) {
+        if arg == "--selftest" {
+            return Ok(Some(DEFAULT_PAIRS));
+        }
+        if let Some(pairs) = arg.strip_prefix("--selftest=") {
+            return match pairs.parse() {
+                Ok(pairs) if pairs > 0 => Ok(Some(pairs)),
+                _ => Err(format!(
+                    "--selftest takes a number of pairs, not {:?}",
+                    pairs
+                )),
+            };
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Default)]
+struct Stats {
+    keys: AtomicU64,
+    lost: AtomicU64,
+    refused: AtomicU64,
+    /// Since the last report.
+    latencies: Mutex<Vec<Duration>>,
+}
+
+/// Starts `pairs` pairs of bots and reports on them until the server stops.
+pub async fn run(state: SharedState, pairs: usize) {
+    warn!("Self-test: {} pairs of bots are typing", pairs);
+    let stats = Arc::new(Stats::default());
+    for pair in 0..pairs {
+        tokio::spawn(run_pair(state.clone(), pair, stats.clone()));
+    }
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        report(&state, &stats, started);
+    }
+}
+
+fn report(state: &SharedState, stats: &Stats, started: Instant) {
+    let mut latencies = mem::take(&mut *stats.latencies.lock().unwrap());
+    latencies.sort();
+    let quantile = |q: f64| {
+        let index = ((latencies.len() as f64 * q) as usize).min(latencies.len().saturating_sub(1));
+        latencies.get(index).copied().unwrap_or_default()
+    };
+    info!(
+        "Self-test at {}s: {} rooms in memory, {} resident, {} keys ({} lost, {} rooms refused), \
+         delivered p50 {:?} p99 {:?} max {:?}",
+        started.elapsed().as_secs(),
+        state.rooms.published().len(),
+        resident_memory().map_or_else(|| "? MiB".to_string(), |kib| format!("{} MiB", kib / 1024)),
+        stats.keys.load(Ordering::Relaxed),
+        stats.lost.load(Ordering::Relaxed),
+        stats.refused.load(Ordering::Relaxed),
+        quantile(0.5),
+        quantile(0.99),
+        latencies.last().copied().unwrap_or_default(),
+    );
+}
+
+/// The process's resident set in KiB, where `/proc` says.
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Two bots taking turns to type a line, room after room.
+async fn run_pair(state: SharedState, pair: usize, stats: Arc<Stats>) {
+    // Spread the pairs out instead of typing in lockstep.
+    tokio::time::sleep(KEY_INTERVAL.mul_f64(rand::random::<f64>())).await;
+    for generation in 0.. {
+        let room_id = format!("selftest-{}-{}", pair, generation);
+        let Some(mut bots) = enter_pair(&state, &room_id).await else {
+            stats.refused.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(REPORT_INTERVAL).await;
+            continue;
+        };
+        let mut ticker = tokio::time::interval(KEY_INTERVAL);
+        'room: for line in 0..LINES_PER_ROOM {
+            let [typist, reader] = &mut bots;
+            let (typist, reader) = if line % 2 == 0 {
+                (typist, reader)
+            } else {
+                (reader, typist)
+            };
+            for pos in 0..LINE_LEN {
+                ticker.tick().await;
+                let key = char::from(b'a' + (pos % 26) as u8).to_string();
+                let sent = Instant::now();
+                if !typist.press(&state, &room_id, &key, Some(pos)).await {
+                    break 'room;
+                }
+                stats.keys.fetch_add(1, Ordering::Relaxed);
+                if reader.await_key(typist.id, pos).await {
+                    stats.latencies.lock().unwrap().push(sent.elapsed());
+                } else {
+                    stats.lost.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            ticker.tick().await;
+            typist.press(&state, &room_id, "Enter", None).await;
+        }
+        for bot in bots {
+            bot.leave(&state, &room_id);
+        }
+    }
+}
+
+async fn enter_pair(state: &SharedState, room_id: &str) -> Option<[Bot; 2]> {
+    let first = Bot::enter(state, room_id, "bot-a").await?;
+    let Some(second) = Bot::enter(state, room_id, "bot-b").await else {
+        first.leave(state, room_id);
+        return None;
+    };
+    Some([first, second])
+}
+
+/// A participant with a connection of its own, like a gRPC session's.
+struct Bot {
+    id: &'static str,
+    connection: Connection,
+    events: broadcast::Receiver<ServerMessage>,
+}
+
+impl Bot {
+    async fn enter(state: &SharedState, room_id: &str, id: &'static str) -> Option<Self> {
+        let (sender, events) = broadcast::channel(32);
+        let connection = Connection {
+            sender,
+            traffic: Arc::new(Counters::default()),
+            retransmit: Arc::new(Mutex::new(Retransmit::default())),
+            ip: None,
+        };
+        enter_room(state, &connection, room_id, id, true, None, None)
+            .await
+            .then_some(Self {
+                id,
+                connection,
+                events,
+            })
+    }
+
+    async fn press(
+        &self,
+        state: &SharedState,
+        room_id: &str,
+        key: &str,
+        cursor_pos: Option<usize>,
+    ) -> bool {
+        let sender = &self.connection.sender;
+        press_key(state, sender, room_id, self.id, key, cursor_pos, None).await
+    }
+
+    /// Waits for `source`'s key press at `pos`; false if it doesn't come.
+    async fn await_key(&mut self, source: &str, pos: usize) -> bool {
+        let arrived = async {
+            loop {
+                match self.events.recv().await {
+                    Ok(ServerMessage::KeyPress {
+                        source: from,
+                        cursor_pos,
+                        ..
+                    }) if &*from == source && cursor_pos == Some(pos) => return true,
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return false,
+                }
+            }
+        };
+        tokio::time::timeout(DELIVERY_TIMEOUT, arrived)
+            .await
+            .unwrap_or(false)
+    }
+
+    fn leave(self, state: &SharedState, room_id: &str) {
+        if let Some(room) = state.rooms.get(room_id) {
+            let state = state.clone();
+            room.cast(move |room| depart(&state, room, self.id, &self.connection.sender));
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! `GET /api/server-info`: what this instance is and what it can do, for
+//! clients to adapt to and for operators to check a deployment. It is open
+//! to anyone who may see the GUI and says nothing a visitor couldn't find
+//! out by trying.
+
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{
+    config::{Config, CreationPolicy, StorageConfig},
+    json_response, SharedState, PROTOCOL_VERSION,
+};
+
+/// Cargo features this binary was built with.
+const BUILT_FEATURES: &[(&str, bool)] = &[
+    ("acme", cfg!(feature = "acme")),
+    ("graphql", cfg!(feature = "graphql")),
+    ("grpc", cfg!(feature = "grpc")),
+    ("discord", cfg!(feature = "discord")),
+    ("postgres", cfg!(feature = "postgres")),
+    ("sled", cfg!(feature = "sled")),
+];
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    version: &'static str,
+    /// The commit built from, if the build knew it.
+    commit: Option<&'static str>,
+    /// Of the WebSocket protocol.
+    protocol_version: u32,
+    built_features: Vec<&'static str>,
+    features: Features,
+    uptime_secs: u64,
+    limits: Limits,
+}
+
+/// What this instance is configured to do.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Features {
+    /// Rooms are kept by a storage backend and outlive a restart.
+    persistence: bool,
+    /// Expired rooms are archived and can be revived.
+    archive: bool,
+    /// Who may create rooms: `open`, `invite` or `admin`.
+    creation: &'static str,
+    login: bool,
+    embed: bool,
+    graphql: bool,
+    grpc: bool,
+    push_notifications: bool,
+    compression: bool,
+    /// Advertised on the local network, with peers at `/api/lan-peers`.
+    lan: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Limits {
+    max_participants: usize,
+    max_devices: usize,
+    max_message_bytes: usize,
+    keypresses_per_sec: Option<u32>,
+    keypress_burst: u32,
+    max_connections: Option<usize>,
+    idle_ttl_secs: u64,
+    max_age_secs: Option<u64>,
+}
+
+pub fn handle(state: &SharedState) -> Response<Body> {
+    let config = state.config();
+    let info = ServerInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: option_env!("TYPETO_GIT_COMMIT"),
+        protocol_version: PROTOCOL_VERSION,
+        built_features: BUILT_FEATURES
+            .iter()
+            .filter(|(_, built)| *built)
+            .map(|(name, _)| *name)
+            .collect(),
+        features: features(&config),
+        uptime_secs: state.started.elapsed().as_secs(),
+        limits: Limits {
+            max_participants: crate::MAX_PARTICIPANTS,
+            max_devices: config.rooms.max_devices,
+            max_message_bytes: config.limits.max_message_bytes,
+            keypresses_per_sec: config.limits.keypresses_per_sec,
+            keypress_burst: config.limits.keypress_burst,
+            max_connections: config.limits.max_connections,
+            idle_ttl_secs: config.rooms.idle_ttl_secs,
+            max_age_secs: config.rooms.max_age_secs,
+        },
+    };
+    json_response(StatusCode::OK, json!(info))
+}
+
+fn features(config: &Config) -> Features {
+    Features {
+        persistence: !matches!(config.storage, StorageConfig::Memory),
+        archive: config.rooms.archive_grace_secs > 0,
+        creation: match config.rooms.creation {
+            CreationPolicy::Open => "open",
+            CreationPolicy::Invite => "invite",
+            CreationPolicy::Admin => "admin",
+        },
+        login: config.oidc.is_some(),
+        embed: config.embed.is_some(),
+        graphql: cfg!(feature = "graphql") && config.graphql.is_some(),
+        grpc: cfg!(feature = "grpc") && config.grpc.is_some(),
+        push_notifications: !config.mentions.push_hosts.is_empty(),
+        compression: config.compression.enabled,
+        lan: config.lan.is_some(),
+    }
+}
@@ -0,0 +1,90 @@
+//! Short links to rooms, `/j/<code>`, made to be read out over the phone:
+//! codes are short, lowercase, and leave out characters that sound or look
+//! alike (0/o, 1/l/i).
+
+use hyper::{header, Body, Response, StatusCode};
+use rand::Rng;
+use tracing::error;
+
+use crate::SharedState;
+
+const ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+const MIN_LEN: usize = 4;
+/// Tries at one length before codes get a character longer.
+const TRIES_PER_LEN: usize = 8;
+
+fn generate(len: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..len)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+        .collect()
+}
+
+/// A code no room has yet, in memory or in the store.
+pub async fn fresh(state: &SharedState) -> String {
+    let mut tries = 0;
+    loop {
+        let code = generate(MIN_LEN + tries / TRIES_PER_LEN);
+        tries += 1;
+        if find_in_memory(state, &code).is_some() {
+            continue;
+        }
+        match state.store.find_shortcode(&code).await {
+            Ok(None) => return code,
+            Ok(Some(_)) => {}
+            Err(err) => {
+                error!("Failed to look up short link {}: {}", code, err);
+                return code;
+            }
+        }
+    }
+}
+
+fn find_in_memory(state: &SharedState, code: &str) -> Option<String> {
+    state
+        .rooms
+        .published()
+        .iter()
+        .find(|room| room.shortcode.as_deref() == Some(code))
+        .map(|room| room.id.clone())
+}
+
+/// `id` percent-encoded for a URL path; room ids are whatever the first
+/// client to ask for them chose.
+fn path_segment(id: &str) -> String {
+    id.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// `GET /j/<code>`: a redirect to the room's page.
+pub async fn redirect(state: &SharedState, code: &str) -> Response<Body> {
+    let code = code.trim_end_matches('/').to_ascii_lowercase();
+    let id = match find_in_memory(state, &code) {
+        Some(id) => Some(id),
+        None => state
+            .store
+            .find_shortcode(&code)
+            .await
+            .unwrap_or_else(|err| {
+                error!("Failed to look up short link {}: {}", code, err);
+                None
+            }),
+    };
+    match id {
+        Some(id) => Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, format!("/{}", path_segment(&id)))
+            .body(Body::empty())
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
@@ -0,0 +1,98 @@
+//! `[slack]`: when the last participant leaves one of the rooms it lists,
+//! the conversation is over and a message goes to a Slack channel through
+//! an incoming webhook, with a link to the room and, if asked, the last few
+//! lines each participant finished. Rooms where nobody finished a line are
+//! left out, and a message that can't be posted is logged, not retried.
+
+use hyper::{header, Body, Method, Request};
+use serde_json::json;
+use tracing::warn;
+
+use crate::{webhook::glob, AppState, Room, SYSTEM_ID};
+
+/// Tells the channel, if `[slack]` lists `room`, that its conversation ended.
+pub fn conversation_ended(state: &AppState, room: &Room) {
+    let config = state.config();
+    let Some(slack) = &config.slack else {
+        return;
+    };
+    if !slack.rooms.iter().any(|pattern| glob(pattern, &room.id)) {
+        return;
+    }
+    let mut participants: Vec<(&String, Vec<&String>)> = room
+        .messages
+        .iter()
+        .filter(|(id, _)| id.as_str() != SYSTEM_ID)
+        .map(|(id, lines)| {
+            // The last line is the one still being typed.
+            let finished = lines[..lines.len().saturating_sub(1)]
+                .iter()
+                .filter(|line| !line.is_empty())
+                .collect();
+            (id, finished)
+        })
+        .filter(|(_, lines): &(_, Vec<_>)| !lines.is_empty())
+        .collect();
+    if participants.is_empty() {
+        return;
+    }
+    participants.sort_by_key(|(id, _)| id.as_str());
+
+    let lines: usize = participants.iter().map(|(_, lines)| lines.len()).sum();
+    let room_name = match &slack.public_url {
+        Some(url) => format!(
+            "<{}/{}|{}>",
+            url.trim_end_matches('/'),
+            room.id,
+            escape(&room.id)
+        ),
+        None => format!("*{}*", escape(&room.id)),
+    };
+    let mut text = format!(
+        "The conversation in typeto.me room {} ended: {} participant{}, {} line{}.",
+        room_name,
+        participants.len(),
+        if participants.len() == 1 { "" } else { "s" },
+        lines,
+        if lines == 1 { "" } else { "s" },
+    );
+    if slack.snippet_lines > 0 {
+        for (id, finished) in &participants {
+            text.push_str(&format!("\n*{}*", escape(id)));
+            let skip = finished.len().saturating_sub(slack.snippet_lines);
+            for line in &finished[skip..] {
+                text.push_str(&format!("\n> {}", escape(line)));
+            }
+        }
+    }
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&slack.webhook_url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({"text": text}).to_string()));
+    let Ok(request) = request else {
+        warn!("[slack] webhook_url {:?} is not a URL", slack.webhook_url);
+        return;
+    };
+    let client = state.http_client.clone();
+    let room_id = room.id.clone();
+    tokio::spawn(async move {
+        match client.request(request).await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!(
+                "Slack message about room {} returned {}",
+                room_id,
+                response.status()
+            ),
+            Err(err) => warn!("Slack message about room {} failed: {}", room_id, err),
+        }
+    });
+}
+
+/// `text` with the characters Slack's markup reserves escaped.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info};
+
+use crate::{storage::RoomRecord, AppState, Room};
+
+/// A room as written on shutdown or handed off: its record plus the
+/// conversation so far. Participants are not kept; everyone reconnects to a
+/// restored room.
+#[derive(Serialize, Deserialize)]
+pub struct SavedRoom {
+    #[serde(flatten)]
+    record: RoomRecord,
+    messages: HashMap<String, Vec<String>>,
+    /// Unix seconds, so the idle TTL keeps counting across the restart.
+    last_update: u64,
+}
+
+impl SavedRoom {
+    pub fn new(room: &Room) -> Self {
+        Self {
+            record: room.record(),
+            messages: room.messages.clone(),
+            last_update: room
+                .last_update
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.record.id
+    }
+
+    pub fn into_room(self) -> Room {
+        let mut room = Room::from_record(self.record, Vec::new());
+        room.messages = self.messages;
+        room.last_update = UNIX_EPOCH + Duration::from_secs(self.last_update);
+        room
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// Unix seconds.
+    saved_at: u64,
+    rooms: Vec<SavedRoom>,
+}
+
+/// Reads the rooms saved by the previous process, if it left a snapshot.
+/// The file is removed once read, so rooms deleted after this start can't
+/// come back from it later.
+pub fn load(path: &Path) -> Result<Vec<Room>, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+    };
+    let snapshot: Snapshot = serde_json::from_str(&text)
+        .map_err(|err| format!("Invalid snapshot {}: {}", path.display(), err))?;
+    std::fs::remove_file(path)
+        .map_err(|err| format!("Failed to remove {}: {}", path.display(), err))?;
+    info!(
+        "Restored {} room(s) from the snapshot taken at {}",
+        snapshot.rooms.len(),
+        snapshot.saved_at
+    );
+    Ok(snapshot
+        .rooms
+        .into_iter()
+        .map(SavedRoom::into_room)
+        .collect())
+}
+
+/// Writes every room in memory to `path`, replacing it atomically.
+fn save(path: &Path, rooms: Vec<SavedRoom>) -> Result<usize, String> {
+    let snapshot = Snapshot {
+        saved_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        rooms,
+    };
+    let json = serde_json::to_vec(&snapshot).map_err(|err| err.to_string())?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, json)
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+    Ok(snapshot.rooms.len())
+}
+
+/// Saves the rooms if `[snapshot]` is configured; called once the server
+/// has stopped accepting connections.
+pub async fn save_on_shutdown(state: &AppState) {
+    let Some(path) = &state.snapshot else {
+        return;
+    };
+    let rooms = state.rooms.each(|room| SavedRoom::new(room)).await;
+    match save(path, rooms) {
+        Ok(count) => info!("Saved {} room(s) to {}", count, path.display()),
+        Err(err) => error!("{}", err),
+    }
+}
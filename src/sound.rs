@@ -0,0 +1,60 @@
+//! Typewriter sounds for others' typing, for clients that play them. A
+//! connection that joins with `"sounds": true` is sent a `keySound` after
+//! each relayed key press and commit, saying which sound goes with it, so a
+//! client needn't work that out from the edit; others aren't sent them.
+
+use serde::{Deserialize, Serialize};
+
+use typeto_client_core::is_character;
+
+use crate::ServerMessage;
+
+/// The column where a typewriter's margin bell rings, a few characters
+/// before the end of an 80-column line.
+const BELL_COLUMN: usize = 72;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "lowercase")]
+pub enum KeySound {
+    /// A character or space typed.
+    Keypress,
+    /// Something deleted.
+    Backspace,
+    /// A character typed that reached the bell column.
+    Bell,
+    /// A line finished: the carriage return.
+    Return,
+}
+
+/// The `keySound` to send after `message`, if it is another participant's
+/// key press or commit that makes one.
+pub fn after(message: &ServerMessage) -> Option<ServerMessage> {
+    let (source, sound) = match message {
+        ServerMessage::KeyPress {
+            key,
+            source,
+            cursor_pos: Some(pos),
+            rev: None,
+        } => (source, for_key(key, *pos)?),
+        ServerMessage::Committed {
+            source, rev: None, ..
+        } => (source, KeySound::Return),
+        _ => return None,
+    };
+    Some(ServerMessage::KeySound {
+        source: source.clone(),
+        sound,
+    })
+}
+
+/// The sound of `key` pressed with the cursor at `pos`.
+fn for_key(key: &str, pos: usize) -> Option<KeySound> {
+    match key {
+        "Backspace" | "Delete" | "DeleteAt" | "CtrlK" => Some(KeySound::Backspace),
+        "Space" => Some(KeySound::Keypress),
+        _ if is_character(key) && pos + 1 == BELL_COLUMN => Some(KeySound::Bell),
+        _ if is_character(key) => Some(KeySound::Keypress),
+        _ => None,
+    }
+}
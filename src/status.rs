@@ -0,0 +1,157 @@
+//! `GET /status`: a page for the users of an instance to tell whether a
+//! problem is theirs or the server's. It shows whether the server is up and
+//! taking rooms, how loaded it is against its limits, and the protocol it
+//! speaks; as HTML for a browser, or as JSON with `?format=json` or
+//! `Accept: application/json`. Unlike `/readyz` it says nothing of the
+//! storage's errors or the sweep's backlog.
+
+use std::{fmt::Write, time::SystemTime};
+
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{health, json_response, SharedState, PROTOCOL_VERSION};
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    /// `ok`; `degraded` while storage doesn't answer or every connection
+    /// allowed is taken; `maintenance` while new rooms are refused; or
+    /// `shuttingDown`.
+    status: &'static str,
+    /// What the operator said about the maintenance.
+    message: Option<String>,
+    version: &'static str,
+    /// Of the WebSocket protocol.
+    protocol_version: u32,
+    uptime_secs: u64,
+    storage_ok: bool,
+    load: Load,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Load {
+    connections: usize,
+    /// Unlimited if null.
+    max_connections: Option<usize>,
+    /// Rooms in memory.
+    rooms: usize,
+    keypresses_per_sec: Option<u32>,
+    broadcast_events_per_sec: Option<u32>,
+}
+
+pub async fn handle(req: &Request<Body>, state: &SharedState) -> Response<Body> {
+    let config = state.config();
+    let storage_ok = health::archived_due(state, SystemTime::now()).await.is_ok();
+    let connections = state.connections.open();
+    let max_connections = config.limits.max_connections;
+    let message = state.maintenance_message();
+    let status = if state.shutdown.peek().is_some() {
+        "shuttingDown"
+    } else if message.is_some() {
+        "maintenance"
+    } else if !storage_ok || max_connections.is_some_and(|max| connections >= max) {
+        "degraded"
+    } else {
+        "ok"
+    };
+    let report = Status {
+        status,
+        message,
+        version: env!("CARGO_PKG_VERSION"),
+        protocol_version: PROTOCOL_VERSION,
+        uptime_secs: state.started.elapsed().as_secs(),
+        storage_ok,
+        load: Load {
+            connections,
+            max_connections,
+            rooms: state.rooms.published().len(),
+            keypresses_per_sec: config.limits.keypresses_per_sec,
+            broadcast_events_per_sec: config.limits.broadcast_events_per_sec,
+        },
+    };
+
+    let query = req.uri().query().unwrap_or_default();
+    let wants_json = url::form_urlencoded::parse(query.as_bytes())
+        .any(|(key, value)| key == "format" && value == "json")
+        || req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|accept| {
+                accept.contains("application/json") && !accept.contains("text/html")
+            });
+    if wants_json {
+        return json_response(StatusCode::OK, json!(report));
+    }
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(page(&report)))
+        .unwrap()
+}
+
+fn page(report: &Status) -> String {
+    let headline = match report.status {
+        "ok" => "All systems normal.",
+        "degraded" => "The server is having trouble; connecting may fail.",
+        "maintenance" => "The server is under maintenance; new rooms are refused.",
+        _ => "The server is restarting; reconnect in a moment.",
+    };
+    let limit = |limit: Option<u32>| limit.map_or("unlimited".to_string(), |l| l.to_string());
+    let mut rows = vec![
+        ("Status", report.status.to_string()),
+        (
+            "Storage",
+            if report.storage_ok { "ok" } else { "down" }.to_string(),
+        ),
+        (
+            "Connections",
+            match report.load.max_connections {
+                Some(max) => format!("{} of {}", report.load.connections, max),
+                None => report.load.connections.to_string(),
+            },
+        ),
+        ("Open rooms", report.load.rooms.to_string()),
+        (
+            "Key presses a second, per connection",
+            limit(report.load.keypresses_per_sec),
+        ),
+        (
+            "Broadcasts a second",
+            limit(report.load.broadcast_events_per_sec),
+        ),
+        ("Version", report.version.to_string()),
+        ("Protocol version", report.protocol_version.to_string()),
+        ("Up for", format!("{} minutes", report.uptime_secs / 60)),
+    ];
+    if let Some(message) = &report.message {
+        rows.insert(1, ("Maintenance", message.clone()));
+    }
+    let mut html = format!(
+        "<!doctype html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"refresh\" content=\"30\">\n<title>typeto.me status</title>\n\
+         </head>\n<body>\n<h1>typeto.me status</h1>\n<p>{}</p>\n<table>\n",
+        headline
+    );
+    for (name, value) in rows {
+        let _ = writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            name,
+            escape(&value)
+        );
+    }
+    html.push_str("</table>\n<p><a href=\"/\">Back to typeto.me</a></p>\n</body>\n</html>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
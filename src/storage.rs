@@ -0,0 +1,415 @@
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+use crate::{
+    archived::Archived,
+    config::StorageConfig,
+    locale::LocaleHint,
+    rollup::{DailyRollup, Rollup, RollupQuery},
+    RoomSettings, UserPrefs,
+};
+
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(any(feature = "sled", feature = "postgres"))]
+mod segment;
+#[cfg(feature = "sled")]
+mod sled;
+
+pub type StoreResult<T> = Result<T, String>;
+
+/// Identities `MemoryStore` keeps preferences for.
+const MAX_MEMORY_PREFS: usize = 10_000;
+
+/// The durable part of a room: everything that should survive the in-memory
+/// `Room` being dropped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomRecord {
+    pub id: String,
+    pub owner_id: Option<String>,
+    pub settings: RoomSettings,
+    pub created_at: u64,
+    /// Digest of the token that lets the creator delete the room.
+    #[serde(default)]
+    pub creator_token_hash: Option<String>,
+    /// Each participant's color, by participant id.
+    #[serde(default)]
+    pub colors: BTreeMap<String, String>,
+    /// The code of the room's short link, `/j/<code>`.
+    #[serde(default)]
+    pub shortcode: Option<String>,
+    /// Each participant's language hint, by participant id.
+    #[serde(default)]
+    pub locales: BTreeMap<String, LocaleHint>,
+    /// Set once the room has expired, while it is kept to be read or revived.
+    #[serde(default)]
+    pub archived: Option<Archived>,
+}
+
+/// One finished line of a room's history: a committed message, a join or
+/// leave notice, or a line left unfinished by one of those.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryLine {
+    pub participant: String,
+    pub text: String,
+}
+
+/// Storage backend for room metadata, room history and per-user preferences.
+pub trait RoomStore: Send + Sync {
+    fn load_room<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<Option<RoomRecord>>>;
+    fn save_room(&self, record: RoomRecord) -> BoxFuture<'_, StoreResult<()>>;
+    /// Removes the room's record and its history.
+    fn delete_room<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<()>>;
+    /// The id of the room whose short link has `code`.
+    fn find_shortcode<'a>(&'a self, code: &'a str) -> BoxFuture<'a, StoreResult<Option<String>>>;
+    /// Appends to the room's history, an op log replayed by `load_history`.
+    fn append_history<'a>(
+        &'a self,
+        id: &'a str,
+        lines: Vec<HistoryLine>,
+    ) -> BoxFuture<'a, StoreResult<()>>;
+    /// The room's history, oldest line first.
+    fn load_history<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<Vec<HistoryLine>>>;
+    fn load_prefs<'a>(&'a self, identity: &'a str)
+        -> BoxFuture<'a, StoreResult<Option<UserPrefs>>>;
+    fn save_prefs(&self, identity: String, prefs: UserPrefs) -> BoxFuture<'_, StoreResult<()>>;
+    /// Adds `totals` to the room's activity on `day`. Rollups outlive their
+    /// room; `delete_room` leaves them.
+    fn add_rollup<'a>(
+        &'a self,
+        room: &'a str,
+        day: &'a str,
+        totals: Rollup,
+    ) -> BoxFuture<'a, StoreResult<()>>;
+    /// The rollups `query` matches, by day and then room.
+    fn load_rollups<'a>(
+        &'a self,
+        query: &'a RollupQuery,
+    ) -> BoxFuture<'a, StoreResult<Vec<DailyRollup>>>;
+    fn delete_rollups<'a>(&'a self, room: &'a str) -> BoxFuture<'a, StoreResult<()>>;
+    /// The ids of rooms archived before `before`, in Unix seconds.
+    fn archived_before(&self, before: u64) -> BoxFuture<'_, StoreResult<Vec<String>>>;
+}
+
+/// Default backend, keeps records for the lifetime of the process. History
+/// isn't kept: it lives in the in-memory room for exactly as long as it
+/// would live here.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    rooms: Mutex<HashMap<String, RoomRecord>>,
+    prefs: Mutex<HashMap<String, UserPrefs>>,
+    /// By day, then room.
+    rollups: Mutex<BTreeMap<(String, String), Rollup>>,
+}
+
+impl RoomStore for MemoryStore {
+    fn load_room<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<Option<RoomRecord>>> {
+        let record = self.rooms.lock().unwrap().get(id).cloned();
+        Box::pin(async move { Ok(record) })
+    }
+
+    fn save_room(&self, record: RoomRecord) -> BoxFuture<'_, StoreResult<()>> {
+        self.rooms.lock().unwrap().insert(record.id.clone(), record);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn delete_room<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<()>> {
+        self.rooms.lock().unwrap().remove(id);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn find_shortcode<'a>(&'a self, code: &'a str) -> BoxFuture<'a, StoreResult<Option<String>>> {
+        let id = self
+            .rooms
+            .lock()
+            .unwrap()
+            .values()
+            .find(|record| record.shortcode.as_deref() == Some(code))
+            .map(|record| record.id.clone());
+        Box::pin(async move { Ok(id) })
+    }
+
+    fn append_history<'a>(
+        &'a self,
+        _id: &'a str,
+        _lines: Vec<HistoryLine>,
+    ) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn load_history<'a>(&'a self, _id: &'a str) -> BoxFuture<'a, StoreResult<Vec<HistoryLine>>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    fn load_prefs<'a>(
+        &'a self,
+        identity: &'a str,
+    ) -> BoxFuture<'a, StoreResult<Option<UserPrefs>>> {
+        let prefs = self.prefs.lock().unwrap().get(identity).cloned();
+        Box::pin(async move { Ok(prefs) })
+    }
+
+    fn save_prefs(&self, identity: String, prefs: UserPrefs) -> BoxFuture<'_, StoreResult<()>> {
+        let mut stored = self.prefs.lock().unwrap();
+        if stored.len() >= MAX_MEMORY_PREFS && !stored.contains_key(&identity) {
+            return Box::pin(async { Err("Too many identities with preferences.".to_string()) });
+        }
+        stored.insert(identity, prefs);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn add_rollup<'a>(
+        &'a self,
+        room: &'a str,
+        day: &'a str,
+        totals: Rollup,
+    ) -> BoxFuture<'a, StoreResult<()>> {
+        self.rollups
+            .lock()
+            .unwrap()
+            .entry((day.to_string(), room.to_string()))
+            .or_default()
+            .add(&totals);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn load_rollups<'a>(
+        &'a self,
+        query: &'a RollupQuery,
+    ) -> BoxFuture<'a, StoreResult<Vec<DailyRollup>>> {
+        let rollups = self
+            .rollups
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((day, room), totals)| DailyRollup {
+                room: room.clone(),
+                day: day.clone(),
+                totals: totals.clone(),
+            })
+            .filter(|rollup| query.matches(rollup))
+            .collect();
+        Box::pin(async move { Ok(rollups) })
+    }
+
+    fn delete_rollups<'a>(&'a self, room: &'a str) -> BoxFuture<'a, StoreResult<()>> {
+        self.rollups
+            .lock()
+            .unwrap()
+            .retain(|(_, rolled_up), _| rolled_up != room);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn archived_before(&self, before: u64) -> BoxFuture<'_, StoreResult<Vec<String>>> {
+        let ids = self
+            .rooms
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|record| record.archived.as_ref().is_some_and(|a| a.at < before))
+            .map(|record| record.id.clone())
+            .collect();
+        Box::pin(async move { Ok(ids) })
+    }
+}
+
+/// The backend chosen by `[storage]`.
+pub async fn open(config: &StorageConfig) -> StoreResult<Arc<dyn RoomStore>> {
+    match config {
+        StorageConfig::Memory => Ok(Arc::new(MemoryStore::default())),
+        #[cfg(feature = "sled")]
+        StorageConfig::Sled { path } => Ok(Arc::new(sled::SledStore::open(path)?)),
+        #[cfg(not(feature = "sled"))]
+        StorageConfig::Sled { .. } => {
+            Err("[storage] backend = \"sled\" needs a build with the `sled` feature".to_string())
+        }
+        #[cfg(feature = "postgres")]
+        StorageConfig::Postgres(postgres) => {
+            Ok(Arc::new(postgres::PostgresStore::connect(postgres).await?))
+        }
+        #[cfg(not(feature = "postgres"))]
+        StorageConfig::Postgres(_) => Err(
+            "[storage] backend = \"postgres\" needs a build with the `postgres` feature"
+                .to_string(),
+        ),
+    }
+}
+
+enum StoreOp {
+    Save(Box<RoomRecord>),
+    Delete(String),
+    /// Deletes a room and its rollups, answering with how that went.
+    Purge(String, oneshot::Sender<StoreResult<()>>),
+    AppendHistory(String, Vec<HistoryLine>),
+    /// Applies an update to an identity's preferences, answering with the
+    /// result; done here so that updates to the same ones don't race.
+    UpdatePrefs(String, UserPrefs, oneshot::Sender<StoreResult<UserPrefs>>),
+    AddRollup(String, String, Rollup),
+    /// Answered once every op queued before it is done.
+    Settle(oneshot::Sender<()>),
+}
+
+/// Funnels writes through a single task so they reach the backend in the
+/// order they were issued, without holding the rooms lock across an await.
+#[derive(Clone)]
+pub struct StoreWriter {
+    tx: mpsc::UnboundedSender<StoreOp>,
+    /// Ops queued and not yet done.
+    pending: Arc<AtomicUsize>,
+}
+
+impl StoreWriter {
+    pub fn spawn(store: Arc<dyn RoomStore>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let done = pending.clone();
+        tokio::spawn(async move {
+            while let Some(op) = rx.recv().await {
+                let result = Self::apply(&*store, op).await;
+                done.fetch_sub(1, Ordering::Relaxed);
+                if let Some((id, Err(err))) = result {
+                    error!("Storage write for {} failed: {}", id, err);
+                }
+            }
+        });
+        Self { tx, pending }
+    }
+
+    /// Carries out `op`, returning what it wrote to and how that went.
+    async fn apply(store: &dyn RoomStore, op: StoreOp) -> Option<(String, StoreResult<()>)> {
+        Some(match op {
+            StoreOp::Save(record) => (record.id.clone(), store.save_room(*record).await),
+            StoreOp::Delete(id) => {
+                let result = store.delete_room(&id).await;
+                (id, result)
+            }
+            StoreOp::Purge(id, done) => {
+                let result = match store.delete_room(&id).await {
+                    Ok(()) => store.delete_rollups(&id).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = &result {
+                    error!("Storage write for {} failed: {}", id, err);
+                }
+                let _ = done.send(result);
+                return None;
+            }
+            StoreOp::AppendHistory(id, lines) => {
+                let result = store.append_history(&id, lines).await;
+                (id, result)
+            }
+            StoreOp::UpdatePrefs(identity, update, done) => {
+                let result = Self::update_prefs_now(store, &identity, update).await;
+                let _ = done.send(result);
+                return None;
+            }
+            StoreOp::AddRollup(room, day, totals) => {
+                let result = store.add_rollup(&room, &day, totals).await;
+                (room, result)
+            }
+            StoreOp::Settle(done) => {
+                let _ = done.send(());
+                return None;
+            }
+        })
+    }
+
+    fn send(&self, op: StoreOp) -> bool {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let sent = self.tx.send(op).is_ok();
+        if !sent {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// How many writes are queued for the backend.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn save(&self, record: RoomRecord) {
+        self.send(StoreOp::Save(Box::new(record)));
+    }
+
+    pub fn delete(&self, id: String) {
+        self.send(StoreOp::Delete(id));
+    }
+
+    /// Deletes room `id`'s record, history and rollups, after the writes
+    /// queued before it, and says whether they are gone.
+    pub async fn purge(&self, id: String) -> StoreResult<()> {
+        let (done, result) = oneshot::channel();
+        if !self.send(StoreOp::Purge(id, done)) {
+            return Err("Storage is shutting down.".to_string());
+        }
+        result
+            .await
+            .unwrap_or_else(|_| Err("Storage is shutting down.".to_string()))
+    }
+
+    pub fn append_history(&self, id: String, lines: Vec<HistoryLine>) {
+        if !lines.is_empty() {
+            self.send(StoreOp::AppendHistory(id, lines));
+        }
+    }
+
+    /// Applies `update` to `identity`'s preferences, after the writes queued
+    /// before it, and returns them as stored.
+    pub async fn update_prefs(
+        &self,
+        identity: String,
+        update: UserPrefs,
+    ) -> StoreResult<UserPrefs> {
+        let (done, result) = oneshot::channel();
+        if !self.send(StoreOp::UpdatePrefs(identity, update, done)) {
+            return Err("Storage is shutting down.".to_string());
+        }
+        result
+            .await
+            .unwrap_or_else(|_| Err("Storage is shutting down.".to_string()))
+    }
+
+    async fn update_prefs_now(
+        store: &dyn RoomStore,
+        identity: &str,
+        update: UserPrefs,
+    ) -> StoreResult<UserPrefs> {
+        let failed = |err: String| {
+            error!("Storage write for prefs:{} failed: {}", identity, err);
+            "Failed to save preferences; try again.".to_string()
+        };
+        let mut prefs = store
+            .load_prefs(identity)
+            .await
+            .map_err(failed)?
+            .unwrap_or_default();
+        prefs.apply(update)?;
+        store
+            .save_prefs(identity.to_string(), prefs.clone())
+            .await
+            .map_err(failed)?;
+        Ok(prefs)
+    }
+
+    pub fn add_rollup(&self, room: String, day: String, totals: Rollup) {
+        self.send(StoreOp::AddRollup(room, day, totals));
+    }
+
+    /// Waits for the writes queued so far to reach the backend.
+    pub async fn settle(&self) {
+        let (done, settled) = oneshot::channel();
+        if self.send(StoreOp::Settle(done)) {
+            let _ = settled.await;
+        }
+    }
+}
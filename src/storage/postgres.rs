@@ -0,0 +1,345 @@
+use futures_util::future::BoxFuture;
+use sqlx::{postgres::PgPoolOptions, types::Json, PgPool, Row};
+
+use super::{
+    segment::{self, SEGMENT_LINES},
+    HistoryLine, RoomRecord, RoomStore, StoreResult,
+};
+use crate::{
+    config::PostgresConfig,
+    rollup::{DailyRollup, Rollup, RollupQuery},
+    RoomSettings, UserPrefs,
+};
+
+/// Rooms, history and preferences in PostgreSQL. The schema is created and
+/// kept up to date by the migrations in `migrations/`, run at startup.
+/// History rows hold a line each until enough have built up to be replaced
+/// by one compressed segment.
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub async fn connect(config: &PostgresConfig) -> StoreResult<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect(&config.url)
+            .await
+            .map_err(|err| format!("Failed to connect to PostgreSQL: {}", err))?;
+        sqlx::migrate!("./migrations")
+            .run(&pool)
+            .await
+            .map_err(|err| format!("PostgreSQL migration failed: {}", err))?;
+        Ok(Self { pool })
+    }
+}
+
+impl RoomStore for PostgresStore {
+    fn load_room<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<Option<RoomRecord>>> {
+        Box::pin(async move {
+            let row = sqlx::query(
+                "SELECT owner_id, settings, created_at, creator_token_hash, colors, shortcode, \
+                 locales, archived FROM rooms WHERE id = $1",
+            )
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|err| err.to_string())?;
+            row.map(|row| {
+                Ok(RoomRecord {
+                    id: id.to_string(),
+                    owner_id: row.try_get("owner_id")?,
+                    settings: row.try_get::<Json<RoomSettings>, _>("settings")?.0,
+                    created_at: row.try_get::<i64, _>("created_at")? as u64,
+                    creator_token_hash: row.try_get("creator_token_hash")?,
+                    colors: row.try_get::<Json<_>, _>("colors")?.0,
+                    shortcode: row.try_get("shortcode")?,
+                    locales: row.try_get::<Json<_>, _>("locales")?.0,
+                    archived: row
+                        .try_get::<Option<Json<_>>, _>("archived")?
+                        .map(|archived| archived.0),
+                })
+            })
+            .transpose()
+            .map_err(|err: sqlx::Error| err.to_string())
+        })
+    }
+
+    fn save_room(&self, record: RoomRecord) -> BoxFuture<'_, StoreResult<()>> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO rooms \
+                 (id, owner_id, settings, created_at, creator_token_hash, colors, shortcode, \
+                 locales, archived) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) \
+                 ON CONFLICT (id) DO UPDATE SET owner_id = $2, settings = $3, \
+                 created_at = $4, creator_token_hash = $5, colors = $6, shortcode = $7, \
+                 locales = $8, archived = $9",
+            )
+            .bind(&record.id)
+            .bind(&record.owner_id)
+            .bind(Json(&record.settings))
+            .bind(record.created_at as i64)
+            .bind(&record.creator_token_hash)
+            .bind(Json(&record.colors))
+            .bind(&record.shortcode)
+            .bind(Json(&record.locales))
+            .bind(record.archived.as_ref().map(Json))
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+        })
+    }
+
+    fn delete_room<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await.map_err(|err| err.to_string())?;
+            for statement in [
+                "DELETE FROM room_history WHERE room_id = $1",
+                "DELETE FROM rooms WHERE id = $1",
+            ] {
+                sqlx::query(statement)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(|err| err.to_string())?;
+            }
+            tx.commit().await.map_err(|err| err.to_string())
+        })
+    }
+
+    fn find_shortcode<'a>(&'a self, code: &'a str) -> BoxFuture<'a, StoreResult<Option<String>>> {
+        Box::pin(async move {
+            sqlx::query_scalar("SELECT id FROM rooms WHERE shortcode = $1 LIMIT 1")
+                .bind(code)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(|err| err.to_string())
+        })
+    }
+
+    fn append_history<'a>(
+        &'a self,
+        id: &'a str,
+        lines: Vec<HistoryLine>,
+    ) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let (participants, texts): (Vec<String>, Vec<String>) = lines
+                .into_iter()
+                .map(|line| (line.participant, line.text))
+                .unzip();
+            let mut tx = self.pool.begin().await.map_err(|err| err.to_string())?;
+            sqlx::query(
+                "INSERT INTO room_history (room_id, participant, text) \
+                 SELECT $1, lines.participant, lines.text \
+                 FROM UNNEST($2::TEXT[], $3::TEXT[]) WITH ORDINALITY \
+                 AS lines (participant, text, n) ORDER BY n",
+            )
+            .bind(id)
+            .bind(participants)
+            .bind(texts)
+            .execute(&mut *tx)
+            .await
+            .map_err(|err| err.to_string())?;
+
+            // Once enough plain lines have built up, the first of them
+            // becomes a compressed segment holding all of them.
+            let tail = sqlx::query(
+                "SELECT seq, participant, text FROM room_history \
+                 WHERE room_id = $1 AND segment IS NULL ORDER BY seq FOR UPDATE",
+            )
+            .bind(id)
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(|err| err.to_string())?;
+            if tail.len() >= SEGMENT_LINES {
+                let lines = tail
+                    .iter()
+                    .map(|row| {
+                        Ok(HistoryLine {
+                            participant: row.try_get("participant")?,
+                            text: row.try_get("text")?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, sqlx::Error>>()
+                    .map_err(|err| err.to_string())?;
+                let first: i64 = tail[0].get("seq");
+                let last: i64 = tail[tail.len() - 1].get("seq");
+                sqlx::query(
+                    "UPDATE room_history SET segment = $2, participant = NULL, text = NULL \
+                     WHERE seq = $1",
+                )
+                .bind(first)
+                .bind(segment::compress(&lines)?)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+                sqlx::query(
+                    "DELETE FROM room_history \
+                     WHERE room_id = $1 AND segment IS NULL AND seq > $2 AND seq <= $3",
+                )
+                .bind(id)
+                .bind(first)
+                .bind(last)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+            }
+            tx.commit().await.map_err(|err| err.to_string())
+        })
+    }
+
+    fn load_history<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<Vec<HistoryLine>>> {
+        Box::pin(async move {
+            let rows = sqlx::query(
+                "SELECT participant, text, segment FROM room_history \
+                 WHERE room_id = $1 ORDER BY seq",
+            )
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| err.to_string())?;
+            let mut history = Vec::new();
+            for row in rows {
+                let read = |row: &sqlx::postgres::PgRow| -> Result<_, sqlx::Error> {
+                    Ok((
+                        row.try_get::<Option<Vec<u8>>, _>("segment")?,
+                        row.try_get::<Option<String>, _>("participant")?,
+                        row.try_get::<Option<String>, _>("text")?,
+                    ))
+                };
+                match read(&row).map_err(|err| err.to_string())? {
+                    (Some(segment), _, _) => history.extend(segment::decompress(&segment)?),
+                    (None, Some(participant), Some(text)) => {
+                        history.push(HistoryLine { participant, text })
+                    }
+                    _ => return Err(format!("Malformed history row in room {}", id)),
+                }
+            }
+            Ok(history)
+        })
+    }
+
+    fn load_prefs<'a>(
+        &'a self,
+        identity: &'a str,
+    ) -> BoxFuture<'a, StoreResult<Option<UserPrefs>>> {
+        Box::pin(async move {
+            sqlx::query_scalar::<_, Json<UserPrefs>>(
+                "SELECT prefs FROM user_prefs WHERE identity = $1",
+            )
+            .bind(identity)
+            .fetch_optional(&self.pool)
+            .await
+            .map(|prefs| prefs.map(|prefs| prefs.0))
+            .map_err(|err| err.to_string())
+        })
+    }
+
+    fn save_prefs(&self, identity: String, prefs: UserPrefs) -> BoxFuture<'_, StoreResult<()>> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO user_prefs (identity, prefs) VALUES ($1, $2) \
+                 ON CONFLICT (identity) DO UPDATE SET prefs = $2",
+            )
+            .bind(identity)
+            .bind(Json(prefs))
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+        })
+    }
+
+    fn add_rollup<'a>(
+        &'a self,
+        room: &'a str,
+        day: &'a str,
+        totals: Rollup,
+    ) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO room_rollups \
+                 (room_id, day, lines, characters, peak_participants, active_minutes) \
+                 VALUES ($1, $2::DATE, $3, $4, $5, $6) \
+                 ON CONFLICT (day, room_id) DO UPDATE SET \
+                 lines = room_rollups.lines + $3, \
+                 characters = room_rollups.characters + $4, \
+                 peak_participants = GREATEST(room_rollups.peak_participants, $5), \
+                 active_minutes = room_rollups.active_minutes + $6",
+            )
+            .bind(room)
+            .bind(day)
+            .bind(totals.lines as i64)
+            .bind(totals.characters as i64)
+            .bind(totals.peak_participants as i64)
+            .bind(totals.active_minutes as i64)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+        })
+    }
+
+    fn load_rollups<'a>(
+        &'a self,
+        query: &'a RollupQuery,
+    ) -> BoxFuture<'a, StoreResult<Vec<DailyRollup>>> {
+        Box::pin(async move {
+            let rows = sqlx::query(
+                "SELECT room_id, day::TEXT AS day, lines, characters, peak_participants, \
+                 active_minutes FROM room_rollups \
+                 WHERE ($1::TEXT IS NULL OR room_id = $1) \
+                 AND ($2::DATE IS NULL OR day >= $2::DATE) \
+                 AND ($3::DATE IS NULL OR day <= $3::DATE) \
+                 ORDER BY day, room_id",
+            )
+            .bind(&query.room)
+            .bind(&query.since)
+            .bind(&query.until)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| err.to_string())?;
+            rows.iter()
+                .map(|row| {
+                    Ok(DailyRollup {
+                        room: row.try_get("room_id")?,
+                        day: row.try_get("day")?,
+                        totals: Rollup {
+                            lines: row.try_get::<i64, _>("lines")? as u64,
+                            characters: row.try_get::<i64, _>("characters")? as u64,
+                            peak_participants: row.try_get::<i64, _>("peak_participants")? as u64,
+                            active_minutes: row.try_get::<i64, _>("active_minutes")? as u64,
+                        },
+                    })
+                })
+                .collect::<Result<_, sqlx::Error>>()
+                .map_err(|err| err.to_string())
+        })
+    }
+
+    fn delete_rollups<'a>(&'a self, room: &'a str) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            sqlx::query("DELETE FROM room_rollups WHERE room_id = $1")
+                .bind(room)
+                .execute(&self.pool)
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        })
+    }
+
+    fn archived_before(&self, before: u64) -> BoxFuture<'_, StoreResult<Vec<String>>> {
+        Box::pin(async move {
+            sqlx::query_scalar(
+                "SELECT id FROM rooms \
+                 WHERE archived IS NOT NULL AND (archived->>'at')::BIGINT < $1",
+            )
+            .bind(before as i64)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| err.to_string())
+        })
+    }
+}
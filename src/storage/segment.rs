@@ -0,0 +1,30 @@
+//! Finished history is compressed in segments once enough lines have built
+//! up, so long-lived rooms take a fraction of the space at rest. The lines
+//! still being added to are kept as plain rows until then.
+
+use super::{HistoryLine, StoreResult};
+
+/// Plain lines a room accumulates before they are compressed into a segment.
+pub const SEGMENT_LINES: usize = 256;
+
+const LEVEL: i32 = 3;
+
+/// Every zstd frame starts with this, and no JSON value can.
+#[cfg(feature = "sled")]
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+pub fn compress(lines: &[HistoryLine]) -> StoreResult<Vec<u8>> {
+    let json = serde_json::to_vec(lines).map_err(|err| err.to_string())?;
+    zstd::encode_all(json.as_slice(), LEVEL).map_err(|err| err.to_string())
+}
+
+pub fn decompress(segment: &[u8]) -> StoreResult<Vec<HistoryLine>> {
+    let json = zstd::decode_all(segment).map_err(|err| err.to_string())?;
+    serde_json::from_slice(&json).map_err(|err| err.to_string())
+}
+
+/// Tells a segment from a single line stored as JSON.
+#[cfg(feature = "sled")]
+pub fn is_segment(value: &[u8]) -> bool {
+    value.starts_with(&ZSTD_MAGIC)
+}
@@ -0,0 +1,281 @@
+use futures_util::future::BoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::Path;
+
+use super::{
+    segment::{self, SEGMENT_LINES},
+    HistoryLine, RoomRecord, RoomStore, StoreResult,
+};
+use crate::{
+    rollup::{DailyRollup, Rollup, RollupQuery},
+    UserPrefs,
+};
+
+/// Embedded database in a local directory, for durability without running a
+/// database server. Values are JSON; history entries are keyed by room id and
+/// a sequence number so a prefix scan returns them in order. An entry is a
+/// single line or, once enough lines have built up, a compressed segment
+/// that takes the place of all of them. Rollups are keyed `<day>/<room>`,
+/// short link codes map to room ids, and archived rooms' ids to when they
+/// were archived.
+pub struct SledStore {
+    db: ::sled::Db,
+    rooms: ::sled::Tree,
+    shortcodes: ::sled::Tree,
+    history: ::sled::Tree,
+    prefs: ::sled::Tree,
+    rollups: ::sled::Tree,
+    archived: ::sled::Tree,
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> StoreResult<Self> {
+        let db = ::sled::open(path)
+            .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+        let tree = |name: &str| db.open_tree(name).map_err(|err| err.to_string());
+        Ok(Self {
+            rooms: tree("rooms")?,
+            shortcodes: tree("shortcodes")?,
+            history: tree("history")?,
+            prefs: tree("prefs")?,
+            rollups: tree("rollups")?,
+            archived: tree("archived")?,
+            db,
+        })
+    }
+
+    fn get<T: DeserializeOwned>(tree: &::sled::Tree, key: &str) -> StoreResult<Option<T>> {
+        match tree.get(key).map_err(|err| err.to_string())? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|err| err.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn put<T: Serialize>(&self, tree: &::sled::Tree, key: &str, value: &T) -> StoreResult<()> {
+        let bytes = serde_json::to_vec(value).map_err(|err| err.to_string())?;
+        tree.insert(key, bytes).map_err(|err| err.to_string())?;
+        self.flush()
+    }
+
+    fn flush(&self) -> StoreResult<()> {
+        self.db.flush().map(|_| ()).map_err(|err| err.to_string())
+    }
+
+    /// Compresses the room's trailing plain lines into one segment, stored
+    /// under the first line's key, once there are enough of them.
+    fn compact_history(&self, id: &str) -> StoreResult<()> {
+        let mut tail = Vec::new();
+        for entry in self.history.scan_prefix(history_prefix(id)).rev() {
+            let (key, value) = entry.map_err(|err| err.to_string())?;
+            if segment::is_segment(&value) {
+                break;
+            }
+            tail.push((key, value));
+        }
+        if tail.len() < SEGMENT_LINES {
+            return Ok(());
+        }
+        tail.reverse();
+        let lines = tail
+            .iter()
+            .map(|(_, value)| serde_json::from_slice(value).map_err(|err| err.to_string()))
+            .collect::<StoreResult<Vec<HistoryLine>>>()?;
+        let mut batch = ::sled::Batch::default();
+        for (key, _) in &tail[1..] {
+            batch.remove(key);
+        }
+        batch.insert(tail[0].0.clone(), segment::compress(&lines)?);
+        self.history
+            .apply_batch(batch)
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Keys of a room's history lines all start with this. The id is length
+/// prefixed, so one room's prefix never matches another room's lines.
+fn history_prefix(id: &str) -> Vec<u8> {
+    let mut prefix = (id.len() as u64).to_be_bytes().to_vec();
+    prefix.extend_from_slice(id.as_bytes());
+    prefix
+}
+
+impl RoomStore for SledStore {
+    fn load_room<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<Option<RoomRecord>>> {
+        Box::pin(async move { Self::get(&self.rooms, id) })
+    }
+
+    fn save_room(&self, record: RoomRecord) -> BoxFuture<'_, StoreResult<()>> {
+        Box::pin(async move {
+            if let Some(code) = &record.shortcode {
+                self.shortcodes
+                    .insert(code, record.id.as_bytes())
+                    .map_err(|err| err.to_string())?;
+            }
+            match &record.archived {
+                Some(archived) => self
+                    .archived
+                    .insert(&record.id, &archived.at.to_be_bytes())
+                    .map_err(|err| err.to_string())?,
+                None => self
+                    .archived
+                    .remove(&record.id)
+                    .map_err(|err| err.to_string())?,
+            };
+            self.put(&self.rooms, &record.id, &record)
+        })
+    }
+
+    fn delete_room<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let record: Option<RoomRecord> = Self::get(&self.rooms, id)?;
+            if let Some(code) = record.and_then(|record| record.shortcode) {
+                self.shortcodes
+                    .remove(code)
+                    .map_err(|err| err.to_string())?;
+            }
+            self.rooms.remove(id).map_err(|err| err.to_string())?;
+            self.archived.remove(id).map_err(|err| err.to_string())?;
+            let mut batch = ::sled::Batch::default();
+            for entry in self.history.scan_prefix(history_prefix(id)) {
+                let (key, _) = entry.map_err(|err| err.to_string())?;
+                batch.remove(key);
+            }
+            self.history
+                .apply_batch(batch)
+                .map_err(|err| err.to_string())?;
+            self.flush()
+        })
+    }
+
+    fn find_shortcode<'a>(&'a self, code: &'a str) -> BoxFuture<'a, StoreResult<Option<String>>> {
+        Box::pin(async move {
+            let id = self.shortcodes.get(code).map_err(|err| err.to_string())?;
+            Ok(id.map(|id| String::from_utf8_lossy(&id).into_owned()))
+        })
+    }
+
+    fn append_history<'a>(
+        &'a self,
+        id: &'a str,
+        lines: Vec<HistoryLine>,
+    ) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let mut batch = ::sled::Batch::default();
+            for line in lines {
+                let seq = self.db.generate_id().map_err(|err| err.to_string())?;
+                let mut key = history_prefix(id);
+                key.extend_from_slice(&seq.to_be_bytes());
+                let value = serde_json::to_vec(&line).map_err(|err| err.to_string())?;
+                batch.insert(key, value);
+            }
+            self.history
+                .apply_batch(batch)
+                .map_err(|err| err.to_string())?;
+            self.compact_history(id)?;
+            self.flush()
+        })
+    }
+
+    fn load_history<'a>(&'a self, id: &'a str) -> BoxFuture<'a, StoreResult<Vec<HistoryLine>>> {
+        Box::pin(async move {
+            self.history
+                .scan_prefix(history_prefix(id))
+                .map(|entry| {
+                    let (_, value) = entry.map_err(|err| err.to_string())?;
+                    if segment::is_segment(&value) {
+                        segment::decompress(&value)
+                    } else {
+                        serde_json::from_slice(&value)
+                            .map(|line| vec![line])
+                            .map_err(|err| err.to_string())
+                    }
+                })
+                .collect::<StoreResult<Vec<_>>>()
+                .map(|entries| entries.into_iter().flatten().collect())
+        })
+    }
+
+    fn load_prefs<'a>(
+        &'a self,
+        identity: &'a str,
+    ) -> BoxFuture<'a, StoreResult<Option<UserPrefs>>> {
+        Box::pin(async move { Self::get(&self.prefs, identity) })
+    }
+
+    fn save_prefs(&self, identity: String, prefs: UserPrefs) -> BoxFuture<'_, StoreResult<()>> {
+        Box::pin(async move { self.put(&self.prefs, &identity, &prefs) })
+    }
+
+    fn add_rollup<'a>(
+        &'a self,
+        room: &'a str,
+        day: &'a str,
+        totals: Rollup,
+    ) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let key = format!("{}/{}", day, room);
+            let mut rollup: Rollup = Self::get(&self.rollups, &key)?.unwrap_or_default();
+            rollup.add(&totals);
+            self.put(&self.rollups, &key, &rollup)
+        })
+    }
+
+    fn load_rollups<'a>(
+        &'a self,
+        query: &'a RollupQuery,
+    ) -> BoxFuture<'a, StoreResult<Vec<DailyRollup>>> {
+        Box::pin(async move {
+            let start = query.since.clone().unwrap_or_default();
+            let mut rollups = Vec::new();
+            for entry in self.rollups.range(start.as_bytes()..) {
+                let (key, value) = entry.map_err(|err| err.to_string())?;
+                let key = String::from_utf8_lossy(&key);
+                let Some((day, room)) = key.split_once('/') else {
+                    continue;
+                };
+                let rollup = DailyRollup {
+                    room: room.to_string(),
+                    day: day.to_string(),
+                    totals: serde_json::from_slice(&value).map_err(|err| err.to_string())?,
+                };
+                if query.matches(&rollup) {
+                    rollups.push(rollup);
+                }
+            }
+            Ok(rollups)
+        })
+    }
+
+    fn delete_rollups<'a>(&'a self, room: &'a str) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let suffix = format!("/{}", room);
+            let mut batch = ::sled::Batch::default();
+            for entry in self.rollups.iter() {
+                let (key, _) = entry.map_err(|err| err.to_string())?;
+                if key.ends_with(suffix.as_bytes()) {
+                    batch.remove(key);
+                }
+            }
+            self.rollups
+                .apply_batch(batch)
+                .map_err(|err| err.to_string())?;
+            self.flush()
+        })
+    }
+
+    fn archived_before(&self, before: u64) -> BoxFuture<'_, StoreResult<Vec<String>>> {
+        Box::pin(async move {
+            let mut ids = Vec::new();
+            for entry in self.archived.iter() {
+                let (id, at) = entry.map_err(|err| err.to_string())?;
+                let at = u64::from_be_bytes(at.as_ref().try_into().unwrap_or_default());
+                if at < before {
+                    ids.push(String::from_utf8_lossy(&id).into_owned());
+                }
+            }
+            Ok(ids)
+        })
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, IntoRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram, UnixListener},
+    },
+};
+use tracing::warn;
+
+/// First descriptor passed by the service manager (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum Inherited {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Takes the listening sockets systemd passed to this process via
+/// `LISTEN_FDS`, if any. The variables are cleared afterwards so they are
+/// only ever consumed once.
+pub fn listen_fds() -> Vec<Inherited> {
+    let pid_matches = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    if !pid_matches {
+        return Vec::new();
+    }
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // Safety: systemd hands these descriptors to us and nothing else
+            // in the process uses them.
+            let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+            // A Unix socket has no IP address, so this tells the two apart.
+            if tcp.local_addr().is_ok() {
+                Inherited::Tcp(tcp)
+            } else {
+                Inherited::Unix(unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) })
+            }
+        })
+        .collect()
+}
+
+/// Sends a state update such as `READY=1` to the service manager. Does
+/// nothing when not running under systemd (`NOTIFY_SOCKET` unset).
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy();
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(path.as_ref()),
+    };
+    let result = addr.and_then(|addr| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+    if let Err(err) = result {
+        warn!("sd_notify {} failed: {}", state, err);
+    }
+}
@@ -0,0 +1,172 @@
+//! Slowing down, then banning, connections that keep misbehaving, as
+//! `[tarpit]` configures. Each message the server refuses is a strike: a key
+//! over the rate limit or that isn't a key, a message that isn't one the
+//! server understands, a binary message. Past `delay_after` strikes every
+//! message waits before it is read, longer with each strike, and at
+//! `ban_after` the connection is closed and its address refused on every
+//! route for `ban_secs`. An address can also be flagged, by `[honeypot]`:
+//! its connections start out with `delay_after` strikes, never forgiven, so
+//! the first thing refused is held back. Bans and flags are kept in memory
+//! and don't survive a restart.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{
+    audit::AuditEvent,
+    config::TarpitConfig,
+    security::{self, Event},
+    SharedState,
+};
+
+/// One connection's strikes.
+pub struct Strikes {
+    count: u32,
+    /// Strikes that aren't forgiven.
+    floor: u32,
+    /// When forgiveness was last counted from.
+    since: Instant,
+}
+
+impl Strikes {
+    pub fn new() -> Self {
+        Self {
+            count: 0,
+            floor: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Starts a flagged address's connection out at `delay_after` strikes.
+    pub fn suspect(&mut self, config: &TarpitConfig) {
+        self.floor = config.delay_after;
+        self.count = self.count.max(self.floor);
+    }
+
+    /// Forgives a strike for each `forgive_secs` gone by without one.
+    fn forgive(&mut self, config: &TarpitConfig) {
+        let period = Duration::from_secs(config.forgive_secs.max(1));
+        let forgiven = self.since.elapsed().as_secs() / period.as_secs();
+        let forgiven = u32::try_from(forgiven).unwrap_or(u32::MAX);
+        if forgiven > 0 {
+            self.count = self.count.saturating_sub(forgiven).max(self.floor);
+            self.since += period * forgiven;
+        }
+    }
+
+    /// How long the next message waits before it is read, if at all.
+    pub fn delay(&mut self, config: &TarpitConfig) -> Option<Duration> {
+        self.forgive(config);
+        let over = self
+            .count
+            .checked_sub(config.delay_after.saturating_add(1))?
+            .min(32);
+        let delay = config
+            .base_delay_ms
+            .saturating_mul(1 << over)
+            .min(config.max_delay_ms);
+        (delay > 0).then(|| Duration::from_millis(delay))
+    }
+}
+
+/// Adds a strike for `reason`, such as `rate_limit`, and once the connection
+/// has `ban_after` of them, bans `ip`. Returns whether the connection should
+/// be closed.
+pub fn strike(
+    state: &SharedState,
+    strikes: &mut Strikes,
+    ip: Option<IpAddr>,
+    reason: &'static str,
+) -> bool {
+    let config = &state.config().tarpit;
+    strikes.forgive(config);
+    if strikes.count == strikes.floor {
+        strikes.since = Instant::now();
+    }
+    strikes.count += 1;
+    if strikes.count < config.ban_after {
+        return false;
+    }
+    warn!(
+        "Closing connection from {:?} after {} strikes, the last for {}",
+        ip, strikes.count, reason
+    );
+    ban(state, ip, reason);
+    true
+}
+
+/// Bans `ip` for `[tarpit] ban_secs` for `reason`, or if it can't be banned
+/// only logs that its connection is being closed.
+pub fn ban(state: &SharedState, ip: Option<IpAddr>, reason: &'static str) {
+    let config = &state.config().tarpit;
+    match ip.filter(|_| config.ban_secs > 0) {
+        Some(banned) => {
+            state.bans.ban(banned, Duration::from_secs(config.ban_secs));
+            state.audit.record(AuditEvent::Banned {
+                ip: banned,
+                secs: config.ban_secs,
+                reason: reason.to_string(),
+            });
+            security::log(
+                ip,
+                Event::Banned {
+                    secs: config.ban_secs,
+                    reason,
+                },
+            );
+        }
+        None => security::log(ip, Event::Disconnected { reason }),
+    }
+}
+
+/// Addresses banned or flagged for misbehaving, and until when.
+#[derive(Default)]
+pub struct Bans {
+    until: Mutex<HashMap<IpAddr, Instant>>,
+    flagged: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Bans {
+    fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.until
+            .lock()
+            .unwrap()
+            .insert(ip, Instant::now() + duration);
+    }
+
+    /// What is left of `ip`'s ban, if it has one.
+    pub fn remaining(&self, ip: IpAddr) -> Option<Duration> {
+        let until = *self.until.lock().unwrap().get(&ip)?;
+        until
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+    }
+
+    pub fn flag(&self, ip: IpAddr, duration: Duration) {
+        self.flagged
+            .lock()
+            .unwrap()
+            .insert(ip, Instant::now() + duration);
+    }
+
+    pub fn is_flagged(&self, ip: IpAddr) -> bool {
+        self.flagged
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    /// Forgets bans and flags that are over.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.until.lock().unwrap().retain(|_, until| *until > now);
+        self.flagged.lock().unwrap().retain(|_, until| *until > now);
+    }
+}
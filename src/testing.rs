@@ -0,0 +1,499 @@
+//! Helpers for integration tests: an in-process server on a loopback port
+//! and WebSocket clients that speak the JSON protocol.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use typeto_server::testing::TestServer;
+//!
+//! let server = TestServer::start().await;
+//! let mut alice = server.client().await;
+//! alice.join("abc", "alice").await;
+//! # }
+//! ```
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signer, SigningKey};
+use futures_util::{SinkExt, StreamExt};
+use hyper::server::conn::AddrIncoming;
+use serde_json::{json, Value};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{io::AsyncReadExt, net::TcpStream};
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header, HeaderValue},
+        Message,
+    },
+    WebSocketStream,
+};
+use tracing_subscriber::{filter::EnvFilter, reload, Registry};
+
+use crate::{
+    build_state,
+    config::Config,
+    deflate::{Deflate, Inflate},
+    serve, SharedState,
+};
+
+mod simulation;
+
+pub use simulation::Simulation;
+
+/// How long a client waits for an expected message before failing the test.
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Makes the server's handler for the connection that sends it panic, as a
+/// bug there would.
+const PANIC: &str = "panic for testing";
+/// Makes the task of the room the connection that sends it is in panic.
+const ROOM_PANIC: &str = "room panic for testing";
+
+/// Panics if `text` is [`PANIC`], or has room `room_id` panic if it is
+/// [`ROOM_PANIC`].
+pub(crate) fn maybe_panic(state: &SharedState, room_id: &str, text: &str) {
+    if text == PANIC {
+        panic!("asked to by a test");
+    }
+    if text == ROOM_PANIC {
+        if let Some(room) = state.rooms.get(room_id) {
+            room.cast(|_| panic!("asked to by a test"));
+        }
+    }
+}
+
+/// The app served on `127.0.0.1` at a free port. Shuts down when dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    /// Where the gRPC API is served, when the config has `[grpc]`.
+    #[cfg(feature = "grpc")]
+    grpc_addr: Option<SocketAddr>,
+    state: SharedState,
+}
+
+impl TestServer {
+    /// A server with the default config, like the public instance.
+    pub async fn start() -> Self {
+        Self::with_config("").await
+    }
+
+    /// A server configured from TOML, in the same format as `typeto.toml`.
+    pub async fn with_config(toml: &str) -> Self {
+        Self::on(bind().await, toml).await
+    }
+
+    /// `count` servers forming a `[cluster]`, each configured from `toml`
+    /// besides.
+    pub async fn cluster(count: usize, toml: &str) -> Vec<Self> {
+        let mut listeners = Vec::new();
+        for _ in 0..count {
+            listeners.push(bind().await);
+        }
+        let nodes: Vec<String> = listeners
+            .iter()
+            .map(|listener| format!("http://{}", listener.local_addr().unwrap()))
+            .collect();
+        let mut servers = Vec::new();
+        for (listener, node) in listeners.into_iter().zip(&nodes) {
+            let config = format!(
+                "{}\n[cluster]\nnode = {:?}\nnodes = {:?}\nsecret = \"test\"",
+                toml, node, nodes
+            );
+            servers.push(Self::on(listener, &config).await);
+        }
+        servers
+    }
+
+    async fn on(listener: tokio::net::TcpListener, toml: &str) -> Self {
+        let config: Config = toml::from_str(toml).expect("invalid test config");
+        #[cfg(feature = "grpc")]
+        let grpc = config.grpc.is_some();
+        // Tests don't install a subscriber, so there is nothing to reload.
+        let (_, log_filter) = reload::Layer::<EnvFilter, Registry>::new(EnvFilter::new("off"));
+        let state = build_state(config, log_filter)
+            .await
+            .expect("failed to build app state");
+        let addr = listener.local_addr().unwrap();
+        let incoming = AddrIncoming::from_listener(listener).unwrap();
+        tokio::spawn(serve(incoming, state.clone()));
+        #[cfg(feature = "grpc")]
+        let grpc_addr = if grpc {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("failed to bind a test port");
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(crate::grpc::serve(listener, state.clone()));
+            Some(addr)
+        } else {
+            None
+        };
+        Self {
+            addr,
+            #[cfg(feature = "grpc")]
+            grpc_addr,
+            state,
+        }
+    }
+
+    /// `http://127.0.0.1:<port>` followed by `path`.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}/ws", self.addr)
+    }
+
+    /// `http://127.0.0.1:<port>` of the gRPC API; the config needs `[grpc]`.
+    #[cfg(feature = "grpc")]
+    pub fn grpc_url(&self) -> String {
+        let addr = self.grpc_addr.expect("the test config has no [grpc]");
+        format!("http://{}", addr)
+    }
+
+    /// A client connected to `/ws`.
+    pub async fn client(&self) -> TestClient {
+        TestClient::connect(&self.ws_url()).await
+    }
+
+    /// A client connected to `/ws` that offers permessage-deflate.
+    pub async fn compressed_client(&self) -> TestClient {
+        TestClient::open(&self.ws_url(), true).await
+    }
+}
+
+impl TestServer {
+    /// Shuts down as the real server does, saving rooms if `[snapshot]` is
+    /// configured.
+    pub async fn stop(self) {
+        crate::snapshot::save_on_shutdown(&self.state).await;
+    }
+
+    /// Expires and archives rooms now, as the server does every minute.
+    pub async fn sweep(&self) {
+        crate::sweep(&self.state).await;
+    }
+
+    /// Replaces the config with one read from `toml`, as a SIGHUP reload
+    /// would.
+    pub fn reconfigure(&self, toml: &str) {
+        let config: Config = toml::from_str(toml).expect("invalid test config");
+        *self.state.config.write().unwrap() = std::sync::Arc::new(config);
+    }
+
+    /// Waits for the writes queued so far to reach storage.
+    pub async fn settle(&self) {
+        self.state.store_writer.settle().await;
+    }
+}
+
+/// The `authorization` header `[archive]` in `toml` signs an S3 request
+/// with, for checking against AWS's published examples.
+pub fn sign_s3(
+    toml: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+) -> String {
+    let config: Config = toml::from_str(toml).expect("invalid test config");
+    let archive = config.archive.expect("the test config has no [archive]");
+    crate::archive::authorization(&archive, method, path, headers, payload_hash)
+}
+
+/// The head and body of the HTTP request on `stream`, once its
+/// `content-length` has arrived; for tests standing in for a webhook or API.
+pub async fn read_request(stream: &mut TcpStream) -> (String, String) {
+    let mut request = Vec::new();
+    loop {
+        let mut chunk = [0; 4096];
+        let n = tokio::time::timeout(TIMEOUT, stream.read(&mut chunk))
+            .await
+            .expect("request incomplete")
+            .unwrap();
+        assert!(n > 0, "request incomplete");
+        request.extend_from_slice(&chunk[..n]);
+        let text = String::from_utf8_lossy(&request).into_owned();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            if body.len() == length {
+                return (head.to_string(), body.to_string());
+            }
+        }
+    }
+}
+
+async fn bind() -> tokio::net::TcpListener {
+    tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("failed to bind a test port")
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.state.request_shutdown();
+    }
+}
+
+/// One WebSocket connection. The assertion methods panic, failing the test,
+/// when the server doesn't answer as expected within [`TIMEOUT`].
+pub struct TestClient {
+    socket: WebSocketStream<Inflate<TcpStream>>,
+    /// Set when the server accepted permessage-deflate; everything this
+    /// client sends is then compressed.
+    deflate: Option<Deflate>,
+    /// The `seq` of the latest message received.
+    last_seq: u64,
+    /// The latest `resumeSecret` received.
+    resume_secret: String,
+}
+
+impl TestClient {
+    pub async fn connect(url: &str) -> Self {
+        Self::open(url, false).await
+    }
+
+    async fn open(url: &str, offer_deflate: bool) -> Self {
+        let mut request = url.into_client_request().expect("invalid WebSocket URL");
+        if offer_deflate {
+            request.headers_mut().insert(
+                header::SEC_WEBSOCKET_EXTENSIONS,
+                HeaderValue::from_static("permessage-deflate; client_max_window_bits"),
+            );
+        }
+        let addr = request.uri().authority().expect("URL has no host").as_str();
+        let stream = TcpStream::connect(addr).await.expect("TCP connect failed");
+        let (mut socket, response) = client_async(request, Inflate::new(stream, false))
+            .await
+            .expect("WebSocket connect failed");
+        let deflate = response
+            .headers()
+            .contains_key(header::SEC_WEBSOCKET_EXTENSIONS)
+            .then_some(Deflate { threshold: 0 });
+        socket.get_mut().enabled = deflate.is_some();
+        Self {
+            socket,
+            deflate,
+            last_seq: 0,
+            resume_secret: String::new(),
+        }
+    }
+
+    /// Whether the server agreed to compress this connection.
+    pub fn compressed(&self) -> bool {
+        self.deflate.is_some()
+    }
+
+    /// How many compressed messages have arrived from the server.
+    pub fn inflated(&self) -> usize {
+        self.socket.get_ref().inflated
+    }
+
+    pub async fn send(&mut self, message: Value) {
+        let text = message.to_string();
+        let message = match self.deflate {
+            Some(deflate) => deflate.message(text),
+            None => Message::Text(text),
+        };
+        self.socket
+            .send(message)
+            .await
+            .expect("WebSocket send failed");
+    }
+
+    /// Sends `text` as it is, JSON or not.
+    pub async fn send_text(&mut self, text: String) {
+        self.socket
+            .send(Message::Text(text))
+            .await
+            .expect("WebSocket send failed");
+    }
+
+    pub async fn send_binary(&mut self, data: Vec<u8>) {
+        self.socket
+            .send(Message::Binary(data))
+            .await
+            .expect("WebSocket send failed");
+    }
+
+    /// The next protocol message, whatever its type.
+    pub async fn recv(&mut self) -> Value {
+        let text = self.recv_text().await;
+        let message: Value = serde_json::from_str(&text).expect("server sent invalid JSON");
+        self.last_seq = message["seq"].as_u64().expect("message without a seq");
+        if let Some(secret) = message["secret"]
+            .as_str()
+            .filter(|_| message["type"] == "resumeSecret")
+        {
+            self.resume_secret = secret.to_string();
+        }
+        message
+    }
+
+    /// The next text frame as it was sent, for a connection that asked for
+    /// something other than JSON.
+    pub async fn recv_text(&mut self) -> String {
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, self.socket.next())
+                .await
+                .expect("timed out waiting for a message")
+                .expect("connection closed")
+                .expect("WebSocket receive failed");
+            if let Message::Text(text) = message {
+                return text;
+            }
+        }
+    }
+
+    /// Skips messages until one of type `kind` arrives, and returns it.
+    pub async fn expect(&mut self, kind: &str) -> Value {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        loop {
+            let message = tokio::time::timeout_at(deadline, self.recv())
+                .await
+                .unwrap_or_else(|_| panic!("timed out waiting for {:?}", kind));
+            if message["type"] == kind {
+                return message;
+            }
+        }
+    }
+
+    /// Fails if any message arrives within `wait`.
+    pub async fn expect_silence(&mut self, wait: Duration) {
+        if let Ok(Some(Ok(message))) = tokio::time::timeout(wait, self.socket.next()).await {
+            panic!("expected no message, got {:?}", message);
+        }
+    }
+
+    /// Fails unless the server closes the connection within [`TIMEOUT`];
+    /// messages before the close are skipped.
+    pub async fn expect_close(&mut self) {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        loop {
+            match tokio::time::timeout_at(deadline, self.socket.next()).await {
+                Err(_) => panic!("timed out waiting for the connection to close"),
+                Ok(None | Some(Ok(Message::Close(_))) | Some(Err(_))) => return,
+                Ok(Some(Ok(_))) => {}
+            }
+        }
+    }
+
+    /// The code and reason of the close frame the server sends within
+    /// [`TIMEOUT`]; messages before it are skipped.
+    pub async fn expect_close_frame(&mut self) -> (u16, String) {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        loop {
+            match tokio::time::timeout_at(deadline, self.socket.next()).await {
+                Err(_) => panic!("timed out waiting for a close frame"),
+                Ok(Some(Ok(Message::Close(Some(frame))))) => {
+                    return (frame.code.into(), frame.reason.into_owned())
+                }
+                Ok(None | Some(Ok(Message::Close(None))) | Some(Err(_))) => {
+                    panic!("connection closed without a close frame")
+                }
+                Ok(Some(Ok(_))) => {}
+            }
+        }
+    }
+
+    /// Joins (or creates) room `id` as `socket_id` and returns the room view
+    /// from the `gotRoom` reply.
+    pub async fn join(&mut self, id: &str, socket_id: &str) -> Value {
+        self.send(json!({"type": "fetchRoom", "id": id, "socketId": socket_id}))
+            .await;
+        self.expect("gotRoom").await["room"].take()
+    }
+
+    /// Proves ownership of `key`, as the GUI does with its stored key pair,
+    /// and returns the participant id it maps to.
+    pub async fn authenticate(&mut self, key: &SigningKey) -> String {
+        self.send(json!({"type": "getChallenge"})).await;
+        let challenge = self.expect("challenge").await["challenge"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let signature = key.sign(format!("typeto-auth:{}", challenge).as_bytes());
+        self.send(json!({
+            "type": "authenticate",
+            "publicKey": URL_SAFE_NO_PAD.encode(key.verifying_key().as_bytes()),
+            "signature": URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+        }))
+        .await;
+        self.expect("authenticated").await["identity"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// The secret to resume this connection's place with.
+    pub fn resume_secret(&self) -> &str {
+        &self.resume_secret
+    }
+
+    /// Acknowledges everything received so far.
+    pub async fn ack(&mut self) {
+        self.send(json!({"type": "ack", "seq": self.last_seq}))
+            .await;
+    }
+
+    /// Rejoins room `id` as `socket_id` after a dropped connection that had
+    /// seen messages up to `last_seq` and was sent `secret`.
+    pub async fn resume(&mut self, id: &str, socket_id: &str, secret: &str, last_seq: u64) {
+        self.resume_secret = secret.to_string();
+        self.send(json!({
+            "type": "fetchRoom",
+            "id": id,
+            "socketId": socket_id,
+            "resumeSecret": secret,
+            "lastSeq": last_seq,
+        }))
+        .await;
+    }
+
+    /// Makes the server's handling of this connection panic.
+    pub async fn crash_handler(&mut self) {
+        self.socket
+            .send(Message::Text(PANIC.to_string()))
+            .await
+            .unwrap();
+    }
+
+    /// Makes the task of the room this connection is in panic.
+    pub async fn crash_room(&mut self) {
+        self.socket
+            .send(Message::Text(ROOM_PANIC.to_string()))
+            .await
+            .unwrap();
+    }
+
+    /// Types `key` at `cursor_pos`, as the GUI does for each keystroke.
+    pub async fn key(&mut self, key: &str, cursor_pos: usize) {
+        self.send(json!({"type": "keyPress", "key": key, "cursorPos": cursor_pos}))
+            .await;
+    }
+
+    /// Types `text` from the start of an empty line.
+    pub async fn type_text(&mut self, text: &str) {
+        for (pos, ch) in text.chars().enumerate() {
+            let key = if ch == ' ' {
+                "Space".to_string()
+            } else {
+                ch.to_string()
+            };
+            self.key(&key, pos).await;
+        }
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.socket.close(None).await;
+    }
+}
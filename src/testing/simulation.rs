@@ -0,0 +1,233 @@
+//! Seeded simulation of one room: clients join, type, leave and come back in
+//! a random interleaving, each keeping its own copy of the room in a
+//! `typeto_client_core::Session`, and at the end every copy must match what
+//! the server would send in a fresh `gotRoom`. Inboxes are small enough that
+//! clients which read slowly miss deltas and have to be resynced.
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::{sync::Arc, time::Instant};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use typeto_client_core::{char_len, Session};
+
+use crate::{Counters, Room, RoomMode, RoomSettingsUpdate, ServerMessage, MAX_PARTICIPANTS};
+
+/// The same capacity as a real connection's queue.
+const INBOX: usize = 32;
+/// What clients type: Latin and Hebrew, so lines mix directions and
+/// characters of more than one byte.
+const LETTERS: &str = "abcdefghijklmnopqrstuvwxyzאבגדהוזחטיכלמנסעפצקרשת";
+
+struct SimClient {
+    id: String,
+    inbox: Option<broadcast::Receiver<ServerMessage>>,
+    traffic: Arc<Counters>,
+    /// Chance that a turn to read or type is taken; low for a flaky
+    /// connection, which lets its inbox back up.
+    pace: f64,
+    /// The room as this client currently believes it to be, kept by the
+    /// same code the other clients use.
+    session: Session,
+    /// `degraded` messages received.
+    degraded: usize,
+}
+
+impl SimClient {
+    fn connected(&self) -> bool {
+        self.inbox.is_some()
+    }
+
+    /// Reads up to `limit` pending messages.
+    fn read(&mut self, limit: usize, seed: u64) {
+        for _ in 0..limit {
+            let Some(inbox) = &mut self.inbox else {
+                return;
+            };
+            let message = match inbox.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Lagged(missed)) => {
+                    self.traffic.dropped(missed);
+                    continue;
+                }
+                Err(err) => panic!("seed {}: {} inbox: {}", seed, self.id, err),
+            };
+            if matches!(message, ServerMessage::Degraded { .. }) {
+                self.degraded += 1;
+            }
+            let text = serde_json::to_string(&message).unwrap();
+            if let Err(err) = self.session.receive(&text) {
+                panic!("seed {}: {} read {}: {}", seed, self.id, text, err);
+            }
+        }
+    }
+}
+
+pub struct Simulation {
+    seed: u64,
+    rng: StdRng,
+    room: Room,
+    clients: Vec<SimClient>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let clients = (0..MAX_PARTICIPANTS)
+            .map(|i| SimClient {
+                id: format!("client{}", i),
+                inbox: None,
+                traffic: Arc::new(Counters::default()),
+                pace: if rng.gen_bool(0.5) { 1.0 } else { 0.1 },
+                session: Session::default(),
+                degraded: 0,
+            })
+            .collect();
+        Self {
+            seed,
+            rng,
+            room: Room::new("sim".to_string()),
+            clients,
+        }
+    }
+
+    /// Plays `steps` random events.
+    pub fn run(&mut self, steps: usize) {
+        for _ in 0..steps {
+            let i = self.rng.gen_range(0..self.clients.len());
+            match self.rng.gen_range(0..100) {
+                0..=4 => self.toggle(i),
+                5..=9 => self.change_mode(i),
+                10..=12 => self.room.resync(Instant::now()),
+                _ if !self.rng.gen_bool(self.clients[i].pace) => {}
+                13..=39 => {
+                    let limit = self.rng.gen_range(1..8);
+                    self.clients[i].read(limit, self.seed);
+                }
+                _ => self.type_key(i),
+            }
+        }
+    }
+
+    /// Connects a client that is away, or disconnects one that is here, the
+    /// way `handle_websocket` does.
+    fn toggle(&mut self, i: usize) {
+        let id = self.clients[i].id.clone();
+        if self.clients[i].connected() {
+            self.clients[i].inbox = None;
+            self.room.leave(&id);
+            if !self.room.participants.is_empty() {
+                self.room.notify_participants();
+            }
+        } else {
+            let (tx, rx) = broadcast::channel(INBOX);
+            let traffic = Arc::new(Counters::default());
+            self.clients[i].traffic = traffic.clone();
+            if self
+                .room
+                .join(id.clone(), tx, traffic, Arc::default())
+                .is_ok()
+            {
+                self.clients[i].inbox = Some(rx);
+                self.clients[i].session = Session::new(Some("sim".to_string()), Some(id));
+                self.clients[i].session.opened();
+                self.room.notify_participants();
+            }
+        }
+    }
+
+    fn change_mode(&mut self, i: usize) {
+        if !self.clients[i].connected() {
+            return;
+        }
+        let mode = if self.rng.gen_bool(0.5) {
+            RoomMode::Live
+        } else {
+            RoomMode::Line
+        };
+        let update = RoomSettingsUpdate {
+            mode: Some(mode),
+            ..RoomSettingsUpdate::default()
+        };
+        let Some(sender) = self
+            .room
+            .participants
+            .iter()
+            .find(|p| p.id == self.clients[i].id)
+            .map(|p| p.sender.clone())
+        else {
+            return;
+        };
+        if self.room.update_settings(&sender, update).is_ok() {
+            self.room.notify_participants();
+        }
+    }
+
+    /// Types one key. A client reads everything sent to it first: the GUI
+    /// echoes its own keys locally, so a snapshot arriving after a local key
+    /// press would roll its line back until the next snapshot.
+    fn type_key(&mut self, i: usize) {
+        let seed = self.seed;
+        let client = &mut self.clients[i];
+        if !client.connected() {
+            return;
+        }
+        client.read(usize::MAX, seed);
+        if client.session.room().is_none() {
+            return;
+        }
+        let len = char_len(client.session.own_line());
+        let key = match self.rng.gen_range(0..100) {
+            0..=4 => "Enter".to_string(),
+            5..=14 => "Backspace".to_string(),
+            15..=19 => "Delete".to_string(),
+            20..=21 => "CtrlK".to_string(),
+            22..=24 => "ArrowLeft".to_string(),
+            25..=39 => "Space".to_string(),
+            _ => LETTERS
+                .chars()
+                .collect::<Vec<_>>()
+                .choose(&mut self.rng)
+                .unwrap()
+                .to_string(),
+        };
+        client.session.set_cursor(self.rng.gen_range(0..=len));
+        let Some(press) = client.session.press(&key) else {
+            return;
+        };
+        let cursor_pos = press["cursorPos"].as_u64().map(|pos| pos as usize);
+        self.room.handle_keypress(&client.id, &key, cursor_pos);
+        self.room.flush_outbox();
+    }
+
+    /// Delivers everything still in flight, then the snapshots due to
+    /// clients that missed deltas.
+    pub fn settle(&mut self) {
+        for client in &mut self.clients {
+            client.read(usize::MAX, self.seed);
+        }
+        self.room.resync(Instant::now());
+        for client in &mut self.clients {
+            client.read(usize::MAX, self.seed);
+        }
+    }
+
+    /// `degraded` messages received by all clients so far.
+    pub fn degraded(&self) -> usize {
+        self.clients.iter().map(|client| client.degraded).sum()
+    }
+
+    /// Panics, naming the seed, unless every connected client's copy of the
+    /// room is what the server would render for it.
+    pub fn assert_converged(&self) {
+        for client in self.clients.iter().filter(|client| client.connected()) {
+            let expected = serde_json::to_value(self.room.render(&client.id)).unwrap();
+            assert_eq!(
+                client.session.room(),
+                Some(&expected),
+                "seed {}: {} diverged from the server",
+                self.seed,
+                client.id
+            );
+        }
+    }
+}
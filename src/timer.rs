@@ -0,0 +1,64 @@
+//! Countdowns for timed writing sprints and games: the room's owner sends
+//! `startTimer { seconds, lock }` and everyone is sent `timer`, with the time
+//! left so that clocks that disagree still count down together. When it runs
+//! out everyone is sent `timerEnded`, and with `lock` nobody can type until
+//! the owner starts another timer or cancels with `seconds: 0`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::SharedState;
+
+/// The longest countdown.
+pub const MAX_SECS: u64 = 24 * 3600;
+
+/// A running countdown.
+#[derive(Debug, Clone, Copy)]
+pub struct Timer {
+    pub ends_at: SystemTime,
+    /// Whether typing locks when it runs out.
+    pub lock: bool,
+    /// Tells this countdown's end from that of one it replaced.
+    pub number: u64,
+}
+
+impl Timer {
+    pub fn view(&self, now: SystemTime) -> TimerView {
+        TimerView {
+            ends_at_ms: self
+                .ends_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            ms_left: self
+                .ends_at
+                .duration_since(now)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            lock: self.lock,
+        }
+    }
+}
+
+/// A countdown as clients see it.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+pub struct TimerView {
+    /// Unix milliseconds, by the server's clock.
+    ends_at_ms: u64,
+    /// Left when this was sent; count down from this rather than from
+    /// `endsAtMs` if the clocks may disagree.
+    ms_left: u64,
+    lock: bool,
+}
+
+/// Ends countdown `number` in room `room_id` after `duration`, unless it was
+/// replaced or cancelled first.
+pub async fn run_out(state: SharedState, room_id: String, number: u64, duration: Duration) {
+    tokio::time::sleep(duration).await;
+    if let Some(room) = state.rooms.get(&room_id) {
+        room.cast(move |room| room.end_timer(number));
+    }
+}
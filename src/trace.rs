@@ -0,0 +1,35 @@
+//! Ids for matching what a user saw to the server's logs. Every HTTP
+//! response carries `X-Request-Id`, the caller's own if it sent a usable one,
+//! and everything logged while serving it, a WebSocket included, is in a span
+//! with that id. A client can add its own `traceId` when it joins a room;
+//! `error` messages quote both, so a bug report can say which connection it
+//! was.
+
+use hyper::{header::HeaderValue, Body, Request};
+
+use crate::generate_random_string;
+
+pub const HEADER: &str = "x-request-id";
+const MAX_ID_LEN: usize = 64;
+
+/// Whether a client-supplied id is short and plain enough to log as is.
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// `req`'s `X-Request-Id` if it is usable, or a new one.
+pub fn request_id(req: &Request<Body>) -> String {
+    req.headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| valid_id(id))
+        .map_or_else(|| generate_random_string(16), str::to_string)
+}
+
+pub fn header_value(id: &str) -> HeaderValue {
+    HeaderValue::from_str(id).expect("request ids are plain ASCII")
+}
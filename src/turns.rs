@@ -0,0 +1,42 @@
+//! Turn-based rooms, for collaborative storytelling and the like: with mode
+//! `turns` only the participant whose turn it is may type, the others being
+//! answered with a notice. Enter passes the turn to the next participant in
+//! the order they joined, and so does `turnSecs` running out if the owner
+//! set it. Everyone is sent `turn` when it passes.
+
+use std::time::Duration;
+
+use crate::{Room, SharedState};
+
+/// The longest a turn can be limited to.
+pub const MAX_TURN_SECS: u64 = 3600;
+
+/// Whose turn follows `holder`'s among `order`; the first's, if `holder`
+/// isn't there.
+pub fn next<'a>(order: &[&'a str], holder: Option<&str>) -> Option<&'a str> {
+    let after = holder
+        .and_then(|holder| order.iter().position(|id| *id == holder))
+        .map_or(0, |at| at + 1);
+    order.get(after).or(order.first()).copied()
+}
+
+/// Starts the time limit of the turn `room` just passed, if it has one.
+pub fn schedule(state: &SharedState, room: &mut Room) {
+    if let Some((number, duration)) = room.take_turn_timer() {
+        tokio::spawn(run_out(state.clone(), room.id.clone(), number, duration));
+    }
+}
+
+/// Passes turn `number` in room `room_id` on after `duration`, unless it was
+/// passed on first.
+async fn run_out(state: SharedState, room_id: String, number: u64, duration: Duration) {
+    tokio::time::sleep(duration).await;
+    if let Some(room) = state.rooms.get(&room_id) {
+        let state = state.clone();
+        room.cast(move |room| {
+            if room.end_turn(number) {
+                schedule(&state, room);
+            }
+        });
+    }
+}
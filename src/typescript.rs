@@ -0,0 +1,62 @@
+//! TypeScript definitions of the WebSocket protocol, generated from the
+//! types the server (de)serializes so that `gui/protocol.d.ts` can't drift
+//! from them. The tests compare the two; `UPDATE_TYPES=1 cargo test` writes
+//! the file afresh.
+
+use ts_rs::{Config, TS};
+
+use crate::{
+    ansi::StreamFormat,
+    archived::ArchivedView,
+    assets::GuiBundles,
+    bidi::Direction,
+    granularity::Granularity,
+    locale::LocaleHint,
+    race::{RaceProgress, RaceView},
+    search::{Hit, Results},
+    sound::KeySound,
+    timer::TimerView,
+    ClientMessage, RoomMode, RoomSettings, RoomSettingsUpdate, RoomView, ServerMessage, UserPrefs,
+};
+
+const HEADER: &str = "\
+// Generated from the server's Rust types; do not edit. Regenerate with
+// `UPDATE_TYPES=1 cargo test --test typescript`.
+//
+// Every ServerMessage also carries `seq`, its number for `ack` and resuming.
+";
+
+/// The contents of `gui/protocol.d.ts`.
+pub fn declarations() -> String {
+    // Counts and times fit in a JavaScript number.
+    let config = Config::new().with_large_int("number");
+    let declarations = [
+        ClientMessage::decl(&config),
+        ServerMessage::decl(&config),
+        RoomView::decl(&config),
+        ArchivedView::decl(&config),
+        GuiBundles::decl(&config),
+        RoomSettings::decl(&config),
+        RoomSettingsUpdate::decl(&config),
+        RoomMode::decl(&config),
+        LocaleHint::decl(&config),
+        Direction::decl(&config),
+        StreamFormat::decl(&config),
+        Granularity::decl(&config),
+        KeySound::decl(&config),
+        UserPrefs::decl(&config),
+        Results::decl(&config),
+        Hit::decl(&config),
+        TimerView::decl(&config),
+        RaceView::decl(&config),
+        RaceProgress::decl(&config),
+    ];
+    let mut out = HEADER.to_string();
+    for declaration in declarations {
+        out.push('\n');
+        out.push_str("export ");
+        out.push_str(&declaration);
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,301 @@
+//! Webhooks: room events, as the audit log records them, posted to operator
+//! URLs. Each hook is scoped to rooms whose ids match one of its globs (`*`
+//! any run of characters, `?` any one), and optionally to some events, so
+//! that e.g. only `oncall-*` rooms page someone. Hooks are managed under
+//! `/admin/webhooks` and kept in memory, like invitations. Every delivery is
+//! signed with the hook's secret as `X-Typeto-Signature: sha256=<hex HMAC of
+//! the body>`; deliveries that fail are logged and not retried.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    audit::{AuditEntry, AuditEvent},
+    generate_random_string, json_response, SharedState,
+};
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HOOKS: usize = 100;
+/// The events a hook can ask for: those of the audit log that are about a
+/// room.
+const EVENTS: [&str; 9] = [
+    "roomCreated",
+    "joined",
+    "settingsChanged",
+    "roomExpired",
+    "roomArchived",
+    "roomRevived",
+    "roomImported",
+    "honeypotTouched",
+    "reported",
+];
+
+#[derive(Deserialize, Default, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookRequest {
+    /// Where events are posted, `http://` or `https://`.
+    url: String,
+    /// Globs of the room ids the hook is for; `["*"]` for every room.
+    rooms: Vec<String>,
+    /// The events the hook is for; every room event if empty.
+    events: Vec<String>,
+    /// Signs deliveries; generated if unset.
+    secret: Option<String>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Hook {
+    id: String,
+    url: String,
+    rooms: Vec<String>,
+    events: Vec<String>,
+    #[serde(skip)]
+    secret: String,
+}
+
+impl Hook {
+    fn wants(&self, room: &str, event: &str) -> bool {
+        self.rooms.iter().any(|pattern| glob(pattern, room))
+            && (self.events.is_empty() || self.events.iter().any(|wanted| wanted == event))
+    }
+}
+
+#[derive(Default)]
+pub struct Webhooks(Mutex<BTreeMap<String, Hook>>);
+
+impl Webhooks {
+    /// Adds a hook as `request` describes, returning it and its secret.
+    pub fn add(&self, request: HookRequest) -> Result<(Hook, String), String> {
+        if !request.url.starts_with("http://") && !request.url.starts_with("https://") {
+            return Err("url must be an http:// or https:// URL.".to_string());
+        }
+        if request.url.parse::<hyper::Uri>().is_err() {
+            return Err(format!("{:?} is not a URL.", request.url));
+        }
+        if request.rooms.is_empty() {
+            return Err("rooms needs at least one glob; \"*\" is every room.".to_string());
+        }
+        let known: HashSet<&str> = EVENTS.into_iter().collect();
+        if let Some(event) = request
+            .events
+            .iter()
+            .find(|event| !known.contains(event.as_str()))
+        {
+            return Err(format!(
+                "{:?} is not a room event; they are {}.",
+                event,
+                EVENTS.join(", ")
+            ));
+        }
+        let secret = match request.secret {
+            Some(secret) if secret.is_empty() => {
+                return Err("secret must not be empty.".to_string())
+            }
+            Some(secret) => secret,
+            None => generate_random_string(32),
+        };
+        let mut hooks = self.0.lock().unwrap();
+        if hooks.len() >= MAX_HOOKS {
+            return Err(format!("There can be at most {} webhooks.", MAX_HOOKS));
+        }
+        let hook = Hook {
+            id: generate_random_string(8),
+            url: request.url,
+            rooms: request.rooms,
+            events: request.events,
+            secret: secret.clone(),
+        };
+        hooks.insert(hook.id.clone(), hook.clone());
+        Ok((hook, secret))
+    }
+
+    pub fn list(&self) -> Vec<Hook> {
+        self.0.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn remove(&self, id: &str) -> bool {
+        self.0.lock().unwrap().remove(id).is_some()
+    }
+
+    /// Gives hook `id` a fresh secret and returns it.
+    pub fn rotate_secret(&self, id: &str) -> Option<String> {
+        let mut hooks = self.0.lock().unwrap();
+        let hook = hooks.get_mut(id)?;
+        hook.secret = generate_random_string(32);
+        Some(hook.secret.clone())
+    }
+
+    fn wanting(&self, room: &str, event: &str) -> Vec<Hook> {
+        self.0
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|hook| hook.wants(room, event))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Handles `GET /admin/webhooks`.
+pub fn list(state: &SharedState) -> Response<Body> {
+    json_response(StatusCode::OK, json!({"webhooks": state.webhooks.list()}))
+}
+
+/// Handles `POST /admin/webhooks`; the reply is the only time the secret is
+/// shown.
+pub fn add(state: &SharedState, request: HookRequest) -> Response<Body> {
+    let (hook, secret) = match state.webhooks.add(request) {
+        Ok(added) => added,
+        Err(err) => return json_response(StatusCode::BAD_REQUEST, json!({"error": err})),
+    };
+    info!("Webhook {} added for rooms {:?}", hook.id, hook.rooms);
+    state.audit.record(AuditEvent::Admin {
+        action: "webhookAdded".to_string(),
+        detail: Some(hook.id.clone()),
+    });
+    let mut reply = json!(hook);
+    reply["secret"] = json!(secret);
+    json_response(StatusCode::OK, reply)
+}
+
+/// Handles `DELETE /admin/webhooks/{id}`.
+pub fn remove(state: &SharedState, id: &str) -> Response<Body> {
+    if !state.webhooks.remove(id) {
+        return json_response(StatusCode::NOT_FOUND, json!({"error": "No such webhook."}));
+    }
+    info!("Webhook {} removed", id);
+    state.audit.record(AuditEvent::Admin {
+        action: "webhookRemoved".to_string(),
+        detail: Some(id.to_string()),
+    });
+    json_response(StatusCode::OK, json!({"removed": id}))
+}
+
+/// Handles `POST /admin/webhooks/{id}/secret`.
+pub fn rotate_secret(state: &SharedState, id: &str) -> Response<Body> {
+    let Some(secret) = state.webhooks.rotate_secret(id) else {
+        return json_response(StatusCode::NOT_FOUND, json!({"error": "No such webhook."}));
+    };
+    info!("Webhook {} has a new secret", id);
+    state.audit.record(AuditEvent::Admin {
+        action: "webhookSecretRotated".to_string(),
+        detail: Some(id.to_string()),
+    });
+    json_response(StatusCode::OK, json!({"id": id, "secret": secret}))
+}
+
+/// Delivers room events to the hooks that want them, for as long as the
+/// server runs.
+pub async fn run(state: SharedState) {
+    let mut events = state.audit.subscribe();
+    loop {
+        let entry = match events.recv().await {
+            Ok(entry) => entry,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Webhooks fell behind and missed {} event(s)", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Some(room) = entry.event.room() else {
+            continue;
+        };
+        let name = event_name(&entry.event);
+        for hook in state.webhooks.wanting(room, name) {
+            tokio::spawn(deliver(state.clone(), hook, entry.clone()));
+        }
+    }
+}
+
+async fn deliver(state: SharedState, hook: Hook, entry: AuditEntry) {
+    let body = serde_json::to_vec(&entry).unwrap_or_default();
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(hook.secret.as_bytes()).expect("HMAC takes any key length");
+    mac.update(&body);
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&hook.url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("x-typeto-webhook", &hook.id)
+        .header("x-typeto-event", event_name(&entry.event))
+        .header("x-typeto-signature", format!("sha256={}", signature))
+        .body(Body::from(body))
+        .unwrap();
+    match tokio::time::timeout(DELIVERY_TIMEOUT, state.http_client.request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => {}
+        Ok(Ok(response)) => warn!(
+            "Webhook {} to {} returned {}",
+            hook.id,
+            hook.url,
+            response.status()
+        ),
+        Ok(Err(err)) => warn!("Webhook {} to {} failed: {}", hook.id, hook.url, err),
+        Err(_) => warn!("Webhook {} to {} timed out", hook.id, hook.url),
+    }
+}
+
+/// The `event` field `event` is serialized with.
+fn event_name(event: &AuditEvent) -> &'static str {
+    match event {
+        AuditEvent::RoomCreated { .. } => "roomCreated",
+        AuditEvent::Joined { .. } => "joined",
+        AuditEvent::SettingsChanged { .. } => "settingsChanged",
+        AuditEvent::RoomExpired { .. } => "roomExpired",
+        AuditEvent::RoomArchived { .. } => "roomArchived",
+        AuditEvent::RoomRevived { .. } => "roomRevived",
+        AuditEvent::RoomImported { .. } => "roomImported",
+        AuditEvent::Banned { .. } => "banned",
+        AuditEvent::HoneypotTouched { .. } => "honeypotTouched",
+        AuditEvent::Admin { .. } => "admin",
+        AuditEvent::Reported { .. } => "reported",
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for any one.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much of the text it has taken.
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,49 @@
+use serde_json::json;
+use typeto_client_core::Session;
+use typeto_server::testing::{TestClient, TestServer};
+
+/// Feeds `session` the server's messages until `done` holds.
+async fn read_until(
+    client: &mut TestClient,
+    session: &mut Session,
+    done: impl Fn(&Session) -> bool,
+) {
+    while !done(session) {
+        let message = client.recv().await;
+        session.receive(&message.to_string()).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn a_session_resumes_after_its_connection_drops() {
+    let server = TestServer::start().await;
+    let mut alice = server.client().await;
+    alice.join("abc", "alice").await;
+
+    let mut session = Session::new(Some("abc".to_string()), Some("bob".to_string()));
+    let mut bob = server.client().await;
+    bob.send(session.opened()).await;
+    read_until(&mut bob, &mut session, |session| session.room().is_some()).await;
+    for key in ["h", "i"] {
+        bob.send(session.press(key).unwrap()).await;
+    }
+    assert_eq!(alice.expect("keyPress").await["key"], "h");
+    assert_eq!(alice.expect("keyPress").await["key"], "i");
+    bob.send(session.ack().unwrap()).await;
+
+    // The connection drops without a close frame, and Alice carries on.
+    drop(bob);
+    session.closed(0);
+    alice.type_text("yo").await;
+    alice.key("Enter", 2).await;
+
+    let mut bob = server.client().await;
+    let hello = session.opened();
+    assert!(hello["lastSeq"].is_u64());
+    bob.send(hello).await;
+    read_until(&mut bob, &mut session, |session| {
+        session.room().unwrap()["messages"]["alice"] == json!(["yo", ""])
+    })
+    .await;
+    assert_eq!(session.own_line(), "hi");
+}
@@ -0,0 +1,136 @@
+#![cfg(feature = "discord")]
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use typeto_server::testing::{read_request, TestServer, TIMEOUT};
+
+async fn admin(server: &TestServer, method: &str, path: &str, body: Value) -> Value {
+    let request = hyper::Request::builder()
+        .method(method)
+        .uri(server.url(path))
+        .header("authorization", "Bearer secret")
+        .body(hyper::Body::from(body.to_string()))
+        .unwrap();
+    let response = hyper::Client::new().request(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let mut reply: Value = serde_json::from_slice(&body).unwrap();
+    reply["status"] = json!(status.as_u16());
+    reply
+}
+
+async fn next_payload(gateway: &mut WebSocketStream<TcpStream>) -> Value {
+    loop {
+        let message = tokio::time::timeout(TIMEOUT, gateway.next())
+            .await
+            .expect("nothing from the bot")
+            .unwrap()
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_bridge_carries_lines_both_ways() {
+    let gateway = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let rest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = TestServer::with_config(&format!(
+        "[admin]\ntoken = \"secret\"\n[discord]\ntoken = \"bot-token\"\n\
+         api_url = \"http://{}/api\"\ngateway_url = \"ws://{}/\"",
+        rest.local_addr().unwrap(),
+        gateway.local_addr().unwrap()
+    ))
+    .await;
+
+    let (stream, _) = tokio::time::timeout(TIMEOUT, gateway.accept())
+        .await
+        .expect("the bot didn't connect")
+        .unwrap();
+    let mut gateway = tokio_tungstenite::accept_async(stream).await.unwrap();
+    let hello = json!({"op": 10, "d": {"heartbeat_interval": 45000}});
+    gateway
+        .send(Message::Text(hello.to_string()))
+        .await
+        .unwrap();
+    let identify = next_payload(&mut gateway).await;
+    assert_eq!(identify["op"], 2);
+    assert_eq!(identify["d"]["token"], "bot-token");
+
+    let mut alice = server.client().await;
+    alice.join("huddle", "alice").await;
+    let refused = admin(
+        &server,
+        "POST",
+        "/admin/bridge/discord",
+        json!({"room": "huddle", "channel": "general"}),
+    )
+    .await;
+    assert_eq!(refused["status"], 400);
+    let bridged = admin(
+        &server,
+        "POST",
+        "/admin/bridge/discord",
+        json!({"room": "huddle", "channel": "42"}),
+    )
+    .await;
+    assert_eq!(bridged["status"], 200);
+    let room = alice.expect("gotRoom").await;
+    assert_eq!(room["room"]["participants"], 2);
+
+    // A line finished in the room is posted to the channel.
+    alice.type_text("hi *all*").await;
+    alice.key("Enter", 8).await;
+    let (mut post, _) = tokio::time::timeout(TIMEOUT, rest.accept())
+        .await
+        .expect("nothing posted")
+        .unwrap();
+    let (head, body) = read_request(&mut post).await;
+    post.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}")
+        .await
+        .unwrap();
+    assert!(head.starts_with("POST /api/channels/42/messages "));
+    assert!(head.contains("authorization: Bot bot-token"));
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["content"], "**alice**: hi \\*all\\*");
+
+    // What is said in the channel is typed into the room; bots are ignored.
+    for (author, content) in [
+        (json!({"username": "otherbot", "bot": true}), "beep"),
+        (json!({"username": "carol"}), "hello there"),
+    ] {
+        let event = json!({
+            "op": 0,
+            "s": 2,
+            "t": "MESSAGE_CREATE",
+            "d": {"channel_id": "42", "content": content, "author": author},
+        });
+        gateway
+            .send(Message::Text(event.to_string()))
+            .await
+            .unwrap();
+    }
+    let committed = loop {
+        let committed = alice.expect("committed").await;
+        if committed["source"] == "discord" {
+            break committed;
+        }
+    };
+    assert_eq!(committed["final"], "carol: hello there");
+
+    let listed = admin(&server, "GET", "/admin/bridge/discord", json!({})).await;
+    assert_eq!(
+        listed["bridges"],
+        json!([{"room": "huddle", "channel": "42"}])
+    );
+    let removed = admin(&server, "DELETE", "/admin/bridge/discord/huddle", json!({})).await;
+    assert_eq!(removed["status"], 200);
+    let room = alice.expect("gotRoom").await;
+    assert_eq!(room["room"]["participants"], 1);
+}
@@ -0,0 +1,107 @@
+#![cfg(feature = "graphql")]
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+use typeto_server::testing::TestServer;
+
+async fn query(server: &TestServer, token: &str, query: &str) -> Value {
+    let request = hyper::Request::post(server.url("/graphql"))
+        .header("authorization", format!("Bearer {}", token))
+        .header("content-type", "application/json")
+        .body(hyper::Body::from(json!({ "query": query }).to_string()))
+        .unwrap();
+    let response = hyper::Client::new().request(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn rooms_and_transcripts_can_be_queried() {
+    let server = TestServer::with_config("[graphql]\n[admin]\ntoken = \"secret\"").await;
+    let mut alice = server.client().await;
+    alice.join("abc", "alice").await;
+    let token = alice.expect("creatorToken").await["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let mut bob = server.client().await;
+    bob.join("abc", "bob").await;
+    alice.type_text("hello").await;
+    alice.key("Enter", 5).await;
+    bob.expect("committed").await;
+
+    let reply = query(
+        &server,
+        &token,
+        r#"{ room(id: "abc") { id live participants ownerId mode }
+             transcript(id: "abc") { participant text } }"#,
+    )
+    .await;
+    assert_eq!(
+        reply["data"]["room"],
+        json!({"id": "abc", "live": true, "participants": ["alice", "bob"],
+               "ownerId": "alice", "mode": "LIVE"})
+    );
+    let lines = reply["data"]["transcript"].as_array().unwrap();
+    assert!(lines.contains(&json!({"participant": "alice", "text": "hello"})));
+
+    let reply = query(&server, "wrong", r#"{ transcript(id: "abc") { text } }"#).await;
+    assert!(reply["data"].is_null());
+    assert!(!reply["errors"].as_array().unwrap().is_empty());
+    let reply = query(&server, &token, "{ rooms { id } }").await;
+    assert!(reply["data"].is_null());
+    let reply = query(&server, "secret", "{ rooms { id } }").await;
+    assert_eq!(reply["data"]["rooms"], json!([{"id": "abc"}]));
+}
+
+#[tokio::test]
+async fn finished_lines_can_be_subscribed_to() {
+    let server = TestServer::with_config("[graphql]").await;
+    let mut alice = server.client().await;
+    alice.join("abc", "alice").await;
+    let token = alice.expect("creatorToken").await["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let mut request = server
+        .ws_url()
+        .replace("/ws", "/graphql")
+        .into_client_request()
+        .unwrap();
+    request.headers_mut().insert(
+        "sec-websocket-protocol",
+        "graphql-transport-ws".parse().unwrap(),
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    let frame = |message: Value| Message::Text(message.to_string());
+    socket
+        .send(frame(
+            json!({"type": "connection_init", "payload": {"token": token}}),
+        ))
+        .await
+        .unwrap();
+    let ack: Value =
+        serde_json::from_str(socket.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(ack["type"], "connection_ack");
+    socket
+        .send(frame(json!({"type": "subscribe", "id": "1", "payload": {
+            "query": r#"subscription { roomLines(id: "abc") { participant text } }"#
+        }})))
+        .await
+        .unwrap();
+    // Give the subscription time to start before the line is finished.
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    alice.type_text("hi").await;
+    alice.key("Enter", 2).await;
+    let next: Value =
+        serde_json::from_str(socket.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(next["type"], "next");
+    assert_eq!(
+        next["payload"]["data"]["roomLines"],
+        json!({"participant": "alice", "text": "hi"})
+    );
+}
@@ -0,0 +1,122 @@
+#![cfg(feature = "grpc")]
+
+use futures_util::StreamExt;
+use std::time::Duration;
+use tonic::{Code, Streaming};
+use typeto_server::{
+    grpc::proto::{
+        event::Event as Kind, typeto_client::TypetoClient, Event, JoinRoomRequest,
+        SendKeypressRequest, StreamEventsRequest,
+    },
+    testing::{TestServer, TIMEOUT},
+};
+
+async fn next(events: &mut Streaming<Event>) -> Kind {
+    tokio::time::timeout(TIMEOUT, events.next())
+        .await
+        .expect("no event in time")
+        .unwrap()
+        .unwrap()
+        .event
+        .unwrap()
+}
+
+#[tokio::test]
+async fn grpc_clients_share_rooms_with_websocket_ones() {
+    let server = TestServer::with_config("[grpc]").await;
+    let mut alice = server.client().await;
+    alice.join("abc", "alice").await;
+
+    let mut client = TypetoClient::connect(server.grpc_url()).await.unwrap();
+    let joined = client
+        .join_room(JoinRoomRequest {
+            room_id: "abc".to_string(),
+            participant_id: "bob".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(joined.participant_id, "bob");
+    let mut events = client
+        .stream_events(StreamEventsRequest {
+            session: joined.session.clone(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let Kind::Room(room) = next(&mut events).await else {
+        panic!("expected the room first");
+    };
+    assert_eq!(room.id, "abc");
+    assert_eq!(room.participants, 2);
+    assert_eq!(room.other_participant_ids, ["alice"]);
+    alice.expect("gotRoom").await;
+
+    for (pos, key) in ["h", "i", "Enter"].into_iter().enumerate() {
+        client
+            .send_keypress(SendKeypressRequest {
+                session: joined.session.clone(),
+                key: key.to_string(),
+                cursor_pos: Some(pos as u64),
+                rev: None,
+            })
+            .await
+            .unwrap();
+    }
+    assert_eq!(alice.expect("committed").await["final"], "hi");
+
+    alice.type_text("yo").await;
+    alice.key("Enter", 2).await;
+    loop {
+        if let Kind::Committed(committed) = next(&mut events).await {
+            assert_eq!(committed.source, "alice");
+            assert_eq!(committed.r#final, "yo");
+            break;
+        }
+    }
+
+    drop(events);
+    let update = alice.expect("gotRoom").await;
+    assert_eq!(update["room"]["participants"], 1);
+    let status = client
+        .send_keypress(SendKeypressRequest {
+            session: joined.session,
+            key: "x".to_string(),
+            cursor_pos: None,
+            rev: None,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn grpc_joins_are_refused_like_websocket_ones() {
+    let server =
+        TestServer::with_config("[grpc]\n[rooms]\nduplicate_connections = \"reject\"").await;
+    let mut alice = server.client().await;
+    alice.join("abc", "alice").await;
+    alice.expect("creatorToken").await;
+
+    let mut client = TypetoClient::connect(server.grpc_url()).await.unwrap();
+    let status = client
+        .join_room(JoinRoomRequest {
+            room_id: "abc".to_string(),
+            participant_id: "alice".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::AlreadyExists);
+    let status = client
+        .join_room(JoinRoomRequest {
+            room_id: String::new(),
+            participant_id: String::new(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    alice.expect_silence(Duration::from_millis(200)).await;
+}
@@ -0,0 +1,37 @@
+use typeto_server::testing::Simulation;
+
+/// Set `TYPETO_SIM_SEED` to replay the seed a failure names.
+fn seeds() -> std::ops::Range<u64> {
+    match std::env::var("TYPETO_SIM_SEED") {
+        Ok(seed) => {
+            let seed: u64 = seed.parse().expect("TYPETO_SIM_SEED must be a number");
+            seed..seed + 1
+        }
+        Err(_) => 0..64,
+    }
+}
+
+#[test]
+fn every_client_ends_up_with_the_servers_view() {
+    for seed in seeds() {
+        let mut sim = Simulation::new(seed);
+        sim.run(3000);
+        sim.settle();
+        sim.assert_converged();
+    }
+}
+
+#[test]
+fn clients_that_fall_behind_are_told() {
+    // Slow readers fall behind in every few seeds.
+    let degraded: usize = seeds()
+        .take(8)
+        .map(|seed| {
+            let mut sim = Simulation::new(seed);
+            sim.run(3000);
+            sim.settle();
+            sim.degraded()
+        })
+        .sum();
+    assert!(degraded > 0);
+}
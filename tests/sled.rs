@@ -0,0 +1,156 @@
+#![cfg(feature = "sled")]
+
+use ed25519_dalek::SigningKey;
+use serde_json::{json, Value};
+use std::{future::Future, path::PathBuf};
+use typeto_server::testing::{TestClient, TestServer};
+
+/// A database directory of its own for each test, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("typeto-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        Self(path)
+    }
+
+    fn config(&self) -> String {
+        format!("[storage]\nbackend = \"sled\"\npath = {:?}", self.0)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs `test` on a runtime of its own. Dropping the runtime stops every
+/// task of the servers it started, which releases the database for the
+/// next run, as a restart would.
+fn run<F: Future>(test: F) -> F::Output {
+    tokio::runtime::Runtime::new().unwrap().block_on(test)
+}
+
+async fn status(server: &TestServer, method: &str, path: &str, token: &str) -> u16 {
+    let request = hyper::Request::builder()
+        .method(method)
+        .uri(server.url(path))
+        .header("authorization", format!("Bearer {}", token))
+        .body(hyper::Body::empty())
+        .unwrap();
+    let response = hyper::Client::new().request(request).await.unwrap();
+    response.status().as_u16()
+}
+
+/// `id`'s finished lines in the room view `room`.
+fn lines_of(room: &Value, id: &str) -> Vec<String> {
+    let lines: Vec<String> = room["messages"][id]
+        .as_array()
+        .map(|lines| {
+            lines
+                .iter()
+                .map(|line| line.as_str().unwrap().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    lines[..lines.len().saturating_sub(1)].to_vec()
+}
+
+async fn authenticated(server: &TestServer) -> (TestClient, String) {
+    let mut client = server.client().await;
+    let id = client.authenticate(&SigningKey::from_bytes(&[8; 32])).await;
+    (client, id)
+}
+
+#[test]
+fn history_prefs_and_short_links_survive_a_restart() {
+    let dir = TempDir::new("sled-restart");
+    // More than a compressed segment's worth, so the history reads back
+    // from a segment and the plain lines after it.
+    let typed: Vec<String> = (0..300).map(|n| format!("line {}", n)).collect();
+
+    let shortcode = run(async {
+        let server = TestServer::with_config(&dir.config()).await;
+        let (mut alice, _) = authenticated(&server).await;
+        alice
+            .send(json!({"type": "setPrefs", "prefs": {"theme": "dark"}}))
+            .await;
+        alice.expect("prefs").await;
+        let room = alice.join("kept", "").await;
+        for line in &typed {
+            alice.type_text(line).await;
+            alice.key("Enter", line.len()).await;
+        }
+        // Answered once every key press before it is done.
+        alice.send(json!({"type": "getPrefs"})).await;
+        alice.expect("prefs").await;
+        server.settle().await;
+        room["shortcode"].as_str().unwrap().to_string()
+    });
+
+    run(async {
+        let server = TestServer::with_config(&dir.config()).await;
+        let (mut alice, id) = authenticated(&server).await;
+        alice.send(json!({"type": "getPrefs"})).await;
+        assert_eq!(alice.expect("prefs").await["prefs"]["theme"], "dark");
+        assert_eq!(
+            status(&server, "GET", &format!("/j/{}", shortcode), "").await,
+            302
+        );
+        let room = alice.join("kept", "").await;
+        assert_eq!(lines_of(&room, &id), typed);
+    });
+}
+
+#[test]
+fn deleted_rooms_are_gone_after_a_restart() {
+    let dir = TempDir::new("sled-delete");
+    let (shortcode, token) = run(async {
+        let server = TestServer::with_config(&dir.config()).await;
+        let (mut alice, _) = authenticated(&server).await;
+        alice
+            .send(json!({"type": "setPrefs", "prefs": {"theme": "dark"}}))
+            .await;
+        alice.expect("prefs").await;
+        let room = alice.join("gone", "").await;
+        let token = alice.expect("creatorToken").await["token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        alice.type_text("bye").await;
+        alice.key("Enter", 3).await;
+        alice.send(json!({"type": "getPrefs"})).await;
+        alice.expect("prefs").await;
+        server.settle().await;
+        let shortcode = room["shortcode"].as_str().unwrap().to_string();
+        (shortcode, token)
+    });
+
+    run(async {
+        let server = TestServer::with_config(&dir.config()).await;
+        assert_eq!(
+            status(&server, "DELETE", "/api/rooms/gone", &token).await,
+            200
+        );
+        assert_eq!(
+            status(&server, "GET", &format!("/j/{}", shortcode), "").await,
+            404
+        );
+    });
+
+    run(async {
+        let server = TestServer::with_config(&dir.config()).await;
+        assert_eq!(
+            status(&server, "GET", &format!("/j/{}", shortcode), "").await,
+            404
+        );
+        let (mut alice, id) = authenticated(&server).await;
+        // Preferences belong to the person, not the room.
+        alice.send(json!({"type": "getPrefs"})).await;
+        assert_eq!(alice.expect("prefs").await["prefs"]["theme"], "dark");
+        let room = alice.join("gone", "").await;
+        assert!(lines_of(&room, &id).is_empty());
+    });
+}
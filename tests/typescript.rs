@@ -0,0 +1,17 @@
+use typeto_server::typescript::declarations;
+
+const PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/gui/protocol.d.ts");
+
+#[test]
+fn gui_types_match_the_protocol() {
+    let generated = declarations();
+    if std::env::var_os("UPDATE_TYPES").is_some() {
+        std::fs::write(PATH, &generated).unwrap();
+        return;
+    }
+    let committed = std::fs::read_to_string(PATH).unwrap_or_default();
+    assert!(
+        committed == generated,
+        "gui/protocol.d.ts is out of date; run `UPDATE_TYPES=1 cargo test --test typescript`"
+    );
+}